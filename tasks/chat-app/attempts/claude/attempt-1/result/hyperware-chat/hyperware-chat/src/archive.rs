@@ -0,0 +1,103 @@
+use std::collections::{HashMap, HashSet};
+
+use hyperware_process_lib::vfs::{create_drive, create_file, open_file};
+use hyperware_process_lib::PackageId;
+use serde::{Deserialize, Serialize};
+
+use crate::{ChatMessage, Group, GroupMessage, StoredMessage};
+
+const ARCHIVES_DRIVE: &str = "archives";
+
+// Bumped whenever the archive layout changes; older versions must stay importable
+pub const ARCHIVE_VERSION: u32 = 1;
+
+// Everything needed to restore a node's chat history. Attachment metadata
+// travels with its message, but the file bytes stay in the attachments drive.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Archive {
+    pub version: u32,
+    pub exported_by: String,
+    pub exported_at: u64,
+    // contact -> messages with that contact
+    pub messages: HashMap<String, Vec<ChatMessage>>,
+    pub groups: Vec<Group>,
+    // group id -> messages in that group
+    pub group_messages: HashMap<String, Vec<GroupMessage>>,
+}
+
+// What an import added; importing the same archive twice adds nothing
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ImportSummary {
+    pub messages_added: usize,
+    pub groups_added: usize,
+    pub group_messages_added: usize,
+}
+
+// Add the messages from `incoming` that `existing` doesn't have yet, matched
+// by id, keeping the conversation in timestamp order. Returns the ids of the
+// messages added.
+pub fn merge_messages<M: StoredMessage>(existing: &mut Vec<M>, incoming: Vec<M>) -> HashSet<String> {
+    let known: HashSet<String> = existing.iter().map(|message| message.id().to_string()).collect();
+    let mut added = HashSet::new();
+    for message in incoming {
+        if !known.contains(message.id()) && added.insert(message.id().to_string()) {
+            existing.push(message);
+        }
+    }
+    if !added.is_empty() {
+        existing.sort_by_key(|message| message.timestamp());
+    }
+    added
+}
+
+// Archive files are named by export time, which also keeps names safe to use as paths
+pub fn is_valid_name(name: &str) -> bool {
+    name.strip_prefix("archive-")
+        .and_then(|rest| rest.strip_suffix(".json"))
+        .map(|stamp| !stamp.is_empty() && stamp.chars().all(|c| c.is_ascii_digit()))
+        .unwrap_or(false)
+}
+
+pub struct ArchiveStore {
+    drive: String,
+}
+
+impl ArchiveStore {
+    pub fn new(package_id: PackageId) -> anyhow::Result<Self> {
+        let drive = create_drive(package_id, ARCHIVES_DRIVE, None)?;
+        Ok(Self { drive })
+    }
+
+    fn path(&self, name: &str) -> String {
+        format!("{}/{}", self.drive, name)
+    }
+
+    // Write an archive to the drive, returning its file name and VFS path
+    pub fn write(&self, archive: &Archive) -> anyhow::Result<(String, String)> {
+        let name = format!("archive-{}.json", archive.exported_at);
+        let path = self.path(&name);
+        let file = create_file(&path, None)?;
+        file.write(&serde_json::to_vec(archive)?)?;
+        Ok((name, path))
+    }
+
+    pub fn read(&self, name: &str) -> anyhow::Result<Vec<u8>> {
+        if !is_valid_name(name) {
+            return Err(anyhow::anyhow!("invalid archive name {}", name));
+        }
+        Ok(open_file(&self.path(name), false, None)?.read()?)
+    }
+}
+
+// Parse an archive file, refusing versions newer than this build understands
+pub fn parse(bytes: &[u8]) -> anyhow::Result<Archive> {
+    let archive: Archive = serde_json::from_slice(bytes)?;
+    if archive.version > ARCHIVE_VERSION {
+        return Err(anyhow::anyhow!(
+            "archive version {} is newer than supported version {}",
+            archive.version,
+            ARCHIVE_VERSION
+        ));
+    }
+    Ok(archive)
+}
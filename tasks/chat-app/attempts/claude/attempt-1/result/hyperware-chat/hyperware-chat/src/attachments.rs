@@ -0,0 +1,335 @@
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hyperware_process_lib::vfs::{create_drive, create_file, open_file, remove_file};
+use hyperware_process_lib::{PackageId, Request};
+use serde::{Deserialize, Serialize};
+
+use crate::hyperware::process::hyperware_chat::{
+    AttachmentChunk, Request as HyperwareChatRequest, SendRequest,
+};
+use crate::schema::RecordDb;
+
+const ATTACHMENTS_DRIVE: &str = "attachments";
+const ATTACHMENTS_DB: &str = "attachments";
+const USAGE_DB: &str = "attachment_usage";
+const PARTIALS_DB: &str = "attachment_partials";
+// Uploads in progress are kept as one map under this key so they can be swept
+const PARTIALS_KEY: &str = "partials";
+
+// Largest file we accept, from our UI or from a peer
+pub const MAX_ATTACHMENT_SIZE: u64 = 10 * 1024 * 1024;
+// Total bytes we accept from any one peer
+const MAX_PEER_BYTES: u64 = 200 * 1024 * 1024;
+// Bytes carried in each attachment-chunk blob
+const CHUNK_SIZE: usize = 256 * 1024;
+// Seconds a peer has to answer each step of a transfer
+const TRANSFER_TIMEOUT_SECS: u64 = 30;
+// Seconds an upload from a peer may go without a chunk before it is dropped
+const PARTIAL_TIMEOUT_SECS: u64 = 120;
+
+// Context of the requests of an outgoing transfer; the rest of the context is
+// the Transfer they belong to
+pub const TRANSFER_CONTEXT: &[u8] = b"attachment-transfer:";
+
+// Images are raster formats only: SVG can carry script
+const ALLOWED_MIME_TYPES: [&str; 8] = [
+    "image/png",
+    "image/jpeg",
+    "image/gif",
+    "image/webp",
+    "image/bmp",
+    "text/plain",
+    "application/pdf",
+    "application/zip",
+];
+const ALLOWED_MIME_PREFIXES: [&str; 2] = ["audio/", "video/"];
+
+// Metadata for a stored file; the bytes live in the attachments drive under `id`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Attachment {
+    pub id: String,
+    pub name: String,
+    pub mime: String,
+    pub size: u64,
+}
+
+// A direct message waiting for its attachments to reach the peer. It rides in
+// the context of each request, so the answer says what to send next.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Transfer {
+    pub target: String,
+    // Attachments still to copy; the first is in progress
+    pub pending: Vec<Attachment>,
+    // Bytes of the first pending attachment the peer already has
+    pub offset: u64,
+    // Sent once every attachment is across
+    pub message: SendRequest,
+}
+
+impl Transfer {
+    fn context(&self) -> anyhow::Result<Vec<u8>> {
+        let mut context = TRANSFER_CONTEXT.to_vec();
+        context.extend(serde_json::to_vec(self)?);
+        Ok(context)
+    }
+
+    pub fn from_context(context: &[u8]) -> Option<Self> {
+        serde_json::from_slice(context.strip_prefix(TRANSFER_CONTEXT)?).ok()
+    }
+
+    // The step after the peer took the chunk at `offset`
+    pub fn advance(mut self) -> Self {
+        if let Some(attachment) = self.pending.first() {
+            self.offset = (self.offset + CHUNK_SIZE as u64).min(attachment.size);
+            if self.offset == attachment.size {
+                self.pending.remove(0);
+                self.offset = 0;
+            }
+        }
+        self
+    }
+}
+
+// An upload from a peer that hasn't finished; its bytes are given back to the
+// peer's quota if it fails or stalls
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PartialUpload {
+    from: String,
+    written: u64,
+    // Seconds since the epoch of the last chunk
+    updated_at: u64,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+pub fn is_allowed_mime(mime: &str) -> bool {
+    // Parameters such as `; charset=utf-8` don't change the type
+    let essence = mime.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    ALLOWED_MIME_TYPES.contains(&essence.as_str())
+        || ALLOWED_MIME_PREFIXES
+            .iter()
+            .any(|prefix| essence.starts_with(prefix))
+}
+
+// Ids double as file names and are prefixed with the uploading node, so a peer
+// can only ever write its own files
+pub fn is_valid_id(id: &str, owner: &str) -> bool {
+    id.strip_prefix(owner)
+        .and_then(|rest| rest.strip_prefix('-'))
+        .map(|rest| !rest.is_empty() && rest.chars().all(|c| c.is_ascii_digit()))
+        .unwrap_or(false)
+}
+
+pub struct AttachmentStore {
+    drive: String,
+    db: RecordDb<Attachment>,
+    // peer -> bytes it has written to our drive
+    usage_db: RecordDb<u64>,
+    // attachment id -> upload still in progress
+    partials_db: RecordDb<HashMap<String, PartialUpload>>,
+}
+
+impl AttachmentStore {
+    pub fn new(package_id: PackageId) -> anyhow::Result<Self> {
+        let drive = create_drive(package_id.clone(), ATTACHMENTS_DRIVE, None)?;
+        let db = RecordDb::open(package_id.clone(), ATTACHMENTS_DB)?;
+        let usage_db = RecordDb::open(package_id.clone(), USAGE_DB)?;
+        let partials_db = RecordDb::open(package_id, PARTIALS_DB)?;
+        Ok(Self {
+            drive,
+            db,
+            usage_db,
+            partials_db,
+        })
+    }
+
+    fn path(&self, id: &str) -> String {
+        format!("{}/{}", self.drive, id)
+    }
+
+    // Only attachments whose bytes have fully arrived have metadata
+    pub fn get(&self, id: &str) -> anyhow::Result<Option<Attachment>> {
+        self.db.get(id)
+    }
+
+    pub fn read(&self, attachment: &Attachment) -> anyhow::Result<Vec<u8>> {
+        Ok(open_file(&self.path(&attachment.id), false, None)?.read()?)
+    }
+
+    // Store a complete file uploaded from our UI
+    pub fn save(&self, attachment: &Attachment, bytes: &[u8]) -> anyhow::Result<()> {
+        let file = create_file(&self.path(&attachment.id), None)?;
+        file.write(bytes)?;
+        self.db.set(&attachment.id, attachment)
+    }
+
+    fn used(&self, peer: &str) -> anyhow::Result<u64> {
+        Ok(self.usage_db.get(peer)?.unwrap_or(0))
+    }
+
+    fn partials(&self) -> anyhow::Result<HashMap<String, PartialUpload>> {
+        Ok(self.partials_db.get(PARTIALS_KEY)?.unwrap_or_default())
+    }
+
+    // Drop an unfinished upload by `from` and give its bytes back
+    fn abandon(&self, partials: &mut HashMap<String, PartialUpload>, id: &str, from: &str) -> anyhow::Result<()> {
+        if partials.get(id).is_some_and(|partial| partial.from == from) {
+            let partial = partials.remove(id).unwrap();
+            let used = self.used(&partial.from)?.saturating_sub(partial.written);
+            self.usage_db.set(&partial.from, &used)?;
+            // The file may never have been created
+            let _ = remove_file(&self.path(id), None);
+        }
+        Ok(())
+    }
+
+    // Append one chunk sent by `from`; chunks must arrive in order. A chunk
+    // that can't be taken ends the upload it belongs to.
+    pub fn receive_chunk(&self, from: &str, chunk: &AttachmentChunk, bytes: &[u8]) -> anyhow::Result<()> {
+        let mut partials = self.partials()?;
+        let result = self.append_chunk(from, chunk, bytes, &mut partials);
+        if result.is_err() {
+            self.abandon(&mut partials, &chunk.id, from)?;
+        }
+        self.partials_db.set(PARTIALS_KEY, &partials)?;
+        result
+    }
+
+    fn append_chunk(
+        &self,
+        from: &str,
+        chunk: &AttachmentChunk,
+        bytes: &[u8],
+        partials: &mut HashMap<String, PartialUpload>,
+    ) -> anyhow::Result<()> {
+        if chunk.size > MAX_ATTACHMENT_SIZE {
+            return Err(anyhow::anyhow!("attachment {} is too large", chunk.id));
+        }
+        if !is_allowed_mime(&chunk.mime) {
+            return Err(anyhow::anyhow!("attachment type {} is not allowed", chunk.mime));
+        }
+        let end = chunk.offset + bytes.len() as u64;
+        if end > chunk.size {
+            return Err(anyhow::anyhow!("chunk overruns attachment {}", chunk.id));
+        }
+        // A restarted upload gives back what the previous attempt wrote
+        if chunk.offset == 0 {
+            self.abandon(partials, &chunk.id, from)?;
+        }
+        let used = self.used(from)?;
+        if used + bytes.len() as u64 > MAX_PEER_BYTES {
+            return Err(anyhow::anyhow!("{} has used its attachment quota", from));
+        }
+
+        let path = self.path(&chunk.id);
+        let mut file = if chunk.offset == 0 {
+            create_file(&path, None)?
+        } else {
+            let file = open_file(&path, false, None)?;
+            let written = file.metadata()?.len;
+            if written != chunk.offset {
+                return Err(anyhow::anyhow!(
+                    "expected chunk at {} for attachment {}, got {}",
+                    written,
+                    chunk.id,
+                    chunk.offset
+                ));
+            }
+            file
+        };
+        file.append(bytes)?;
+        self.usage_db.set(from, &(used + bytes.len() as u64))?;
+
+        if end == chunk.size {
+            // Finished files keep counting against the peer's quota
+            partials.remove(&chunk.id);
+            let attachment = Attachment {
+                id: chunk.id.clone(),
+                name: chunk.name.clone(),
+                mime: chunk.mime.clone(),
+                size: chunk.size,
+            };
+            self.db.set(&attachment.id, &attachment)?;
+        } else {
+            let partial = partials.entry(chunk.id.clone()).or_insert(PartialUpload {
+                from: from.to_string(),
+                written: 0,
+                updated_at: 0,
+            });
+            partial.written += bytes.len() as u64;
+            partial.updated_at = now_secs();
+        }
+        Ok(())
+    }
+
+    // Drop uploads whose sender stopped sending, e.g. because it went offline
+    pub fn expire_partials(&self) -> anyhow::Result<()> {
+        let mut partials = self.partials()?;
+        let now = now_secs();
+        let stalled: Vec<(String, String)> = partials
+            .iter()
+            .filter(|(_, partial)| partial.updated_at + PARTIAL_TIMEOUT_SECS < now)
+            .map(|(id, partial)| (id.clone(), partial.from.clone()))
+            .collect();
+        if stalled.is_empty() {
+            return Ok(());
+        }
+        for (id, from) in stalled {
+            self.abandon(&mut partials, &id, &from)?;
+        }
+        self.partials_db.set(PARTIALS_KEY, &partials)
+    }
+
+    // Copy a message's attachments to hyperware-chat on `target`, then the
+    // message itself. Each step is sent when the peer answers the one before,
+    // through the main loop, so nothing waits on the transfer.
+    pub fn start_transfer(&self, target: &str, attachments: Vec<Attachment>, message: SendRequest) -> anyhow::Result<()> {
+        self.send_next(Transfer {
+            target: target.to_string(),
+            pending: attachments,
+            offset: 0,
+            message,
+        })
+    }
+
+    pub fn send_next(&self, transfer: Transfer) -> anyhow::Result<()> {
+        let request = Request::new()
+            .target((transfer.target.as_str(), "hyperware-chat", "hyperware-chat", "template.os"))
+            .expects_response(TRANSFER_TIMEOUT_SECS)
+            .context(transfer.context()?);
+        let Some(attachment) = transfer.pending.first() else {
+            request
+                .body(HyperwareChatRequest::Send(transfer.message.clone()))
+                .send()?;
+            return Ok(());
+        };
+
+        // An empty file still needs one chunk to be recorded by the peer
+        let len = (attachment.size - transfer.offset).min(CHUNK_SIZE as u64) as usize;
+        let mut bytes = vec![0; len];
+        let mut file = open_file(&self.path(&attachment.id), false, None)?;
+        file.seek(SeekFrom::Start(transfer.offset))?;
+        let read = file.read_at(&mut bytes)?;
+        if read != len {
+            return Err(anyhow::anyhow!("attachment {} is shorter than its size", attachment.id));
+        }
+        request
+            .body(HyperwareChatRequest::AttachmentChunk(AttachmentChunk {
+                id: attachment.id.clone(),
+                name: attachment.name.clone(),
+                mime: attachment.mime.clone(),
+                size: attachment.size,
+                offset: transfer.offset,
+            }))
+            .blob_bytes(bytes)
+            .send()?;
+        Ok(())
+    }
+}
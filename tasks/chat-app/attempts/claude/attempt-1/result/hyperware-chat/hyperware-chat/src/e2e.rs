@@ -0,0 +1,430 @@
+use std::collections::HashSet;
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use hkdf::Hkdf;
+use hyperware_process_lib::vfs::{create_drive, create_file, open_dir, open_file};
+use hyperware_process_lib::PackageId;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey as ExchangeKey, StaticSecret};
+
+use crate::hyperware::process::hyperware_chat::{EncryptedContent, PublicKeys, SenderKey as WitSenderKey};
+use crate::schema::RecordDb;
+
+const KEYS_DRIVE: &str = "keys";
+const IDENTITY_FILE: &str = "identity.json";
+const PEER_KEYS_DB: &str = "peer_keys";
+const SENDER_KEYS_DB: &str = "sender_keys";
+const KEY_HOLDERS_DB: &str = "sender_key_holders";
+
+// Context of a request carrying our sender key; the rest of the context is
+// the KeyDelivery it belongs to
+pub const SENDER_KEY_CONTEXT: &[u8] = b"sender-key:";
+// Context of a key exchange that runs in the background, for checking group
+// events members relay
+pub const KEY_EXCHANGE_CONTEXT: &[u8] = b"key-exchange";
+
+const NONCE_LEN: usize = 12;
+
+// Our long-term key pairs, as stored in the keys drive
+#[derive(Serialize, Deserialize)]
+struct IdentityFile {
+    signing_key: [u8; 32],
+    exchange_key: [u8; 32],
+}
+
+struct Identity {
+    signing: SigningKey,
+    exchange: StaticSecret,
+}
+
+impl Identity {
+    fn generate() -> Self {
+        let mut signing_key = [0u8; 32];
+        let mut exchange_key = [0u8; 32];
+        OsRng.fill_bytes(&mut signing_key);
+        OsRng.fill_bytes(&mut exchange_key);
+        Self::from_file(IdentityFile {
+            signing_key,
+            exchange_key,
+        })
+    }
+
+    fn from_file(file: IdentityFile) -> Self {
+        Self {
+            signing: SigningKey::from_bytes(&file.signing_key),
+            exchange: StaticSecret::from(file.exchange_key),
+        }
+    }
+
+    fn to_file(&self) -> IdentityFile {
+        IdentityFile {
+            signing_key: self.signing.to_bytes(),
+            exchange_key: self.exchange.to_bytes(),
+        }
+    }
+}
+
+// Symmetric key a node encrypts its group messages with. Each rotation bumps
+// the generation so members can tell which key a message used.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SenderKey {
+    pub generation: u32,
+    pub key: [u8; 32],
+}
+
+// Which of our keys a sender key request carried, so its answer can mark
+// the key delivered
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyDelivery {
+    pub group_id: String,
+    pub generation: u32,
+}
+
+impl KeyDelivery {
+    pub fn context(&self) -> anyhow::Result<Vec<u8>> {
+        let mut context = SENDER_KEY_CONTEXT.to_vec();
+        context.extend(serde_json::to_vec(self)?);
+        Ok(context)
+    }
+
+    pub fn from_context(context: &[u8]) -> Option<Self> {
+        serde_json::from_slice(context.strip_prefix(SENDER_KEY_CONTEXT)?).ok()
+    }
+}
+
+// What a signature covers: the ciphertext plus the context it belongs to,
+// so a payload can't be replayed as a different message
+fn signed_bytes(nonce: &[u8], ciphertext: &[u8], aad: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(24 + nonce.len() + ciphertext.len() + aad.len());
+    for part in [nonce, ciphertext, aad] {
+        bytes.extend_from_slice(&(part.len() as u64).to_be_bytes());
+        bytes.extend_from_slice(part);
+    }
+    bytes
+}
+
+fn to_key(bytes: &[u8]) -> anyhow::Result<[u8; 32]> {
+    bytes
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("keys must be 32 bytes, got {}", bytes.len()))
+}
+
+// Encrypt under `key` and sign the result with `signing`
+fn seal(
+    signing: &SigningKey,
+    key: &[u8; 32],
+    plaintext: &[u8],
+    aad: &[u8],
+    key_generation: Option<u32>,
+) -> anyhow::Result<EncryptedContent> {
+    let mut nonce = [0u8; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+    let ciphertext = ChaCha20Poly1305::new(Key::from_slice(key))
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg: plaintext, aad })
+        .map_err(|_| anyhow::anyhow!("failed to encrypt"))?;
+    let signature = signing.sign(&signed_bytes(&nonce, &ciphertext, aad));
+    Ok(EncryptedContent {
+        nonce: nonce.to_vec(),
+        ciphertext,
+        signature: signature.to_bytes().to_vec(),
+        key_generation,
+    })
+}
+
+// Check the signature against `verifying`, then decrypt under `key`
+fn open(
+    verifying: &VerifyingKey,
+    key: &[u8; 32],
+    payload: &EncryptedContent,
+    aad: &[u8],
+) -> anyhow::Result<Vec<u8>> {
+    let signature = Signature::from_slice(&payload.signature)?;
+    verifying
+        .verify(&signed_bytes(&payload.nonce, &payload.ciphertext, aad), &signature)
+        .map_err(|_| anyhow::anyhow!("bad signature"))?;
+    if payload.nonce.len() != NONCE_LEN {
+        return Err(anyhow::anyhow!("bad nonce"));
+    }
+    ChaCha20Poly1305::new(Key::from_slice(key))
+        .decrypt(
+            Nonce::from_slice(&payload.nonce),
+            Payload {
+                msg: &payload.ciphertext,
+                aad,
+            },
+        )
+        .map_err(|_| anyhow::anyhow!("failed to decrypt message"))
+}
+
+// Context bound into a direct or group message
+pub fn message_aad(author: &str, conversation: &str, message_id: &str) -> Vec<u8> {
+    format!("message\n{}\n{}\n{}", author, conversation, message_id).into_bytes()
+}
+
+// Context bound into an edit of a message
+pub fn edit_aad(author: &str, conversation: &str, message_id: &str) -> Vec<u8> {
+    format!("edit\n{}\n{}\n{}", author, conversation, message_id).into_bytes()
+}
+
+fn sender_key_aad(group_id: &str, generation: u32, from: &str, to: &str) -> Vec<u8> {
+    format!("sender-key\n{}\n{}\n{}\n{}", group_id, generation, from, to).into_bytes()
+}
+
+fn sender_key_id(group_id: &str, node: &str, generation: u32) -> String {
+    format!("{}:{}:{}", group_id, node, generation)
+}
+
+// Short hex digest of a node's public keys, for checking out of band
+pub fn fingerprint(keys: &PublicKeys) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(&keys.signing_key);
+    hasher.update(&keys.exchange_key);
+    hasher.finalize()[..16]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+// Key material for end-to-end encryption: our identity, the public keys of
+// peers (trusted on first use) and group sender keys
+pub struct E2eStore {
+    our_node: String,
+    identity: Identity,
+    peer_keys_db: RecordDb<PublicKeys>,
+    // "own:{group}" -> our current key; "{group}:{node}:{generation}" -> the
+    // key a member, us included, used for that generation
+    sender_keys_db: RecordDb<SenderKey>,
+    // "{group}:{our node}:{generation}" -> members that took that key of ours
+    key_holders_db: RecordDb<HashSet<String>>,
+}
+
+impl E2eStore {
+    pub fn new(package_id: PackageId, our_node: &str) -> anyhow::Result<Self> {
+        let drive = create_drive(package_id.clone(), KEYS_DRIVE, None)?;
+        let path = format!("{}/{}", drive, IDENTITY_FILE);
+        // Only a missing file means we have no identity yet. Any other failure
+        // must not replace keys our peers already trust.
+        let exists = open_dir(&drive, false, None)?
+            .read()?
+            .iter()
+            .any(|entry| entry.path.ends_with(&format!("/{}", IDENTITY_FILE)));
+        let identity = if exists {
+            let bytes = open_file(&path, false, None)?.read()?;
+            Identity::from_file(serde_json::from_slice(&bytes)?)
+        } else {
+            let identity = Identity::generate();
+            create_file(&path, None)?.write(&serde_json::to_vec(&identity.to_file())?)?;
+            identity
+        };
+
+        Ok(Self {
+            our_node: our_node.to_string(),
+            identity,
+            peer_keys_db: RecordDb::open(package_id.clone(), PEER_KEYS_DB)?,
+            sender_keys_db: RecordDb::open(package_id.clone(), SENDER_KEYS_DB)?,
+            key_holders_db: RecordDb::open(package_id, KEY_HOLDERS_DB)?,
+        })
+    }
+
+    pub fn public_keys(&self) -> PublicKeys {
+        PublicKeys {
+            signing_key: self.identity.signing.verifying_key().to_bytes().to_vec(),
+            exchange_key: ExchangeKey::from(&self.identity.exchange).to_bytes().to_vec(),
+        }
+    }
+
+    // Peer key methods
+    pub fn peer_keys(&self, node: &str) -> anyhow::Result<Option<PublicKeys>> {
+        self.peer_keys_db.get(node)
+    }
+
+    // Remember a peer's keys the first time we see them. Different keys later
+    // are refused until the old ones are forgotten.
+    pub fn remember_peer(&self, node: &str, keys: &PublicKeys) -> anyhow::Result<()> {
+        VerifyingKey::from_bytes(&to_key(&keys.signing_key)?)?;
+        to_key(&keys.exchange_key)?;
+        match self.peer_keys(node)? {
+            Some(known) if known.signing_key == keys.signing_key && known.exchange_key == keys.exchange_key => {
+                Ok(())
+            }
+            Some(_) => Err(anyhow::anyhow!("keys for {} changed; forget the old keys to accept them", node)),
+            None => self.peer_keys_db.set(node, keys),
+        }
+    }
+
+    pub fn forget_peer(&self, node: &str) -> anyhow::Result<()> {
+        self.peer_keys_db.delete(node)
+    }
+
+    // Sign a group event of ours, so other members can relay it
+    pub fn sign(&self, bytes: &[u8]) -> Vec<u8> {
+        self.identity.signing.sign(bytes).to_bytes().to_vec()
+    }
+
+    // Check that `author` signed a group event another member relayed
+    pub fn verify(&self, author: &str, bytes: &[u8], signature: &[u8]) -> anyhow::Result<()> {
+        let signature = Signature::from_slice(signature)?;
+        self.verifying_key(author)?
+            .verify(bytes, &signature)
+            .map_err(|_| anyhow::anyhow!("bad signature from {}", author))
+    }
+
+    fn verifying_key(&self, node: &str) -> anyhow::Result<VerifyingKey> {
+        if node == self.our_node {
+            return Ok(self.identity.signing.verifying_key());
+        }
+        let keys = self
+            .peer_keys(node)?
+            .ok_or_else(|| anyhow::anyhow!("no keys for {}", node))?;
+        Ok(VerifyingKey::from_bytes(&to_key(&keys.signing_key)?)?)
+    }
+
+    // Key shared with one peer: X25519 agreement, stretched with HKDF
+    fn direct_key(&self, peer: &str) -> anyhow::Result<[u8; 32]> {
+        let keys = self
+            .peer_keys(peer)?
+            .ok_or_else(|| anyhow::anyhow!("no keys for {}; exchange keys first", peer))?;
+        let shared = self
+            .identity
+            .exchange
+            .diffie_hellman(&ExchangeKey::from(to_key(&keys.exchange_key)?));
+        let (first, second) = if self.our_node.as_str() < peer {
+            (self.our_node.as_str(), peer)
+        } else {
+            (peer, self.our_node.as_str())
+        };
+        let mut key = [0u8; 32];
+        Hkdf::<Sha256>::new(None, shared.as_bytes())
+            .expand(format!("hyperware-chat direct {} {}", first, second).as_bytes(), &mut key)
+            .map_err(|_| anyhow::anyhow!("failed to derive key for {}", peer))?;
+        Ok(key)
+    }
+
+    fn seal(
+        &self,
+        key: &[u8; 32],
+        plaintext: &[u8],
+        aad: &[u8],
+        key_generation: Option<u32>,
+    ) -> anyhow::Result<EncryptedContent> {
+        seal(&self.identity.signing, key, plaintext, aad, key_generation)
+    }
+
+    fn open(
+        &self,
+        key: &[u8; 32],
+        payload: &EncryptedContent,
+        aad: &[u8],
+        author: &str,
+    ) -> anyhow::Result<Vec<u8>> {
+        open(&self.verifying_key(author)?, key, payload, aad)
+            .map_err(|e| anyhow::anyhow!("{} from {}", e, author))
+    }
+
+    // Direct conversation methods
+    pub fn encrypt_direct(&self, peer: &str, plaintext: &str, aad: &[u8]) -> anyhow::Result<EncryptedContent> {
+        self.seal(&self.direct_key(peer)?, plaintext.as_bytes(), aad, None)
+    }
+
+    pub fn decrypt_direct(&self, peer: &str, payload: &EncryptedContent, aad: &[u8]) -> anyhow::Result<String> {
+        let plaintext = self.open(&self.direct_key(peer)?, payload, aad, peer)?;
+        Ok(String::from_utf8(plaintext)?)
+    }
+
+    // Group sender key methods
+    pub fn own_sender_key(&self, group_id: &str) -> anyhow::Result<Option<SenderKey>> {
+        self.sender_keys_db.get(&format!("own:{}", group_id))
+    }
+
+    // Replace our key for a group. Members are given it as they sync the group.
+    pub fn rotate_sender_key(&self, group_id: &str) -> anyhow::Result<SenderKey> {
+        let generation = self
+            .own_sender_key(group_id)?
+            .map_or(0, |current| current.generation.wrapping_add(1));
+        let mut key = [0u8; 32];
+        OsRng.fill_bytes(&mut key);
+        let sender_key = SenderKey { generation, key };
+        // Kept by generation too, so our messages sealed with earlier keys
+        // still open when they come back to us in a group sync
+        self.sender_keys_db
+            .set(&sender_key_id(group_id, &self.our_node, generation), &sender_key)?;
+        self.sender_keys_db.set(&format!("own:{}", group_id), &sender_key)?;
+        Ok(sender_key)
+    }
+
+    pub fn has_sender_key(&self, peer: &str, group_id: &str, generation: u32) -> anyhow::Result<bool> {
+        Ok(self
+            .key_holders_db
+            .get(&sender_key_id(group_id, &self.our_node, generation))?
+            .is_some_and(|holders| holders.contains(peer)))
+    }
+
+    pub fn mark_sender_key_delivered(&self, peer: &str, group_id: &str, generation: u32) -> anyhow::Result<()> {
+        let id = sender_key_id(group_id, &self.our_node, generation);
+        let mut holders = self.key_holders_db.get(&id)?.unwrap_or_default();
+        if holders.insert(peer.to_string()) {
+            self.key_holders_db.set(&id, &holders)?;
+        }
+        Ok(())
+    }
+
+    // Our sender key, encrypted so only `peer` can read it
+    pub fn seal_sender_key(&self, peer: &str, group_id: &str, sender_key: &SenderKey) -> anyhow::Result<WitSenderKey> {
+        let aad = sender_key_aad(group_id, sender_key.generation, &self.our_node, peer);
+        Ok(WitSenderKey {
+            group_id: group_id.to_string(),
+            generation: sender_key.generation,
+            key: self.seal(&self.direct_key(peer)?, &sender_key.key, &aad, None)?,
+        })
+    }
+
+    // Decrypt and keep a sender key a group member gave us
+    pub fn receive_sender_key(&self, from: &str, sender_key: &WitSenderKey) -> anyhow::Result<()> {
+        let aad = sender_key_aad(&sender_key.group_id, sender_key.generation, from, &self.our_node);
+        let key = self.open(&self.direct_key(from)?, &sender_key.key, &aad, from)?;
+        self.sender_keys_db.set(
+            &sender_key_id(&sender_key.group_id, from, sender_key.generation),
+            &SenderKey {
+                generation: sender_key.generation,
+                key: to_key(&key)?,
+            },
+        )
+    }
+
+    pub fn encrypt_group(&self, group_id: &str, plaintext: &str, aad: &[u8]) -> anyhow::Result<EncryptedContent> {
+        let sender_key = self
+            .own_sender_key(group_id)?
+            .ok_or_else(|| anyhow::anyhow!("no sender key for group {}", group_id))?;
+        self.seal(&sender_key.key, plaintext.as_bytes(), aad, Some(sender_key.generation))
+    }
+
+    pub fn decrypt_group(
+        &self,
+        group_id: &str,
+        author: &str,
+        payload: &EncryptedContent,
+        aad: &[u8],
+    ) -> anyhow::Result<String> {
+        let generation = payload
+            .key_generation
+            .ok_or_else(|| anyhow::anyhow!("group message from {} has no key generation", author))?;
+        let mut sender_key = self.sender_keys_db.get(&sender_key_id(group_id, author, generation))?;
+        // Our keys from before generations were kept
+        if sender_key.is_none() && author == self.our_node {
+            sender_key = self
+                .own_sender_key(group_id)?
+                .filter(|key| key.generation == generation);
+        }
+        let sender_key = sender_key
+            .ok_or_else(|| {
+                anyhow::anyhow!("no key {} from {} for group {}", generation, author, group_id)
+            })?;
+        let plaintext = self.open(&sender_key.key, payload, aad, author)?;
+        Ok(String::from_utf8(plaintext)?)
+    }
+}
@@ -2,20 +2,48 @@ use std::collections::{HashMap, HashSet};
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use crate::hyperware::process::hyperware_chat::{
-    Request as HyperwareChatRequest, Response as HyperwareChatResponse, SendRequest, HyperwareChatMessage,
+    AttachmentChunk, ConversationRef, DeleteRequest, EditRequest, EncryptedContent, GroupDigest,
+    Request as HyperwareChatRequest,
+    ReadReceipt, Response as HyperwareChatResponse, ReactRequest, SearchHit as WitSearchHit,
+    SendRequest, SenderKey, HyperwareChatMessage, ThreadRequest, TypingNotice,
 };
-use hyperware_process_lib::kv::{self, Kv};
 use hyperware_process_lib::logging::{error, info, init_logging, Level};
 use hyperware_process_lib::{
     await_message, call_init, get_blob,
-    http::server::{
-        send_response, HttpBindingConfig, HttpServer, HttpServerRequest, StatusCode,
-        WsBindingConfig, WsMessageType,
-    },
-    println, Address, LazyLoadBlob, Message as ProcessMessage, Request, Response, PackageId,
+    http::server::{HttpBindingConfig, HttpServer, HttpServerRequest, WsBindingConfig},
+    println, timer, Address, Message as ProcessMessage, Request, Response, PackageId, SendError,
     hyperware::process::standard, // Added for our() function
 };
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+mod archive;
+mod attachments;
+mod e2e;
+mod limits;
+mod migrations;
+mod presence;
+mod reads;
+mod reconcile;
+mod router;
+mod routes;
+mod schema;
+mod search;
+mod sync;
+mod threads;
+mod ws;
+use archive::{Archive, ArchiveStore, ImportSummary};
+use attachments::{Attachment, AttachmentStore};
+use e2e::E2eStore;
+use limits::RateLimiter;
+use presence::PeerPresence;
+use reads::{ConversationSummary, ReadMarker};
+use reconcile::{ActionKind, Clock, GroupAction, GroupDelta, GroupMerge, MembershipChange};
+use routes::HttpContext;
+use schema::{RecordDb, SchemaMeta};
+use search::{SearchHit, SearchIndex, SearchQuery};
+use sync::{SyncCursor, SyncLog};
+use ws::{Conversation, WsClients, WsEvent};
 
 wit_bindgen::generate!({
     path: "target/wit",
@@ -25,32 +53,78 @@ wit_bindgen::generate!({
 });
 
 // Paths for API endpoints
+// HTTP API routes are declared in routes.rs
 const PROCESS_PATH: &str = "hyperware-chat:hyperware-chat:template.os";
-const HTTP_API_PATH: &str = "/messages";
 const WS_PATH: &str = "/";
-const CONTACTS_API_PATH: &str = "/contacts";
-const GROUPS_API_PATH: &str = "/groups";
-
-// We'll create the full paths in the init function since we can't use format!() in constants
 
 // Database names
 const MESSAGES_DB: &str = "messages";
 const GROUPS_DB: &str = "groups";
 const GROUP_MESSAGES_DB: &str = "group_messages";
+const GROUP_ACTIONS_DB: &str = "group_actions";
+const BLOCKED_DB: &str = "blocked";
+const CONVERSATIONS_DB: &str = "conversations";
+const READS_DB: &str = "reads";
+const MESSAGE_REQUESTS_DB: &str = "message_requests";
+const LAST_SEEN_DB: &str = "last_seen";
+const SETTINGS_DB: &str = "settings";
+const CONTACTS_DB: &str = "contacts";
+
+// The block list is stored as a single set under this key
+const BLOCKED_KEY: &str = "nodes";
+// KV can't list its keys, so the contacts and groups we hold are indexed here
+const CONTACTS_KEY: &str = "contacts";
+const GROUPS_KEY: &str = "groups";
+// Groups we left and forgot; their records stay so members still learn we left
+const DELETED_GROUPS_KEY: &str = "deleted_groups";
+// Nodes with messages waiting in quarantine, and nodes we accepted messages from
+const PENDING_KEY: &str = "pending";
+const APPROVED_KEY: &str = "approved";
+const SETTINGS_KEY: &str = "settings";
+// Saved contacts are stored as a single map of node -> name under this key
+const CONTACT_NAMES_KEY: &str = "names";
 
 #[derive(Debug, Clone, Serialize, Deserialize, process_macros::SerdeJsonInto)]
 struct NewMessage {
     hyperware_chat: String,
+    id: String,
     author: String,
     content: String,
     timestamp: u64,
+    reply_to: Option<String>,
+    attachments: Vec<Attachment>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 struct ChatMessage {
+    #[serde(default)]
+    id: String,
     author: String,
     content: String,
     timestamp: u64,
+    // Id of the message this one replies to
+    #[serde(default)]
+    reply_to: Option<String>,
+    #[serde(default)]
+    attachments: Vec<Attachment>,
+    // Ciphertext as received when end-to-end encrypted; `content` caches the
+    // decrypted text
+    #[serde(default)]
+    encrypted: Option<EncryptedContent>,
+    #[serde(default)]
+    edits: Vec<MessageEdit>,
+    #[serde(default)]
+    deleted: bool,
+    // emoji -> nodes that reacted with it
+    #[serde(default)]
+    reactions: HashMap<String, HashSet<String>>,
+}
+
+// A previous version of an edited message
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct MessageEdit {
+    content: String,
+    edited_at: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, process_macros::SerdeJsonInto)]
@@ -60,14 +134,167 @@ struct Group {
     members: HashSet<String>,
     created_by: String,
     created_at: u64,
+    // Every membership change, kept so members can reconcile after a partition
+    #[serde(default)]
+    membership: Vec<MembershipChange>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 struct GroupMessage {
     group_id: String,
+    #[serde(default)]
+    id: String,
     author: String,
     content: String,
     timestamp: u64,
+    #[serde(default)]
+    reply_to: Option<String>,
+    #[serde(default)]
+    attachments: Vec<Attachment>,
+    // Ciphertext as received when end-to-end encrypted; `content` caches the
+    // decrypted text
+    #[serde(default)]
+    encrypted: Option<EncryptedContent>,
+    #[serde(default)]
+    edits: Vec<MessageEdit>,
+    #[serde(default)]
+    deleted: bool,
+    #[serde(default)]
+    reactions: HashMap<String, HashSet<String>>,
+    // Position in the author's sequence of events in this group; 0 for
+    // messages stored before group sync
+    #[serde(default)]
+    seq: u64,
+    // The author's signature over the message as sent, which covers
+    // `body_digest` rather than the text, so other members can relay it
+    #[serde(default)]
+    body_digest: Vec<u8>,
+    #[serde(default)]
+    signature: Vec<u8>,
+}
+
+// Operations shared by direct and group messages
+trait StoredMessage {
+    fn id(&self) -> &str;
+    fn author(&self) -> &str;
+    fn content(&self) -> &str;
+    fn timestamp(&self) -> u64;
+    fn reply_to(&self) -> Option<&str>;
+    fn set_id(&mut self, id: String);
+    fn to_wit(&self) -> HyperwareChatMessage;
+    fn is_deleted(&self) -> bool;
+    fn edit(&mut self, content: String, edited_at: u64);
+    fn tombstone(&mut self);
+    fn react(&mut self, emoji: &str, node: &str, add: bool);
+}
+
+macro_rules! impl_stored_message {
+    ($message:ty) => {
+        impl StoredMessage for $message {
+            fn id(&self) -> &str {
+                &self.id
+            }
+
+            fn author(&self) -> &str {
+                &self.author
+            }
+
+            fn content(&self) -> &str {
+                &self.content
+            }
+
+            fn timestamp(&self) -> u64 {
+                self.timestamp
+            }
+
+            fn reply_to(&self) -> Option<&str> {
+                self.reply_to.as_deref()
+            }
+
+            fn set_id(&mut self, id: String) {
+                self.id = id;
+            }
+
+            fn to_wit(&self) -> HyperwareChatMessage {
+                HyperwareChatMessage {
+                    id: self.id.clone(),
+                    author: self.author.clone(),
+                    content: self.content.clone(),
+                    reply_to: self.reply_to.clone(),
+                }
+            }
+
+            fn is_deleted(&self) -> bool {
+                self.deleted
+            }
+
+            fn edit(&mut self, content: String, edited_at: u64) {
+                let previous = std::mem::replace(&mut self.content, content);
+                self.edits.push(MessageEdit {
+                    content: previous,
+                    edited_at,
+                });
+            }
+
+            // Deleted for everyone: keep the entry so ids stay stable, drop the text
+            fn tombstone(&mut self) {
+                self.content.clear();
+                self.encrypted = None;
+                self.edits.clear();
+                self.reactions.clear();
+                self.deleted = true;
+            }
+
+            fn react(&mut self, emoji: &str, node: &str, add: bool) {
+                if add {
+                    self.reactions
+                        .entry(emoji.to_string())
+                        .or_default()
+                        .insert(node.to_string());
+                } else if let Some(nodes) = self.reactions.get_mut(emoji) {
+                    nodes.remove(node);
+                    if nodes.is_empty() {
+                        self.reactions.remove(emoji);
+                    }
+                }
+            }
+        }
+    };
+}
+
+impl_stored_message!(ChatMessage);
+impl_stored_message!(GroupMessage);
+
+// A change to an existing message, made locally or by a peer
+#[derive(Debug, Clone)]
+enum MessageAction {
+    Edit { content: String },
+    Delete { for_everyone: bool },
+    React { emoji: String, add: bool },
+}
+
+// User preferences
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Settings {
+    // Tell peers when we have read their messages
+    #[serde(default = "default_true")]
+    read_receipts: bool,
+    // Encrypt what we send to other nodes end to end
+    #[serde(default)]
+    e2e: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            read_receipts: true,
+            e2e: false,
+        }
+    }
+}
+
+fn default_true() -> bool {
+    true
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, process_macros::SerdeJsonInto)]
@@ -76,39 +303,86 @@ struct CreateGroupRequest {
     members: Vec<String>,
 }
 
+// Request enum for deserializing HTTP server messages
 #[derive(Debug, Clone, Serialize, Deserialize, process_macros::SerdeJsonInto)]
-struct GroupMemberRequest {
+struct GroupMessageRequest {
     group_id: String,
-    member: String,
+    message: String,
+    #[serde(default)]
+    reply_to: Option<String>,
+    #[serde(default)]
+    attachments: Vec<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, process_macros::SerdeJsonInto)]
-struct SendGroupMessageRequest {
-    group_id: String,
-    message: String,
+struct Contact {
+    id: String,
+    name: String,
 }
 
-// Wrapper for HTTP responses
-#[derive(Debug, Serialize, Deserialize)]
-struct ApiResponse<T> {
-    success: bool,
-    data: Option<T>,
-    error: Option<String>,
+#[derive(Debug, Clone, Serialize, Deserialize, process_macros::SerdeJsonInto)]
+struct BlockNodeRequest {
+    node: String,
 }
 
-// Request enum for deserializing HTTP server messages
 #[derive(Debug, Clone, Serialize, Deserialize, process_macros::SerdeJsonInto)]
-struct GroupMessageRequest {
-    group_id: String,
-    message: String,
+struct SubscribeRequest {
+    conversations: Vec<Conversation>,
 }
 
+// A reconnecting client's cursors; conversations it doesn't list are only
+// reported, not replayed
 #[derive(Debug, Clone, Serialize, Deserialize, process_macros::SerdeJsonInto)]
-struct AddContactRequest {
-    id: String,
-    name: String,
+struct SyncRequest {
+    #[serde(default)]
+    conversations: Vec<SyncCursor>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, process_macros::SerdeJsonInto)]
+struct TypingRequest {
+    conversation: Conversation,
+    is_typing: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, process_macros::SerdeJsonInto)]
+struct EditMessageRequest {
+    conversation: Conversation,
+    message_id: String,
+    content: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, process_macros::SerdeJsonInto)]
+struct DeleteMessageRequest {
+    conversation: Conversation,
+    message_id: String,
+    for_everyone: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, process_macros::SerdeJsonInto)]
+struct ReactToMessageRequest {
+    conversation: Conversation,
+    message_id: String,
+    emoji: String,
+    add: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, process_macros::SerdeJsonInto)]
+struct GetThreadRequest {
+    conversation: Conversation,
+    message_id: String,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize, process_macros::SerdeJsonInto)]
+struct MarkReadRequest {
+    conversation: Conversation,
+    // Defaults to the newest message
+    #[serde(default)]
+    message_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, process_macros::SerdeJsonInto)]
+struct GetConversationsRequest {}
+
 #[derive(Debug, Clone, Serialize, Deserialize, process_macros::SerdeJsonInto)]
 struct GetMessagesRequest {}
 
@@ -130,11 +404,22 @@ enum RequestType {
     HttpRequest(HttpServerRequest),
     GroupMessage { GroupMessage: GroupMessageRequest },
     CreateGroup { CreateGroup: CreateGroupRequest },
-    AddContact { AddContact: AddContactRequest },
+    AddContact { AddContact: Contact },
+    RemoveContact { RemoveContact: String },
     GetMessages { GetMessages: GetMessagesRequest },
     GetContacts { GetContacts: GetContactsRequest },
     GetGroups { GetGroups: GetGroupsRequest },
     GetGroupMessages { GetGroupMessages: GetGroupMessagesRequest },
+    Subscribe { Subscribe: SubscribeRequest },
+    Unsubscribe { Unsubscribe: SubscribeRequest },
+    Typing { Typing: TypingRequest },
+    EditMessage { EditMessage: EditMessageRequest },
+    DeleteMessage { DeleteMessage: DeleteMessageRequest },
+    ReactToMessage { ReactToMessage: ReactToMessageRequest },
+    GetThread { GetThread: GetThreadRequest },
+    MarkRead { MarkRead: MarkReadRequest },
+    GetConversations { GetConversations: GetConversationsRequest },
+    Sync { Sync: SyncRequest },
 }
 
 type MessageArchive = HashMap<String, Vec<ChatMessage>>;
@@ -142,58 +427,112 @@ type MessageArchive = HashMap<String, Vec<ChatMessage>>;
 // Used to store contacts info
 struct ChatStore {
     package_id: PackageId,
-    messages_db: Kv<String, Vec<ChatMessage>>,
-    groups_db: Kv<String, Group>,
-    group_messages_db: Kv<String, Vec<GroupMessage>>,
+    // Versioned stored records; see schema.rs
+    messages_db: RecordDb<Vec<ChatMessage>>,
+    groups_db: RecordDb<Group>,
+    group_messages_db: RecordDb<Vec<GroupMessage>>,
+    // Edits, deletes and reactions in each group, in their authors' sequences
+    group_actions_db: RecordDb<Vec<GroupAction>>,
+    blocked_db: RecordDb<HashSet<String>>,
+    conversations_db: RecordDb<HashSet<String>>,
+    reads_db: RecordDb<ReadMarker>,
+    // First messages from nodes we don't know yet, until we approve them
+    message_requests_db: RecordDb<Vec<ChatMessage>>,
+    // When we last heard from each node, in seconds
+    last_seen_db: RecordDb<u64>,
+    settings_db: RecordDb<Settings>,
+    contacts_db: RecordDb<HashMap<String, String>>,
+    attachments: AttachmentStore,
+    archives: ArchiveStore,
+    search: SearchIndex,
+    e2e: E2eStore,
+    schema: SchemaMeta,
 }
 
 impl ChatStore {
-    fn new(package_id: PackageId) -> anyhow::Result<Self> {
-        let messages_db = kv::open(package_id.clone(), MESSAGES_DB, None)?;
-        let groups_db = kv::open(package_id.clone(), GROUPS_DB, None)?;
-        let group_messages_db = kv::open(package_id.clone(), GROUP_MESSAGES_DB, None)?;
+    fn new(package_id: PackageId, our_node: &str) -> anyhow::Result<Self> {
+        let messages_db = RecordDb::open(package_id.clone(), MESSAGES_DB)?;
+        let groups_db = RecordDb::open(package_id.clone(), GROUPS_DB)?;
+        let group_messages_db = RecordDb::open(package_id.clone(), GROUP_MESSAGES_DB)?;
+        let group_actions_db = RecordDb::open(package_id.clone(), GROUP_ACTIONS_DB)?;
+        let blocked_db = RecordDb::open(package_id.clone(), BLOCKED_DB)?;
+        let conversations_db = RecordDb::open(package_id.clone(), CONVERSATIONS_DB)?;
+        let reads_db = RecordDb::open(package_id.clone(), READS_DB)?;
+        let message_requests_db = RecordDb::open(package_id.clone(), MESSAGE_REQUESTS_DB)?;
+        let last_seen_db = RecordDb::open(package_id.clone(), LAST_SEEN_DB)?;
+        let settings_db = RecordDb::open(package_id.clone(), SETTINGS_DB)?;
+        let contacts_db = RecordDb::open(package_id.clone(), CONTACTS_DB)?;
+        let attachments = AttachmentStore::new(package_id.clone())?;
+        let archives = ArchiveStore::new(package_id.clone())?;
+        let search = SearchIndex::new(package_id.clone())?;
+        let e2e = E2eStore::new(package_id.clone(), our_node)?;
+        let schema = SchemaMeta::open(package_id.clone())?;
 
         Ok(Self {
             package_id,
             messages_db,
             groups_db,
             group_messages_db,
+            group_actions_db,
+            blocked_db,
+            conversations_db,
+            reads_db,
+            message_requests_db,
+            last_seen_db,
+            settings_db,
+            contacts_db,
+            attachments,
+            archives,
+            search,
+            e2e,
+            schema,
         })
     }
 
+    // Conversation index methods
+    fn get_indexed(&self, key: &str) -> anyhow::Result<HashSet<String>> {
+        Ok(self.conversations_db.get(key)?.unwrap_or_default())
+    }
+
+    fn index_conversation(&self, key: &str, id: &str) -> anyhow::Result<()> {
+        let mut ids = self.get_indexed(key)?;
+        if ids.insert(id.to_string()) {
+            self.conversations_db.set(key, &ids)?;
+        }
+        Ok(())
+    }
+
+    fn unindex_conversation(&self, key: &str, id: &str) -> anyhow::Result<()> {
+        let mut ids = self.get_indexed(key)?;
+        if ids.remove(id) {
+            self.conversations_db.set(key, &ids)?;
+        }
+        Ok(())
+    }
+
     // Message methods
     fn get_messages(&self, contact: &str) -> anyhow::Result<Vec<ChatMessage>> {
-        match self.messages_db.get(&contact.to_string()) {
-            Ok(messages) => Ok(messages),
-            Err(_) => Ok(Vec::new()),
-        }
+        Ok(self.messages_db.get(contact)?.unwrap_or_default())
     }
 
     fn add_message(&self, contact: &str, message: ChatMessage) -> anyhow::Result<()> {
+        let conversation = Conversation::Direct(contact.to_string());
+        self.search.index(&conversation, &message.id, &message.content)?;
         let mut messages = self.get_messages(contact)?;
         messages.push(message);
-        self.messages_db.set(&contact.to_string(), &messages, None)?;
+        self.messages_db.set(contact, &messages)?;
+        self.index_conversation(CONTACTS_KEY, contact)?;
         Ok(())
     }
 
     fn get_all_messages(&self) -> anyhow::Result<MessageArchive> {
-        // We'll iterate through all the messages we've stored
-        // This is a simplified implementation that assumes we've actually interacted with contacts
-        // In a real app, we would have a way to list all keys in the KV store
-        
-        // Use a temporary HashMap to store the result
         let mut messages = HashMap::new();
-        
-        // Get a list of all contacts we've messaged 
-        // (Ideally we'd get this from the KV store, but for simplicity we'll just return what we have)
-        let contacts = vec!["world".to_string(), "hello".to_string()]; // Example contacts
-        
-        // Get messages for each contact
-        for contact in contacts {
-            if let Ok(contact_messages) = self.get_messages(&contact) {
-                if !contact_messages.is_empty() {
-                    messages.insert(contact, contact_messages);
-                }
+
+        // Get messages for each contact we've talked to
+        for contact in self.get_indexed(CONTACTS_KEY)? {
+            let contact_messages = self.get_messages(&contact)?;
+            if !contact_messages.is_empty() {
+                messages.insert(contact, contact_messages);
             }
         }
         
@@ -203,249 +542,1255 @@ impl ChatStore {
     // Group methods
     fn create_group(&self, name: &str, members: HashSet<String>, created_by: &str) -> anyhow::Result<Group> {
         let id = format!("group_{}", SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis());
-        let group = Group {
+        let created_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let mut group = Group {
             id: id.clone(),
             name: name.to_string(),
-            members,
+            members: HashSet::new(),
             created_by: created_by.to_string(),
-            created_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            created_at,
+            membership: Vec::new(),
         };
-        
-        self.groups_db.set(&id, &group, None)?;
+        // The founding members are the creator's first changes, so they sync
+        // like any later ones
+        let mut members: Vec<String> = members.into_iter().collect();
+        members.sort();
+        for (index, member) in members.into_iter().enumerate() {
+            let mut change = MembershipChange {
+                author: created_by.to_string(),
+                seq: index as u64 + 1,
+                member,
+                added: true,
+                timestamp: created_at,
+                signature: Vec::new(),
+            };
+            change.signature = self.e2e.sign(&reconcile::signed_membership(&id, &change)?);
+            reconcile::apply_membership(&mut group, change);
+        }
+
+        self.groups_db.set(&id, &group)?;
+        self.index_conversation(GROUPS_KEY, &id)?;
         Ok(group)
     }
 
     fn get_group(&self, group_id: &str) -> anyhow::Result<Option<Group>> {
-        match self.groups_db.get(&group_id.to_string()) {
-            Ok(group) => Ok(Some(group)),
-            Err(_) => Ok(None),
-        }
+        self.groups_db.get(group_id)
     }
 
-    fn add_member_to_group(&self, group_id: &str, member: &str) -> anyhow::Result<bool> {
-        if let Some(mut group) = self.get_group(group_id)? {
-            let was_added = group.members.insert(member.to_string());
-            if was_added {
-                self.groups_db.set(&group_id.to_string(), &group, None)?;
-            }
-            Ok(was_added)
-        } else {
-            Ok(false)
-        }
+    fn add_member_to_group(&self, group_id: &str, member: &str, author: &str) -> anyhow::Result<bool> {
+        self.change_membership(group_id, member, true, author)
     }
 
-    fn remove_member_from_group(&self, group_id: &str, member: &str) -> anyhow::Result<bool> {
-        if let Some(mut group) = self.get_group(group_id)? {
-            let was_removed = group.members.remove(member);
-            if was_removed {
-                self.groups_db.set(&group_id.to_string(), &group, None)?;
-            }
-            Ok(was_removed)
-        } else {
-            Ok(false)
+    fn remove_member_from_group(&self, group_id: &str, member: &str, author: &str) -> anyhow::Result<bool> {
+        self.change_membership(group_id, member, false, author)
+    }
+
+    // Log a membership change by `author`; false if there is no such group or
+    // the member is already in the requested state
+    fn change_membership(&self, group_id: &str, member: &str, added: bool, author: &str) -> anyhow::Result<bool> {
+        let Some(mut group) = self.get_group(group_id)? else {
+            return Ok(false);
+        };
+        if group.members.contains(member) == added {
+            return Ok(false);
         }
+        let messages = self.get_group_messages(group_id)?;
+        let actions = self.get_group_actions(group_id)?;
+        let mut change = MembershipChange {
+            author: author.to_string(),
+            seq: reconcile::next_seq(&group, &messages, &actions, author),
+            member: member.to_string(),
+            added,
+            timestamp: get_timestamp(),
+            signature: Vec::new(),
+        };
+        change.signature = self.e2e.sign(&reconcile::signed_membership(group_id, &change)?);
+        reconcile::apply_membership(&mut group, change);
+        self.groups_db.set(group_id, &group)?;
+        Ok(true)
     }
 
-    fn get_all_groups(&self) -> anyhow::Result<Vec<Group>> {
-        // For a real implementation, we would need to list keys from KV store
-        // This is a simplified version
-        let groups = Vec::new();
-        
-        // We'd normally iterate over all keys, for now let's return an empty list
-        // as we can't enumerate keys in KV store without additional tracking
+    // Forget a group: it is no longer listed, synced or merged into, but its
+    // record stays so members that sync with us still learn we left
+    fn delete_group(&self, group_id: &str) -> anyhow::Result<()> {
+        self.group_messages_db.delete(group_id)?;
+        self.group_actions_db.delete(group_id)?;
+        self.unindex_conversation(GROUPS_KEY, group_id)?;
+        self.index_conversation(DELETED_GROUPS_KEY, group_id)
+    }
 
+    fn is_group_deleted(&self, group_id: &str) -> anyhow::Result<bool> {
+        Ok(self.get_indexed(DELETED_GROUPS_KEY)?.contains(group_id))
+    }
+
+    fn get_all_groups(&self) -> anyhow::Result<Vec<Group>> {
+        let mut groups = Vec::new();
+        for group_id in self.get_indexed(GROUPS_KEY)? {
+            if let Some(group) = self.get_group(&group_id)? {
+                groups.push(group);
+            }
+        }
+        groups.sort_by_key(|group| group.created_at);
         Ok(groups)
     }
 
     // Group message methods
-    fn add_group_message(&self, group_id: &str, message: GroupMessage) -> anyhow::Result<()> {
-        let mut messages = match self.group_messages_db.get(&group_id.to_string()) {
-            Ok(messages) => messages,
-            Err(_) => Vec::new(),
+    // Store a new message of ours, numbering it in our sequence for the group
+    // and signing it
+    fn add_group_message(&self, group_id: &str, mut message: GroupMessage) -> anyhow::Result<GroupMessage> {
+        let conversation = Conversation::Group(group_id.to_string());
+        self.search.index(&conversation, &message.id, &message.content)?;
+        let mut messages = self.get_group_messages(group_id)?;
+        if let Some(group) = self.get_group(group_id)? {
+            let actions = self.get_group_actions(group_id)?;
+            message.seq = reconcile::next_seq(&group, &messages, &actions, &message.author);
+            message.body_digest = reconcile::body_digest(&message)?;
+            message.signature = self.e2e.sign(&reconcile::signed_message(&message)?);
+        }
+        messages.push(message.clone());
+        self.group_messages_db.set(group_id, &messages)?;
+        Ok(message)
+    }
+
+    fn get_group_actions(&self, group_id: &str) -> anyhow::Result<Vec<GroupAction>> {
+        Ok(self.group_actions_db.get(group_id)?.unwrap_or_default())
+    }
+
+    // Log an edit, delete or reaction of ours in a group, already applied to
+    // our copy, so members get it as they sync
+    fn add_group_action(&self, group_id: &str, author: &str, message_id: &str, kind: ActionKind) -> anyhow::Result<()> {
+        let Some(group) = self.get_group(group_id)? else {
+            return Err(anyhow::anyhow!("group {} not found", group_id));
         };
-        
-        messages.push(message);
-        self.group_messages_db.set(&group_id.to_string(), &messages, None)?;
-        Ok(())
+        let messages = self.get_group_messages(group_id)?;
+        let mut actions = self.get_group_actions(group_id)?;
+        let mut action = GroupAction {
+            author: author.to_string(),
+            seq: reconcile::next_seq(&group, &messages, &actions, author),
+            message_id: message_id.to_string(),
+            kind,
+            timestamp: get_timestamp(),
+            signature: Vec::new(),
+        };
+        action.signature = self.e2e.sign(&reconcile::signed_action(group_id, &action)?);
+        actions.push(action);
+        self.group_actions_db.set(group_id, &actions)
     }
 
-    fn get_group_messages(&self, group_id: &str) -> anyhow::Result<Vec<GroupMessage>> {
-        match self.group_messages_db.get(&group_id.to_string()) {
-            Ok(messages) => Ok(messages),
-            Err(_) => Ok(Vec::new()),
-        }
+    // Group sync methods
+    fn group_digest(&self, group_id: &str) -> anyhow::Result<GroupDigest> {
+        let clock = match self.get_group(group_id)? {
+            Some(group) => reconcile::clock(
+                &group,
+                &self.get_group_messages(group_id)?,
+                &self.get_group_actions(group_id)?,
+            ),
+            None => Clock::new(),
+        };
+        Ok(reconcile::to_digest(group_id, &clock))
     }
-}
 
-fn make_http_address(our: &Address) -> Address {
-    Address::from((our.node(), "http_server", "distro", "sys"))
-}
+    // Merge events `from` sent us. Events by other members count only with
+    // their author's signature, so no member can speak for another. A group
+    // we don't know is only taken on from its creator, and only if its
+    // membership includes us.
+    fn merge_group_delta(&self, from: &str, our_node: &str, delta: GroupDelta) -> anyhow::Result<GroupMerge> {
+        if self.is_group_deleted(&delta.group.id)? {
+            return Err(anyhow::anyhow!("group {} was deleted", delta.group.id));
+        }
+        let existing = self.get_group(&delta.group.id)?;
+        let is_new = existing.is_none();
+        let mut group = match existing {
+            Some(group) => {
+                if from != group.created_by && !group.members.contains(from) {
+                    return Err(anyhow::anyhow!("{} is not a member of group {}", from, group.id));
+                }
+                group
+            }
+            None => {
+                if from != delta.group.created_by {
+                    return Err(anyhow::anyhow!("{} did not create group {}", from, delta.group.id));
+                }
+                // Members follow from the changes, not from what the sender claims
+                Group {
+                    members: HashSet::new(),
+                    membership: Vec::new(),
+                    ..delta.group
+                }
+            }
+        };
+        let group_id = group.id.clone();
+        // `from` speaks for itself; anyone else's event needs their signature
+        let is_authentic = |author: &str, signed: anyhow::Result<Vec<u8>>, signature: &[u8]| {
+            author == from || signed.and_then(|bytes| self.e2e.verify(author, &bytes, signature)).is_ok()
+        };
 
-fn make_contacts_address(our: &Address) -> Address {
-    Address::from((our.node(), "contacts", "contacts", "sys"))
-}
+        let now = get_timestamp();
+        let mut changes = delta.membership;
+        changes.sort_by_key(|change| change.timestamp);
+        let mut membership_changed = false;
+        for change in changes {
+            if reconcile::is_from_future(change.timestamp, now)
+                || !reconcile::may_change_membership(&group, &change)
+                || !is_authentic(
+                    &change.author,
+                    reconcile::signed_membership(&group_id, &change),
+                    &change.signature,
+                )
+            {
+                info!("Skipping membership change by {} in {} from {}", change.author, group_id, from);
+                continue;
+            }
+            membership_changed |= reconcile::apply_membership(&mut group, change);
+        }
+        if is_new && !(group.members.contains(our_node) && group.members.contains(from)) {
+            return Err(anyhow::anyhow!("not a member of group {}", group_id));
+        }
+        if is_new || membership_changed {
+            self.groups_db.set(&group_id, &group)?;
+        }
+        // Groups stored before the index existed are indexed once synced
+        self.index_conversation(GROUPS_KEY, &group_id)?;
 
-// Get timestamp in seconds
-fn get_timestamp() -> u64 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap_or_default()
-        .as_secs()
-}
+        // A deleted message has no text left to check, so another member may
+        // only pass it on with its author's delete
+        let deletes: HashSet<(String, String)> = delta
+            .actions
+            .iter()
+            .filter(|action| matches!(action.kind, ActionKind::Delete))
+            .filter(|action| is_authentic(&action.author, reconcile::signed_action(&group_id, action), &action.signature))
+            .map(|action| (action.author.clone(), action.message_id.clone()))
+            .collect();
 
-fn handle_http_server_request(
-    body: &[u8],
-    request: HttpServerRequest,
-    store: &ChatStore,
-    server: &mut HttpServer,
-) -> anyhow::Result<()> {
-    match request {
-        HttpServerRequest::WebSocketOpen {
-            ref path,
-            channel_id,
-        } => server.handle_websocket_open(path, channel_id),
-        HttpServerRequest::WebSocketClose(channel_id) => server.handle_websocket_close(channel_id),
-        HttpServerRequest::WebSocketPush { .. } => {
-            let Some(blob) = get_blob() else {
-                return Ok(());
+        let conversation = Conversation::Group(group_id.clone());
+        let mut messages = self.get_group_messages(&group_id)?;
+        let mut held: HashSet<(String, u64)> = messages
+            .iter()
+            .map(|message| (message.author.clone(), message.seq))
+            .collect();
+        let mut held_ids: HashSet<String> = messages.iter().map(|message| message.id.clone()).collect();
+        let mut added = Vec::new();
+        for mut message in delta.messages {
+            // Removed members' messages are dropped along with them
+            if message.group_id != group_id
+                || message.seq == 0
+                || !is_valid_message_id(&message.id, &message.author)
+                || !group.members.contains(&message.author)
+                || held.contains(&(message.author.clone(), message.seq))
+                || held_ids.contains(&message.id)
+                || reconcile::is_from_future(message.timestamp, now)
+            {
+                continue;
+            }
+            let body_matches = if message.deleted {
+                deletes.contains(&(message.author.clone(), message.id.clone()))
+            } else {
+                reconcile::body_digest(&message)? == message.body_digest
             };
-            
-            // Log the raw request for debugging
-            let request_str = String::from_utf8_lossy(&blob.bytes);
-            info!("Received WebSocket message RAW: {}", request_str);
-
-            let our_addr = standard::our();
-            
-            // Try to parse as RequestType to handle various message types
-            match serde_json::from_slice::<RequestType>(&blob.bytes) {
-                Ok(RequestType::HttpRequest(_)) => {
-                    // This should not happen in a WebSocket message
-                    info!("Received HttpRequest via WebSocket - this is unexpected");
-                },
-                Ok(RequestType::ChatRequest(request)) => {
-                    handle_chat_request(
-                        &our_addr,
-                        &make_http_address(&our_addr),
-                        request,
-                        true,
-                        store,
-                        server,
-                    )?;
-                },
-                Ok(RequestType::GroupMessage { GroupMessage: group_msg }) => {
-                    info!("Received group message via WebSocket: {:?}", group_msg);
-                    
-                    // Check if group exists and user is a member
-                    if let Ok(Some(group)) = store.get_group(&group_msg.group_id) {
-                        if !group.members.contains(&our_addr.node().to_string()) {
-                            info!("User is not a member of group {}", group_msg.group_id);
-                            return Ok(());
-                        }
-                        
-                        // Add message to group
-                        let group_message = GroupMessage {
-                            group_id: group_msg.group_id.clone(),
-                            author: our_addr.node().to_string(),
-                            content: group_msg.message.clone(),
-                            timestamp: get_timestamp(),
-                        };
-                        
-                        if let Ok(_) = store.add_group_message(&group_msg.group_id, group_message.clone()) {
-                            // Send WebSocket message to update UI
-                            let update_blob = LazyLoadBlob {
-                                mime: Some("application/json".to_string()),
-                                bytes: serde_json::to_vec(&serde_json::json!({
-                                    "NewGroupMessage": {
-                                        "group_id": group_msg.group_id,
-                                        "author": our_addr.node().to_string(),
-                                        "content": group_msg.message,
-                                        "timestamp": group_message.timestamp,
-                                    }
-                                }))
-                                .unwrap(),
-                            };
-                            server.ws_push_all_channels(WS_PATH, WsMessageType::Text, update_blob);
-                        }
-                    } else {
-                        info!("Group not found: {}", group_msg.group_id);
+            if message.author != from
+                && !(body_matches && is_authentic(&message.author, reconcile::signed_message(&message), &message.signature))
+            {
+                info!("Skipping group message {} from {}: not signed by its author", message.id, from);
+                continue;
+            }
+            // Only ciphertext is synced. A message we can't open yet, e.g.
+            // before its author's key reached us, is left for the next sync.
+            if let Some(ref payload) = message.encrypted {
+                let aad = e2e::message_aad(&message.author, &group_id, &message.id);
+                match self.e2e.decrypt_group(&group_id, &message.author, payload, &aad) {
+                    Ok(content) => message.content = content,
+                    Err(e) => {
+                        info!("Skipping group message {} from {}: {}", message.id, message.author, e);
+                        continue;
                     }
-                },
-                Ok(RequestType::CreateGroup { CreateGroup: create_req }) => {
-                    info!("Received create group request via WebSocket: {:?}", create_req);
-                    
-                    let members: HashSet<String> = create_req.members.into_iter().collect();
-                    
-                    // Add the creator to the group members
-                    let mut all_members = members.clone();
-                    all_members.insert(our_addr.node().to_string());
-                    
-                    match store.create_group(&create_req.name, all_members, our_addr.node()) {
-                        Ok(group) => {
-                            // Send WebSocket message to update UI
-                            let update_blob = LazyLoadBlob {
-                                mime: Some("application/json".to_string()),
-                                bytes: serde_json::to_vec(&serde_json::json!({
-                                    "NewGroup": group
-                                }))
-                                .unwrap(),
-                            };
-                            server.ws_push_all_channels(WS_PATH, WsMessageType::Text, update_blob);
-                        }
+                }
+            }
+            held.insert((message.author.clone(), message.seq));
+            held_ids.insert(message.id.clone());
+            self.search.index(&conversation, &message.id, &message.content)?;
+            messages.push(message.clone());
+            added.push(message);
+        }
+        if !added.is_empty() {
+            messages.sort_by_key(|message| message.timestamp);
+            self.group_messages_db.set(&group_id, &messages)?;
+        }
+
+        // Edits, deletes and reactions, once the messages they change are here
+        let mut actions = self.get_group_actions(&group_id)?;
+        let mut held_actions: HashSet<(String, u64)> =
+            actions.iter().map(|action| (action.author.clone(), action.seq)).collect();
+        let mut incoming = delta.actions;
+        incoming.sort_by_key(|action| action.seq);
+        let mut events = Vec::new();
+        let mut actions_added = false;
+        for action in incoming {
+            if held_actions.contains(&(action.author.clone(), action.seq))
+                || !group.members.contains(&action.author)
+                || reconcile::is_from_future(action.timestamp, now)
+                || !is_authentic(&action.author, reconcile::signed_action(&group_id, &action), &action.signature)
+                || !self.has_message(&conversation, &action.message_id)?
+            {
+                continue;
+            }
+            let message_action = match &action.kind {
+                ActionKind::Edit {
+                    encrypted: Some(payload),
+                    ..
+                } => {
+                    let aad = e2e::edit_aad(&action.author, &group_id, &action.message_id);
+                    match self.e2e.decrypt_group(&group_id, &action.author, payload, &aad) {
+                        Ok(content) => MessageAction::Edit { content },
                         Err(e) => {
-                            info!("Failed to create group: {}", e);
+                            info!("Skipping edit of {} by {}: {}", action.message_id, action.author, e);
+                            continue;
                         }
                     }
+                }
+                ActionKind::Edit { content, .. } => MessageAction::Edit {
+                    content: content.clone(),
                 },
-                Ok(RequestType::AddContact { AddContact: contact }) => {
-                    info!("Received add contact request via WebSocket: {:?}", contact);
-                    
-                    // In a real implementation, we would add the contact to a contacts database
-                    // For now, we'll just acknowledge it with a WebSocket message
-                    let update_blob = LazyLoadBlob {
-                        mime: Some("application/json".to_string()),
-                        bytes: serde_json::to_vec(&serde_json::json!({
-                            "ContactAdded": {
-                                "id": contact.id,
-                                "name": contact.name
-                            }
-                        }))
-                        .unwrap(),
-                    };
-                    server.ws_push_all_channels(WS_PATH, WsMessageType::Text, update_blob);
+                ActionKind::Delete => MessageAction::Delete { for_everyone: true },
+                ActionKind::React { emoji, add } => MessageAction::React {
+                    emoji: emoji.clone(),
+                    add: *add,
                 },
-                Ok(RequestType::GetMessages { .. }) => {
-                    info!("Received GetMessages request via WebSocket");
-                    
-                    // Get all messages and send them back
-                    match store.get_all_messages() {
-                        Ok(messages) => {
-                            let update_blob = LazyLoadBlob {
-                                mime: Some("application/json".to_string()),
-                                bytes: serde_json::to_vec(&serde_json::json!({
-                                    "Messages": messages
-                                }))
-                                .unwrap(),
-                            };
-                            server.ws_push_all_channels(WS_PATH, WsMessageType::Text, update_blob);
+            };
+            // Kept even if it doesn't apply: it holds its author's place in
+            // the sequence
+            match apply_message_action(self, &conversation, &action.message_id, &action.author, &message_action) {
+                Ok(event) => events.push(event),
+                Err(e) => info!("Group action by {} in {} doesn't apply: {}", action.author, group_id, e),
+            }
+            held_actions.insert((action.author.clone(), action.seq));
+            actions.push(action);
+            actions_added = true;
+        }
+        if actions_added {
+            self.group_actions_db.set(&group_id, &actions)?;
+        }
+
+        Ok(GroupMerge {
+            group,
+            is_new,
+            membership_changed,
+            messages: added,
+            events,
+        })
+    }
+
+    // Archive methods
+    fn export_archive(&self, our_node: &str) -> anyhow::Result<Archive> {
+        let groups = self.get_all_groups()?;
+        let mut group_messages = HashMap::new();
+        for group in &groups {
+            group_messages.insert(group.id.clone(), self.get_group_messages(&group.id)?);
+        }
+
+        Ok(Archive {
+            version: archive::ARCHIVE_VERSION,
+            exported_by: our_node.to_string(),
+            exported_at: get_timestamp(),
+            messages: self.get_all_messages()?,
+            groups,
+            group_messages,
+        })
+    }
+
+    // Merge an archive into the store by message id; safe to repeat
+    fn import_archive(&self, archive: Archive) -> anyhow::Result<ImportSummary> {
+        let mut summary = ImportSummary::default();
+
+        for (contact, mut incoming) in archive.messages {
+            assign_legacy_ids(&mut incoming);
+            let mut messages = self.get_messages(&contact)?;
+            let added = archive::merge_messages(&mut messages, incoming);
+            if !added.is_empty() {
+                // Only what this import added; the rest is indexed already
+                let conversation = Conversation::Direct(contact.clone());
+                for message in messages.iter().filter(|message| added.contains(&message.id)) {
+                    self.search.index(&conversation, &message.id, &message.content)?;
+                }
+                self.messages_db.set(&contact, &messages)?;
+                summary.messages_added += added.len();
+            }
+            self.index_conversation(CONTACTS_KEY, &contact)?;
+        }
+
+        // Groups we already have keep their current members
+        for group in archive.groups {
+            if self.get_group(&group.id)?.is_none() {
+                self.groups_db.set(&group.id, &group)?;
+                summary.groups_added += 1;
+            }
+            self.index_conversation(GROUPS_KEY, &group.id)?;
+        }
+
+        for (group_id, mut incoming) in archive.group_messages {
+            if self.get_group(&group_id)?.is_none() {
+                info!("Skipping messages for unknown group {}", group_id);
+                continue;
+            }
+            assign_legacy_ids(&mut incoming);
+            let mut messages = self.get_group_messages(&group_id)?;
+            let added = archive::merge_messages(&mut messages, incoming);
+            if !added.is_empty() {
+                let conversation = Conversation::Group(group_id.clone());
+                for message in messages.iter().filter(|message| added.contains(&message.id)) {
+                    self.search.index(&conversation, &message.id, &message.content)?;
+                }
+                self.group_messages_db.set(&group_id, &messages)?;
+                summary.group_messages_added += added.len();
+            }
+        }
+
+        Ok(summary)
+    }
+
+    // Read state methods
+    fn get_read_marker(&self, conversation: &Conversation) -> anyhow::Result<Option<ReadMarker>> {
+        self.reads_db.get(&reads::key(conversation))
+    }
+
+    // Move the read marker forward to `message_id`, or to the newest message.
+    // Returns None if the conversation has no such message.
+    fn mark_read(
+        &self,
+        conversation: &Conversation,
+        message_id: Option<&str>,
+    ) -> anyhow::Result<Option<ReadMarker>> {
+        let read_at = get_timestamp();
+        let current = self.get_read_marker(conversation)?;
+        let advanced = match conversation {
+            Conversation::Direct(node) => {
+                reads::advance(&self.get_messages(node)?, message_id, current, read_at)
+            }
+            Conversation::Group(group_id) => {
+                reads::advance(&self.get_group_messages(group_id)?, message_id, current, read_at)
+            }
+        };
+        let Some((marker, moved)) = advanced else {
+            return Ok(None);
+        };
+        if moved {
+            self.reads_db.set(&reads::key(conversation), &marker)?;
+        }
+        Ok(Some(marker))
+    }
+
+    // Every conversation we hold, most recently active first
+    fn list_conversations(&self, our_node: &str) -> anyhow::Result<Vec<ConversationSummary>> {
+        let mut summaries = Vec::new();
+        for contact in self.get_indexed(CONTACTS_KEY)? {
+            let conversation = Conversation::Direct(contact.clone());
+            let messages = self.get_messages(&contact)?;
+            let last_read = self.get_read_marker(&conversation)?;
+            summaries.push(ConversationSummary {
+                unread: reads::unread_count(&messages, last_read.as_ref(), our_node),
+                last_message_at: messages.last().map(|message| message.timestamp),
+                name: contact,
+                conversation,
+                last_read,
+            });
+        }
+        for group in self.get_all_groups()? {
+            let conversation = Conversation::Group(group.id.clone());
+            let messages = self.get_group_messages(&group.id)?;
+            let last_read = self.get_read_marker(&conversation)?;
+            summaries.push(ConversationSummary {
+                unread: reads::unread_count(&messages, last_read.as_ref(), our_node),
+                last_message_at: messages.last().map(|message| message.timestamp),
+                name: group.name,
+                conversation,
+                last_read,
+            });
+        }
+        summaries.sort_by(|a, b| b.last_message_at.cmp(&a.last_message_at));
+        Ok(summaries)
+    }
+
+    // Message request methods
+    // A node is known once we have a conversation with it, approved it, or
+    // share a group with it that we or a known node created
+    fn is_known_sender(&self, node: &str, our_node: &str) -> anyhow::Result<bool> {
+        let contacts = self.get_indexed(CONTACTS_KEY)?;
+        let approved = self.get_indexed(APPROVED_KEY)?;
+        let is_known = |node: &str| contacts.contains(node) || approved.contains(node);
+        if is_known(node) {
+            return Ok(true);
+        }
+        Ok(self.get_all_groups()?.iter().any(|group| {
+            group.members.contains(node) && (group.created_by == our_node || is_known(&group.created_by))
+        }))
+    }
+
+    fn get_message_requests(&self) -> anyhow::Result<MessageArchive> {
+        let mut requests = HashMap::new();
+        for node in self.get_indexed(PENDING_KEY)? {
+            if let Some(messages) = self.message_requests_db.get(&node)? {
+                requests.insert(node, messages);
+            }
+        }
+        Ok(requests)
+    }
+
+    // Hold a message from an unknown node. Only the latest messages of the
+    // most recently active nodes are kept, so strangers can't fill our store.
+    fn quarantine_message(&self, node: &str, message: ChatMessage) -> anyhow::Result<()> {
+        let pending = self.get_indexed(PENDING_KEY)?;
+        if !pending.contains(node) && pending.len() >= limits::MAX_PENDING_NODES {
+            let mut last_active = Vec::new();
+            for pending_node in pending {
+                let last = self
+                    .message_requests_db
+                    .get(&pending_node)?
+                    .and_then(|messages| messages.last().map(|message| message.timestamp))
+                    .unwrap_or(0);
+                last_active.push((last, pending_node));
+            }
+            if let Some((_, oldest)) = last_active.into_iter().min() {
+                info!("Dropping message requests from {} to make room", oldest);
+                self.drop_message_requests(&oldest)?;
+            }
+        }
+
+        let mut messages = self.message_requests_db.get(node)?.unwrap_or_default();
+        messages.push(message);
+        let excess = messages.len().saturating_sub(limits::MAX_REQUESTS_PER_NODE);
+        messages.drain(..excess);
+        self.message_requests_db.set(node, &messages)?;
+        self.index_conversation(PENDING_KEY, node)?;
+        Ok(())
+    }
+
+    // Accept a node's messages: its quarantined messages join our history
+    fn approve_node(&self, node: &str) -> anyhow::Result<Vec<ChatMessage>> {
+        let messages = self.message_requests_db.get(node)?.unwrap_or_default();
+        for message in &messages {
+            self.add_message(node, message.clone())?;
+        }
+        self.index_conversation(APPROVED_KEY, node)?;
+        self.drop_message_requests(node)?;
+        Ok(messages)
+    }
+
+    fn drop_message_requests(&self, node: &str) -> anyhow::Result<()> {
+        self.message_requests_db.delete(node)?;
+        self.unindex_conversation(PENDING_KEY, node)?;
+        Ok(())
+    }
+
+    // Presence methods
+    fn get_last_seen(&self, node: &str) -> anyhow::Result<Option<u64>> {
+        self.last_seen_db.get(node)
+    }
+
+    fn set_last_seen(&self, node: &str, timestamp: u64) -> anyhow::Result<()> {
+        self.last_seen_db.set(node, &timestamp)
+    }
+
+    // Nodes we exchange heartbeats with: everyone we have a conversation with,
+    // approved, or share a group with
+    fn presence_peers(&self, our_node: &str) -> anyhow::Result<HashSet<String>> {
+        let mut peers = self.get_indexed(CONTACTS_KEY)?;
+        peers.extend(self.get_indexed(APPROVED_KEY)?);
+        for group in self.get_all_groups()? {
+            peers.extend(group.members);
+        }
+        peers.remove(our_node);
+        let blocked = self.get_blocked_nodes()?;
+        peers.retain(|node| !blocked.contains(node));
+        Ok(peers)
+    }
+
+    // Settings methods
+    fn get_settings(&self) -> anyhow::Result<Settings> {
+        Ok(self.settings_db.get(SETTINGS_KEY)?.unwrap_or_default())
+    }
+
+    fn set_settings(&self, settings: &Settings) -> anyhow::Result<()> {
+        self.settings_db.set(SETTINGS_KEY, settings)
+    }
+
+    // Search methods
+    fn search(&self, query: &SearchQuery) -> anyhow::Result<Vec<SearchHit>> {
+        let tokens = search::tokenize(&query.text);
+        if tokens.is_empty() {
+            return Err(anyhow::anyhow!("search query has no words"));
+        }
+
+        // Message ids found in each conversation, so each is loaded once
+        let mut found: HashMap<Conversation, HashSet<String>> = HashMap::new();
+        for posting in self.search.lookup(&tokens)? {
+            if query
+                .conversation
+                .as_ref()
+                .is_some_and(|conversation| conversation != &posting.conversation)
+            {
+                continue;
+            }
+            found
+                .entry(posting.conversation)
+                .or_default()
+                .insert(posting.message_id);
+        }
+
+        let mut hits = Vec::new();
+        for (conversation, ids) in found {
+            match conversation {
+                Conversation::Direct(ref node) => {
+                    for message in self.get_messages(node)? {
+                        if ids.contains(&message.id) && search::matches(&message, query, &tokens) {
+                            hits.push(SearchHit {
+                                conversation: conversation.clone(),
+                                timestamp: message.timestamp,
+                                wit: message.to_wit(),
+                                message: serde_json::json!(message),
+                            });
+                        }
+                    }
+                }
+                Conversation::Group(ref group_id) => {
+                    for message in self.get_group_messages(group_id)? {
+                        if ids.contains(&message.id) && search::matches(&message, query, &tokens) {
+                            hits.push(SearchHit {
+                                conversation: conversation.clone(),
+                                timestamp: message.timestamp,
+                                wit: message.to_wit(),
+                                message: serde_json::json!(message),
+                            });
+                        }
+                    }
+                }
+            }
+        }
+
+        hits.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+        hits.truncate(query.limit);
+        Ok(hits)
+    }
+
+    // Index every stored message, e.g. history from before search existed
+    fn rebuild_search_index(&self) -> anyhow::Result<usize> {
+        let mut indexed = 0;
+        for (contact, messages) in self.get_all_messages()? {
+            let conversation = Conversation::Direct(contact);
+            for message in &messages {
+                self.search.index(&conversation, &message.id, &message.content)?;
+            }
+            indexed += messages.len();
+        }
+        for group in self.get_all_groups()? {
+            let conversation = Conversation::Group(group.id.clone());
+            let messages = self.get_group_messages(&group.id)?;
+            for message in &messages {
+                self.search.index(&conversation, &message.id, &message.content)?;
+            }
+            indexed += messages.len();
+        }
+        Ok(indexed)
+    }
+
+    fn get_group_messages(&self, group_id: &str) -> anyhow::Result<Vec<GroupMessage>> {
+        Ok(self.group_messages_db.get(group_id)?.unwrap_or_default())
+    }
+
+    // Apply `update` to one stored message; returns false if there is no such message
+    fn update_message(
+        &self,
+        conversation: &Conversation,
+        message_id: &str,
+        update: impl FnOnce(&mut dyn StoredMessage) -> anyhow::Result<()>,
+    ) -> anyhow::Result<bool> {
+        match conversation {
+            Conversation::Direct(node) => {
+                let mut messages = self.get_messages(node)?;
+                let Some(message) = messages.iter_mut().find(|m| m.id == message_id) else {
+                    return Ok(false);
+                };
+                update(message)?;
+                self.search.index(conversation, &message.id, &message.content)?;
+                self.messages_db.set(node, &messages)?;
+            }
+            Conversation::Group(group_id) => {
+                let mut messages = self.get_group_messages(group_id)?;
+                let Some(message) = messages.iter_mut().find(|m| m.id == message_id) else {
+                    return Ok(false);
+                };
+                update(message)?;
+                self.search.index(conversation, &message.id, &message.content)?;
+                self.group_messages_db.set(group_id, &messages)?;
+            }
+        }
+        Ok(true)
+    }
+
+    // Whether a peer's message is in our history or waiting among its message
+    // requests, e.g. because the peer resent it after missing our answer
+    fn has_direct_message(&self, node: &str, message_id: &str) -> anyhow::Result<bool> {
+        if self.has_message(&Conversation::Direct(node.to_string()), message_id)? {
+            return Ok(true);
+        }
+        Ok(self
+            .message_requests_db
+            .get(node)?
+            .is_some_and(|messages| messages.iter().any(|m| m.id == message_id)))
+    }
+
+    fn has_message(&self, conversation: &Conversation, message_id: &str) -> anyhow::Result<bool> {
+        Ok(match conversation {
+            Conversation::Direct(node) => self.get_messages(node)?.iter().any(|m| m.id == message_id),
+            Conversation::Group(group_id) => self
+                .get_group_messages(group_id)?
+                .iter()
+                .any(|m| m.id == message_id),
+        })
+    }
+
+    // The thread containing a message, root first, in WIT form
+    fn get_thread(
+        &self,
+        conversation: &Conversation,
+        message_id: &str,
+    ) -> anyhow::Result<Option<Vec<HyperwareChatMessage>>> {
+        Ok(match conversation {
+            Conversation::Direct(node) => threads::thread(&self.get_messages(node)?, message_id)
+                .map(|thread| thread.iter().map(StoredMessage::to_wit).collect()),
+            Conversation::Group(group_id) => {
+                threads::thread(&self.get_group_messages(group_id)?, message_id)
+                    .map(|thread| thread.iter().map(StoredMessage::to_wit).collect())
+            }
+        })
+    }
+
+    // The thread containing a message, root first, as stored
+    fn get_thread_json(
+        &self,
+        conversation: &Conversation,
+        message_id: &str,
+    ) -> anyhow::Result<Option<serde_json::Value>> {
+        Ok(match conversation {
+            Conversation::Direct(node) => threads::thread(&self.get_messages(node)?, message_id)
+                .map(|thread| serde_json::json!(thread)),
+            Conversation::Group(group_id) => {
+                threads::thread(&self.get_group_messages(group_id)?, message_id)
+                    .map(|thread| serde_json::json!(thread))
+            }
+        })
+    }
+
+    fn get_thread_summaries(&self, conversation: &Conversation) -> anyhow::Result<Vec<threads::ThreadSummary>> {
+        Ok(match conversation {
+            Conversation::Direct(node) => threads::summarize(&self.get_messages(node)?),
+            Conversation::Group(group_id) => threads::summarize(&self.get_group_messages(group_id)?),
+        })
+    }
+
+    // Drop a message from our copy of the conversation only
+    fn remove_message(&self, conversation: &Conversation, message_id: &str) -> anyhow::Result<bool> {
+        match conversation {
+            Conversation::Direct(node) => {
+                let mut messages = self.get_messages(node)?;
+                let count = messages.len();
+                messages.retain(|m| m.id != message_id);
+                if messages.len() == count {
+                    return Ok(false);
+                }
+                self.messages_db.set(node, &messages)?;
+            }
+            Conversation::Group(group_id) => {
+                let mut messages = self.get_group_messages(group_id)?;
+                let count = messages.len();
+                messages.retain(|m| m.id != message_id);
+                if messages.len() == count {
+                    return Ok(false);
+                }
+                self.group_messages_db.set(group_id, &messages)?;
+            }
+        }
+        Ok(true)
+    }
+
+    // Look up attachments by id, skipping any we don't have (yet)
+    fn resolve_attachments(&self, ids: &[String]) -> anyhow::Result<Vec<Attachment>> {
+        let mut attachments = Vec::new();
+        for id in ids {
+            match self.attachments.get(id)? {
+                Some(attachment) => attachments.push(attachment),
+                None => info!("Unknown attachment {}", id),
+            }
+        }
+        Ok(attachments)
+    }
+
+    // Contact methods
+    fn get_contacts(&self) -> anyhow::Result<Vec<Contact>> {
+        let names = self.contacts_db.get(CONTACT_NAMES_KEY)?.unwrap_or_default();
+        let mut contacts: Vec<Contact> = names.into_iter().map(|(id, name)| Contact { id, name }).collect();
+        contacts.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(contacts)
+    }
+
+    fn add_contact(&self, contact: &Contact) -> anyhow::Result<()> {
+        let mut names = self.contacts_db.get(CONTACT_NAMES_KEY)?.unwrap_or_default();
+        names.insert(contact.id.clone(), contact.name.clone());
+        self.contacts_db.set(CONTACT_NAMES_KEY, &names)
+    }
+
+    fn remove_contact(&self, id: &str) -> anyhow::Result<bool> {
+        let mut names = self.contacts_db.get(CONTACT_NAMES_KEY)?.unwrap_or_default();
+        let was_removed = names.remove(id).is_some();
+        if was_removed {
+            self.contacts_db.set(CONTACT_NAMES_KEY, &names)?;
+        }
+        Ok(was_removed)
+    }
+
+    // Block list methods
+    fn get_blocked_nodes(&self) -> anyhow::Result<HashSet<String>> {
+        Ok(self.blocked_db.get(BLOCKED_KEY)?.unwrap_or_default())
+    }
+
+    fn is_blocked(&self, node: &str) -> anyhow::Result<bool> {
+        Ok(self.get_blocked_nodes()?.contains(node))
+    }
+
+    fn block_node(&self, node: &str) -> anyhow::Result<bool> {
+        let mut nodes = self.get_blocked_nodes()?;
+        let was_added = nodes.insert(node.to_string());
+        if was_added {
+            self.blocked_db.set(BLOCKED_KEY, &nodes)?;
+        }
+        Ok(was_added)
+    }
+
+    fn unblock_node(&self, node: &str) -> anyhow::Result<bool> {
+        let mut nodes = self.get_blocked_nodes()?;
+        let was_removed = nodes.remove(node);
+        if was_removed {
+            self.blocked_db.set(BLOCKED_KEY, &nodes)?;
+        }
+        Ok(was_removed)
+    }
+}
+
+fn make_http_address(our: &Address) -> Address {
+    Address::from((our.node(), "http_server", "distro", "sys"))
+}
+
+fn make_contacts_address(our: &Address) -> Address {
+    Address::from((our.node(), "contacts", "contacts", "sys"))
+}
+
+// Check that a request from another node came from hyperware-chat on that
+// node, that the node isn't blocked, and that it only writes into its own
+// conversation with us under its own name
+fn authenticate_remote(
+    our: &Address,
+    source: &Address,
+    request: &HyperwareChatRequest,
+    store: &ChatStore,
+) -> anyhow::Result<()> {
+    if store.is_blocked(&source.node)? {
+        return Err(anyhow::anyhow!("node {} is blocked", source.node));
+    }
+
+    match request {
+        HyperwareChatRequest::Send(SendRequest {
+            target,
+            message,
+            author,
+            id,
+            attachments: attachment_ids,
+            encrypted,
+            ..
+        }) => {
+            if source.process != our.process {
+                return Err(anyhow::anyhow!(
+                    "messages must come from {}, not {}",
+                    PROCESS_PATH,
+                    source.process
+                ));
+            }
+            // Don't let remote nodes use us to send messages on their behalf
+            if target != &our.node {
+                return Err(anyhow::anyhow!("refusing to relay message to {}", target));
+            }
+            let size = message.len() + encrypted.as_ref().map_or(0, |payload| payload.ciphertext.len());
+            if size > limits::MAX_MESSAGE_SIZE {
+                return Err(anyhow::anyhow!("message of {} bytes is too large", size));
+            }
+            // A claimed author must be the node that actually sent the message
+            if let Some(claimed) = author {
+                if claimed != &source.node {
+                    return Err(anyhow::anyhow!(
+                        "author {} does not match sender {}",
+                        claimed,
+                        source.node
+                    ));
+                }
+            }
+            if let Some(id) = id {
+                if !is_valid_message_id(id, &source.node) {
+                    return Err(anyhow::anyhow!("{} may not send message {}", source.node, id));
+                }
+            }
+            // Peers may only attach files they uploaded themselves
+            for id in attachment_ids.iter().flatten() {
+                if !attachments::is_valid_id(id, &source.node) {
+                    return Err(anyhow::anyhow!("{} may not attach {}", source.node, id));
+                }
+            }
+        }
+        HyperwareChatRequest::TypingNotice(TypingNotice { group_id, .. })
+        | HyperwareChatRequest::ReadReceipt(ReadReceipt { group_id, .. })
+        | HyperwareChatRequest::Edit(EditRequest { group_id, .. })
+        | HyperwareChatRequest::Delete(DeleteRequest { group_id, .. })
+        | HyperwareChatRequest::React(ReactRequest { group_id, .. }) => {
+            if source.process != our.process {
+                return Err(anyhow::anyhow!(
+                    "conversation updates must come from {}, not {}",
+                    PROCESS_PATH,
+                    source.process
+                ));
+            }
+            if let Some(group_id) = group_id {
+                let is_member = store
+                    .get_group(group_id)?
+                    .map(|group| group.members.contains(&source.node))
+                    .unwrap_or(false);
+                if !is_member {
+                    return Err(anyhow::anyhow!(
+                        "{} is not a member of group {}",
+                        source.node,
+                        group_id
+                    ));
+                }
+            }
+            if let HyperwareChatRequest::Edit(EditRequest {
+                content, encrypted, ..
+            }) = request
+            {
+                let size = content.len() + encrypted.as_ref().map_or(0, |payload| payload.ciphertext.len());
+                if size > limits::MAX_MESSAGE_SIZE {
+                    return Err(anyhow::anyhow!("edit of {} bytes is too large", size));
+                }
+            }
+        }
+        HyperwareChatRequest::Thread(ThreadRequest { conversation, .. }) => match conversation {
+            ConversationRef::Direct(node) => {
+                if node != &source.node {
+                    return Err(anyhow::anyhow!(
+                        "{} may not read threads with {}",
+                        source.node,
+                        node
+                    ));
+                }
+            }
+            ConversationRef::Group(group_id) => {
+                let is_member = store
+                    .get_group(group_id)?
+                    .map(|group| group.members.contains(&source.node))
+                    .unwrap_or(false);
+                if !is_member {
+                    return Err(anyhow::anyhow!(
+                        "{} is not a member of group {}",
+                        source.node,
+                        group_id
+                    ));
+                }
+            }
+        },
+        HyperwareChatRequest::AttachmentChunk(AttachmentChunk { id, .. }) => {
+            if source.process != our.process {
+                return Err(anyhow::anyhow!(
+                    "attachments must come from {}, not {}",
+                    PROCESS_PATH,
+                    source.process
+                ));
+            }
+            if !attachments::is_valid_id(id, &source.node) {
+                return Err(anyhow::anyhow!("{} may not write attachment {}", source.node, id));
+            }
+            // Files only come from nodes whose messages we'd accept; a message
+            // request holds text alone until it is approved
+            if !store.is_known_sender(&source.node, &our.node)? {
+                return Err(anyhow::anyhow!("{} is not a known sender", source.node));
+            }
+        }
+        HyperwareChatRequest::Search(_) => {
+            return Err(anyhow::anyhow!("search is only available to local processes"));
+        }
+        HyperwareChatRequest::GroupSync(GroupDigest { group_id, .. }) => {
+            if source.process != our.process {
+                return Err(anyhow::anyhow!(
+                    "group sync must come from {}, not {}",
+                    PROCESS_PATH,
+                    source.process
+                ));
+            }
+            // A group we don't know may be one we were just added to
+            if let Some(group) = store.get_group(group_id)? {
+                if !reconcile::was_member(&group, &source.node) {
+                    return Err(anyhow::anyhow!(
+                        "{} was never a member of group {}",
+                        source.node,
+                        group_id
+                    ));
+                }
+            }
+        }
+        HyperwareChatRequest::Heartbeat => {
+            if source.process != our.process {
+                return Err(anyhow::anyhow!(
+                    "heartbeats must come from {}, not {}",
+                    PROCESS_PATH,
+                    source.process
+                ));
+            }
+        }
+        HyperwareChatRequest::KeyExchange(_) => {
+            if source.process != our.process {
+                return Err(anyhow::anyhow!(
+                    "keys must come from {}, not {}",
+                    PROCESS_PATH,
+                    source.process
+                ));
+            }
+        }
+        HyperwareChatRequest::SenderKey(SenderKey { group_id, .. }) => {
+            if source.process != our.process {
+                return Err(anyhow::anyhow!(
+                    "keys must come from {}, not {}",
+                    PROCESS_PATH,
+                    source.process
+                ));
+            }
+            let is_member = store
+                .get_group(group_id)?
+                .map(|group| group.members.contains(&source.node))
+                .unwrap_or(false);
+            if !is_member {
+                return Err(anyhow::anyhow!(
+                    "{} is not a member of group {}",
+                    source.node,
+                    group_id
+                ));
+            }
+        }
+        HyperwareChatRequest::History(node) => {
+            // Remote nodes may only read their own conversation with us
+            if node != &source.node {
+                return Err(anyhow::anyhow!(
+                    "{} may not read history with {}",
+                    source.node,
+                    node
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Ids are assigned by the author so every copy of a message shares one
+fn new_message_id(author: &str) -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    format!("{}-{}", author, nanos)
+}
+
+// Peers pick the ids of their own messages, and those must be theirs so they
+// can't collide with, and then edit or delete, someone else's
+fn is_valid_message_id(id: &str, author: &str) -> bool {
+    id.strip_prefix(author)
+        .and_then(|rest| rest.strip_prefix('-'))
+        .map(|rest| !rest.is_empty() && rest.chars().all(|c| c.is_ascii_digit()))
+        .unwrap_or(false)
+}
+
+// Messages stored before ids existed have an empty id. Each gets one derived
+// from its author, timestamp and content, so every node holding a copy, and
+// every archive exported before, assigns the same. Returns how many changed.
+fn assign_legacy_ids<M: StoredMessage>(messages: &mut [M]) -> usize {
+    let mut taken: HashSet<String> = messages.iter().map(|m| m.id().to_string()).collect();
+    let mut assigned = 0;
+    for message in messages.iter_mut().filter(|m| m.id().is_empty()) {
+        let digest = Sha256::digest(message.content().as_bytes());
+        let mut suffix = u64::from_be_bytes(digest[..8].try_into().unwrap_or_default()) % 1_000_000_000;
+        // Identical messages sent in the same second still need their own ids
+        let mut id = format!("{}-{}{:09}", message.author(), message.timestamp(), suffix);
+        while taken.contains(&id) {
+            suffix = (suffix + 1) % 1_000_000_000;
+            id = format!("{}-{}{:09}", message.author(), message.timestamp(), suffix);
+        }
+        taken.insert(id.clone());
+        message.set_id(id);
+        assigned += 1;
+    }
+    assigned
+}
+
+// Get timestamp in seconds
+fn get_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn handle_http_server_request(
+    body: &[u8],
+    request: HttpServerRequest,
+    store: &ChatStore,
+    server: &mut HttpServer,
+    clients: &mut WsClients,
+) -> anyhow::Result<()> {
+    match request {
+        HttpServerRequest::WebSocketOpen {
+            ref path,
+            channel_id,
+        } => server.handle_websocket_open(path, channel_id),
+        HttpServerRequest::WebSocketClose(channel_id) => {
+            server.handle_websocket_close(channel_id);
+            clients.remove_channel(channel_id);
+        }
+        HttpServerRequest::WebSocketPush { channel_id, .. } => {
+            let Some(blob) = get_blob() else {
+                return Ok(());
+            };
+            
+            // Log the raw request for debugging
+            let request_str = String::from_utf8_lossy(&blob.bytes);
+            info!("Received WebSocket message RAW: {}", request_str);
+
+            let our_addr = standard::our();
+            
+            // Try to parse as RequestType to handle various message types
+            match serde_json::from_slice::<RequestType>(&blob.bytes) {
+                Ok(RequestType::HttpRequest(_)) => {
+                    // This should not happen in a WebSocket message
+                    info!("Received HttpRequest via WebSocket - this is unexpected");
+                },
+                Ok(RequestType::ChatRequest(request)) => {
+                    handle_chat_request(
+                        &our_addr,
+                        &make_http_address(&our_addr),
+                        request,
+                        true,
+                        store,
+                        server,
+                        clients,
+                    )?;
+                },
+                Ok(RequestType::GroupMessage { GroupMessage: group_msg }) => {
+                    info!("Received group message via WebSocket: {:?}", group_msg);
+                    
+                    // Check if group exists and user is a member
+                    if let Some(group) = store.get_group(&group_msg.group_id)? {
+                        if !group.members.contains(&our_addr.node().to_string()) {
+                            info!("User is not a member of group {}", group_msg.group_id);
+                            return Ok(());
+                        }
+
+                        if let Some(ref reply_to) = group_msg.reply_to {
+                            let conversation = Conversation::Group(group_msg.group_id.clone());
+                            if !store.has_message(&conversation, reply_to)? {
+                                info!("Reply to unknown message {} in group {}", reply_to, group_msg.group_id);
+                                return Ok(());
+                            }
+                        }
+                        
+                        // Add message to group
+                        let mut group_message = GroupMessage {
+                            group_id: group_msg.group_id.clone(),
+                            id: new_message_id(our_addr.node()),
+                            author: our_addr.node().to_string(),
+                            content: group_msg.message.clone(),
+                            timestamp: get_timestamp(),
+                            reply_to: group_msg.reply_to.clone(),
+                            attachments: store.resolve_attachments(&group_msg.attachments)?,
+                            ..Default::default()
+                        };
+                        encrypt_group_message(&our_addr, &mut group_message, store)?;
+                        
+                        if let Ok(group_message) = store.add_group_message(&group_msg.group_id, group_message) {
+                            // Send WebSocket message to update UI
+                            clients.push(server, &WsEvent::NewGroupMessage(group_message));
+                            announce_group(&our_addr, &group_msg.group_id, store);
+                        }
+                    } else {
+                        info!("Group not found: {}", group_msg.group_id);
+                    }
+                },
+                Ok(RequestType::CreateGroup { CreateGroup: create_req }) => {
+                    info!("Received create group request via WebSocket: {:?}", create_req);
+                    
+                    let members: HashSet<String> = create_req.members.into_iter().collect();
+                    
+                    // Add the creator to the group members
+                    let mut all_members = members.clone();
+                    all_members.insert(our_addr.node().to_string());
+                    
+                    match store.create_group(&create_req.name, all_members, our_addr.node()) {
+                        Ok(group) => {
+                            announce_group(&our_addr, &group.id, store);
+                            // Send WebSocket message to update UI
+                            clients.push(server, &WsEvent::NewGroup(group));
+                        }
+                        Err(e) => {
+                            info!("Failed to create group: {}", e);
+                        }
+                    }
+                },
+                Ok(RequestType::AddContact { AddContact: contact }) => {
+                    info!("Received add contact request via WebSocket: {:?}", contact);
+                    
+                    match store.add_contact(&contact) {
+                        Ok(()) => clients.push(
+                            server,
+                            &WsEvent::ContactAdded {
+                                id: contact.id,
+                                name: contact.name,
+                            },
+                        ),
+                        Err(e) => error!("Failed to add contact: {:?}", e),
+                    }
+                },
+                Ok(RequestType::RemoveContact { RemoveContact: id }) => {
+                    match store.remove_contact(&id) {
+                        Ok(true) => clients.push(server, &WsEvent::ContactRemoved { id }),
+                        Ok(false) => {}
+                        Err(e) => error!("Failed to remove contact: {:?}", e),
+                    }
+                },
+                Ok(RequestType::GetMessages { .. }) => {
+                    info!("Received GetMessages request via WebSocket");
+                    
+                    // Get all messages and send them back
+                    match store.get_all_messages() {
+                        Ok(messages) => {
+                            ws::reply(channel_id, &serde_json::json!({ "Messages": messages }));
                         }
                         Err(e) => {
-                            info!("Failed to get messages: {}", e);
+                            error!("Failed to get messages: {:?}", e);
+                            ws::reply(channel_id, &serde_json::json!({ "Error": e.to_string() }));
                         }
                     }
                 },
                 Ok(RequestType::GetContacts { .. }) => {
                     info!("Received GetContacts request via WebSocket");
                     
-                    // In a real implementation, we would get contacts from a database
-                    // For now, just send an empty list
-                    let update_blob = LazyLoadBlob {
-                        mime: Some("application/json".to_string()),
-                        bytes: serde_json::to_vec(&serde_json::json!({
-                            "Contacts": []
-                        }))
-                        .unwrap(),
-                    };
-                    server.ws_push_all_channels(WS_PATH, WsMessageType::Text, update_blob);
+                    match store.get_contacts() {
+                        Ok(contacts) => {
+                            ws::reply(channel_id, &serde_json::json!({ "Contacts": contacts }));
+                        }
+                        Err(e) => {
+                            error!("Failed to get contacts: {:?}", e);
+                            ws::reply(channel_id, &serde_json::json!({ "Error": e.to_string() }));
+                        }
+                    }
                 },
                 Ok(RequestType::GetGroups { .. }) => {
                     info!("Received GetGroups request via WebSocket");
@@ -453,34 +1798,30 @@ fn handle_http_server_request(
                     // Get all groups
                     match store.get_all_groups() {
                         Ok(groups) => {
-                            let update_blob = LazyLoadBlob {
-                                mime: Some("application/json".to_string()),
-                                bytes: serde_json::to_vec(&serde_json::json!({
-                                    "Groups": groups
-                                }))
-                                .unwrap(),
-                            };
-                            server.ws_push_all_channels(WS_PATH, WsMessageType::Text, update_blob);
+                            ws::reply(channel_id, &serde_json::json!({ "Groups": groups }));
                             
                             // For each group, also send its messages
                             for group in groups {
-                                if let Ok(messages) = store.get_group_messages(&group.id) {
-                                    let group_msg_blob = LazyLoadBlob {
-                                        mime: Some("application/json".to_string()),
-                                        bytes: serde_json::to_vec(&serde_json::json!({
+                                match store.get_group_messages(&group.id) {
+                                    Ok(messages) => ws::reply(
+                                        channel_id,
+                                        &serde_json::json!({
                                             "GroupMessages": {
                                                 "group_id": group.id,
                                                 "messages": messages
                                             }
-                                        }))
-                                        .unwrap(),
-                                    };
-                                    server.ws_push_all_channels(WS_PATH, WsMessageType::Text, group_msg_blob);
+                                        }),
+                                    ),
+                                    Err(e) => {
+                                        error!("Failed to get messages for group {}: {:?}", group.id, e);
+                                        ws::reply(channel_id, &serde_json::json!({ "Error": e.to_string() }));
+                                    }
                                 }
                             }
                         }
                         Err(e) => {
-                            info!("Failed to get groups: {}", e);
+                            error!("Failed to get groups: {:?}", e);
+                            ws::reply(channel_id, &serde_json::json!({ "Error": e.to_string() }));
                         }
                     }
                 },
@@ -490,22 +1831,124 @@ fn handle_http_server_request(
                     // Get messages for a specific group
                     match store.get_group_messages(&req.group_id) {
                         Ok(messages) => {
-                            let update_blob = LazyLoadBlob {
-                                mime: Some("application/json".to_string()),
-                                bytes: serde_json::to_vec(&serde_json::json!({
+                            ws::reply(
+                                channel_id,
+                                &serde_json::json!({
                                     "GroupMessages": {
                                         "group_id": req.group_id,
                                         "messages": messages
                                     }
-                                }))
-                                .unwrap(),
-                            };
-                            server.ws_push_all_channels(WS_PATH, WsMessageType::Text, update_blob);
+                                }),
+                            );
+                        }
+                        Err(e) => {
+                            error!("Failed to get group messages: {:?}", e);
+                            ws::reply(channel_id, &serde_json::json!({ "Error": e.to_string() }));
+                        }
+                    }
+                },
+                Ok(RequestType::Subscribe { Subscribe: req }) => {
+                    info!("Channel {} subscribing to {:?}", channel_id, req.conversations);
+                    clients.subscribe(channel_id, req.conversations);
+                },
+                Ok(RequestType::Unsubscribe { Unsubscribe: req }) => {
+                    info!("Channel {} unsubscribing from {:?}", channel_id, req.conversations);
+                    clients.unsubscribe(channel_id, req.conversations);
+                },
+                Ok(RequestType::Typing { Typing: req }) => {
+                    send_typing_notice(&our_addr, &req, store, server, clients)?;
+                },
+                Ok(RequestType::EditMessage { EditMessage: req }) => {
+                    handle_local_message_action(
+                        &our_addr,
+                        req.conversation,
+                        req.message_id,
+                        MessageAction::Edit { content: req.content },
+                        store,
+                        server,
+                        clients,
+                    )?;
+                },
+                Ok(RequestType::DeleteMessage { DeleteMessage: req }) => {
+                    handle_local_message_action(
+                        &our_addr,
+                        req.conversation,
+                        req.message_id,
+                        MessageAction::Delete { for_everyone: req.for_everyone },
+                        store,
+                        server,
+                        clients,
+                    )?;
+                },
+                Ok(RequestType::GetThread { GetThread: req }) => {
+                    match store.get_thread_json(&req.conversation, &req.message_id) {
+                        Ok(Some(thread)) => {
+                            ws::reply(
+                                channel_id,
+                                &serde_json::json!({
+                                    "Thread": {
+                                        "conversation": req.conversation,
+                                        "message_id": req.message_id,
+                                        "messages": thread
+                                    }
+                                }),
+                            );
                         }
-                        Err(e) => {
-                            info!("Failed to get group messages: {}", e);
+                        Ok(None) => info!("Thread not found: {}", req.message_id),
+                        Err(e) => info!("Failed to get thread: {}", e),
+                    }
+                },
+                Ok(RequestType::MarkRead { MarkRead: req }) => {
+                    if mark_conversation_read(
+                        &our_addr,
+                        &req.conversation,
+                        req.message_id.as_deref(),
+                        store,
+                        server,
+                        clients,
+                    )?
+                    .is_none()
+                    {
+                        info!("Nothing to mark read in {:?}", req.conversation);
+                    }
+                },
+                Ok(RequestType::GetConversations { .. }) => {
+                    match store.list_conversations(our_addr.node()) {
+                        Ok(conversations) => {
+                            ws::reply(channel_id, &serde_json::json!({ "Conversations": conversations }));
                         }
+                        Err(e) => info!("Failed to list conversations: {}", e),
+                    }
+                },
+                Ok(RequestType::Sync { Sync: req }) => {
+                    // Replay what the client missed, then tell it where every
+                    // conversation stands
+                    let mut cursors: HashMap<Conversation, u64> = req
+                        .conversations
+                        .into_iter()
+                        .map(|cursor| (cursor.conversation, cursor.seq))
+                        .collect();
+                    let mut statuses = Vec::new();
+                    for summary in store.list_conversations(our_addr.node())? {
+                        let since = cursors.remove(&summary.conversation);
+                        statuses.push(clients.replay(channel_id, &summary.conversation, since)?);
                     }
+                    // Conversations we no longer have
+                    for (conversation, seq) in cursors {
+                        statuses.push(clients.replay(channel_id, &conversation, Some(seq))?);
+                    }
+                    ws::reply(channel_id, &serde_json::json!({ "Synced": statuses }));
+                },
+                Ok(RequestType::ReactToMessage { ReactToMessage: req }) => {
+                    handle_local_message_action(
+                        &our_addr,
+                        req.conversation,
+                        req.message_id,
+                        MessageAction::React { emoji: req.emoji, add: req.add },
+                        store,
+                        server,
+                        clients,
+                    )?;
                 },
                 Err(e) => {
                     info!("Failed to parse WebSocket message: {}", e);
@@ -531,6 +1974,11 @@ fn handle_http_server_request(
                                         let request = HyperwareChatRequest::Send(SendRequest {
                                             target: target.to_string(),
                                             message: message.to_string(),
+                                            author: None,
+                                            id: None,
+                                            reply_to: send.get("reply_to").and_then(|v| v.as_str()).map(String::from),
+                                            attachments: send.get("attachments").and_then(|v| serde_json::from_value(v.clone()).ok()),
+                                            encrypted: None,
                                         });
                                         
                                         handle_chat_request(
@@ -540,6 +1988,7 @@ fn handle_http_server_request(
                                             true,
                                             store,
                                             server,
+                                            clients,
                                         )?;
                                         return Ok(());
                                     }
@@ -558,6 +2007,11 @@ fn handle_http_server_request(
                                 let request = HyperwareChatRequest::Send(SendRequest {
                                     target: target.to_string(),
                                     message: message.to_string(),
+                                    author: None,
+                                    id: None,
+                                    reply_to: send.get("reply_to").and_then(|v| v.as_str()).map(String::from),
+                                    attachments: send.get("attachments").and_then(|v| serde_json::from_value(v.clone()).ok()),
+                                    encrypted: None,
                                 });
                                 
                                 handle_chat_request(
@@ -567,6 +2021,7 @@ fn handle_http_server_request(
                                     true,
                                     store,
                                     server,
+                                    clients,
                                 )?;
                                 return Ok(());
                             }
@@ -575,597 +2030,1164 @@ fn handle_http_server_request(
                 }
             }
         }
-        HttpServerRequest::Http(request) => {
-            let path = request.bound_path(None);
-            let method_str = request.method().unwrap();
-            let method = method_str.as_str();
-            
-            // Handle messages API
-            if path.starts_with(HTTP_API_PATH) {
-                match method {
-                    // Get all messages
-                    "GET" => {
-                        let headers = HashMap::from([(
-                            "Content-Type".to_string(),
-                            "application/json".to_string(),
-                        )]);
-
-                        let messages = store.get_all_messages().unwrap_or_default();
-                        
-                        send_response(
-                            StatusCode::OK,
-                            Some(headers),
-                            serde_json::to_vec(&serde_json::json!({
-                                "History": {
-                                    "messages": messages
-                                }
-                            }))
-                            .unwrap(),
-                        );
-                    }
-                    // Send a message
-                    "POST" => {
-                        let Some(blob) = get_blob() else {
-                            send_response(StatusCode::BAD_REQUEST, None, vec![]);
-                            return Ok(());
-                        };
-                        
-                        // Log the raw request to help with debugging
-                        let request_str = String::from_utf8_lossy(&blob.bytes);
-                        info!("Received HTTP POST request: {}", request_str);
-                        
-                        let our_addr = standard::our();
-                        
-                        // Try to parse as HyperwareChatRequest directly first
-                        if let Ok(request) = serde_json::from_slice::<HyperwareChatRequest>(&blob.bytes) {
-                            handle_chat_request(
-                                &our_addr,
-                                &make_http_address(&our_addr),
-                                request,
-                                true,
-                                store,
-                                server,
-                            )?;
-                        } 
-                        // Try to parse as a JSON object that might contain a Send field
-                        else if let Ok(json_value) = serde_json::from_slice::<serde_json::Value>(&blob.bytes) {
-                            if let Some(send_obj) = json_value.get("Send") {
-                                if let (Some(target), Some(message)) = (
-                                    send_obj.get("target").and_then(|v| v.as_str()), 
-                                    send_obj.get("message").and_then(|v| v.as_str())
-                                ) {
-                                    let request = HyperwareChatRequest::Send(SendRequest {
-                                        target: target.to_string(),
-                                        message: message.to_string(),
-                                    });
-                                    
-                                    handle_chat_request(
-                                        &our_addr,
-                                        &make_http_address(&our_addr),
-                                        request,
-                                        true,
-                                        store,
-                                        server,
-                                    )?;
-                                } else {
-                                    info!("JSON has Send field but missing target or message");
-                                    send_response(StatusCode::BAD_REQUEST, None, vec![]);
-                                    return Ok(());
-                                }
-                            } else {
-                                info!("JSON doesn't contain Send field: {:?}", json_value);
-                                send_response(StatusCode::BAD_REQUEST, None, vec![]);
-                                return Ok(());
-                            }
-                        } else {
-                            info!("Failed to parse HTTP blob as either HyperwareChatRequest or JSON");
-                            send_response(StatusCode::BAD_REQUEST, None, vec![]);
-                            return Ok(());
-                        }
+        HttpServerRequest::Http(request) => {
+            let mut context = HttpContext {
+                our: standard::our(),
+                store,
+                server,
+                clients,
+            };
+            routes::router().dispatch(&mut context, &request);
+        }
+    };
+
+    Ok(())
+}
+
+fn handle_chat_request(
+    our: &Address,
+    source: &Address,
+    request: HyperwareChatRequest,
+    is_http: bool,
+    store: &ChatStore,
+    server: &HttpServer,
+    clients: &mut WsClients,
+) -> anyhow::Result<()> {
+    match request {
+        HyperwareChatRequest::Send(SendRequest {
+            ref target,
+            ref message,
+            ref author,
+            ref id,
+            ref reply_to,
+            ref attachments,
+            ref encrypted,
+        }) => {
+            // Counterparty is the other node in the hyperware-chat with us
+            let (counterparty, sender) = if target == &our.node {
+                (&source.node, source.node.clone())
+            } else {
+                (target, our.node.clone())
+            };
+
+            // A local caller may only claim to be us; authenticate_remote
+            // already checked the claims of other nodes
+            if let Some(claimed) = author.as_ref().filter(|_| source.node == our.node) {
+                if claimed != &sender {
+                    if !is_http {
+                        Response::new()
+                            .body(HyperwareChatResponse::Err(format!(
+                                "author {} does not match sender {}",
+                                claimed, sender
+                            )))
+                            .send()?;
+                    }
+                    return Err(anyhow::anyhow!(
+                        "rejecting spoofed author {} from {}",
+                        claimed,
+                        sender
+                    ));
+                }
+            }
+            let author = sender;
+            let id = id.clone().unwrap_or_else(|| new_message_id(&author));
+
+            // Each message is taken once; a resend only needs our answer again
+            if target == &our.node && source.node != our.node && store.has_direct_message(&source.node, &id)? {
+                info!("Ignoring repeated message {} from {}", id, source.node);
+                Response::new().body(HyperwareChatResponse::Send).send()?;
+                return Ok(());
+            }
+
+            // Encrypted content is bound to its author, conversation and id
+            let mut ciphertext = None;
+            let content = match encrypted {
+                Some(payload) if target == &our.node => {
+                    let aad = e2e::message_aad(&author, target, &id);
+                    match store.e2e.decrypt_direct(&source.node, payload, &aad) {
+                        Ok(plaintext) => {
+                            ciphertext = Some(payload.clone());
+                            plaintext
+                        }
+                        Err(e) => {
+                            if !is_http {
+                                Response::new()
+                                    .body(HyperwareChatResponse::Err(e.to_string()))
+                                    .send()?;
+                            }
+                            return Err(e);
+                        }
+                    }
+                }
+                _ => message.clone(),
+            };
+
+            // Our own replies must point at a message we have; a peer's reply may
+            // point at one we deleted for ourselves, so don't check those
+            if let Some(parent) = reply_to {
+                let conversation = Conversation::Direct(counterparty.to_string());
+                if target != &our.node && !store.has_message(&conversation, parent)? {
+                    if !is_http {
+                        Response::new()
+                            .body(HyperwareChatResponse::Err(format!("unknown message {}", parent)))
+                            .send()?;
+                    }
+                    return Err(anyhow::anyhow!("reply to unknown message {}", parent));
+                }
+            }
+
+            let attachment_ids = attachments.clone().unwrap_or_default();
+            let attachments = store.resolve_attachments(&attachment_ids)?;
+            let mut delivered = false;
+
+            // If the target is not us, send a request to the target
+            if target == &our.node {
+                println!("{}: {}", source.node, content);
+            } else {
+                if attachments.len() != attachment_ids.len() {
+                    return Err(anyhow::anyhow!("message refers to unknown attachments"));
+                }
+
+                // With end-to-end encryption on, only ciphertext leaves this node
+                if store.get_settings()?.e2e {
+                    ensure_peer_keys(target, store)?;
+                    let aad = e2e::message_aad(&author, target, &id);
+                    ciphertext = Some(store.e2e.encrypt_direct(target, &content, &aad)?);
+                }
+
+                let request = SendRequest {
+                    target: target.clone(),
+                    message: if ciphertext.is_some() {
+                        String::new()
+                    } else {
+                        content.clone()
+                    },
+                    author: Some(author.clone()),
+                    id: Some(id.clone()),
+                    reply_to: reply_to.clone(),
+                    attachments: Some(attachment_ids),
+                    encrypted: ciphertext.clone(),
+                };
+                if attachments.is_empty() {
+                    let response = match Request::new()
+                        .target((target, "hyperware-chat", "hyperware-chat", "template.os"))
+                        .body(HyperwareChatRequest::Send(request))
+                        .send_and_await_response(5)?
+                    {
+                        Ok(response) => response,
+                        Err(e) => {
+                            mark_unreachable(target, store, server, clients);
+                            return Err(e.into());
+                        }
+                    };
+                    mark_seen(target, store, server, clients);
+                    if let Ok(HyperwareChatResponse::Err(reason)) = response.body().try_into() {
+                        return Err(anyhow::anyhow!("{} rejected message: {}", target, reason));
+                    }
+                    delivered = true;
+                } else {
+                    // Attachments are copied over first so they are there when
+                    // the message lands; handle_transfer_response takes it from here
+                    store.attachments.start_transfer(target, attachments.clone(), request)?;
+                }
+            }
+
+            // Insert message into archive
+            let new_message = ChatMessage {
+                id: id.clone(),
+                author: author.clone(),
+                content: content.clone(),
+                timestamp: get_timestamp(),
+                reply_to: reply_to.clone(),
+                attachments,
+                encrypted: ciphertext,
+                ..Default::default()
+            };
+
+            // First contact from a node we don't know waits for our approval
+            // instead of landing in our history
+            if target == &our.node && source.node != our.node && !store.is_known_sender(&source.node, &our.node)? {
+                store.quarantine_message(&source.node, new_message.clone())?;
+                Response::new().body(HyperwareChatResponse::Send).send()?;
+                clients.push(
+                    server,
+                    &WsEvent::MessageRequest(NewMessage {
+                        hyperware_chat: source.node.clone(),
+                        id,
+                        author,
+                        content,
+                        timestamp: new_message.timestamp,
+                        reply_to: new_message.reply_to,
+                        attachments: new_message.attachments,
+                    }),
+                );
+                return Ok(());
+            }
+
+            // Add message to store
+            store.add_message(counterparty, new_message.clone())?;
+
+            // The peer accepted our message: let the UI mark it delivered
+            if delivered {
+                clients.push(
+                    server,
+                    &WsEvent::DeliveryReceipt {
+                        conversation: Conversation::Direct(counterparty.to_string()),
+                        message_id: id.clone(),
+                    },
+                );
+            }
+
+            if is_http {
+                // If is HTTP from FE: done
+                return Ok(());
+            }
+
+            // Not HTTP from FE: send response to node & update any FE listeners
+            Response::new().body(HyperwareChatResponse::Send).send()?;
+
+            // Send a WebSocket message to the http server in order to update the UI
+            clients.push(
+                server,
+                &WsEvent::NewMessage(NewMessage {
+                    hyperware_chat: counterparty.to_string(),
+                    id,
+                    author,
+                    content,
+                    timestamp: new_message.timestamp,
+                    reply_to: new_message.reply_to,
+                    attachments: new_message.attachments,
+                }),
+            );
+        }
+        HyperwareChatRequest::TypingNotice(TypingNotice {
+            group_id,
+            is_typing,
+        }) => {
+            clients.push(
+                server,
+                &WsEvent::Typing {
+                    conversation: source_conversation(source, group_id),
+                    node: source.node.clone(),
+                    is_typing,
+                },
+            );
+        }
+        HyperwareChatRequest::ReadReceipt(ReadReceipt {
+            group_id,
+            message_id,
+        }) => {
+            clients.push(
+                server,
+                &WsEvent::ReadReceipt {
+                    conversation: source_conversation(source, group_id),
+                    node: source.node.clone(),
+                    message_id,
+                },
+            );
+        }
+        HyperwareChatRequest::Edit(EditRequest {
+            group_id,
+            message_id,
+            content,
+            encrypted,
+        }) => {
+            let conversation = source_conversation(source, group_id);
+            let content = match encrypted {
+                Some(payload) => decrypt_edit(our, &source.node, &conversation, &message_id, &payload, store)?,
+                None => content,
+            };
+            let action = MessageAction::Edit { content };
+            let event = apply_message_action(store, &conversation, &message_id, &source.node, &action)?;
+            clients.push(server, &event);
+        }
+        HyperwareChatRequest::Delete(DeleteRequest {
+            group_id,
+            message_id,
+        }) => {
+            let conversation = source_conversation(source, group_id);
+            let action = MessageAction::Delete { for_everyone: true };
+            let event = apply_message_action(store, &conversation, &message_id, &source.node, &action)?;
+            clients.push(server, &event);
+        }
+        HyperwareChatRequest::React(ReactRequest {
+            group_id,
+            message_id,
+            emoji,
+            add,
+        }) => {
+            let conversation = source_conversation(source, group_id);
+            let action = MessageAction::React { emoji, add };
+            let event = apply_message_action(store, &conversation, &message_id, &source.node, &action)?;
+            clients.push(server, &event);
+        }
+        HyperwareChatRequest::History(ref node) => {
+            let messages = store.get_messages(node)?;
+            
+            // Convert to WIT message format
+            let wit_messages: Vec<HyperwareChatMessage> = messages
+                .iter()
+                .map(StoredMessage::to_wit)
+                .collect();
+            
+            Response::new()
+                .body(HyperwareChatResponse::History(wit_messages))
+                .send()?;
+        }
+        HyperwareChatRequest::AttachmentChunk(ref chunk) => {
+            let bytes = get_blob().map(|blob| blob.bytes).unwrap_or_default();
+            let response = match store.attachments.receive_chunk(&source.node, chunk, &bytes) {
+                Ok(()) => HyperwareChatResponse::AttachmentChunk,
+                Err(e) => {
+                    info!("Rejecting attachment chunk from {}: {}", source.node, e);
+                    HyperwareChatResponse::Err(e.to_string())
+                }
+            };
+            Response::new().body(response).send()?;
+        }
+        HyperwareChatRequest::Thread(ThreadRequest {
+            conversation,
+            message_id,
+        }) => {
+            let response = match store.get_thread(&conversation.into(), &message_id)? {
+                Some(thread) => HyperwareChatResponse::Thread(thread),
+                None => HyperwareChatResponse::Err(format!("message {} not found", message_id)),
+            };
+            Response::new().body(response).send()?;
+        }
+        HyperwareChatRequest::Search(search_request) => {
+            let query = SearchQuery::from(search_request);
+            let response = match store.search(&query) {
+                Ok(hits) => HyperwareChatResponse::Search(
+                    hits.into_iter()
+                        .map(|hit| WitSearchHit {
+                            conversation: hit.conversation.into(),
+                            message: hit.wit,
+                            timestamp: hit.timestamp,
+                        })
+                        .collect(),
+                ),
+                Err(e) => HyperwareChatResponse::Err(e.to_string()),
+            };
+            Response::new().body(response).send()?;
+        }
+        HyperwareChatRequest::KeyExchange(keys) => {
+            let response = if source.node == our.node {
+                HyperwareChatResponse::Err("key exchange is between nodes".to_string())
+            } else {
+                match store.e2e.remember_peer(&source.node, &keys) {
+                    Ok(()) => HyperwareChatResponse::KeyExchange(store.e2e.public_keys()),
+                    Err(e) => {
+                        info!("Rejecting keys from {}: {}", source.node, e);
+                        HyperwareChatResponse::Err(e.to_string())
+                    }
+                }
+            };
+            Response::new().body(response).send()?;
+        }
+        HyperwareChatRequest::SenderKey(sender_key) => {
+            let response = match store.e2e.receive_sender_key(&source.node, &sender_key) {
+                Ok(()) => HyperwareChatResponse::SenderKey,
+                Err(e) => {
+                    info!("Rejecting sender key from {}: {}", source.node, e);
+                    HyperwareChatResponse::Err(e.to_string())
+                }
+            };
+            Response::new().body(response).send()?;
+        }
+        // The sender is marked seen before we get here; just answer
+        HyperwareChatRequest::Heartbeat => {
+            Response::new().body(HyperwareChatResponse::Heartbeat).send()?;
+        }
+        HyperwareChatRequest::GroupSync(digest) => {
+            let theirs = reconcile::from_digest(&digest);
+            match store.get_group(&digest.group_id)? {
+                Some(group) => {
+                    let messages = store.get_group_messages(&group.id)?;
+                    let actions = store.get_group_actions(&group.id)?;
+                    let ours = reconcile::clock(&group, &messages, &actions);
+                    let mut delta = reconcile::delta(&group, &messages, &actions, &theirs, &our.node);
+                    // Former members only learn that they were removed
+                    if !group.members.contains(&source.node) {
+                        delta.messages.clear();
+                        delta.actions.clear();
+                    }
+                    Response::new()
+                        .body(HyperwareChatResponse::GroupSync(reconcile::to_digest(&group.id, &ours)))
+                        .blob_bytes(serde_json::to_vec(&delta)?)
+                        .send()?;
+                    if source.node != our.node && reconcile::is_behind(&ours, &theirs, &source.node) {
+                        request_group_sync(&group.id, &source.node, store)?;
+                    }
+                    if let Err(e) = share_sender_key(our, &group.id, &source.node, &theirs, store) {
+                        info!("Failed to give {} our key for group {}: {}", source.node, group.id, e);
+                    }
+                }
+                None => {
+                    Response::new()
+                        .body(HyperwareChatResponse::GroupSync(reconcile::to_digest(
+                            &digest.group_id,
+                            &Clock::new(),
+                        )))
+                        .send()?;
+                    // Pull from the sender, which only answers if we are a
+                    // member. Like a message, an invitation from a node we
+                    // don't know waits until we approve it; the creator keeps
+                    // announcing the group until then.
+                    if source.node != our.node {
+                        if store.is_known_sender(&source.node, &our.node)? {
+                            request_group_sync(&digest.group_id, &source.node, store)?;
+                        } else {
+                            info!("Ignoring invitation to group {} from unknown {}", digest.group_id, source.node);
+                        }
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// The conversation a peer's request refers to: the group if given, otherwise
+// our direct conversation with the peer
+fn source_conversation(source: &Address, group_id: Option<String>) -> Conversation {
+    match group_id {
+        Some(group_id) => Conversation::Group(group_id),
+        None => Conversation::Direct(source.node.clone()),
+    }
+}
+
+// The other nodes in a conversation, plus the group id to send them if any
+fn conversation_peers(
+    our: &Address,
+    conversation: &Conversation,
+    store: &ChatStore,
+) -> anyhow::Result<(Vec<String>, Option<String>)> {
+    let (members, group_id) = match conversation {
+        Conversation::Direct(node) => (vec![node.clone()], None),
+        Conversation::Group(group_id) => {
+            let Some(group) = store.get_group(group_id)? else {
+                return Err(anyhow::anyhow!("group {} not found", group_id));
+            };
+            (group.members.into_iter().collect(), Some(group_id.clone()))
+        }
+    };
+    let peers = members.into_iter().filter(|node| node != &our.node).collect();
+    Ok((peers, group_id))
+}
+
+// Fire-and-forget a request to hyperware-chat on each peer
+fn notify_peers(peers: &[String], request: HyperwareChatRequest) {
+    for peer in peers {
+        if let Err(e) = Request::new()
+            .target((peer, "hyperware-chat", "hyperware-chat", "template.os"))
+            .body(request.clone())
+            .send()
+        {
+            info!("Failed to notify {}: {}", peer, e);
+        }
+    }
+}
+
+// A node answered or wrote to us, so it is online now
+fn mark_seen(node: &str, store: &ChatStore, server: &HttpServer, clients: &mut WsClients) {
+    let now = get_timestamp();
+    if let Err(e) = store.set_last_seen(node, now) {
+        error!("Failed to record last seen for {}: {}", node, e);
+    }
+    clients.set_presence(server, node, true, Some(now));
+}
+
+fn mark_unreachable(node: &str, store: &ChatStore, server: &HttpServer, clients: &mut WsClients) {
+    let last_seen = store.get_last_seen(node).unwrap_or_else(|e| {
+        error!("Failed to read last seen for {}: {}", node, e);
+        None
+    });
+    clients.set_presence(server, node, false, last_seen);
+}
+
+// Ping every peer; answers and timeouts come back through the main loop
+fn send_heartbeats(our: &Address, store: &ChatStore) {
+    let peers = match store.presence_peers(our.node()) {
+        Ok(peers) => peers,
+        Err(e) => {
+            error!("Failed to list heartbeat peers: {}", e);
+            return;
+        }
+    };
+    for peer in peers {
+        if let Err(e) = Request::new()
+            .target((peer.as_str(), "hyperware-chat", "hyperware-chat", "template.os"))
+            .body(HyperwareChatRequest::Heartbeat)
+            .context(presence::HEARTBEAT_CONTEXT)
+            .expects_response(presence::HEARTBEAT_TIMEOUT_SECS)
+            .send()
+        {
+            info!("Failed to send heartbeat to {}: {}", peer, e);
+        }
+    }
+}
+
+// A request we sent went unanswered; for heartbeats that means the peer is offline
+fn handle_send_error(
+    send_error: &SendError,
+    store: &ChatStore,
+    server: &HttpServer,
+    clients: &mut WsClients,
+) {
+    let context = send_error.context.as_deref().unwrap_or_default();
+    if context == presence::HEARTBEAT_CONTEXT {
+        mark_unreachable(&send_error.target.node, store, server, clients);
+    } else if let Some(transfer) = attachments::Transfer::from_context(context) {
+        // The peer drops what it received once the upload stalls
+        error!(
+            "Transfer of message {:?} to {} timed out",
+            transfer.message.id, transfer.target
+        );
+        mark_unreachable(&send_error.target.node, store, server, clients);
+    } else if context == e2e::KEY_EXCHANGE_CONTEXT {
+        // Asked again on the next sync
+        info!("{} didn't answer our key exchange", send_error.target.node);
+    } else if let Some(delivery) = e2e::KeyDelivery::from_context(context) {
+        // Not marked delivered, so the key goes again on the next sync
+        info!(
+            "{} didn't answer our key for group {}",
+            send_error.target.node, delivery.group_id
+        );
+    } else {
+        error!("got SendError: {send_error}");
+    }
+}
+
+// A peer answered a step of a transfer: send it the next chunk, or once it
+// took the message itself, let the UI mark that delivered
+fn handle_transfer_response(
+    source: &Address,
+    body: &[u8],
+    transfer: attachments::Transfer,
+    store: &ChatStore,
+    server: &HttpServer,
+    clients: &mut WsClients,
+) -> anyhow::Result<()> {
+    mark_seen(&source.node, store, server, clients);
+    if let Ok(HyperwareChatResponse::Err(reason)) = body.try_into() {
+        return Err(anyhow::anyhow!(
+            "{} rejected message {:?}: {}",
+            source.node,
+            transfer.message.id,
+            reason
+        ));
+    }
+    if !transfer.pending.is_empty() {
+        return store.attachments.send_next(transfer.advance());
+    }
+    clients.push(
+        server,
+        &WsEvent::DeliveryReceipt {
+            conversation: Conversation::Direct(source.node.clone()),
+            message_id: transfer.message.id.unwrap_or_default(),
+        },
+    );
+    Ok(())
+}
+
+// Every peer we exchange heartbeats with and what we know of them
+fn list_presence(our: &Address, store: &ChatStore, clients: &WsClients) -> anyhow::Result<Vec<PeerPresence>> {
+    let mut peers = Vec::new();
+    for node in store.presence_peers(our.node())? {
+        peers.push(PeerPresence {
+            online: clients.is_online(&node),
+            last_seen: store.get_last_seen(&node)?,
+            node,
+        });
+    }
+    peers.sort_by(|a, b| a.node.cmp(&b.node));
+    Ok(peers)
+}
+
+// Send `peer` our digest of a group. It answers with the events we lack, and
+// pulls from us in turn if we hold events it doesn't.
+fn request_group_sync(group_id: &str, peer: &str, store: &ChatStore) -> anyhow::Result<()> {
+    Request::new()
+        .target((peer, "hyperware-chat", "hyperware-chat", "template.os"))
+        .body(HyperwareChatRequest::GroupSync(store.group_digest(group_id)?))
+        .context(reconcile::SYNC_CONTEXT)
+        .expects_response(reconcile::SYNC_TIMEOUT_SECS)
+        .send()?;
+    Ok(())
+}
+
+// Sync a group with every other member, e.g. after we changed it
+fn announce_group(our: &Address, group_id: &str, store: &ChatStore) {
+    let members = match store.get_group(group_id) {
+        Ok(Some(group)) => group.members,
+        Ok(None) => return,
+        Err(e) => {
+            error!("Failed to read group {}: {:?}", group_id, e);
+            return;
+        }
+    };
+    for member in members.iter().filter(|member| *member != our.node()) {
+        if let Err(e) = request_group_sync(group_id, member, store) {
+            info!("Failed to sync group {} with {}: {}", group_id, member, e);
+        }
+    }
+}
+
+// Reconcile the groups we are in, so members that were offline catch up.
+// With many groups each heartbeat takes its turn of them.
+fn sync_groups(our: &Address, store: &ChatStore) {
+    let groups = match store.get_all_groups() {
+        Ok(groups) => groups,
+        Err(e) => {
+            error!("Failed to list groups for sync: {:?}", e);
+            return;
+        }
+    };
+    let groups: Vec<&Group> = groups
+        .iter()
+        .filter(|group| group.members.contains(our.node()))
+        .collect();
+    let rounds = groups.len().div_ceil(reconcile::MAX_SYNCS_PER_ROUND).max(1);
+    let round = (get_timestamp() * 1000 / presence::HEARTBEAT_INTERVAL_MS) as usize % rounds;
+    for group in groups.iter().skip(round).step_by(rounds) {
+        request_member_keys(our, group, store);
+        announce_group(our, &group.id, store);
+    }
+}
+
+// Ask members whose keys we lack for them, so we can check the signatures
+// on events other members relay from them
+fn request_member_keys(our: &Address, group: &Group, store: &ChatStore) {
+    for member in group.members.iter().filter(|member| *member != our.node()) {
+        match store.e2e.peer_keys(member) {
+            Ok(Some(_)) => {}
+            Ok(None) => {
+                let sent = Request::new()
+                    .target((member.as_str(), "hyperware-chat", "hyperware-chat", "template.os"))
+                    .body(HyperwareChatRequest::KeyExchange(store.e2e.public_keys()))
+                    .context(e2e::KEY_EXCHANGE_CONTEXT.to_vec())
+                    .expects_response(5)
+                    .send();
+                if let Err(e) = sent {
+                    error!("Failed to ask {} for keys: {:?}", member, e);
+                }
+            }
+            Err(e) => error!("Failed to read keys of {}: {:?}", member, e),
+        }
+    }
+}
+
+// A member answered our group sync: merge the events it sent
+fn handle_group_sync_response(
+    our: &Address,
+    source: &Address,
+    body: &[u8],
+    store: &ChatStore,
+    server: &HttpServer,
+    clients: &mut WsClients,
+) -> anyhow::Result<()> {
+    let response: HyperwareChatResponse = serde_json::from_slice(body)?;
+    let digest = match response {
+        HyperwareChatResponse::GroupSync(digest) => digest,
+        HyperwareChatResponse::Err(reason) => {
+            info!("{} refused group sync: {}", source.node, reason);
+            return Ok(());
+        }
+        _ => return Ok(()),
+    };
+    // No blob: the member doesn't have the group
+    let Some(blob) = get_blob() else {
+        return Ok(());
+    };
+    let delta: GroupDelta = serde_json::from_slice(&blob.bytes)?;
+    if delta.group.id != digest.group_id {
+        return Err(anyhow::anyhow!("group sync from {} mixes up groups", source.node));
+    }
+
+    let merge = store.merge_group_delta(&source.node, our.node(), delta)?;
+    if merge.is_new || merge.membership_changed {
+        if let Err(e) = rotate_group_sender_key(our, &merge.group.id, store) {
+            error!("Failed to rotate key for group {}: {}", merge.group.id, e);
+        }
+        // Members learn the new membership, and then get our key, as we sync
+        announce_group(our, &merge.group.id, store);
+    } else if let Err(e) = share_sender_key(our, &merge.group.id, &source.node, &reconcile::from_digest(&digest), store) {
+        info!("Failed to give {} our key for group {}: {}", source.node, merge.group.id, e);
+    }
+    if merge.is_new {
+        clients.push(server, &WsEvent::NewGroup(merge.group.clone()));
+    } else if merge.membership_changed {
+        clients.push(server, &WsEvent::GroupUpdated(merge.group.clone()));
+    }
+    for message in merge.messages {
+        clients.push(server, &WsEvent::NewGroupMessage(message));
+    }
+    for event in merge.events {
+        clients.push(server, &event);
+    }
+    Ok(())
+}
 
-                        send_response(StatusCode::CREATED, None, vec![]);
-                    }
-                    _ => send_response(StatusCode::METHOD_NOT_ALLOWED, None, vec![]),
-                }
+// Apply an edit, delete or reaction by `actor` to our copy of a conversation,
+// returning the event that tells the UI about it
+fn apply_message_action(
+    store: &ChatStore,
+    conversation: &Conversation,
+    message_id: &str,
+    actor: &str,
+    action: &MessageAction,
+) -> anyhow::Result<WsEvent> {
+    let timestamp = get_timestamp();
+    let found = match action {
+        MessageAction::Delete { for_everyone: false } => {
+            store.remove_message(conversation, message_id)?
+        }
+        _ => store.update_message(conversation, message_id, |message| {
+            if message.is_deleted() {
+                return Err(anyhow::anyhow!("message {} was deleted", message_id));
             }
-            // Handle groups API
-            else if path.starts_with(GROUPS_API_PATH) {
-                let headers = HashMap::from([(
-                    "Content-Type".to_string(),
-                    "application/json".to_string(),
-                )]);
-
-                match method {
-                    "GET" => {
-                        // Get a specific group or list all groups
-                        let query_params = request.query_params();
-                        if let Some(group_id) = query_params.get("id") {
-                            // Get specific group and its messages
-                            if let Ok(Some(group)) = store.get_group(group_id) {
-                                let messages = store.get_group_messages(group_id).unwrap_or_default();
-                                
-                                send_response(
-                                    StatusCode::OK,
-                                    Some(headers),
-                                    serde_json::to_vec(&ApiResponse {
-                                        success: true,
-                                        data: Some(serde_json::json!({
-                                            "group": group,
-                                            "messages": messages
-                                        })),
-                                        error: None,
-                                    })
-                                    .unwrap(),
-                                );
-                            } else {
-                                send_response(
-                                    StatusCode::NOT_FOUND,
-                                    Some(headers),
-                                    serde_json::to_vec(&ApiResponse::<()> {
-                                        success: false,
-                                        data: None,
-                                        error: Some("Group not found".to_string()),
-                                    })
-                                    .unwrap(),
-                                );
-                            }
-                        } else {
-                            // List all groups
-                            let groups = store.get_all_groups().unwrap_or_default();
-                            
-                            send_response(
-                                StatusCode::OK,
-                                Some(headers),
-                                serde_json::to_vec(&ApiResponse {
-                                    success: true,
-                                    data: Some(serde_json::json!({ "groups": groups })),
-                                    error: None,
-                                })
-                                .unwrap(),
-                            );
-                        }
-                    }
-                    "POST" => {
-                        // Create a new group
-                        let Some(blob) = get_blob() else {
-                            send_response(StatusCode::BAD_REQUEST, None, vec![]);
-                            return Ok(());
-                        };
-                        
-                        // Parse create group request
-                        if let Ok(create_group_req) = serde_json::from_slice::<CreateGroupRequest>(&blob.bytes) {
-                            let members: HashSet<String> = create_group_req.members.into_iter().collect();
-                            
-                            // Add the creator to the group members
-                            let our_addr = standard::our();
-                            let mut all_members = members.clone();
-                            all_members.insert(our_addr.node().to_string());
-                            
-                            match store.create_group(&create_group_req.name, all_members, our_addr.node()) {
-                                Ok(group) => {
-                                    send_response(
-                                        StatusCode::CREATED,
-                                        Some(headers),
-                                        serde_json::to_vec(&ApiResponse {
-                                            success: true,
-                                            data: Some(serde_json::json!({ "group": group })),
-                                            error: None,
-                                        })
-                                        .unwrap(),
-                                    );
-                                }
-                                Err(e) => {
-                                    send_response(
-                                        StatusCode::INTERNAL_SERVER_ERROR,
-                                        Some(headers),
-                                        serde_json::to_vec(&ApiResponse::<()> {
-                                            success: false,
-                                            data: None,
-                                            error: Some(format!("Failed to create group: {}", e)),
-                                        })
-                                        .unwrap(),
-                                    );
-                                }
-                            }
-                        } else {
-                            send_response(
-                                StatusCode::BAD_REQUEST,
-                                Some(headers),
-                                serde_json::to_vec(&ApiResponse::<()> {
-                                    success: false,
-                                    data: None,
-                                    error: Some("Invalid group data".to_string()),
-                                })
-                                .unwrap(),
-                            );
-                        }
-                    }
-                    "PUT" => {
-                        // Add/remove members or send message to group
-                        let Some(blob) = get_blob() else {
-                            send_response(StatusCode::BAD_REQUEST, None, vec![]);
-                            return Ok(());
-                        };
-                        
-                        let query_params = request.query_params();
-                        let empty_string = "".to_string();
-                        let action = query_params.get("action").unwrap_or(&empty_string);
-                        
-                        match action.as_str() {
-                            "add_member" => {
-                                if let Ok(member_req) = serde_json::from_slice::<GroupMemberRequest>(&blob.bytes) {
-                                    match store.add_member_to_group(&member_req.group_id, &member_req.member) {
-                                        Ok(true) => {
-                                            send_response(
-                                                StatusCode::OK,
-                                                Some(headers),
-                                                serde_json::to_vec(&ApiResponse::<()> {
-                                                    success: true,
-                                                    data: None,
-                                                    error: None,
-                                                })
-                                                .unwrap(),
-                                            );
-                                        }
-                                        Ok(false) => {
-                                            send_response(
-                                                StatusCode::NOT_FOUND,
-                                                Some(headers),
-                                                serde_json::to_vec(&ApiResponse::<()> {
-                                                    success: false,
-                                                    data: None,
-                                                    error: Some("Group not found or member already in group".to_string()),
-                                                })
-                                                .unwrap(),
-                                            );
-                                        }
-                                        Err(e) => {
-                                            send_response(
-                                                StatusCode::INTERNAL_SERVER_ERROR,
-                                                Some(headers),
-                                                serde_json::to_vec(&ApiResponse::<()> {
-                                                    success: false,
-                                                    data: None,
-                                                    error: Some(format!("Failed to add member: {}", e)),
-                                                })
-                                                .unwrap(),
-                                            );
-                                        }
-                                    }
-                                } else {
-                                    send_response(
-                                        StatusCode::BAD_REQUEST,
-                                        Some(headers),
-                                        serde_json::to_vec(&ApiResponse::<()> {
-                                            success: false,
-                                            data: None,
-                                            error: Some("Invalid member data".to_string()),
-                                        })
-                                        .unwrap(),
-                                    );
-                                }
-                            }
-                            "remove_member" => {
-                                if let Ok(member_req) = serde_json::from_slice::<GroupMemberRequest>(&blob.bytes) {
-                                    match store.remove_member_from_group(&member_req.group_id, &member_req.member) {
-                                        Ok(true) => {
-                                            send_response(
-                                                StatusCode::OK,
-                                                Some(headers),
-                                                serde_json::to_vec(&ApiResponse::<()> {
-                                                    success: true,
-                                                    data: None,
-                                                    error: None,
-                                                })
-                                                .unwrap(),
-                                            );
-                                        }
-                                        Ok(false) => {
-                                            send_response(
-                                                StatusCode::NOT_FOUND,
-                                                Some(headers),
-                                                serde_json::to_vec(&ApiResponse::<()> {
-                                                    success: false,
-                                                    data: None,
-                                                    error: Some("Group not found or member not in group".to_string()),
-                                                })
-                                                .unwrap(),
-                                            );
-                                        }
-                                        Err(e) => {
-                                            send_response(
-                                                StatusCode::INTERNAL_SERVER_ERROR,
-                                                Some(headers),
-                                                serde_json::to_vec(&ApiResponse::<()> {
-                                                    success: false,
-                                                    data: None,
-                                                    error: Some(format!("Failed to remove member: {}", e)),
-                                                })
-                                                .unwrap(),
-                                            );
-                                        }
-                                    }
-                                } else {
-                                    send_response(
-                                        StatusCode::BAD_REQUEST,
-                                        Some(headers),
-                                        serde_json::to_vec(&ApiResponse::<()> {
-                                            success: false,
-                                            data: None,
-                                            error: Some("Invalid member data".to_string()),
-                                        })
-                                        .unwrap(),
-                                    );
-                                }
-                            }
-                            "send_message" => {
-                                if let Ok(msg_req) = serde_json::from_slice::<SendGroupMessageRequest>(&blob.bytes) {
-                                    // Check if group exists and user is a member
-                                    if let Ok(Some(group)) = store.get_group(&msg_req.group_id) {
-                                        let our_addr = standard::our();
-                                        if !group.members.contains(&our_addr.node().to_string()) {
-                                            send_response(
-                                                StatusCode::FORBIDDEN,
-                                                Some(headers),
-                                                serde_json::to_vec(&ApiResponse::<()> {
-                                                    success: false,
-                                                    data: None,
-                                                    error: Some("You are not a member of this group".to_string()),
-                                                })
-                                                .unwrap(),
-                                            );
-                                            return Ok(());
-                                        }
-                                        
-                                        // Add message to group
-                                        let group_message = GroupMessage {
-                                            group_id: msg_req.group_id.clone(),
-                                            author: our_addr.node().to_string(),
-                                            content: msg_req.message.clone(),
-                                            timestamp: get_timestamp(),
-                                        };
-                                        
-                                        match store.add_group_message(&msg_req.group_id, group_message.clone()) {
-                                            Ok(_) => {
-                                                // Notify all group members
-                                                for member in &group.members {
-                                                    if member != &our_addr.node().to_string() {
-                                                        // Send message to member
-                                                        // In a real implementation, we would need to implement this
-                                                    }
-                                                }
-                                                
-                                                // Send WebSocket message to update UI
-                                                let blob = LazyLoadBlob {
-                                                    mime: Some("application/json".to_string()),
-                                                    bytes: serde_json::to_vec(&serde_json::json!({
-                                                        "NewGroupMessage": {
-                                                            "group_id": msg_req.group_id,
-                                                            "author": our_addr.node().to_string(),
-                                                            "content": msg_req.message,
-                                                            "timestamp": group_message.timestamp,
-                                                        }
-                                                    }))
-                                                    .unwrap(),
-                                                };
-                                                server.ws_push_all_channels(WS_PATH, WsMessageType::Text, blob);
-                                                
-                                                send_response(
-                                                    StatusCode::OK,
-                                                    Some(headers),
-                                                    serde_json::to_vec(&ApiResponse::<()> {
-                                                        success: true,
-                                                        data: None,
-                                                        error: None,
-                                                    })
-                                                    .unwrap(),
-                                                );
-                                            }
-                                            Err(e) => {
-                                                send_response(
-                                                    StatusCode::INTERNAL_SERVER_ERROR,
-                                                    Some(headers),
-                                                    serde_json::to_vec(&ApiResponse::<()> {
-                                                        success: false,
-                                                        data: None,
-                                                        error: Some(format!("Failed to send message: {}", e)),
-                                                    })
-                                                    .unwrap(),
-                                                );
-                                            }
-                                        }
-                                    } else {
-                                        send_response(
-                                            StatusCode::NOT_FOUND,
-                                            Some(headers),
-                                            serde_json::to_vec(&ApiResponse::<()> {
-                                                success: false,
-                                                data: None,
-                                                error: Some("Group not found".to_string()),
-                                            })
-                                            .unwrap(),
-                                        );
-                                    }
-                                } else {
-                                    send_response(
-                                        StatusCode::BAD_REQUEST,
-                                        Some(headers),
-                                        serde_json::to_vec(&ApiResponse::<()> {
-                                            success: false,
-                                            data: None,
-                                            error: Some("Invalid message data".to_string()),
-                                        })
-                                        .unwrap(),
-                                    );
-                                }
-                            }
-                            _ => {
-                                send_response(
-                                    StatusCode::BAD_REQUEST,
-                                    Some(headers),
-                                    serde_json::to_vec(&ApiResponse::<()> {
-                                        success: false,
-                                        data: None,
-                                        error: Some("Invalid action".to_string()),
-                                    })
-                                    .unwrap(),
-                                );
-                            }
-                        }
+            match action {
+                MessageAction::Edit { content } => {
+                    if message.author() != actor {
+                        return Err(anyhow::anyhow!("{} may not edit a message by {}", actor, message.author()));
                     }
-                    "DELETE" => {
-                        // Delete a group (not implemented for simplicity)
-                        send_response(
-                            StatusCode::NOT_IMPLEMENTED,
-                            Some(headers),
-                            serde_json::to_vec(&ApiResponse::<()> {
-                                success: false,
-                                data: None,
-                                error: Some("Not implemented".to_string()),
-                            })
-                            .unwrap(),
-                        );
+                    message.edit(content.clone(), timestamp);
+                }
+                MessageAction::Delete { .. } => {
+                    if message.author() != actor {
+                        return Err(anyhow::anyhow!("{} may not delete a message by {}", actor, message.author()));
                     }
-                    _ => send_response(StatusCode::METHOD_NOT_ALLOWED, None, vec![]),
+                    message.tombstone();
                 }
+                MessageAction::React { emoji, add } => message.react(emoji, actor, *add),
             }
-            // Handle contacts API - proxy to the system contacts process
-            else if path.starts_with(CONTACTS_API_PATH) {
-                // This would forward requests to the contacts system process
-                // For simplicity, we're not implementing this fully
-                let headers = HashMap::from([(
-                    "Content-Type".to_string(),
-                    "application/json".to_string(),
-                )]);
-                
-                // Just return an empty contacts list for now
-                send_response(
-                    StatusCode::OK,
-                    Some(headers),
-                    serde_json::to_vec(&ApiResponse {
-                        success: true,
-                        data: Some(serde_json::json!({ "contacts": [] })),
-                        error: None,
-                    })
-                    .unwrap(),
-                );
-            }
-            else {
-                send_response(StatusCode::NOT_FOUND, None, vec![]);
-            }
-        }
+            Ok(())
+        })?,
     };
+    if !found {
+        return Err(anyhow::anyhow!("message {} not found in {:?}", message_id, conversation));
+    }
 
-    Ok(())
+    let conversation = conversation.clone();
+    let message_id = message_id.to_string();
+    Ok(match action {
+        MessageAction::Edit { content } => WsEvent::MessageEdited {
+            conversation,
+            message_id,
+            content: content.clone(),
+            edited_at: timestamp,
+        },
+        MessageAction::Delete { for_everyone } => WsEvent::MessageDeleted {
+            conversation,
+            message_id,
+            for_everyone: *for_everyone,
+        },
+        MessageAction::React { emoji, add } => WsEvent::ReactionChanged {
+            conversation,
+            message_id,
+            emoji: emoji.clone(),
+            node: actor.to_string(),
+            add: *add,
+        },
+    })
 }
 
-fn handle_chat_request(
+// Apply a message action from our UI, then pass it on to the other members
+// of the conversation
+fn handle_local_message_action(
     our: &Address,
-    source: &Address,
-    request: HyperwareChatRequest,
-    is_http: bool,
+    conversation: Conversation,
+    message_id: String,
+    action: MessageAction,
     store: &ChatStore,
     server: &HttpServer,
+    clients: &mut WsClients,
 ) -> anyhow::Result<()> {
-    match request {
-        HyperwareChatRequest::Send(SendRequest {
-            ref target,
-            ref message,
-        }) => {
-            // Counterparty is the other node in the hyperware-chat with us
-            let (counterparty, author) = if target == &our.node {
-                (&source.node, source.node.clone())
-            } else {
-                (target, our.node.clone())
-            };
+    let event = apply_message_action(store, &conversation, &message_id, &our.node, &action)?;
+    clients.push(server, &event);
 
-            // If the target is not us, send a request to the target
-            if target == &our.node {
-                println!("{}: {}", source.node, message);
-            } else {
-                let request = HyperwareChatRequest::Send(SendRequest {
-                    target: target.clone(),
-                    message: message.clone(),
-                });
-                Request::new()
-                    .target((target, "hyperware-chat", "hyperware-chat", "template.os"))
-                    .body(request)
-                    .send_and_await_response(5)??;
+    // Deleting for ourselves only is nobody else's business
+    if let MessageAction::Delete { for_everyone: false } = action {
+        return Ok(());
+    }
+    // Group actions join our sequence, so members that miss them catch up
+    if let Conversation::Group(ref group_id) = conversation {
+        let kind = match action {
+            MessageAction::Edit { content } => {
+                if store.get_settings()?.e2e {
+                    ActionKind::Edit {
+                        content: String::new(),
+                        encrypted: Some(encrypt_edit(our, &conversation, &message_id, &content, store)?),
+                    }
+                } else {
+                    ActionKind::Edit { content, encrypted: None }
+                }
             }
+            MessageAction::Delete { .. } => ActionKind::Delete,
+            MessageAction::React { emoji, add } => ActionKind::React { emoji, add },
+        };
+        store.add_group_action(group_id, &our.node, &message_id, kind)?;
+        announce_group(our, group_id, store);
+        return Ok(());
+    }
 
-            // Insert message into archive
-            let new_message = ChatMessage {
-                author: author.clone(),
-                content: message.clone(),
-                timestamp: get_timestamp(),
+    let (peers, group_id) = conversation_peers(our, &conversation, store)?;
+    let request = match action {
+        MessageAction::Delete { for_everyone: false } => return Ok(()),
+        MessageAction::Edit { content } => {
+            let encrypted = if store.get_settings()?.e2e {
+                Some(encrypt_edit(our, &conversation, &message_id, &content, store)?)
+            } else {
+                None
             };
-            
-            // Add message to store
-            store.add_message(counterparty, new_message.clone())?;
+            HyperwareChatRequest::Edit(EditRequest {
+                group_id,
+                message_id,
+                content: if encrypted.is_some() { String::new() } else { content },
+                encrypted,
+            })
+        }
+        MessageAction::Delete { for_everyone: true } => HyperwareChatRequest::Delete(DeleteRequest {
+            group_id,
+            message_id,
+        }),
+        MessageAction::React { emoji, add } => HyperwareChatRequest::React(ReactRequest {
+            group_id,
+            message_id,
+            emoji,
+            add,
+        }),
+    };
+    notify_peers(&peers, request);
+    Ok(())
+}
 
-            if is_http {
-                // If is HTTP from FE: done
-                return Ok(());
-            }
+// Fetch a peer's public keys, sending ours, unless we already have them
+fn ensure_peer_keys(peer: &str, store: &ChatStore) -> anyhow::Result<()> {
+    if store.e2e.peer_keys(peer)?.is_some() {
+        return Ok(());
+    }
+    let response = Request::new()
+        .target((peer, "hyperware-chat", "hyperware-chat", "template.os"))
+        .body(HyperwareChatRequest::KeyExchange(store.e2e.public_keys()))
+        .send_and_await_response(5)??;
+    let response: HyperwareChatResponse = response.body().try_into()?;
+    match response {
+        HyperwareChatResponse::KeyExchange(keys) => store.e2e.remember_peer(peer, &keys),
+        HyperwareChatResponse::Err(reason) => {
+            Err(anyhow::anyhow!("{} refused key exchange: {}", peer, reason))
+        }
+        _ => Err(anyhow::anyhow!("unexpected key exchange response from {}", peer)),
+    }
+}
 
-            // Not HTTP from FE: send response to node & update any FE listeners
-            Response::new().body(HyperwareChatResponse::Send).send()?;
+// Start a new sender key for a group we are in, so members removed since the
+// last rotation can't read what we send next. Members get it as they sync.
+fn rotate_group_sender_key(our: &Address, group_id: &str, store: &ChatStore) -> anyhow::Result<()> {
+    if !store.get_settings()?.e2e {
+        return Ok(());
+    }
+    let Some(group) = store.get_group(group_id)? else {
+        return Err(anyhow::anyhow!("group {} not found", group_id));
+    };
+    if group.members.contains(&our.node) {
+        store.e2e.rotate_sender_key(group_id)?;
+    }
+    Ok(())
+}
 
-            // Send a WebSocket message to the http server in order to update the UI
-            let blob = LazyLoadBlob {
-                mime: Some("application/json".to_string()),
-                bytes: serde_json::to_vec(&serde_json::json!({
-                    "NewMessage": NewMessage {
-                        hyperware_chat: counterparty.to_string(),
-                        author,
-                        content: message.to_string(),
-                        timestamp: new_message.timestamp,
-                    }
-                }))
-                .unwrap(),
-            };
-            server.ws_push_all_channels(WS_PATH, WsMessageType::Text, blob);
+// Give `peer` our current key for a group once its digest shows it holds
+// every membership change we do. Before that it may not count us as a member
+// and would refuse the key. Each key goes to each member until one answer
+// marks it delivered.
+fn share_sender_key(
+    our: &Address,
+    group_id: &str,
+    peer: &str,
+    peer_clock: &Clock,
+    store: &ChatStore,
+) -> anyhow::Result<()> {
+    if peer == our.node || !store.get_settings()?.e2e {
+        return Ok(());
+    }
+    let Some(group) = store.get_group(group_id)? else {
+        return Ok(());
+    };
+    if !group.members.contains(&our.node)
+        || !group.members.contains(peer)
+        || !reconcile::knows_membership(&group, peer_clock)
+    {
+        return Ok(());
+    }
+    let Some(sender_key) = store.e2e.own_sender_key(group_id)? else {
+        return Ok(());
+    };
+    if store.e2e.has_sender_key(peer, group_id, sender_key.generation)? {
+        return Ok(());
+    }
+    ensure_peer_keys(peer, store)?;
+    let delivery = e2e::KeyDelivery {
+        group_id: group_id.to_string(),
+        generation: sender_key.generation,
+    };
+    Request::new()
+        .target((peer, "hyperware-chat", "hyperware-chat", "template.os"))
+        .body(HyperwareChatRequest::SenderKey(store.e2e.seal_sender_key(peer, group_id, &sender_key)?))
+        .context(delivery.context()?)
+        .expects_response(5)
+        .send()?;
+    Ok(())
+}
+
+// A member answered the request carrying our sender key
+fn handle_key_delivery_response(
+    source: &Address,
+    body: &[u8],
+    delivery: e2e::KeyDelivery,
+    store: &ChatStore,
+) -> anyhow::Result<()> {
+    let response: HyperwareChatResponse = body.try_into()?;
+    match response {
+        HyperwareChatResponse::SenderKey => {
+            store
+                .e2e
+                .mark_sender_key_delivered(&source.node, &delivery.group_id, delivery.generation)
         }
-        HyperwareChatRequest::History(ref node) => {
-            let messages = store.get_messages(node)?;
-            
-            // Convert to WIT message format
-            let wit_messages: Vec<HyperwareChatMessage> = messages
-                .iter()
-                .map(|msg| HyperwareChatMessage {
-                    author: msg.author.clone(),
-                    content: msg.content.clone(),
-                })
-                .collect();
-            
-            Response::new()
-                .body(HyperwareChatResponse::History(wit_messages))
-                .send()?;
+        HyperwareChatResponse::Err(reason) => Err(anyhow::anyhow!(
+            "{} rejected our key for group {}: {}",
+            source.node,
+            delivery.group_id,
+            reason
+        )),
+        _ => Err(anyhow::anyhow!("unexpected sender key response from {}", source.node)),
+    }
+}
+
+// With end-to-end encryption on, seal a new group message of ours with our
+// sender key. `content` stays as the local copy; only the ciphertext is synced.
+fn encrypt_group_message(our: &Address, message: &mut GroupMessage, store: &ChatStore) -> anyhow::Result<()> {
+    if !store.get_settings()?.e2e {
+        return Ok(());
+    }
+    if store.e2e.own_sender_key(&message.group_id)?.is_none() {
+        store.e2e.rotate_sender_key(&message.group_id)?;
+    }
+    let aad = e2e::message_aad(&message.author, &message.group_id, &message.id);
+    message.encrypted = Some(store.e2e.encrypt_group(&message.group_id, &message.content, &aad)?);
+    Ok(())
+}
+
+// Encrypt an edit of our message for the other members of its conversation
+fn encrypt_edit(
+    our: &Address,
+    conversation: &Conversation,
+    message_id: &str,
+    content: &str,
+    store: &ChatStore,
+) -> anyhow::Result<EncryptedContent> {
+    match conversation {
+        Conversation::Direct(node) => {
+            ensure_peer_keys(node, store)?;
+            let aad = e2e::edit_aad(&our.node, node, message_id);
+            store.e2e.encrypt_direct(node, content, &aad)
+        }
+        Conversation::Group(group_id) => {
+            if store.e2e.own_sender_key(group_id)?.is_none() {
+                store.e2e.rotate_sender_key(group_id)?;
+            }
+            let aad = e2e::edit_aad(&our.node, group_id, message_id);
+            store.e2e.encrypt_group(group_id, content, &aad)
+        }
+    }
+}
+
+fn decrypt_edit(
+    our: &Address,
+    author: &str,
+    conversation: &Conversation,
+    message_id: &str,
+    payload: &EncryptedContent,
+    store: &ChatStore,
+) -> anyhow::Result<String> {
+    match conversation {
+        Conversation::Direct(_) => {
+            let aad = e2e::edit_aad(author, &our.node, message_id);
+            store.e2e.decrypt_direct(author, payload, &aad)
+        }
+        Conversation::Group(group_id) => {
+            let aad = e2e::edit_aad(author, group_id, message_id);
+            store.e2e.decrypt_group(group_id, author, payload, &aad)
         }
     }
+}
+
+// Tell the other side of a conversation that we started or stopped typing
+fn send_typing_notice(
+    our: &Address,
+    typing: &TypingRequest,
+    store: &ChatStore,
+    server: &HttpServer,
+    clients: &mut WsClients,
+) -> anyhow::Result<()> {
+    let (peers, group_id) = conversation_peers(our, &typing.conversation, store)?;
+    notify_peers(
+        &peers,
+        HyperwareChatRequest::TypingNotice(TypingNotice {
+            group_id,
+            is_typing: typing.is_typing,
+        }),
+    );
+
+    // Other local tabs viewing the conversation see it too
+    clients.push(
+        server,
+        &WsEvent::Typing {
+            conversation: typing.conversation.clone(),
+            node: our.node.clone(),
+            is_typing: typing.is_typing,
+        },
+    );
     Ok(())
 }
 
+// Mark a conversation read locally, update other open tabs and, if enabled,
+// send a read receipt to the other nodes in it
+fn mark_conversation_read(
+    our: &Address,
+    conversation: &Conversation,
+    message_id: Option<&str>,
+    store: &ChatStore,
+    server: &HttpServer,
+    clients: &mut WsClients,
+) -> anyhow::Result<Option<ReadMarker>> {
+    let Some(marker) = store.mark_read(conversation, message_id)? else {
+        return Ok(None);
+    };
+
+    if store.get_settings()?.read_receipts {
+        let (peers, group_id) = conversation_peers(our, conversation, store)?;
+        notify_peers(
+            &peers,
+            HyperwareChatRequest::ReadReceipt(ReadReceipt {
+                group_id,
+                message_id: marker.message_id.clone(),
+            }),
+        );
+    }
+
+    clients.push(
+        server,
+        &WsEvent::ReadReceipt {
+            conversation: conversation.clone(),
+            node: our.node.clone(),
+            message_id: marker.message_id.clone(),
+        },
+    );
+    Ok(Some(marker))
+}
+
 fn handle_message(
     message: &ProcessMessage,
     store: &ChatStore,
     server: &mut HttpServer,
+    clients: &mut WsClients,
+    limiter: &mut RateLimiter,
 ) -> anyhow::Result<()> {
+    let body = message.body();
+    let source = message.source();
+    let our_addr = standard::our();
+
     if !message.is_request() {
+        match message.context() {
+            // Time for the next round of heartbeats
+            Some(presence::TIMER_CONTEXT) if source.node == our_addr.node => {
+                send_heartbeats(&our_addr, store);
+                sync_groups(&our_addr, store);
+                limiter.evict_idle();
+                if let Err(e) = store.attachments.expire_partials() {
+                    error!("Failed to expire partial uploads: {}", e);
+                }
+                timer::set_timer(presence::HEARTBEAT_INTERVAL_MS, Some(presence::TIMER_CONTEXT.to_vec()));
+            }
+            Some(presence::HEARTBEAT_CONTEXT) if source.node != our_addr.node => {
+                mark_seen(&source.node, store, server, clients);
+            }
+            Some(e2e::KEY_EXCHANGE_CONTEXT) if source.node != our_addr.node => {
+                match serde_json::from_slice::<HyperwareChatResponse>(body) {
+                    Ok(HyperwareChatResponse::KeyExchange(keys)) => {
+                        if let Err(e) = store.e2e.remember_peer(&source.node, &keys) {
+                            info!("Rejecting keys from {}: {}", source.node, e);
+                        }
+                    }
+                    Ok(HyperwareChatResponse::Err(reason)) => {
+                        info!("{} refused key exchange: {}", source.node, reason);
+                    }
+                    _ => {}
+                }
+            }
+            Some(reconcile::SYNC_CONTEXT) if source.node != our_addr.node => {
+                if let Err(e) = handle_group_sync_response(&our_addr, source, body, store, server, clients) {
+                    info!("Failed to merge group sync from {}: {}", source.node, e);
+                }
+            }
+            Some(context) if source.node != our_addr.node => {
+                if let Some(delivery) = e2e::KeyDelivery::from_context(context) {
+                    if let Err(e) = handle_key_delivery_response(source, body, delivery, store) {
+                        info!("Sender key not delivered: {}", e);
+                    }
+                } else if let Some(transfer) = attachments::Transfer::from_context(context) {
+                    if let Err(e) = handle_transfer_response(source, body, transfer, store, server, clients) {
+                        error!("Failed to continue transfer to {}: {}", source.node, e);
+                    }
+                }
+            }
+            _ => {}
+        }
         return Ok(());
     }
 
-    let body = message.body();
-    let source = message.source();
-    let our_addr = standard::our();
+    // Other nodes may only talk to us through chat requests
+    if source.node != our_addr.node {
+        let Ok(chat_request) = serde_json::from_slice::<HyperwareChatRequest>(body) else {
+            info!("Ignoring non-chat request from {}", source);
+            return Ok(());
+        };
+        // Fire-and-forget requests get no answer, not even a refusal
+        let expects_response = matches!(message, ProcessMessage::Request { expects_response: Some(_), .. });
+        if !limiter.allow(&source.node, &chat_request) {
+            info!("Rate limiting {}", source.node);
+            if expects_response {
+                Response::new()
+                    .body(HyperwareChatResponse::Err("rate limited".to_string()))
+                    .send()?;
+            }
+            return Ok(());
+        }
+        if let Err(e) = authenticate_remote(&our_addr, source, &chat_request, store) {
+            info!("Rejecting request from {}: {}", source, e);
+            if expects_response {
+                Response::new()
+                    .body(HyperwareChatResponse::Err(e.to_string()))
+                    .send()?;
+            }
+            return Ok(());
+        }
+        mark_seen(&source.node, store, server, clients);
+        return handle_chat_request(&our_addr, source, chat_request, false, store, server, clients);
+    }
 
     // Try to parse the message as either a chat request or HTTP request
     match serde_json::from_slice::<RequestType>(body) {
         Ok(RequestType::HttpRequest(request)) => {
-            handle_http_server_request(body, request, store, server)?;
+            if source != &make_http_address(&our_addr) {
+                info!("Ignoring HTTP request not sent by http_server: {}", source);
+                return Ok(());
+            }
+            handle_http_server_request(body, request, store, server, clients)?;
         },
         Ok(RequestType::ChatRequest(chat_request)) => {
-            handle_chat_request(&our_addr, source, chat_request, false, store, server)?;
+            handle_chat_request(&our_addr, source, chat_request, false, store, server, clients)?;
         },
         // Handle other RequestType variants
         Ok(RequestType::GroupMessage { GroupMessage: group_msg }) => {
@@ -1177,6 +3199,9 @@ fn handle_message(
         Ok(RequestType::AddContact { AddContact: contact }) => {
             info!("Received AddContact in handle_message, this is unexpected: {:?}", contact);
         },
+        Ok(RequestType::RemoveContact { .. }) => {
+            info!("Received RemoveContact in handle_message, this is unexpected");
+        },
         Ok(RequestType::GetMessages { .. }) => {
             info!("Received GetMessages in handle_message, this is unexpected");
         },
@@ -1189,12 +3214,30 @@ fn handle_message(
         Ok(RequestType::GetGroupMessages { .. }) => {
             info!("Received GetGroupMessages in handle_message, this is unexpected");
         },
+        Ok(RequestType::Subscribe { .. }) | Ok(RequestType::Unsubscribe { .. }) => {
+            info!("Received subscription change in handle_message, this is unexpected");
+        },
+        Ok(RequestType::Typing { .. }) => {
+            info!("Received Typing in handle_message, this is unexpected");
+        },
+        Ok(RequestType::EditMessage { .. })
+        | Ok(RequestType::DeleteMessage { .. })
+        | Ok(RequestType::ReactToMessage { .. })
+        | Ok(RequestType::GetThread { .. }) => {
+            info!("Received message action in handle_message, this is unexpected");
+        },
+        Ok(RequestType::MarkRead { .. }) | Ok(RequestType::GetConversations { .. }) => {
+            info!("Received read state request in handle_message, this is unexpected");
+        },
+        Ok(RequestType::Sync { .. }) => {
+            info!("Received Sync in handle_message, this is unexpected");
+        },
         Err(e) => {
             // If from HTTP server, try to parse directly as HttpServerRequest
             if source == &make_http_address(&our_addr) {
                 match serde_json::from_slice::<HttpServerRequest>(body) {
                     Ok(request) => {
-                        handle_http_server_request(body, request, store, server)?;
+                        handle_http_server_request(body, request, store, server, clients)?;
                     },
                     Err(_) => {
                         error!("couldn't parse message from http_server: {body:?}, error: {e}");
@@ -1204,7 +3247,7 @@ fn handle_message(
                 // Try to parse as HyperwareChatRequest
                 match serde_json::from_slice::<HyperwareChatRequest>(body) {
                     Ok(chat_request) => {
-                        handle_chat_request(&our_addr, source, chat_request, false, store, server)?;
+                        handle_chat_request(&our_addr, source, chat_request, false, store, server, clients)?;
                     },
                     Err(_) => {
                         error!("couldn't parse message: {body:?}, error: {e}");
@@ -1224,7 +3267,7 @@ fn init(our: Address) {
 
     // Create store for persistent data
     let package_id = our.package_id();
-    let store = match ChatStore::new(package_id) {
+    let store = match ChatStore::new(package_id.clone(), our.node()) {
         Ok(store) => store,
         Err(e) => {
             error!("Failed to create store: {}", e);
@@ -1232,43 +3275,35 @@ fn init(our: Address) {
         }
     };
 
+    // Bring stored records up to date before anything reads them
+    if let Err(e) = migrations::run(&store) {
+        error!("Failed to migrate store: {:?}", e);
+        return;
+    }
+
     let mut server = HttpServer::new(5);
+    let sync_log = match SyncLog::new(package_id.clone()) {
+        Ok(sync_log) => sync_log,
+        Err(e) => {
+            error!("Failed to open sync log: {}", e);
+            return;
+        }
+    };
+    let mut clients = WsClients::new(sync_log);
+    let mut limiter = RateLimiter::default();
 
-    // Create full paths with process prefix
     let full_ws_path = format!("/{}", PROCESS_PATH);
-    let full_http_api_path = format!("/{}{}", PROCESS_PATH, HTTP_API_PATH);
-    let full_contacts_api_path = format!("/{}{}", PROCESS_PATH, CONTACTS_API_PATH);
-    let full_groups_api_path = format!("/{}{}", PROCESS_PATH, GROUPS_API_PATH);
-    
-    info!("Binding paths: {}, {}, {}, {}", full_ws_path, full_http_api_path, full_contacts_api_path, full_groups_api_path);
-    
+
     // Bind UI files to routes with index.html at "/"
     server
         .serve_ui("ui", vec!["/", &full_ws_path], HttpBindingConfig::default())
         .expect("failed to serve UI");
-    
-    // API endpoints with full process paths
-    server
-        .bind_http_path(&full_http_api_path, HttpBindingConfig::default())
-        .expect("failed to bind messages API");
-    server
-        .bind_http_path(&full_contacts_api_path, HttpBindingConfig::default())
-        .expect("failed to bind contacts API");
-    server
-        .bind_http_path(&full_groups_api_path, HttpBindingConfig::default())
-        .expect("failed to bind groups API");
-    
-    // Also bind the short paths for backward compatibility
-    server
-        .bind_http_path(HTTP_API_PATH, HttpBindingConfig::default())
-        .expect("failed to bind short messages API");
-    server
-        .bind_http_path(CONTACTS_API_PATH, HttpBindingConfig::default())
-        .expect("failed to bind short contacts API");
-    server
-        .bind_http_path(GROUPS_API_PATH, HttpBindingConfig::default())
-        .expect("failed to bind short groups API");
-    
+
+    // API endpoints, under the process path and as short aliases
+    routes::router()
+        .bind(&mut server)
+        .expect("failed to bind HTTP API");
+
     // WebSocket for real-time updates
     server
         .bind_ws_path(&full_ws_path, WsBindingConfig::default())
@@ -1277,11 +3312,15 @@ fn init(our: Address) {
         .bind_ws_path(WS_PATH, WsBindingConfig::default())
         .expect("failed to bind short WS API");
 
+    // Start the heartbeat cycle; each timer response sends a round and sets the next timer
+    timer::set_timer(presence::HEARTBEAT_INTERVAL_MS, Some(presence::TIMER_CONTEXT.to_vec()));
+    send_heartbeats(&our, &store);
+
     loop {
         match await_message() {
-            Err(send_error) => error!("got SendError: {send_error}"),
+            Err(ref send_error) => handle_send_error(send_error, &store, &server, &mut clients),
             Ok(ref message) => {
-                match handle_message(message, &store, &mut server) {
+                match handle_message(message, &store, &mut server, &mut clients, &mut limiter) {
                     Ok(_) => {}
                     Err(e) => error!("got error while handling message: {e:?}"),
                 }
@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::hyperware::process::hyperware_chat::Request as HyperwareChatRequest;
+
+// Burst a node may send before being slowed down
+const BUCKET_CAPACITY: f64 = 30.0;
+// Sustained requests per second a node may send
+const REFILL_PER_SEC: f64 = 0.5;
+
+// Largest message or edit we accept from another node, in bytes
+pub const MAX_MESSAGE_SIZE: usize = 16 * 1024;
+// Quarantined messages kept per unknown node, and unknown nodes kept
+pub const MAX_REQUESTS_PER_NODE: usize = 50;
+pub const MAX_PENDING_NODES: usize = 100;
+
+// Stored and pushed requests cost the most; frequent, cheap ones less
+fn cost(request: &HyperwareChatRequest) -> f64 {
+    match request {
+        HyperwareChatRequest::TypingNotice(_)
+        | HyperwareChatRequest::ReadReceipt(_)
+        | HyperwareChatRequest::Heartbeat => 0.25,
+        // A full-size attachment is 40 chunks
+        HyperwareChatRequest::AttachmentChunk(_) => 0.1,
+        // Every shared group is synced each round of heartbeats
+        HyperwareChatRequest::GroupSync(_) => 0.1,
+        _ => 1.0,
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    refilled_at: u64,
+}
+
+impl TokenBucket {
+    fn refill(&mut self, now: u64) {
+        let elapsed = now.saturating_sub(self.refilled_at) as f64 / 1000.0;
+        self.tokens = (self.tokens + elapsed * REFILL_PER_SEC).min(BUCKET_CAPACITY);
+        self.refilled_at = now;
+    }
+}
+
+// Per-node token buckets for requests from other nodes. Kept in memory only:
+// a restart just gives everyone a full bucket.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    buckets: HashMap<String, TokenBucket>,
+}
+
+impl RateLimiter {
+    // Take the cost of `request` from the node's bucket; false if it is empty
+    pub fn allow(&mut self, node: &str, request: &HyperwareChatRequest) -> bool {
+        let now = now_ms();
+        let bucket = self
+            .buckets
+            .entry(node.to_string())
+            .or_insert(TokenBucket {
+                tokens: BUCKET_CAPACITY,
+                refilled_at: now,
+            });
+        bucket.refill(now);
+
+        let cost = cost(request);
+        if bucket.tokens < cost {
+            return false;
+        }
+        bucket.tokens -= cost;
+        true
+    }
+
+    // Forget nodes whose buckets have filled up again; a full bucket is the
+    // same as none, so only memory changes
+    pub fn evict_idle(&mut self) {
+        let now = now_ms();
+        self.buckets.retain(|_, bucket| {
+            bucket.refill(now);
+            bucket.tokens < BUCKET_CAPACITY
+        });
+    }
+}
@@ -0,0 +1,76 @@
+use anyhow::Context;
+use hyperware_process_lib::logging::info;
+use hyperware_process_lib::hyperware::process::standard;
+use hyperware_process_lib::Request;
+
+use crate::schema::SCHEMA_VERSION;
+use crate::{assign_legacy_ids, make_contacts_address, ChatStore, CONTACTS_KEY};
+
+type Migration = fn(&ChatStore) -> anyhow::Result<()>;
+
+// MIGRATIONS[n] brings the store from schema version n to n + 1
+const MIGRATIONS: &[Migration] = &[upgrade_first_release];
+
+const _: () = assert!(MIGRATIONS.len() == SCHEMA_VERSION as usize);
+
+// Bring the store up to SCHEMA_VERSION. The version is saved after every
+// step, so an interrupted run picks up where it stopped.
+pub fn run(store: &ChatStore) -> anyhow::Result<()> {
+    let mut version = store.schema.version()?;
+    if version > SCHEMA_VERSION {
+        return Err(anyhow::anyhow!(
+            "store is at schema version {}, newer than supported {}",
+            version,
+            SCHEMA_VERSION
+        ));
+    }
+    while version < SCHEMA_VERSION {
+        info!("Migrating store from schema version {} to {}", version, version + 1);
+        MIGRATIONS[version as usize](store)
+            .with_context(|| format!("migration to schema version {} failed", version + 1))?;
+        version += 1;
+        store.schema.set_version(version)?;
+    }
+    Ok(())
+}
+
+// 0 -> 1: the first release stored bare records, gave messages no ids and
+// kept no index of its conversations. KV can't list its keys, so every node
+// in the system contacts is checked for messages; the groups of that release
+// can't be found and come back when a member next syncs them. Newer
+// databases (search, attachments, keys) start out empty.
+fn upgrade_first_release(store: &ChatStore) -> anyhow::Result<()> {
+    let nodes = system_contacts().unwrap_or_else(|e| {
+        info!("Couldn't list system contacts to index: {}", e);
+        Vec::new()
+    });
+    let mut assigned = 0;
+    for node in nodes {
+        let Some(mut messages) = store.messages_db.get(&node)? else {
+            continue;
+        };
+        if messages.is_empty() {
+            continue;
+        }
+        assigned += assign_legacy_ids(&mut messages);
+        // Written back in a versioned envelope
+        store.messages_db.set(&node, &messages)?;
+        store.index_conversation(CONTACTS_KEY, &node)?;
+    }
+    let indexed = store.rebuild_search_index()?;
+    info!("Assigned ids to {} messages and indexed {} for search", assigned, indexed);
+    Ok(())
+}
+
+// Names of the nodes in the contacts:contacts:sys process
+fn system_contacts() -> anyhow::Result<Vec<String>> {
+    let response = Request::to(make_contacts_address(&standard::our()))
+        .body(serde_json::to_vec(&serde_json::json!("GetNames"))?)
+        .send_and_await_response(5)??;
+    let value: serde_json::Value = serde_json::from_slice(response.body())?;
+    let names = value
+        .get("GetNames")
+        .cloned()
+        .ok_or_else(|| anyhow::anyhow!("unexpected response from contacts: {}", value))?;
+    Ok(serde_json::from_value(names)?)
+}
@@ -0,0 +1,20 @@
+use serde::{Deserialize, Serialize};
+
+// How often we ping the nodes we talk to
+pub const HEARTBEAT_INTERVAL_MS: u64 = 60_000;
+// Seconds a peer has to answer a heartbeat before it counts as offline
+pub const HEARTBEAT_TIMEOUT_SECS: u64 = 10;
+
+// Contexts that tell heartbeat traffic apart from other responses
+pub const TIMER_CONTEXT: &[u8] = b"heartbeat-timer";
+pub const HEARTBEAT_CONTEXT: &[u8] = b"heartbeat";
+
+// What we know about a peer's reachability
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerPresence {
+    pub node: String,
+    // None until the peer has answered or failed a heartbeat since we started
+    pub online: Option<bool>,
+    // Seconds since the epoch when we last heard from the peer
+    pub last_seen: Option<u64>,
+}
@@ -0,0 +1,98 @@
+use serde::{Deserialize, Serialize};
+
+use crate::ws::Conversation;
+use crate::StoredMessage;
+
+// How far we have read a conversation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReadMarker {
+    pub message_id: String,
+    // Timestamp of the message, used if it is later removed from our copy
+    pub timestamp: u64,
+    pub read_at: u64,
+}
+
+// One entry in the conversation list, with its unread badge
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConversationSummary {
+    pub conversation: Conversation,
+    pub name: String,
+    pub last_message_at: Option<u64>,
+    pub unread: usize,
+    pub last_read: Option<ReadMarker>,
+}
+
+// KV key for a conversation's read marker
+pub fn key(conversation: &Conversation) -> String {
+    match conversation {
+        Conversation::Direct(node) => format!("direct:{}", node),
+        Conversation::Group(group_id) => format!("group:{}", group_id),
+    }
+}
+
+// Marker for `message_id`, or for the newest message if not given. None if
+// there is no such message.
+pub fn marker_for<M: StoredMessage>(
+    messages: &[M],
+    message_id: Option<&str>,
+    read_at: u64,
+) -> Option<ReadMarker> {
+    let message = match message_id {
+        Some(id) => messages.iter().find(|message| message.id() == id)?,
+        None => messages.last()?,
+    };
+    Some(ReadMarker {
+        message_id: message.id().to_string(),
+        timestamp: message.timestamp(),
+        read_at,
+    })
+}
+
+// Whether `marker` is further into the conversation than `current`. Messages
+// from the same second are told apart by their place in our copy, or by id
+// once `current`'s message is gone from it.
+pub fn is_ahead<M: StoredMessage>(messages: &[M], marker: &ReadMarker, current: &ReadMarker) -> bool {
+    let position = |marker: &ReadMarker| messages.iter().position(|m| m.id() == marker.message_id);
+    match (position(marker), position(current)) {
+        (Some(new), Some(old)) => new > old,
+        _ => (marker.timestamp, &marker.message_id) > (current.timestamp, &current.message_id),
+    }
+}
+
+// The marker after reading up to `message_id`, or the newest message, and
+// whether it moved. Reading an older message again doesn't make newer ones
+// unread. None if there is no such message.
+pub fn advance<M: StoredMessage>(
+    messages: &[M],
+    message_id: Option<&str>,
+    current: Option<ReadMarker>,
+    read_at: u64,
+) -> Option<(ReadMarker, bool)> {
+    let marker = marker_for(messages, message_id, read_at)?;
+    match current {
+        Some(current) if !is_ahead(messages, &marker, &current) => Some((current, false)),
+        _ => Some((marker, true)),
+    }
+}
+
+// Messages from other nodes after the read marker
+pub fn unread_count<M: StoredMessage>(
+    messages: &[M],
+    marker: Option<&ReadMarker>,
+    our_node: &str,
+) -> usize {
+    let start = match marker {
+        None => 0,
+        Some(marker) => match messages.iter().position(|m| m.id() == marker.message_id) {
+            Some(index) => index + 1,
+            None => messages
+                .iter()
+                .position(|m| (m.timestamp(), m.id()) > (marker.timestamp, marker.message_id.as_str()))
+                .unwrap_or(messages.len()),
+        },
+    };
+    messages[start..]
+        .iter()
+        .filter(|m| m.author() != our_node && !m.is_deleted())
+        .count()
+}
@@ -0,0 +1,327 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::hyperware::process::hyperware_chat::{AuthorSeq, EncryptedContent, GroupDigest};
+use crate::ws::WsEvent;
+use crate::{Group, GroupMessage};
+
+// Context on group sync requests, to recognise their responses
+pub const SYNC_CONTEXT: &[u8] = b"group-sync";
+// Seconds a member has to answer a group sync
+pub const SYNC_TIMEOUT_SECS: u64 = 15;
+// How far ahead of our clock a member's timestamps may be; events stamped
+// further ahead wait until they aren't
+pub const MAX_CLOCK_SKEW_SECS: u64 = 5 * 60;
+// Groups we sync per heartbeat; more are spread over several heartbeats so
+// each peer stays within its rate limit
+pub const MAX_SYNCS_PER_ROUND: usize = 50;
+
+// A member added to or removed from a group. Numbered in its author's
+// sequence, which it shares with the author's messages in the group.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MembershipChange {
+    pub author: String,
+    pub seq: u64,
+    pub member: String,
+    pub added: bool,
+    pub timestamp: u64,
+    // The author's signature over `signed_membership`; empty for changes
+    // made before events were signed, which only their author passes on
+    #[serde(default)]
+    pub signature: Vec<u8>,
+}
+
+impl MembershipChange {
+    // Total order used to settle concurrent changes to the same member
+    fn order(&self) -> (u64, &str, u64) {
+        (self.timestamp, &self.author, self.seq)
+    }
+}
+
+// What an edit, delete or reaction in a group does
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ActionKind {
+    // `content` is empty when the edit is end-to-end encrypted
+    Edit {
+        content: String,
+        encrypted: Option<EncryptedContent>,
+    },
+    Delete,
+    React {
+        emoji: String,
+        add: bool,
+    },
+}
+
+// An edit, delete or reaction by `author` to a message in a group. Numbered in
+// the author's sequence like its messages, so it is synced like them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupAction {
+    pub author: String,
+    pub seq: u64,
+    pub message_id: String,
+    pub kind: ActionKind,
+    pub timestamp: u64,
+    pub signature: Vec<u8>,
+}
+
+// Events one member lacks, sent in the blob of a group sync response
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupDelta {
+    pub group: Group,
+    pub membership: Vec<MembershipChange>,
+    pub messages: Vec<GroupMessage>,
+    #[serde(default)]
+    pub actions: Vec<GroupAction>,
+}
+
+// What merging a delta changed, for telling the UI
+pub struct GroupMerge {
+    pub group: Group,
+    pub is_new: bool,
+    pub membership_changed: bool,
+    pub messages: Vec<GroupMessage>,
+    pub events: Vec<WsEvent>,
+}
+
+// author -> highest seq such that we hold every event 1..=seq from them
+pub type Clock = HashMap<String, u64>;
+
+// Messages stored before sequencing have seq 0 and take no part in syncing
+fn sequenced(group: &Group, messages: &[GroupMessage], actions: &[GroupAction]) -> Vec<(String, u64)> {
+    group
+        .membership
+        .iter()
+        .map(|change| (change.author.clone(), change.seq))
+        .chain(
+            messages
+                .iter()
+                .filter(|message| message.seq > 0)
+                .map(|message| (message.author.clone(), message.seq)),
+        )
+        .chain(actions.iter().map(|action| (action.author.clone(), action.seq)))
+        .collect()
+}
+
+pub fn clock(group: &Group, messages: &[GroupMessage], actions: &[GroupAction]) -> Clock {
+    let mut seen: HashMap<String, Vec<u64>> = HashMap::new();
+    for (author, seq) in sequenced(group, messages, actions) {
+        seen.entry(author).or_default().push(seq);
+    }
+    seen.into_iter()
+        .map(|(author, mut seqs)| {
+            seqs.sort_unstable();
+            seqs.dedup();
+            let contiguous = seqs
+                .iter()
+                .enumerate()
+                .take_while(|(index, seq)| **seq == *index as u64 + 1)
+                .count();
+            (author, contiguous as u64)
+        })
+        .collect()
+}
+
+// Seq for the next event `author` adds to the group
+pub fn next_seq(group: &Group, messages: &[GroupMessage], actions: &[GroupAction], author: &str) -> u64 {
+    sequenced(group, messages, actions)
+        .into_iter()
+        .filter(|(event_author, _)| event_author == author)
+        .map(|(_, seq)| seq)
+        .max()
+        .unwrap_or(0)
+        + 1
+}
+
+pub fn is_missing(clock: &Clock, author: &str, seq: u64) -> bool {
+    seq > clock.get(author).copied().unwrap_or(0)
+}
+
+// Whether `theirs` holds events by `author` we don't
+pub fn is_behind(ours: &Clock, theirs: &Clock, author: &str) -> bool {
+    theirs
+        .get(author)
+        .is_some_and(|seq| is_missing(ours, author, *seq))
+}
+
+// Events a member with `clock` lacks. Ours go out as they are; other
+// members' only with their author's signature, which the member checks, so
+// it can catch up while their authors are offline.
+pub fn delta(
+    group: &Group,
+    messages: &[GroupMessage],
+    actions: &[GroupAction],
+    clock: &Clock,
+    our_node: &str,
+) -> GroupDelta {
+    let relayable = |author: &str, seq: u64, signature: &[u8]| {
+        (author == our_node || !signature.is_empty()) && is_missing(clock, author, seq)
+    };
+    GroupDelta {
+        group: group.clone(),
+        membership: group
+            .membership
+            .iter()
+            .filter(|change| relayable(&change.author, change.seq, &change.signature))
+            .cloned()
+            .collect(),
+        actions: actions
+            .iter()
+            .filter(|action| relayable(&action.author, action.seq, &action.signature))
+            .cloned()
+            .collect(),
+        messages: messages
+            .iter()
+            .filter(|message| message.seq > 0 && relayable(&message.author, message.seq, &message.signature))
+            .map(as_sent)
+            .collect(),
+    }
+}
+
+// A message as its author sent it. Edits and reactions travel as actions of
+// their own, and our decrypted copy of encrypted text stays on this node.
+fn as_sent(message: &GroupMessage) -> GroupMessage {
+    let content = match (&message.encrypted, message.edits.first()) {
+        (Some(_), _) => String::new(),
+        (None, Some(original)) => original.content.clone(),
+        (None, None) => message.content.clone(),
+    };
+    GroupMessage {
+        content,
+        edits: Vec::new(),
+        reactions: HashMap::new(),
+        ..message.clone()
+    }
+}
+
+// Record a change in the group's log. The member's state follows the latest
+// change to it, so every node ends up with the same members whatever order
+// the changes arrive in. Returns false if we already had the change.
+pub fn apply_membership(group: &mut Group, change: MembershipChange) -> bool {
+    if group
+        .membership
+        .iter()
+        .any(|known| known.author == change.author && known.seq == change.seq)
+    {
+        return false;
+    }
+    let is_latest = group
+        .membership
+        .iter()
+        .filter(|known| known.member == change.member)
+        .all(|known| known.order() < change.order());
+    if is_latest {
+        if change.added {
+            group.members.insert(change.member.clone());
+        } else {
+            group.members.remove(&change.member);
+        }
+    }
+    group.membership.push(change);
+    true
+}
+
+// Whether `node` is or ever was in the group. Former members may still ask
+// for a sync, to learn that they were removed.
+pub fn was_member(group: &Group, node: &str) -> bool {
+    node == group.created_by
+        || group.members.contains(node)
+        || group
+            .membership
+            .iter()
+            .any(|change| change.added && change.member == node)
+}
+
+// Whether a member with `clock` holds every membership change we hold, so it
+// sees the same members we do
+pub fn knows_membership(group: &Group, clock: &Clock) -> bool {
+    group
+        .membership
+        .iter()
+        .all(|change| !is_missing(clock, &change.author, change.seq))
+}
+
+// Only the creator adds and removes members; anyone may leave
+pub fn may_change_membership(group: &Group, change: &MembershipChange) -> bool {
+    change.author == group.created_by || (!change.added && change.member == change.author)
+}
+
+// Whether a member's timestamp is further ahead of `now` than clock skew
+// explains. Such events are left for a later sync rather than adjusted, which
+// would break their signature.
+pub fn is_from_future(timestamp: u64, now: u64) -> bool {
+    timestamp > now.saturating_add(MAX_CLOCK_SKEW_SECS)
+}
+
+// What authors sign, per kind of event. Each names the group and leaves out
+// the signature and whatever changes after sending (edits, reactions, our
+// decrypted copy of encrypted content).
+pub fn signed_membership(group_id: &str, change: &MembershipChange) -> anyhow::Result<Vec<u8>> {
+    Ok(serde_json::to_vec(&(
+        "membership",
+        group_id,
+        &change.author,
+        change.seq,
+        &change.member,
+        change.added,
+        change.timestamp,
+    ))?)
+}
+
+// Digest of what a message said when sent. Signatures cover it rather than
+// the text, so a message deleted since can still be relayed.
+pub fn body_digest(message: &GroupMessage) -> anyhow::Result<Vec<u8>> {
+    let content = if message.encrypted.is_some() { "" } else { message.content.as_str() };
+    Ok(Sha256::digest(serde_json::to_vec(&(content, &message.encrypted))?).to_vec())
+}
+
+pub fn signed_message(message: &GroupMessage) -> anyhow::Result<Vec<u8>> {
+    Ok(serde_json::to_vec(&(
+        "message",
+        &message.group_id,
+        &message.id,
+        &message.author,
+        message.seq,
+        &message.body_digest,
+        message.timestamp,
+        &message.reply_to,
+        &message.attachments,
+    ))?)
+}
+
+pub fn signed_action(group_id: &str, action: &GroupAction) -> anyhow::Result<Vec<u8>> {
+    Ok(serde_json::to_vec(&(
+        "action",
+        group_id,
+        &action.author,
+        action.seq,
+        &action.message_id,
+        &action.kind,
+        action.timestamp,
+    ))?)
+}
+
+pub fn to_digest(group_id: &str, clock: &Clock) -> GroupDigest {
+    let mut clock: Vec<AuthorSeq> = clock
+        .iter()
+        .map(|(author, seq)| AuthorSeq {
+            author: author.clone(),
+            seq: *seq,
+        })
+        .collect();
+    clock.sort_by(|a, b| a.author.cmp(&b.author));
+    GroupDigest {
+        group_id: group_id.to_string(),
+        clock,
+    }
+}
+
+pub fn from_digest(digest: &GroupDigest) -> Clock {
+    digest
+        .clock
+        .iter()
+        .map(|entry| (entry.author.clone(), entry.seq))
+        .collect()
+}
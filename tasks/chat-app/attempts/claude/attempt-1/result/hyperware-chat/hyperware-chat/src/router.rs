@@ -0,0 +1,259 @@
+use std::collections::{HashMap, HashSet};
+
+use hyperware_process_lib::http::server::{
+    send_response, HttpBindingConfig, HttpServer, HttpServerError, IncomingHttpRequest, StatusCode,
+};
+use hyperware_process_lib::logging::error;
+use hyperware_process_lib::{get_blob, LazyLoadBlob};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+// Wrapper for HTTP responses
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApiResponse<T> {
+    pub success: bool,
+    pub data: Option<T>,
+    pub error: Option<String>,
+}
+
+// What a handler sends back on success
+pub enum ApiReply {
+    // Sent as the `data` of a successful ApiResponse
+    Json(StatusCode, serde_json::Value),
+    // Sent as-is, e.g. file downloads
+    Raw {
+        headers: HashMap<String, String>,
+        body: Vec<u8>,
+    },
+}
+
+impl ApiReply {
+    pub fn ok(data: serde_json::Value) -> Self {
+        ApiReply::Json(StatusCode::OK, data)
+    }
+
+    pub fn created(data: serde_json::Value) -> Self {
+        ApiReply::Json(StatusCode::CREATED, data)
+    }
+}
+
+// A failed request, sent as an ApiResponse with `error` set
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: StatusCode,
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, message)
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, message)
+    }
+
+    pub fn forbidden(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::FORBIDDEN, message)
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(error: anyhow::Error) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, error.to_string())
+    }
+}
+
+impl From<serde_json::Error> for ApiError {
+    fn from(error: serde_json::Error) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, error.to_string())
+    }
+}
+
+pub type ApiResult = Result<ApiReply, ApiError>;
+
+// Body of a JSON reply. One that can't be serialized becomes a server error
+// rather than taking the process down.
+fn json_body<T: Serialize>(status: StatusCode, response: &ApiResponse<T>) -> (StatusCode, Vec<u8>) {
+    match serde_json::to_vec(response) {
+        Ok(body) => (status, body),
+        Err(e) => {
+            error!("Failed to serialize response: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                br#"{"success":false,"data":null,"error":"Failed to serialize response"}"#.to_vec(),
+            )
+        }
+    }
+}
+
+// An incoming HTTP request plus its body, as seen by a handler
+pub struct ApiRequest<'a> {
+    pub http: &'a IncomingHttpRequest,
+    blob: Option<LazyLoadBlob>,
+}
+
+impl ApiRequest<'_> {
+    // A `:name` segment of the route pattern
+    pub fn param(&self, name: &str) -> Result<&str, ApiError> {
+        self.http
+            .url_params()
+            .get(name)
+            .map(|value| value.as_str())
+            .ok_or_else(|| ApiError::bad_request(format!("Missing path parameter {}", name)))
+    }
+
+    pub fn query(&self, name: &str) -> Option<&str> {
+        self.http.query_params().get(name).map(|value| value.as_str())
+    }
+
+    pub fn header(&self, name: &str) -> Option<String> {
+        self.http
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.to_string())
+    }
+
+    pub fn bytes(&self) -> Result<&[u8], ApiError> {
+        self.blob
+            .as_ref()
+            .map(|blob| blob.bytes.as_slice())
+            .ok_or_else(|| ApiError::bad_request("Missing request body"))
+    }
+
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T, ApiError> {
+        serde_json::from_slice(self.bytes()?)
+            .map_err(|e| ApiError::bad_request(format!("Invalid request body: {}", e)))
+    }
+}
+
+pub type Handler<C> = fn(&mut C, &ApiRequest) -> ApiResult;
+
+struct Route<C> {
+    method: &'static str,
+    pattern: &'static str,
+    handler: Handler<C>,
+}
+
+// Maps method + path pattern to a handler. Patterns use `:name` segments for
+// path parameters and are bound both under the process path and as a short
+// alias, e.g. `/hyperware-chat:hyperware-chat:template.os/groups` and `/groups`.
+pub struct Router<C> {
+    process_path: &'static str,
+    routes: Vec<Route<C>>,
+}
+
+impl<C> Router<C> {
+    pub fn new(process_path: &'static str) -> Self {
+        Self {
+            process_path,
+            routes: Vec::new(),
+        }
+    }
+
+    pub fn get(self, pattern: &'static str, handler: Handler<C>) -> Self {
+        self.route("GET", pattern, handler)
+    }
+
+    pub fn post(self, pattern: &'static str, handler: Handler<C>) -> Self {
+        self.route("POST", pattern, handler)
+    }
+
+    pub fn put(self, pattern: &'static str, handler: Handler<C>) -> Self {
+        self.route("PUT", pattern, handler)
+    }
+
+    pub fn delete(self, pattern: &'static str, handler: Handler<C>) -> Self {
+        self.route("DELETE", pattern, handler)
+    }
+
+    fn route(mut self, method: &'static str, pattern: &'static str, handler: Handler<C>) -> Self {
+        self.routes.push(Route {
+            method,
+            pattern,
+            handler,
+        });
+        self
+    }
+
+    // Bind every pattern, under the process path and as a short alias
+    pub fn bind(&self, server: &mut HttpServer) -> Result<(), HttpServerError> {
+        let mut bound = HashSet::new();
+        for route in &self.routes {
+            if !bound.insert(route.pattern) {
+                continue;
+            }
+            server.bind_http_path(
+                format!("/{}{}", self.process_path, route.pattern),
+                HttpBindingConfig::default(),
+            )?;
+            server.bind_http_path(route.pattern, HttpBindingConfig::default())?;
+        }
+        Ok(())
+    }
+
+    // Run the handler for a request and send its response
+    pub fn dispatch(&self, context: &mut C, request: &IncomingHttpRequest) {
+        let pattern = request.bound_path(Some(self.process_path));
+        let method = request
+            .method()
+            .map(|method| method.as_str().to_string())
+            .unwrap_or_default();
+
+        let matching: Vec<&Route<C>> = self
+            .routes
+            .iter()
+            .filter(|route| route.pattern == pattern)
+            .collect();
+        let result = if matching.is_empty() {
+            Err(ApiError::not_found(format!("No route for {}", pattern)))
+        } else if let Some(route) = matching.iter().find(|route| route.method == method) {
+            let api_request = ApiRequest {
+                http: request,
+                blob: get_blob(),
+            };
+            (route.handler)(context, &api_request)
+        } else {
+            Err(ApiError::new(
+                StatusCode::METHOD_NOT_ALLOWED,
+                format!("{} not allowed on {}", method, pattern),
+            ))
+        };
+
+        let json_headers = HashMap::from([(
+            "Content-Type".to_string(),
+            "application/json".to_string(),
+        )]);
+        let (status, body) = match result {
+            Ok(ApiReply::Json(status, data)) => json_body(
+                status,
+                &ApiResponse {
+                    success: true,
+                    data: Some(data),
+                    error: None,
+                },
+            ),
+            Ok(ApiReply::Raw { headers, body }) => {
+                send_response(StatusCode::OK, Some(headers), body);
+                return;
+            }
+            Err(error) => json_body(
+                error.status,
+                &ApiResponse::<()> {
+                    success: false,
+                    data: None,
+                    error: Some(error.message),
+                },
+            ),
+        };
+        send_response(status, Some(json_headers), body);
+    }
+}
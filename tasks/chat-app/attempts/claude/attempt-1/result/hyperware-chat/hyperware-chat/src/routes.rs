@@ -0,0 +1,654 @@
+use std::collections::{HashMap, HashSet};
+
+use hyperware_process_lib::http::server::{get_mime_type, HttpServer, StatusCode};
+use hyperware_process_lib::logging::{error, info};
+use hyperware_process_lib::Address;
+use serde::{Deserialize, Serialize};
+
+use crate::archive;
+use crate::attachments::{self, Attachment};
+use crate::e2e;
+use crate::hyperware::process::hyperware_chat::Request as HyperwareChatRequest;
+use crate::router::{ApiError, ApiReply, ApiRequest, ApiResult, Router};
+use crate::search::{self, SearchQuery};
+use crate::ws::{Conversation, WsClients, WsEvent};
+use crate::{
+    announce_group, encrypt_group_message, get_timestamp, handle_chat_request, list_presence, make_http_address,
+    mark_conversation_read, new_message_id, request_group_sync, rotate_group_sender_key, BlockNodeRequest, ChatStore,
+    Contact, CreateGroupRequest, Group, GroupMessage, GroupMessageRequest, MarkReadRequest, NewMessage, Settings,
+    PROCESS_PATH,
+};
+
+// State every HTTP handler works with
+pub struct HttpContext<'a> {
+    pub our: Address,
+    pub store: &'a ChatStore,
+    pub server: &'a HttpServer,
+    pub clients: &'a mut WsClients,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct GroupMemberBody {
+    member: String,
+}
+
+// Body of the old `PUT /groups?action=add_member|remove_member`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct LegacyGroupMemberBody {
+    group_id: String,
+    member: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct GroupMessageBody {
+    message: String,
+    #[serde(default)]
+    reply_to: Option<String>,
+    #[serde(default)]
+    attachments: Vec<String>,
+}
+
+// The HTTP API. Every pattern is served under `/{PROCESS_PATH}` and at the root.
+// Responses are an ApiResponse (`{"success", "data", "error"}`) except file
+// downloads and `GET /messages`, which keeps its original
+// `{"History": {"messages": ...}}` body.
+//
+// Routes from before the router are kept as aliases of the new ones:
+// - `GET /groups?id=<id>` for `GET /groups/:id`
+// - `PUT /groups?action=add_member|remove_member` with `{"group_id", "member"}`
+//   for `POST /groups/:id/members` and `DELETE /groups/:id/members/:member`
+// - `PUT /groups?action=send_message` with `{"group_id", "message"}` for
+//   `POST /groups/:id/messages`
+// - `DELETE /blocked` with `{"node"}` for `DELETE /blocked/:node`
+pub fn router<'a>() -> Router<HttpContext<'a>> {
+    Router::new(PROCESS_PATH)
+        .get("/messages", get_all_messages)
+        .post("/messages", send_message)
+        .get("/messages/:contact", get_conversation)
+        .get("/messages/:contact/threads/:message_id", get_direct_thread)
+        .get("/contacts", get_contacts)
+        .post("/contacts", add_contact)
+        .delete("/contacts/:id", remove_contact)
+        .get("/conversations", get_conversations)
+        .post("/conversations/read", mark_read)
+        .get("/settings", get_settings)
+        .put("/settings", update_settings)
+        .get("/keys", get_our_keys)
+        .get("/keys/:node", get_peer_keys)
+        .delete("/keys/:node", forget_peer_keys)
+        .get("/presence", get_presence)
+        .get("/groups", get_groups)
+        .post("/groups", create_group)
+        .put("/groups", legacy_update_group)
+        .get("/groups/:id", get_group)
+        .delete("/groups/:id", delete_group)
+        .get("/groups/:id/threads/:message_id", get_group_thread)
+        .post("/groups/:id/members", add_group_member)
+        .delete("/groups/:id/members/:member", remove_group_member)
+        .post("/groups/:id/messages", send_group_message)
+        .get("/blocked", get_blocked)
+        .post("/blocked", block_node)
+        .delete("/blocked", legacy_unblock_node)
+        .delete("/blocked/:node", unblock_node)
+        .get("/requests", get_message_requests)
+        .post("/requests/:node/approve", approve_message_request)
+        .post("/requests/:node/block", block_message_request)
+        .delete("/requests/:node", decline_message_request)
+        .post("/attachments", upload_attachment)
+        .get("/attachments/:id", download_attachment)
+        .post("/archives", export_archive)
+        .get("/archives/:name", download_archive)
+        .post("/archives/import", import_archive)
+        .get("/search", search_messages)
+        .post("/search/reindex", reindex_search)
+}
+
+// Every direct conversation, in the body this route had before the router
+fn get_all_messages(ctx: &mut HttpContext, _req: &ApiRequest) -> ApiResult {
+    let messages = ctx.store.get_all_messages()?;
+    Ok(ApiReply::Raw {
+        headers: HashMap::from([("Content-Type".to_string(), "application/json".to_string())]),
+        body: serde_json::to_vec(&serde_json::json!({ "History": { "messages": messages } }))?,
+    })
+}
+
+fn send_message(ctx: &mut HttpContext, req: &ApiRequest) -> ApiResult {
+    let request: HyperwareChatRequest = req.json()?;
+    if !matches!(request, HyperwareChatRequest::Send(_)) {
+        return Err(ApiError::bad_request("Expected a Send request"));
+    }
+    handle_chat_request(
+        &ctx.our,
+        &make_http_address(&ctx.our),
+        request,
+        true,
+        ctx.store,
+        ctx.server,
+        ctx.clients,
+    )
+    .map_err(|e| ApiError::bad_request(format!("Failed to send message: {}", e)))?;
+    Ok(ApiReply::created(serde_json::Value::Null))
+}
+
+fn get_conversation(ctx: &mut HttpContext, req: &ApiRequest) -> ApiResult {
+    let contact = req.param("contact")?;
+    let conversation = Conversation::Direct(contact.to_string());
+    let messages = ctx.store.get_messages(contact)?;
+    let threads = ctx.store.get_thread_summaries(&conversation)?;
+    Ok(ApiReply::ok(serde_json::json!({
+        "messages": messages,
+        "threads": threads
+    })))
+}
+
+fn get_direct_thread(ctx: &mut HttpContext, req: &ApiRequest) -> ApiResult {
+    let conversation = Conversation::Direct(req.param("contact")?.to_string());
+    get_thread(ctx, &conversation, req.param("message_id")?)
+}
+
+fn get_group_thread(ctx: &mut HttpContext, req: &ApiRequest) -> ApiResult {
+    let conversation = Conversation::Group(req.param("id")?.to_string());
+    get_thread(ctx, &conversation, req.param("message_id")?)
+}
+
+fn get_thread(ctx: &mut HttpContext, conversation: &Conversation, message_id: &str) -> ApiResult {
+    let thread = ctx
+        .store
+        .get_thread_json(conversation, message_id)?
+        .ok_or_else(|| ApiError::not_found("Thread not found"))?;
+    Ok(ApiReply::ok(serde_json::json!({ "messages": thread })))
+}
+
+fn get_contacts(ctx: &mut HttpContext, _req: &ApiRequest) -> ApiResult {
+    Ok(ApiReply::ok(serde_json::json!({ "contacts": ctx.store.get_contacts()? })))
+}
+
+fn add_contact(ctx: &mut HttpContext, req: &ApiRequest) -> ApiResult {
+    let contact: Contact = req.json()?;
+    ctx.store.add_contact(&contact)?;
+    ctx.clients.push(
+        ctx.server,
+        &WsEvent::ContactAdded {
+            id: contact.id.clone(),
+            name: contact.name.clone(),
+        },
+    );
+    Ok(ApiReply::created(serde_json::json!({ "contact": contact })))
+}
+
+fn remove_contact(ctx: &mut HttpContext, req: &ApiRequest) -> ApiResult {
+    let id = req.param("id")?;
+    if !ctx.store.remove_contact(id)? {
+        return Err(ApiError::not_found("Contact not found"));
+    }
+    ctx.clients
+        .push(ctx.server, &WsEvent::ContactRemoved { id: id.to_string() });
+    Ok(ApiReply::ok(serde_json::Value::Null))
+}
+
+// Direct and group conversations with their unread counts
+fn get_conversations(ctx: &mut HttpContext, _req: &ApiRequest) -> ApiResult {
+    let conversations = ctx.store.list_conversations(ctx.our.node())?;
+    Ok(ApiReply::ok(serde_json::json!({ "conversations": conversations })))
+}
+
+fn mark_read(ctx: &mut HttpContext, req: &ApiRequest) -> ApiResult {
+    let body: MarkReadRequest = req.json()?;
+    let marker = mark_conversation_read(
+        &ctx.our,
+        &body.conversation,
+        body.message_id.as_deref(),
+        ctx.store,
+        ctx.server,
+        ctx.clients,
+    )?
+    .ok_or_else(|| ApiError::not_found("Message not found"))?;
+    Ok(ApiReply::ok(serde_json::json!({ "last_read": marker })))
+}
+
+fn get_settings(ctx: &mut HttpContext, _req: &ApiRequest) -> ApiResult {
+    Ok(ApiReply::ok(serde_json::json!({ "settings": ctx.store.get_settings()? })))
+}
+
+fn update_settings(ctx: &mut HttpContext, req: &ApiRequest) -> ApiResult {
+    let settings: Settings = req.json()?;
+    ctx.store.set_settings(&settings)?;
+    Ok(ApiReply::ok(serde_json::json!({ "settings": settings })))
+}
+
+fn get_groups(ctx: &mut HttpContext, req: &ApiRequest) -> ApiResult {
+    if let Some(group_id) = req.query("id") {
+        return group_details(ctx, group_id);
+    }
+    let groups = ctx.store.get_all_groups()?;
+    Ok(ApiReply::ok(serde_json::json!({ "groups": groups })))
+}
+
+fn create_group(ctx: &mut HttpContext, req: &ApiRequest) -> ApiResult {
+    let create_req: CreateGroupRequest = req.json()?;
+    // The creator is always a member
+    let mut members: HashSet<String> = create_req.members.into_iter().collect();
+    members.insert(ctx.our.node().to_string());
+
+    let group = ctx
+        .store
+        .create_group(&create_req.name, members, ctx.our.node())?;
+    announce_group(&ctx.our, &group.id, ctx.store);
+    Ok(ApiReply::created(serde_json::json!({ "group": group })))
+}
+
+// A group we hold and haven't deleted
+fn find_group(ctx: &HttpContext, group_id: &str) -> Result<Group, ApiError> {
+    if ctx.store.is_group_deleted(group_id)? {
+        return Err(ApiError::not_found("Group not found"));
+    }
+    ctx.store
+        .get_group(group_id)?
+        .ok_or_else(|| ApiError::not_found("Group not found"))
+}
+
+fn get_group(ctx: &mut HttpContext, req: &ApiRequest) -> ApiResult {
+    group_details(ctx, req.param("id")?)
+}
+
+fn group_details(ctx: &mut HttpContext, group_id: &str) -> ApiResult {
+    let group = find_group(ctx, group_id)?;
+    let messages = ctx.store.get_group_messages(group_id)?;
+    let threads = ctx
+        .store
+        .get_thread_summaries(&Conversation::Group(group_id.to_string()))?;
+    Ok(ApiReply::ok(serde_json::json!({
+        "group": group,
+        "messages": messages,
+        "threads": threads
+    })))
+}
+
+// Leave the group and forget it. The other members keep it; they learn we
+// left from the announce or when they next sync with us.
+fn delete_group(ctx: &mut HttpContext, req: &ApiRequest) -> ApiResult {
+    let group_id = req.param("id")?;
+    let group = find_group(ctx, group_id)?;
+    if group.members.contains(ctx.our.node()) {
+        ctx.store
+            .remove_member_from_group(group_id, ctx.our.node(), ctx.our.node())?;
+        announce_group(&ctx.our, group_id, ctx.store);
+    }
+    ctx.store.delete_group(group_id)?;
+    ctx.clients.push(
+        ctx.server,
+        &WsEvent::GroupDeleted {
+            group_id: group_id.to_string(),
+        },
+    );
+    Ok(ApiReply::ok(serde_json::Value::Null))
+}
+
+// Only the group's creator may add or remove members; anyone may leave
+fn check_membership_change(ctx: &HttpContext, group_id: &str, member: &str, added: bool) -> Result<(), ApiError> {
+    let group = find_group(ctx, group_id)?;
+    if group.created_by != ctx.our.node() && (added || member != ctx.our.node()) {
+        return Err(ApiError::forbidden("Only the group's creator can change its members"));
+    }
+    Ok(())
+}
+
+fn add_group_member(ctx: &mut HttpContext, req: &ApiRequest) -> ApiResult {
+    let body: GroupMemberBody = req.json()?;
+    add_member(ctx, req.param("id")?, &body.member)
+}
+
+fn add_member(ctx: &mut HttpContext, group_id: &str, member: &str) -> ApiResult {
+    check_membership_change(ctx, group_id, member, true)?;
+    if !ctx
+        .store
+        .add_member_to_group(group_id, member, ctx.our.node())?
+    {
+        return Err(ApiError::not_found(
+            "Group not found or member already in group",
+        ));
+    }
+    rotate_keys_after_membership_change(ctx, group_id);
+    announce_group(&ctx.our, group_id, ctx.store);
+    Ok(ApiReply::ok(serde_json::Value::Null))
+}
+
+fn remove_group_member(ctx: &mut HttpContext, req: &ApiRequest) -> ApiResult {
+    remove_member(ctx, req.param("id")?, req.param("member")?)
+}
+
+fn remove_member(ctx: &mut HttpContext, group_id: &str, member: &str) -> ApiResult {
+    check_membership_change(ctx, group_id, member, false)?;
+    if !ctx
+        .store
+        .remove_member_from_group(group_id, member, ctx.our.node())?
+    {
+        return Err(ApiError::not_found("Group not found or member not in group"));
+    }
+    rotate_keys_after_membership_change(ctx, group_id);
+    announce_group(&ctx.our, group_id, ctx.store);
+    // The removed member is no longer announced to; tell it directly
+    if let Err(e) = request_group_sync(group_id, member, ctx.store) {
+        info!("Failed to tell {} about removal from {}: {}", member, group_id, e);
+    }
+    Ok(ApiReply::ok(serde_json::Value::Null))
+}
+
+// The membership change already happened, so a failed rotation is only
+// logged. Members get the new key once the announce reaches them.
+fn rotate_keys_after_membership_change(ctx: &mut HttpContext, group_id: &str) {
+    if let Err(e) = rotate_group_sender_key(&ctx.our, group_id, ctx.store) {
+        error!("Failed to rotate key for group {}: {}", group_id, e);
+    }
+}
+
+fn send_group_message(ctx: &mut HttpContext, req: &ApiRequest) -> ApiResult {
+    let body: GroupMessageBody = req.json()?;
+    post_group_message(ctx, req.param("id")?, body)
+}
+
+// The routes from before the router, picked by `?action=`
+fn legacy_update_group(ctx: &mut HttpContext, req: &ApiRequest) -> ApiResult {
+    match req.query("action") {
+        Some("add_member") => {
+            let body: LegacyGroupMemberBody = req.json()?;
+            add_member(ctx, &body.group_id, &body.member)
+        }
+        Some("remove_member") => {
+            let body: LegacyGroupMemberBody = req.json()?;
+            remove_member(ctx, &body.group_id, &body.member)
+        }
+        Some("send_message") => {
+            let body: GroupMessageRequest = req.json()?;
+            let group_id = body.group_id.clone();
+            post_group_message(
+                ctx,
+                &group_id,
+                GroupMessageBody {
+                    message: body.message,
+                    reply_to: body.reply_to,
+                    attachments: body.attachments,
+                },
+            )
+        }
+        _ => Err(ApiError::bad_request("Invalid action")),
+    }
+}
+
+fn post_group_message(ctx: &mut HttpContext, group_id: &str, body: GroupMessageBody) -> ApiResult {
+    let group = find_group(ctx, group_id)?;
+    if !group.members.contains(ctx.our.node()) {
+        return Err(ApiError::forbidden("You are not a member of this group"));
+    }
+    if let Some(ref reply_to) = body.reply_to {
+        let conversation = Conversation::Group(group_id.to_string());
+        if !ctx.store.has_message(&conversation, reply_to)? {
+            return Err(ApiError::bad_request("Reply to unknown message"));
+        }
+    }
+
+    let mut group_message = GroupMessage {
+        group_id: group_id.to_string(),
+        id: new_message_id(ctx.our.node()),
+        author: ctx.our.node().to_string(),
+        content: body.message,
+        timestamp: get_timestamp(),
+        reply_to: body.reply_to,
+        attachments: ctx.store.resolve_attachments(&body.attachments)?,
+        ..Default::default()
+    };
+    encrypt_group_message(&ctx.our, &mut group_message, ctx.store)?;
+    let group_message = ctx.store.add_group_message(group_id, group_message)?;
+    ctx.clients
+        .push(ctx.server, &WsEvent::NewGroupMessage(group_message.clone()));
+    announce_group(&ctx.our, group_id, ctx.store);
+    Ok(ApiReply::created(serde_json::json!({ "message": group_message })))
+}
+
+// Online state and last-seen time of every node we exchange heartbeats with
+fn get_presence(ctx: &mut HttpContext, _req: &ApiRequest) -> ApiResult {
+    let presence = list_presence(&ctx.our, ctx.store, ctx.clients)?;
+    Ok(ApiReply::ok(serde_json::json!({ "presence": presence })))
+}
+
+fn get_blocked(ctx: &mut HttpContext, _req: &ApiRequest) -> ApiResult {
+    let blocked = ctx.store.get_blocked_nodes()?;
+    Ok(ApiReply::ok(serde_json::json!({ "blocked": blocked })))
+}
+
+fn block_node(ctx: &mut HttpContext, req: &ApiRequest) -> ApiResult {
+    let block_req: BlockNodeRequest = req.json()?;
+    ctx.store.block_node(&block_req.node)?;
+    Ok(ApiReply::ok(serde_json::Value::Null))
+}
+
+fn unblock_node(ctx: &mut HttpContext, req: &ApiRequest) -> ApiResult {
+    ctx.store.unblock_node(req.param("node")?)?;
+    Ok(ApiReply::ok(serde_json::Value::Null))
+}
+
+// The route from before the router, naming the node in the body
+fn legacy_unblock_node(ctx: &mut HttpContext, req: &ApiRequest) -> ApiResult {
+    let body: BlockNodeRequest = req.json()?;
+    ctx.store.unblock_node(&body.node)?;
+    Ok(ApiReply::ok(serde_json::Value::Null))
+}
+
+// Quarantined first messages from nodes we don't know, by node
+fn get_message_requests(ctx: &mut HttpContext, _req: &ApiRequest) -> ApiResult {
+    let requests = ctx.store.get_message_requests()?;
+    Ok(ApiReply::ok(serde_json::json!({ "requests": requests })))
+}
+
+// Accept the node: its messages move into our history and it is no longer
+// quarantined
+fn approve_message_request(ctx: &mut HttpContext, req: &ApiRequest) -> ApiResult {
+    let node = req.param("node")?;
+    let messages = ctx.store.approve_node(node)?;
+    for message in &messages {
+        ctx.clients.push(
+            ctx.server,
+            &WsEvent::NewMessage(NewMessage {
+                hyperware_chat: node.to_string(),
+                id: message.id.clone(),
+                author: message.author.clone(),
+                content: message.content.clone(),
+                timestamp: message.timestamp,
+                reply_to: message.reply_to.clone(),
+                attachments: message.attachments.clone(),
+            }),
+        );
+    }
+    Ok(ApiReply::ok(serde_json::json!({ "messages": messages })))
+}
+
+fn block_message_request(ctx: &mut HttpContext, req: &ApiRequest) -> ApiResult {
+    let node = req.param("node")?;
+    ctx.store.block_node(node)?;
+    ctx.store.drop_message_requests(node)?;
+    Ok(ApiReply::ok(serde_json::Value::Null))
+}
+
+// Drop the messages; the node may still write again later
+fn decline_message_request(ctx: &mut HttpContext, req: &ApiRequest) -> ApiResult {
+    ctx.store.drop_message_requests(req.param("node")?)?;
+    Ok(ApiReply::ok(serde_json::Value::Null))
+}
+
+// Upload a file from our UI: POST /attachments?name=<file name>
+fn upload_attachment(ctx: &mut HttpContext, req: &ApiRequest) -> ApiResult {
+    let name = req
+        .query("name")
+        .ok_or_else(|| ApiError::bad_request("Missing file name"))?;
+    let bytes = req.bytes()?;
+    if bytes.len() as u64 > attachments::MAX_ATTACHMENT_SIZE {
+        return Err(ApiError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "Attachment is too large",
+        ));
+    }
+
+    let mime = req
+        .header("content-type")
+        .unwrap_or_else(|| get_mime_type(name));
+    if !attachments::is_allowed_mime(&mime) {
+        return Err(ApiError::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            format!("Attachment type {} is not allowed", mime),
+        ));
+    }
+
+    let attachment = Attachment {
+        id: new_message_id(ctx.our.node()),
+        name: name.to_string(),
+        mime,
+        size: bytes.len() as u64,
+    };
+    ctx.store.attachments.save(&attachment, bytes)?;
+    Ok(ApiReply::created(serde_json::json!({ "attachment": attachment })))
+}
+
+fn download_attachment(ctx: &mut HttpContext, req: &ApiRequest) -> ApiResult {
+    let attachment = ctx
+        .store
+        .attachments
+        .get(req.param("id")?)?
+        .ok_or_else(|| ApiError::not_found("Attachment not found"))?;
+    let bytes = ctx.store.attachments.read(&attachment)?;
+    Ok(ApiReply::Raw {
+        headers: HashMap::from([
+            ("Content-Type".to_string(), attachment.mime.clone()),
+            // Never rendered as a page of ours, whatever the file claims to be
+            (
+                "Content-Disposition".to_string(),
+                format!("attachment; filename=\"{}\"", attachment.name.replace('"', "")),
+            ),
+            ("X-Content-Type-Options".to_string(), "nosniff".to_string()),
+        ]),
+        body: bytes,
+    })
+}
+
+// Write the whole chat history to an archive file in our VFS
+fn export_archive(ctx: &mut HttpContext, _req: &ApiRequest) -> ApiResult {
+    let archive = ctx.store.export_archive(ctx.our.node())?;
+    let (name, path) = ctx.store.archives.write(&archive)?;
+    Ok(ApiReply::created(serde_json::json!({
+        "name": name,
+        "path": path,
+        "version": archive.version,
+        "exported_at": archive.exported_at
+    })))
+}
+
+fn download_archive(ctx: &mut HttpContext, req: &ApiRequest) -> ApiResult {
+    let name = req.param("name")?;
+    if !archive::is_valid_name(name) {
+        return Err(ApiError::bad_request("Invalid archive name"));
+    }
+    let bytes = ctx
+        .store
+        .archives
+        .read(name)
+        .map_err(|_| ApiError::not_found("Archive not found"))?;
+    Ok(ApiReply::Raw {
+        headers: HashMap::from([
+            ("Content-Type".to_string(), "application/json".to_string()),
+            (
+                "Content-Disposition".to_string(),
+                format!("attachment; filename=\"{}\"", name),
+            ),
+        ]),
+        body: bytes,
+    })
+}
+
+// Merge an archive into our history: either one already in our archives
+// drive (?name=archive-<time>.json) or one uploaded as the request body
+fn import_archive(ctx: &mut HttpContext, req: &ApiRequest) -> ApiResult {
+    let bytes = match req.query("name") {
+        Some(name) => ctx
+            .store
+            .archives
+            .read(name)
+            .map_err(|_| ApiError::not_found("Archive not found"))?,
+        None => req.bytes()?.to_vec(),
+    };
+    let archive = archive::parse(&bytes)
+        .map_err(|e| ApiError::bad_request(format!("Invalid archive: {}", e)))?;
+    let summary = ctx.store.import_archive(archive)?;
+    Ok(ApiReply::ok(serde_json::json!({ "imported": summary })))
+}
+
+fn parse_number<T: std::str::FromStr>(req: &ApiRequest, name: &str) -> Result<Option<T>, ApiError> {
+    req.query(name)
+        .map(|value| {
+            value
+                .parse()
+                .map_err(|_| ApiError::bad_request(format!("Invalid {}", name)))
+        })
+        .transpose()
+}
+
+// GET /search?q=<words>[&author=<node>][&contact=<node>|&group=<id>][&since=<secs>][&until=<secs>][&limit=<n>]
+fn search_messages(ctx: &mut HttpContext, req: &ApiRequest) -> ApiResult {
+    let text = req
+        .query("q")
+        .ok_or_else(|| ApiError::bad_request("Missing search query"))?;
+    let conversation = match (req.query("contact"), req.query("group")) {
+        (Some(_), Some(_)) => {
+            return Err(ApiError::bad_request("Use either contact or group, not both"))
+        }
+        (Some(contact), None) => Some(Conversation::Direct(contact.to_string())),
+        (None, Some(group_id)) => Some(Conversation::Group(group_id.to_string())),
+        (None, None) => None,
+    };
+    let query = SearchQuery {
+        text: text.to_string(),
+        author: req.query("author").map(|author| author.to_string()),
+        conversation,
+        since: parse_number(req, "since")?,
+        until: parse_number(req, "until")?,
+        limit: parse_number::<usize>(req, "limit")?
+            .map_or(search::DEFAULT_LIMIT, |limit| limit.min(search::MAX_LIMIT)),
+    };
+
+    let hits = ctx
+        .store
+        .search(&query)
+        .map_err(|e| ApiError::bad_request(e.to_string()))?;
+    Ok(ApiReply::ok(serde_json::json!({ "hits": hits })))
+}
+
+// Index history stored before search existed; safe to repeat
+fn reindex_search(ctx: &mut HttpContext, _req: &ApiRequest) -> ApiResult {
+    let indexed = ctx.store.rebuild_search_index()?;
+    Ok(ApiReply::ok(serde_json::json!({ "indexed": indexed })))
+}
+
+// Our public keys and their fingerprint, to compare with a peer out of band
+fn get_our_keys(ctx: &mut HttpContext, _req: &ApiRequest) -> ApiResult {
+    let keys = ctx.store.e2e.public_keys();
+    Ok(ApiReply::ok(serde_json::json!({
+        "fingerprint": e2e::fingerprint(&keys),
+        "keys": keys
+    })))
+}
+
+fn get_peer_keys(ctx: &mut HttpContext, req: &ApiRequest) -> ApiResult {
+    let keys = ctx
+        .store
+        .e2e
+        .peer_keys(req.param("node")?)?
+        .ok_or_else(|| ApiError::not_found("No keys for node"))?;
+    Ok(ApiReply::ok(serde_json::json!({
+        "fingerprint": e2e::fingerprint(&keys),
+        "keys": keys
+    })))
+}
+
+// Forget a peer's keys, e.g. after it reinstalled, so new ones are accepted
+fn forget_peer_keys(ctx: &mut HttpContext, req: &ApiRequest) -> ApiResult {
+    ctx.store.e2e.forget_peer(req.param("node")?)?;
+    Ok(ApiReply::ok(serde_json::Value::Null))
+}
@@ -0,0 +1,139 @@
+use std::marker::PhantomData;
+
+use anyhow::Context;
+use hyperware_process_lib::kv::{self, Kv};
+use hyperware_process_lib::PackageId;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+
+// Version of the records this build writes. Bump it whenever a stored type
+// changes shape in a way `#[serde(default)]` can't absorb, and add a
+// migration that brings older records up to date.
+pub const SCHEMA_VERSION: u32 = 1;
+
+const META_DB: &str = "meta";
+const SCHEMA_VERSION_KEY: &str = "schema_version";
+
+// How a record is stored: its data plus the schema version that wrote it.
+// `__v` is a name no record type uses, so a bare record is never mistaken
+// for an envelope.
+#[derive(Debug, Serialize)]
+struct Envelope<T> {
+    #[serde(rename = "__v")]
+    version: u32,
+    data: T,
+}
+
+// Records written before envelopes were introduced are bare JSON and count as
+// version 0
+fn unwrap_envelope(value: Value) -> (u32, Value) {
+    if let Value::Object(ref fields) = value {
+        if fields.len() == 2 {
+            if let (Some(Value::Number(version)), Some(data)) = (fields.get("__v"), fields.get("data")) {
+                if let Some(version) = version.as_u64() {
+                    return (version as u32, data.clone());
+                }
+            }
+        }
+    }
+    (0, value)
+}
+
+// Whether a KV error only says the key isn't there
+fn is_missing_key(error: &anyhow::Error) -> bool {
+    matches!(error.downcast_ref::<kv::KvError>(), Some(kv::KvError::KeyNotFound))
+}
+
+// A KV database of versioned records. Missing keys read as None; records that
+// don't decode are errors rather than silently empty.
+pub struct RecordDb<T> {
+    name: &'static str,
+    db: Kv<String, Value>,
+    _record: PhantomData<T>,
+}
+
+impl<T: Serialize + DeserializeOwned> RecordDb<T> {
+    pub fn open(package_id: PackageId, name: &'static str) -> anyhow::Result<Self> {
+        let db = kv::open(package_id, name, None)?;
+        Ok(Self {
+            name,
+            db,
+            _record: PhantomData,
+        })
+    }
+
+    pub fn get(&self, key: &str) -> anyhow::Result<Option<T>> {
+        // The KV process answers a missing key with an error; any other
+        // error is a failed read, not an absent record
+        let value = match self.db.get(&key.to_string()) {
+            Ok(value) => value,
+            Err(e) if is_missing_key(&e) => return Ok(None),
+            Err(e) => return Err(e.context(format!("failed to read {} from {}", key, self.name))),
+        };
+        let (version, data) = unwrap_envelope(value);
+        if version > SCHEMA_VERSION {
+            return Err(anyhow::anyhow!(
+                "record {} in {} has schema version {}, newer than supported {}",
+                key,
+                self.name,
+                version,
+                SCHEMA_VERSION
+            ));
+        }
+        let record = serde_json::from_value(data)
+            .with_context(|| format!("corrupt record {} in {} (schema version {})", key, self.name, version))?;
+        Ok(Some(record))
+    }
+
+    pub fn set(&self, key: &str, record: &T) -> anyhow::Result<()> {
+        let envelope = Envelope {
+            version: SCHEMA_VERSION,
+            data: record,
+        };
+        self.db.set(&key.to_string(), &serde_json::to_value(envelope)?, None)?;
+        Ok(())
+    }
+
+    pub fn delete(&self, key: &str) -> anyhow::Result<()> {
+        self.db.delete(&key.to_string(), None)?;
+        Ok(())
+    }
+
+    // Read a record and write it back in the current envelope
+    pub fn rewrite(&self, key: &str) -> anyhow::Result<bool> {
+        match self.get(key)? {
+            Some(record) => {
+                self.set(key, &record)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+// The schema version the stored data is at
+pub struct SchemaMeta {
+    db: Kv<String, u32>,
+}
+
+impl SchemaMeta {
+    pub fn open(package_id: PackageId) -> anyhow::Result<Self> {
+        let db = kv::open(package_id, META_DB, None)?;
+        Ok(Self { db })
+    }
+
+    // 0 for data from before versioning
+    pub fn version(&self) -> anyhow::Result<u32> {
+        match self.db.get(&SCHEMA_VERSION_KEY.to_string()) {
+            Ok(version) => Ok(version),
+            Err(e) if is_missing_key(&e) => Ok(0),
+            Err(e) => Err(e.context("failed to read the schema version")),
+        }
+    }
+
+    pub fn set_version(&self, version: u32) -> anyhow::Result<()> {
+        self.db.set(&SCHEMA_VERSION_KEY.to_string(), &version, None)?;
+        Ok(())
+    }
+}
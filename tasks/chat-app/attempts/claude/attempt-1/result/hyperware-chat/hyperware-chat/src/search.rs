@@ -0,0 +1,203 @@
+use std::collections::HashSet;
+
+use hyperware_process_lib::PackageId;
+use serde::{Deserialize, Serialize};
+
+use crate::hyperware::process::hyperware_chat::{HyperwareChatMessage, SearchRequest};
+use crate::reads;
+use crate::schema::RecordDb;
+use crate::ws::Conversation;
+use crate::StoredMessage;
+
+const CHUNKS_DB: &str = "search_index_chunks";
+const TAILS_DB: &str = "search_tails";
+const INDEXED_DB: &str = "search_indexed";
+// Postings per stored chunk
+const CHUNK_LEN: usize = 256;
+
+pub const DEFAULT_LIMIT: usize = 50;
+pub const MAX_LIMIT: usize = 500;
+// Longer "words" are almost always pasted blobs and would bloat the index
+const MAX_TOKEN_LEN: usize = 64;
+
+// One message containing a word
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Posting {
+    pub conversation: Conversation,
+    pub message_id: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SearchQuery {
+    pub text: String,
+    pub author: Option<String>,
+    pub conversation: Option<Conversation>,
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    pub limit: usize,
+}
+
+impl From<SearchRequest> for SearchQuery {
+    fn from(request: SearchRequest) -> Self {
+        Self {
+            text: request.query,
+            author: request.author,
+            conversation: request.conversation.map(Conversation::from),
+            since: request.since,
+            until: request.until,
+            limit: request
+                .limit
+                .map_or(DEFAULT_LIMIT, |limit| (limit as usize).min(MAX_LIMIT)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SearchHit {
+    pub conversation: Conversation,
+    pub timestamp: u64,
+    // The message as stored, for the UI
+    pub message: serde_json::Value,
+    // The message in WIT form, for process callers
+    #[serde(skip)]
+    pub wit: HyperwareChatMessage,
+}
+
+// Lowercased alphanumeric words
+pub fn tokenize(text: &str) -> HashSet<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty() && word.chars().count() <= MAX_TOKEN_LEN)
+        .map(|word| word.to_lowercase())
+        .collect()
+}
+
+// Whether a stored message still satisfies the query. Postings are only ever
+// added, so this also drops hits on text that was since edited or deleted.
+pub fn matches<M: StoredMessage>(message: &M, query: &SearchQuery, tokens: &HashSet<String>) -> bool {
+    if message.is_deleted() {
+        return false;
+    }
+    if let Some(ref author) = query.author {
+        if message.author() != author {
+            return false;
+        }
+    }
+    if query.since.is_some_and(|since| message.timestamp() < since)
+        || query.until.is_some_and(|until| message.timestamp() > until)
+    {
+        return false;
+    }
+    tokenize(message.content()).is_superset(tokens)
+}
+
+// Inverted index: word -> messages containing it. A word's postings are
+// stored in fixed-size chunks, "{word}#{n}", and a tail holding the chunk
+// still being filled, so indexing a word reads and writes one record. A
+// record per message lists the words it is indexed under, so a message is
+// never listed twice and an edit only adds its new words.
+pub struct SearchIndex {
+    chunks_db: RecordDb<Vec<Posting>>,
+    tails_db: RecordDb<Tail>,
+    // "{conversation}\n{message id}" -> words the message is indexed under
+    indexed_db: RecordDb<HashSet<String>>,
+}
+
+// The postings of a word not yet moved to a full chunk
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Tail {
+    // Full chunks stored under "{word}#{n}"
+    chunks: u32,
+    postings: Vec<Posting>,
+}
+
+impl Tail {
+    fn len(&self) -> usize {
+        self.chunks as usize * CHUNK_LEN + self.postings.len()
+    }
+}
+
+fn chunk_key(token: &str, chunk: u32) -> String {
+    format!("{}#{}", token, chunk)
+}
+
+fn message_key(conversation: &Conversation, message_id: &str) -> String {
+    format!("{}\n{}", reads::key(conversation), message_id)
+}
+
+impl SearchIndex {
+    pub fn new(package_id: PackageId) -> anyhow::Result<Self> {
+        Ok(Self {
+            chunks_db: RecordDb::open(package_id.clone(), CHUNKS_DB)?,
+            tails_db: RecordDb::open(package_id.clone(), TAILS_DB)?,
+            indexed_db: RecordDb::open(package_id, INDEXED_DB)?,
+        })
+    }
+
+    fn tail(&self, token: &str) -> anyhow::Result<Tail> {
+        Ok(self.tails_db.get(token)?.unwrap_or_default())
+    }
+
+    fn add(&self, token: &str, posting: Posting) -> anyhow::Result<()> {
+        let mut tail = self.tail(token)?;
+        tail.postings.push(posting);
+        if tail.postings.len() >= CHUNK_LEN {
+            self.chunks_db.set(&chunk_key(token, tail.chunks), &tail.postings)?;
+            tail.chunks += 1;
+            tail.postings.clear();
+        }
+        self.tails_db.set(token, &tail)
+    }
+
+    pub fn index(&self, conversation: &Conversation, message_id: &str, content: &str) -> anyhow::Result<()> {
+        let key = message_key(conversation, message_id);
+        let mut indexed = self.indexed_db.get(&key)?.unwrap_or_default();
+        let mut added = false;
+        for token in tokenize(content) {
+            if indexed.contains(&token) {
+                continue;
+            }
+            self.add(
+                &token,
+                Posting {
+                    conversation: conversation.clone(),
+                    message_id: message_id.to_string(),
+                },
+            )?;
+            indexed.insert(token);
+            added = true;
+        }
+        if added {
+            self.indexed_db.set(&key, &indexed)?;
+        }
+        Ok(())
+    }
+
+    // Candidates for messages containing every token: the postings of the
+    // rarest one. Only that word's chunks are loaded; the caller checks the
+    // other words against each candidate's content with `matches`.
+    pub fn lookup(&self, tokens: &HashSet<String>) -> anyhow::Result<HashSet<Posting>> {
+        let mut rarest: Option<(&String, Tail)> = None;
+        for token in tokens {
+            let tail = self.tail(token)?;
+            if tail.len() == 0 {
+                // A word nothing contains: nothing contains them all
+                return Ok(HashSet::new());
+            }
+            let is_rarer = match &rarest {
+                Some((_, best)) => tail.len() < best.len(),
+                None => true,
+            };
+            if is_rarer {
+                rarest = Some((token, tail));
+            }
+        }
+        let Some((token, tail)) = rarest else {
+            return Ok(HashSet::new());
+        };
+        let mut found: HashSet<Posting> = tail.postings.into_iter().collect();
+        for chunk in 0..tail.chunks {
+            found.extend(self.chunks_db.get(&chunk_key(token, chunk))?.unwrap_or_default());
+        }
+        Ok(found)
+    }
+}
@@ -0,0 +1,148 @@
+use hyperware_process_lib::PackageId;
+use serde::{Deserialize, Serialize};
+
+use crate::reads;
+use crate::schema::RecordDb;
+use crate::ws::{Conversation, WsEvent};
+
+const HEADS_DB: &str = "sync_heads";
+const EVENTS_DB: &str = "sync_events";
+
+// Events kept per conversation; clients further behind reload it instead
+const MAX_LOG_LEN: usize = 500;
+
+// An event as pushed to clients. Conversation events carry their sequence
+// number, after the variant key so `Object.keys(event)[0]` still names it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SequencedEvent {
+    #[serde(flatten)]
+    pub event: WsEvent,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+}
+
+// Last sequence number a client has seen in a conversation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncCursor {
+    pub conversation: Conversation,
+    pub seq: u64,
+}
+
+// Where a conversation stands after a sync
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncStatus {
+    pub conversation: Conversation,
+    // Latest sequence number; the client's new cursor
+    pub head: u64,
+    // The client is too far behind to replay and should reload the conversation
+    pub reset: bool,
+}
+
+// Where a conversation's log stands; its events are stored one per key
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct LogHead {
+    head: u64,
+}
+
+// Recent events per conversation, numbered so clients can catch up on what
+// they missed. Each event has its own key, "{conversation}:{seq}", so an
+// append writes one event instead of the whole log.
+pub struct SyncLog {
+    heads_db: RecordDb<LogHead>,
+    events_db: RecordDb<SequencedEvent>,
+}
+
+fn event_key(conversation: &Conversation, seq: u64) -> String {
+    format!("{}:{}", reads::key(conversation), seq)
+}
+
+impl SyncLog {
+    pub fn new(package_id: PackageId) -> anyhow::Result<Self> {
+        let heads_db = RecordDb::open(package_id.clone(), HEADS_DB)?;
+        let events_db = RecordDb::open(package_id, EVENTS_DB)?;
+        Ok(Self { heads_db, events_db })
+    }
+
+    fn head(&self, conversation: &Conversation) -> anyhow::Result<u64> {
+        Ok(self
+            .heads_db
+            .get(&reads::key(conversation))?
+            .map_or(0, |log| log.head))
+    }
+
+    // Sequence numbers of the events still kept
+    fn kept(head: u64) -> std::ops::RangeInclusive<u64> {
+        (head.saturating_sub(MAX_LOG_LEN as u64) + 1)..=head
+    }
+
+    // Record an event, returning its sequence number
+    pub fn append(&self, conversation: &Conversation, event: &WsEvent) -> anyhow::Result<u64> {
+        let head = self.head(conversation)? + 1;
+        self.events_db.set(
+            &event_key(conversation, head),
+            &SequencedEvent {
+                event: event.clone(),
+                seq: Some(head),
+            },
+        )?;
+        self.heads_db.set(&reads::key(conversation), &LogHead { head })?;
+        if head > MAX_LOG_LEN as u64 {
+            self.events_db
+                .delete(&event_key(conversation, head - MAX_LOG_LEN as u64))?;
+        }
+        Ok(head)
+    }
+
+    // Drop the kept events that carry a message's content, once it is deleted,
+    // so replays don't bring it back. Its place in the sequence stays.
+    pub fn redact(&self, conversation: &Conversation, message_id: &str) -> anyhow::Result<()> {
+        for seq in Self::kept(self.head(conversation)?) {
+            let key = event_key(conversation, seq);
+            let Some(logged) = self.events_db.get(&key)? else {
+                continue;
+            };
+            let carries_message = match logged.event {
+                WsEvent::NewMessage(ref message) => message.id == message_id,
+                WsEvent::NewGroupMessage(ref message) => message.id == message_id,
+                WsEvent::MessageEdited { message_id: ref id, .. } => id == message_id,
+                _ => false,
+            };
+            if carries_message {
+                self.events_db.delete(&key)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Events after `since`. None as the cursor means the client has never
+    // seen the conversation and gets no replay, only the head.
+    pub fn since(
+        &self,
+        conversation: &Conversation,
+        since: Option<u64>,
+    ) -> anyhow::Result<(SyncStatus, Vec<SequencedEvent>)> {
+        let head = self.head(conversation)?;
+        let mut status = SyncStatus {
+            conversation: conversation.clone(),
+            head,
+            reset: false,
+        };
+        let Some(since) = since else {
+            return Ok((status, Vec::new()));
+        };
+
+        let kept = Self::kept(head);
+        // A cursor ahead of us is from an older store; one before the oldest
+        // kept event has missed events we no longer have
+        if since > head || since + 1 < *kept.start() {
+            status.reset = true;
+            return Ok((status, Vec::new()));
+        }
+        // Redacted events leave gaps, which are skipped
+        let mut events = Vec::new();
+        for seq in since + 1..=head {
+            events.extend(self.events_db.get(&event_key(conversation, seq))?);
+        }
+        Ok((status, events))
+    }
+}
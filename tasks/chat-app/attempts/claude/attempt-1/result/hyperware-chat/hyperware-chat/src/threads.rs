@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::StoredMessage;
+
+// Reply counts for one thread, so the UI can list threads without loading them
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ThreadSummary {
+    pub root_id: String,
+    pub reply_count: usize,
+    pub last_reply_at: u64,
+    pub participants: Vec<String>,
+}
+
+// Message id -> id of the message it replies to
+fn parent_links<M: StoredMessage>(messages: &[M]) -> HashMap<&str, Option<&str>> {
+    messages
+        .iter()
+        .map(|message| (message.id(), message.reply_to()))
+        .collect()
+}
+
+// Follow reply links up to the message that started the thread. Stops at the
+// last message we have, e.g. if the parent was deleted for us only.
+fn thread_root<'a>(parents: &HashMap<&'a str, Option<&'a str>>, id: &'a str) -> &'a str {
+    let mut current = id;
+    // Bounded so a malformed reply cycle can't loop forever
+    for _ in 0..parents.len() {
+        match parents.get(current).copied().flatten() {
+            Some(parent) if parents.contains_key(parent) => current = parent,
+            _ => break,
+        }
+    }
+    current
+}
+
+// One summary per thread that has replies, most recently active first
+pub fn summarize<M: StoredMessage>(messages: &[M]) -> Vec<ThreadSummary> {
+    let parents = parent_links(messages);
+    let mut summaries: HashMap<&str, ThreadSummary> = HashMap::new();
+
+    for message in messages.iter().filter(|message| message.reply_to().is_some()) {
+        let root = thread_root(&parents, message.id());
+        if root == message.id() {
+            continue;
+        }
+        let summary = summaries.entry(root).or_insert_with(|| ThreadSummary {
+            root_id: root.to_string(),
+            reply_count: 0,
+            last_reply_at: 0,
+            participants: Vec::new(),
+        });
+        summary.reply_count += 1;
+        summary.last_reply_at = summary.last_reply_at.max(message.timestamp());
+        if !summary.participants.iter().any(|node| node == message.author()) {
+            summary.participants.push(message.author().to_string());
+        }
+    }
+
+    let mut summaries: Vec<ThreadSummary> = summaries.into_values().collect();
+    summaries.sort_by(|a, b| b.last_reply_at.cmp(&a.last_reply_at));
+    summaries
+}
+
+// The thread containing `message_id`: its root followed by every reply, in the
+// order they were stored
+pub fn thread<M: StoredMessage + Clone>(messages: &[M], message_id: &str) -> Option<Vec<M>> {
+    let parents = parent_links(messages);
+    if !parents.contains_key(message_id) {
+        return None;
+    }
+    let root_id = thread_root(&parents, message_id);
+
+    let root = messages.iter().find(|message| message.id() == root_id)?;
+    let mut thread = vec![root.clone()];
+    thread.extend(
+        messages
+            .iter()
+            .filter(|message| {
+                message.id() != root_id && thread_root(&parents, message.id()) == root_id
+            })
+            .cloned(),
+    );
+    Some(thread)
+}
@@ -0,0 +1,279 @@
+use std::collections::{HashMap, HashSet};
+
+use hyperware_process_lib::http::server::{send_ws_push, HttpServer, WsMessageType};
+use hyperware_process_lib::logging::error;
+use hyperware_process_lib::LazyLoadBlob;
+use serde::{Deserialize, Serialize};
+
+use crate::hyperware::process::hyperware_chat::ConversationRef;
+use crate::sync::{SequencedEvent, SyncLog, SyncStatus};
+use crate::{Group, GroupMessage, NewMessage};
+
+// A conversation a UI client can subscribe to
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Conversation {
+    Direct(String),
+    Group(String),
+}
+
+impl From<ConversationRef> for Conversation {
+    fn from(conversation: ConversationRef) -> Self {
+        match conversation {
+            ConversationRef::Direct(node) => Conversation::Direct(node),
+            ConversationRef::Group(group_id) => Conversation::Group(group_id),
+        }
+    }
+}
+
+impl From<Conversation> for ConversationRef {
+    fn from(conversation: Conversation) -> Self {
+        match conversation {
+            Conversation::Direct(node) => ConversationRef::Direct(node),
+            Conversation::Group(group_id) => ConversationRef::Group(group_id),
+        }
+    }
+}
+
+// Events pushed to UI clients over the WebSocket
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum WsEvent {
+    NewMessage(NewMessage),
+    // First message from a node we don't know; waits for approval
+    MessageRequest(NewMessage),
+    NewGroupMessage(GroupMessage),
+    NewGroup(Group),
+    // A group's members changed on another node
+    GroupUpdated(Group),
+    // We left and forgot the group, e.g. from another tab
+    GroupDeleted {
+        group_id: String,
+    },
+    ContactAdded {
+        id: String,
+        name: String,
+    },
+    ContactRemoved {
+        id: String,
+    },
+    Typing {
+        conversation: Conversation,
+        node: String,
+        is_typing: bool,
+    },
+    Presence {
+        node: String,
+        online: bool,
+        // Seconds since the epoch when we last heard from the node
+        last_seen: Option<u64>,
+    },
+    DeliveryReceipt {
+        conversation: Conversation,
+        message_id: String,
+    },
+    // `node` has read the conversation up to `message_id`; our own node when
+    // another tab marked it read
+    ReadReceipt {
+        conversation: Conversation,
+        node: String,
+        message_id: String,
+    },
+    MessageEdited {
+        conversation: Conversation,
+        message_id: String,
+        content: String,
+        edited_at: u64,
+    },
+    MessageDeleted {
+        conversation: Conversation,
+        message_id: String,
+        for_everyone: bool,
+    },
+    ReactionChanged {
+        conversation: Conversation,
+        message_id: String,
+        emoji: String,
+        node: String,
+        add: bool,
+    },
+}
+
+impl WsEvent {
+    // The conversation this event belongs to, or None if every client should get it
+    fn conversation(&self) -> Option<Conversation> {
+        match self {
+            WsEvent::NewMessage(message) => {
+                Some(Conversation::Direct(message.hyperware_chat.clone()))
+            }
+            WsEvent::NewGroupMessage(message) => {
+                Some(Conversation::Group(message.group_id.clone()))
+            }
+            WsEvent::Typing { conversation, .. }
+            | WsEvent::DeliveryReceipt { conversation, .. }
+            | WsEvent::ReadReceipt { conversation, .. }
+            | WsEvent::MessageEdited { conversation, .. }
+            | WsEvent::MessageDeleted { conversation, .. }
+            | WsEvent::ReactionChanged { conversation, .. } => Some(conversation.clone()),
+            WsEvent::MessageRequest(_)
+            | WsEvent::NewGroup(_)
+            | WsEvent::GroupUpdated(_)
+            | WsEvent::GroupDeleted { .. }
+            | WsEvent::ContactAdded { .. }
+            | WsEvent::ContactRemoved { .. }
+            | WsEvent::Presence { .. } => None,
+        }
+    }
+
+    // Whether a client that missed the event should get it on sync
+    fn is_replayable(&self) -> bool {
+        !matches!(self, WsEvent::Typing { .. })
+    }
+}
+
+// Tracks which conversations each connected WebSocket channel is viewing
+pub struct WsClients {
+    // Channels that never sent a Subscribe are not in here and get every event,
+    // so older UIs keep working
+    subscriptions: HashMap<u32, HashSet<Conversation>>,
+    // Last presence pushed for each node, so only changes are pushed
+    presence: HashMap<String, bool>,
+    // Numbered conversation events, replayed to clients that reconnect
+    log: SyncLog,
+}
+
+impl WsClients {
+    pub fn new(log: SyncLog) -> Self {
+        Self {
+            subscriptions: HashMap::new(),
+            presence: HashMap::new(),
+            log,
+        }
+    }
+
+    pub fn subscribe(&mut self, channel_id: u32, conversations: Vec<Conversation>) {
+        self.subscriptions
+            .entry(channel_id)
+            .or_default()
+            .extend(conversations);
+    }
+
+    pub fn unsubscribe(&mut self, channel_id: u32, conversations: Vec<Conversation>) {
+        let subscribed = self.subscriptions.entry(channel_id).or_default();
+        for conversation in conversations {
+            subscribed.remove(&conversation);
+        }
+    }
+
+    pub fn remove_channel(&mut self, channel_id: u32) {
+        self.subscriptions.remove(&channel_id);
+    }
+
+    fn wants(&self, channel_id: u32, conversation: &Option<Conversation>) -> bool {
+        match (conversation, self.subscriptions.get(&channel_id)) {
+            (Some(conversation), Some(subscribed)) => subscribed.contains(conversation),
+            _ => true,
+        }
+    }
+
+    // Push an event to every channel subscribed to its conversation
+    pub fn push(&self, server: &HttpServer, event: &WsEvent) {
+        let conversation = event.conversation();
+        if let WsEvent::MessageDeleted {
+            conversation,
+            message_id,
+            ..
+        } = event
+        {
+            if let Err(e) = self.log.redact(conversation, message_id) {
+                error!("Failed to redact {} from the log of {:?}: {}", message_id, conversation, e);
+            }
+        }
+        // Logged even with no one connected, so clients can catch up later
+        let seq = match conversation {
+            Some(ref conversation) if event.is_replayable() => {
+                match self.log.append(conversation, event) {
+                    Ok(seq) => Some(seq),
+                    Err(e) => {
+                        error!("Failed to log event for {:?}: {}", conversation, e);
+                        None
+                    }
+                }
+            }
+            _ => None,
+        };
+        let channels: HashSet<u32> = server
+            .get_ws_channels()
+            .into_values()
+            .flatten()
+            .filter(|channel_id| self.wants(*channel_id, &conversation))
+            .collect();
+        if channels.is_empty() {
+            return;
+        }
+
+        let bytes = match serde_json::to_vec(&SequencedEvent {
+            event: event.clone(),
+            seq,
+        }) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                error!("Failed to serialize event for {:?}: {}", conversation, e);
+                return;
+            }
+        };
+        for channel_id in channels {
+            send_ws_push(channel_id, WsMessageType::Text, json_blob(bytes.clone()));
+        }
+    }
+
+    // Record a node's presence, pushing an event only when it changes
+    pub fn set_presence(&mut self, server: &HttpServer, node: &str, online: bool, last_seen: Option<u64>) {
+        if self.presence.insert(node.to_string(), online) == Some(online) {
+            return;
+        }
+        self.push(
+            server,
+            &WsEvent::Presence {
+                node: node.to_string(),
+                online,
+                last_seen,
+            },
+        );
+    }
+
+    // Send one channel the events it missed in a conversation since `since`
+    pub fn replay(
+        &self,
+        channel_id: u32,
+        conversation: &Conversation,
+        since: Option<u64>,
+    ) -> anyhow::Result<SyncStatus> {
+        let (status, events) = self.log.since(conversation, since)?;
+        for event in events {
+            match serde_json::to_vec(&event) {
+                Ok(bytes) => send_ws_push(channel_id, WsMessageType::Text, json_blob(bytes)),
+                Err(e) => error!("Failed to serialize event {:?} of {:?}: {}", event.seq, conversation, e),
+            }
+        }
+        Ok(status)
+    }
+
+    // None if we haven't heard from or failed to reach the node since starting
+    pub fn is_online(&self, node: &str) -> Option<bool> {
+        self.presence.get(node).copied()
+    }
+}
+
+// Reply to a single channel, e.g. with the result of a query it sent
+pub fn reply(channel_id: u32, body: &serde_json::Value) {
+    match serde_json::to_vec(body) {
+        Ok(bytes) => send_ws_push(channel_id, WsMessageType::Text, json_blob(bytes)),
+        Err(e) => error!("Failed to serialize reply to channel {}: {}", channel_id, e),
+    }
+}
+
+fn json_blob(bytes: Vec<u8>) -> LazyLoadBlob {
+    LazyLoadBlob {
+        mime: Some("application/json".to_string()),
+        bytes,
+    }
+}
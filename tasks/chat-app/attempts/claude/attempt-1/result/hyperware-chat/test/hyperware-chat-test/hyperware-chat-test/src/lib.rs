@@ -1,7 +1,16 @@
-use crate::hyperware::process::hyperware_chat::{HyperwareChatMessage, Request as HyperwareChatRequest, Response as HyperwareChatResponse, SendRequest};
+use std::collections::{HashMap, HashSet};
+
+use crate::hyperware::process::hyperware_chat::{
+    AttachmentChunk, ConversationRef, DeleteRequest, EditRequest, EncryptedContent, GroupDigest, HyperwareChatMessage,
+    PublicKeys,
+    ReadReceipt, Request as HyperwareChatRequest, Response as HyperwareChatResponse, SearchRequest, SendRequest,
+    SenderKey, ThreadRequest,
+};
 use crate::hyperware::process::tester::{Request as TesterRequest, Response as TesterResponse, RunRequest, FailResponse};
 
-use hyperware_process_lib::{await_message, call_init, print_to_terminal, println, Address, ProcessId, Request, Response};
+use hyperware_process_lib::http::client::send_request_await_response;
+use hyperware_process_lib::http::Method;
+use hyperware_process_lib::{await_message, call_init, print_to_terminal, println, Address, Message, ProcessId, Request, Response};
 
 mod tester_lib;
 
@@ -12,6 +21,411 @@ wit_bindgen::generate!({
     additional_derives: [PartialEq, serde::Deserialize, serde::Serialize, process_macros::SerdeJsonInto],
 });
 
+// Port the master node serves HTTP on; see tests.toml
+const MASTER_HTTP_PORT: u16 = 8080;
+const PROCESS_PATH: &str = "hyperware-chat:hyperware-chat:template.os";
+
+fn chat_address(node: &str) -> Address {
+    Address {
+        node: node.to_string(),
+        process: ProcessId::new(Some("hyperware-chat"), "hyperware-chat", "template.os"),
+    }
+}
+
+// Ask hyperware-chat on `node` as a local process there: our own directly,
+// another node's through this test process on that node
+fn local_request(our: &Address, node: &str, request: HyperwareChatRequest) -> anyhow::Result<HyperwareChatResponse> {
+    let target = if node == our.node {
+        chat_address(node)
+    } else {
+        Address::new(node, our.process.clone())
+    };
+    let response = Request::new()
+        .target(target)
+        .body(request)
+        .send_and_await_response(15)??;
+    Ok(response.body().try_into()?)
+}
+
+// Ask hyperware-chat on another node as this test process, i.e. not as the
+// chat process on our node
+fn remote_request(node: &str, request: HyperwareChatRequest) -> anyhow::Result<HyperwareChatResponse> {
+    let response = Request::new()
+        .target(chat_address(node))
+        .body(request)
+        .send_and_await_response(15)??;
+    Ok(response.body().try_into()?)
+}
+
+// The master node's test drives hyperware-chat on this node through us
+fn proxy_request(our: &Address, message: &Message) -> anyhow::Result<()> {
+    let response = Request::new()
+        .target(chat_address(&our.node))
+        .body(message.body().to_vec())
+        .send_and_await_response(15)??;
+    Response::new()
+        .body(response.body().to_vec())
+        .send()?;
+    Ok(())
+}
+
+fn send(our: &Address, from: &str, to: &str, message: &str, reply_to: Option<String>) -> anyhow::Result<HyperwareChatResponse> {
+    local_request(our, from, HyperwareChatRequest::Send(SendRequest {
+        target: to.to_string(),
+        message: message.to_string(),
+        author: None,
+        id: None,
+        reply_to,
+        attachments: None,
+        encrypted: None,
+    }))
+}
+
+fn history(our: &Address, node: &str, with: &str) -> anyhow::Result<Vec<HyperwareChatMessage>> {
+    match local_request(our, node, HyperwareChatRequest::History(with.to_string()))? {
+        HyperwareChatResponse::History(messages) => Ok(messages),
+        response => Err(anyhow::anyhow!("{} answered history with {:?}", node, response)),
+    }
+}
+
+// Who said what, in order
+fn said(messages: &[HyperwareChatMessage]) -> Vec<(String, String)> {
+    messages
+        .iter()
+        .map(|HyperwareChatMessage { author, content, .. }| (author.clone(), content.clone()))
+        .collect()
+}
+
+fn expect_err(what: &str, response: HyperwareChatResponse) -> anyhow::Result<()> {
+    match response {
+        HyperwareChatResponse::Err(_) => Ok(()),
+        response => Err(anyhow::anyhow!("{} was accepted: {:?}", what, response)),
+    }
+}
+
+// GET a path of hyperware-chat from the master node's own HTTP server,
+// without logging in
+fn http_get(path: &str, headers: HashMap<String, String>) -> anyhow::Result<u16> {
+    let url = format!("http://localhost:{}/{}{}", MASTER_HTTP_PORT, PROCESS_PATH, path);
+    let response = send_request_await_response(Method::GET, url.parse()?, Some(headers), 10, vec![])?;
+    Ok(response.status().as_u16())
+}
+
+// Both nodes write, so each knows the other; the master's history then
+// holds the whole conversation
+fn start_conversation(our: &Address, node0: &str, node1: &str) -> anyhow::Result<()> {
+    print_to_terminal(0, "hyperware_chat_test: conversation");
+    for (from, to, message) in [(node0, node1, "hello"), (node1, node0, "hi back"), (node0, node1, "hello again")] {
+        let HyperwareChatResponse::Send = send(our, from, to, message, None)? else {
+            return Err(anyhow::anyhow!("{} did not take {:?}", to, message));
+        };
+    }
+
+    let ours = said(&history(our, node0, node1)?);
+    let expected = vec![
+        (node0.to_string(), "hello".to_string()),
+        (node1.to_string(), "hi back".to_string()),
+        (node0.to_string(), "hello again".to_string()),
+    ];
+    if ours != expected {
+        println!("{ours:?} != {expected:?}");
+        return Err(anyhow::anyhow!("{} history is wrong", node0));
+    }
+    Ok(())
+}
+
+// node0 was unknown to node1 when it said "hello", so that waits for
+// approval; once node1 wrote back, node0 is a contact and gets through
+fn test_message_requests(our: &Address, node0: &str, node1: &str) -> anyhow::Result<()> {
+    print_to_terminal(0, "hyperware_chat_test: message requests");
+    let theirs = said(&history(our, node1, node0)?);
+    if theirs.iter().any(|(_, content)| content == "hello")
+        || !theirs.iter().any(|(_, content)| content == "hello again")
+    {
+        return Err(anyhow::anyhow!("{} did not quarantine first contact: {:?}", node1, theirs));
+    }
+    Ok(())
+}
+
+// A node asking too often is turned away. Run last: node0 stays limited on
+// node1 until its bucket refills.
+fn test_rate_limit(node0: &str, node1: &str) -> anyhow::Result<()> {
+    print_to_terminal(0, "hyperware_chat_test: rate limit");
+    for _ in 0..100 {
+        if let HyperwareChatResponse::Err(reason) = remote_request(node1, HyperwareChatRequest::History(node0.to_string()))? {
+            if reason == "rate limited" {
+                return Ok(());
+            }
+            return Err(anyhow::anyhow!("{} refused history: {}", node1, reason));
+        }
+    }
+    Err(anyhow::anyhow!("{} never rate limited {}", node1, node0))
+}
+
+// Every message has its own id, prefixed by its author, and both sides of a
+// conversation know it by the same one
+fn test_message_ids(our: &Address, node0: &str, node1: &str) -> anyhow::Result<()> {
+    print_to_terminal(0, "hyperware_chat_test: message ids");
+    let ours = history(our, node0, node1)?;
+    let theirs = history(our, node1, node0)?;
+    let mut seen = HashSet::new();
+    for message in &ours {
+        if !message.id.starts_with(&format!("{}-", message.author)) || !seen.insert(message.id.clone()) {
+            return Err(anyhow::anyhow!("bad or repeated id {:?}", message.id));
+        }
+    }
+    if theirs.is_empty() {
+        return Err(anyhow::anyhow!("{} has no history with {}", node1, node0));
+    }
+    for message in &theirs {
+        if !seen.contains(&message.id) {
+            return Err(anyhow::anyhow!("{} knows {:?} by another id", node1, message.content));
+        }
+    }
+    Ok(())
+}
+
+// Only hyperware-chat on another node may change our copy of its messages;
+// any other process there is refused
+fn test_peer_updates(our: &Address, node0: &str, node1: &str) -> anyhow::Result<()> {
+    print_to_terminal(0, "hyperware_chat_test: peer updates");
+    let message_id = history(our, node1, node0)?
+        .into_iter()
+        .find(|message| message.author == node0)
+        .map(|message| message.id)
+        .ok_or_else(|| anyhow::anyhow!("{} lost a message", node1))?;
+    let requests = [
+        HyperwareChatRequest::Edit(EditRequest {
+            group_id: None,
+            message_id: message_id.clone(),
+            content: "edited by the test".to_string(),
+            encrypted: None,
+        }),
+        HyperwareChatRequest::Delete(DeleteRequest {
+            group_id: None,
+            message_id,
+        }),
+    ];
+    for request in requests {
+        let what = format!("{:?} from the test process", request);
+        expect_err(&what, remote_request(node1, request)?)?;
+    }
+
+    // Nothing above changed the conversation
+    let theirs = said(&history(our, node1, node0)?);
+    if !theirs.iter().any(|(_, content)| content == "hello again")
+        || theirs.iter().any(|(_, content)| content.contains("test"))
+    {
+        return Err(anyhow::anyhow!("{} history was changed: {:?}", node1, theirs));
+    }
+    Ok(())
+}
+
+// A claimed author must be the sending node
+fn test_spoofed_author(our: &Address, node0: &str, node1: &str) -> anyhow::Result<()> {
+    print_to_terminal(0, "hyperware_chat_test: spoofed author");
+    let response = local_request(our, node0, HyperwareChatRequest::Send(SendRequest {
+        target: node1.to_string(),
+        message: "spoofed".into(),
+        author: Some(node1.to_string()),
+        id: None,
+        reply_to: None,
+        attachments: None,
+        encrypted: None,
+    }))?;
+    expect_err("a spoofed author", response)
+}
+
+// A reply travels with its parent, and both nodes can fetch the thread
+fn test_threads(our: &Address, node0: &str, node1: &str) -> anyhow::Result<()> {
+    print_to_terminal(0, "hyperware_chat_test: threads");
+    let parent = history(our, node0, node1)?
+        .into_iter()
+        .find(|message| message.content == "hi back")
+        .ok_or_else(|| anyhow::anyhow!("{} lost a message", node0))?;
+    let HyperwareChatResponse::Send = send(our, node0, node1, "replying", Some(parent.id.clone()))? else {
+        return Err(anyhow::anyhow!("{} did not take our reply", node1));
+    };
+    expect_err("a reply to an unknown message", send(our, node0, node1, "lost", Some(format!("{}-1", node1)))?)?;
+
+    for (node, with) in [(node0, node1), (node1, node0)] {
+        let response = local_request(our, node, HyperwareChatRequest::Thread(ThreadRequest {
+            conversation: ConversationRef::Direct(with.to_string()),
+            message_id: parent.id.clone(),
+        }))?;
+        let HyperwareChatResponse::Thread(thread) = response else {
+            return Err(anyhow::anyhow!("{} answered thread with {:?}", node, response));
+        };
+        let contents: Vec<&str> = thread.iter().map(|message| message.content.as_str()).collect();
+        if contents != ["hi back", "replying"]
+            || thread[1].reply_to.as_deref() != Some(parent.id.as_str())
+        {
+            return Err(anyhow::anyhow!("{} has thread {:?}", node, contents));
+        }
+    }
+
+    // Another node only reads threads of its own conversation with us
+    expect_err("a thread of someone else's conversation", remote_request(node1, HyperwareChatRequest::Thread(ThreadRequest {
+        conversation: ConversationRef::Direct(node1.to_string()),
+        message_id: parent.id,
+    }))?)
+}
+
+fn search(our: &Address, node: &str, query: &str, author: Option<&str>) -> anyhow::Result<Vec<String>> {
+    let response = local_request(our, node, HyperwareChatRequest::Search(SearchRequest {
+        query: query.to_string(),
+        author: author.map(|author| author.to_string()),
+        conversation: None,
+        since: None,
+        until: None,
+        limit: None,
+    }))?;
+    let HyperwareChatResponse::Search(hits) = response else {
+        return Err(anyhow::anyhow!("{} answered search with {:?}", node, response));
+    };
+    Ok(hits.into_iter().map(|hit| hit.message.content).collect())
+}
+
+// Every word must match, and the author filter applies on top
+fn test_search(our: &Address, node0: &str, node1: &str) -> anyhow::Result<()> {
+    print_to_terminal(0, "hyperware_chat_test: search");
+    for (query, author, expected) in [
+        ("again", None, vec!["hello again"]),
+        ("hello again", None, vec!["hello again"]),
+        ("hello", Some(node1), vec![]),
+        ("back", Some(node1), vec!["hi back"]),
+        ("nothing", None, vec![]),
+    ] {
+        let found = search(our, node0, query, author)?;
+        if found != expected {
+            return Err(anyhow::anyhow!("search for {:?} by {:?} found {:?}", query, author, found));
+        }
+    }
+
+    // Another node can't search our history
+    expect_err("a search from another node", remote_request(node1, HyperwareChatRequest::Search(SearchRequest {
+        query: "hello".to_string(),
+        author: None,
+        conversation: None,
+        since: None,
+        until: None,
+        limit: None,
+    }))?)
+}
+
+// Only hyperware-chat on another node may say how far it has read
+fn test_read_receipts(our: &Address, node0: &str, node1: &str) -> anyhow::Result<()> {
+    print_to_terminal(0, "hyperware_chat_test: read receipts");
+    let message_id = history(our, node1, node0)?
+        .last()
+        .map(|message| message.id.clone())
+        .ok_or_else(|| anyhow::anyhow!("{} has no history with {}", node1, node0))?;
+    expect_err("a read receipt from the test process", remote_request(node1, HyperwareChatRequest::ReadReceipt(ReadReceipt {
+        group_id: None,
+        message_id,
+    }))?)
+}
+
+// Heartbeats are answered, but only hyperware-chat on another node may send
+// one, since it marks the sender online
+fn test_heartbeats(our: &Address, node0: &str, node1: &str) -> anyhow::Result<()> {
+    print_to_terminal(0, "hyperware_chat_test: heartbeats");
+    let HyperwareChatResponse::Heartbeat = local_request(our, node0, HyperwareChatRequest::Heartbeat)? else {
+        return Err(anyhow::anyhow!("{} did not answer a heartbeat", node0));
+    };
+    expect_err("a heartbeat from the test process", remote_request(node1, HyperwareChatRequest::Heartbeat)?)
+}
+
+// A group nobody has is synced as empty, and only members' chat processes
+// may sync at all
+fn test_group_sync(our: &Address, node0: &str, node1: &str) -> anyhow::Result<()> {
+    print_to_terminal(0, "hyperware_chat_test: group sync");
+    let digest = GroupDigest {
+        group_id: format!("{}-unknown-group", node0),
+        clock: Vec::new(),
+    };
+    let HyperwareChatResponse::GroupSync(theirs) = local_request(our, node0, HyperwareChatRequest::GroupSync(digest.clone()))? else {
+        return Err(anyhow::anyhow!("{} did not answer a group sync", node0));
+    };
+    if theirs.group_id != digest.group_id || !theirs.clock.is_empty() {
+        return Err(anyhow::anyhow!("{} claims events in a group it doesn't have", node0));
+    }
+    expect_err("a group sync from the test process", remote_request(node1, HyperwareChatRequest::GroupSync(digest))?)
+}
+
+// Keys are only exchanged between the chat processes of two nodes
+fn test_key_exchange(our: &Address, node0: &str, node1: &str) -> anyhow::Result<()> {
+    print_to_terminal(0, "hyperware_chat_test: key exchange");
+    let keys = PublicKeys {
+        signing_key: vec![0; 32],
+        exchange_key: vec![0; 32],
+    };
+    expect_err("a key exchange with ourselves", local_request(our, node0, HyperwareChatRequest::KeyExchange(keys.clone()))?)?;
+    expect_err("keys from the test process", remote_request(node1, HyperwareChatRequest::KeyExchange(keys))?)?;
+    expect_err("a sender key from the test process", remote_request(node1, HyperwareChatRequest::SenderKey(SenderKey {
+        group_id: format!("{}-test", node0),
+        generation: 0,
+        key: EncryptedContent {
+            nonce: vec![0; 12],
+            ciphertext: vec![0; 48],
+            signature: vec![0; 64],
+            key_generation: None,
+        },
+    }))?)
+}
+
+// Only hyperware-chat on another node may write attachments to our drive
+fn test_attachment_chunks(node0: &str, node1: &str) -> anyhow::Result<()> {
+    print_to_terminal(0, "hyperware_chat_test: attachment chunks");
+    let response = remote_request(node1, HyperwareChatRequest::AttachmentChunk(AttachmentChunk {
+        id: format!("{}-test", node0),
+        name: "test.txt".to_string(),
+        mime: "text/plain".to_string(),
+        size: 0,
+        offset: 0,
+    }))?;
+    expect_err("an attachment chunk from the test process", response)
+}
+
+// The HTTP API, old routes included, only answers the node's owner
+fn test_http_auth() -> anyhow::Result<()> {
+    print_to_terminal(0, "hyperware_chat_test: http auth");
+    for path in ["/messages", "/contacts", "/conversations", "/groups", "/groups?id=group_1", "/settings", "/blocked", "/requests"] {
+        let status = http_get(path, HashMap::new())?;
+        if status != 401 && status != 403 {
+            return Err(anyhow::anyhow!("GET {} without logging in answered {}", path, status));
+        }
+    }
+    Ok(())
+}
+
+// Archives hold the whole history, so downloading one needs a login too
+fn test_archive_auth() -> anyhow::Result<()> {
+    print_to_terminal(0, "hyperware_chat_test: archive auth");
+    let status = http_get("/archives/archive-1.json", HashMap::new())?;
+    if status != 401 && status != 403 {
+        return Err(anyhow::anyhow!("archive download without logging in answered {}", status));
+    }
+    Ok(())
+}
+
+// Conversation events only go to the node's owner. That includes the replay
+// of missed events to a reconnecting client, which only an authenticated
+// socket can ask for, so it can't be driven from here.
+fn test_ws_auth() -> anyhow::Result<()> {
+    print_to_terminal(0, "hyperware_chat_test: ws auth");
+    let headers = HashMap::from([
+        ("Connection".to_string(), "Upgrade".to_string()),
+        ("Upgrade".to_string(), "websocket".to_string()),
+        ("Sec-WebSocket-Version".to_string(), "13".to_string()),
+        ("Sec-WebSocket-Key".to_string(), "dGhlIHNhbXBsZSBub25jZQ==".to_string()),
+    ]);
+    if http_get("", headers)? == 101 {
+        return Err(anyhow::anyhow!("WebSocket opened without logging in"));
+    }
+    Ok(())
+}
+
 fn handle_message (our: &Address) -> anyhow::Result<()> {
     let message = await_message().unwrap();
 
@@ -19,6 +433,9 @@ fn handle_message (our: &Address) -> anyhow::Result<()> {
         unimplemented!();
     }
     let source = message.source();
+    if source.node != our.node && source.process == our.process {
+        return proxy_request(our, &message);
+    }
     if our.node != source.node {
         return Err(anyhow::anyhow!(
             "rejecting foreign Message from {:?}",
@@ -32,7 +449,7 @@ fn handle_message (our: &Address) -> anyhow::Result<()> {
     print_to_terminal(0, "hyperware_chat_test: a");
     assert!(node_names.len() >= 2);
     if our.node != node_names[0] {
-        // we are not master node: return
+        // we are not master node: answer, then serve the master's requests
         Response::new()
             .body(TesterResponse::Run(Ok(())))
             .send()
@@ -41,46 +458,25 @@ fn handle_message (our: &Address) -> anyhow::Result<()> {
     }
 
     // we are master node
+    let node0 = node_names[0].as_str();
+    let node1 = node_names[1].as_str();
 
-    let our_hyperware_chat_address = Address {
-        node: our.node.clone(),
-        process: ProcessId::new(Some("hyperware-chat"), "hyperware-chat", "template.os"),
-    };
-    let their_hyperware_chat_address = Address {
-        node: node_names[1].clone(),
-        process: ProcessId::new(Some("hyperware-chat"), "hyperware-chat", "template.os"),
-    };
-
-    // Send
-    print_to_terminal(0, "hyperware_chat_test: b");
-    let message: String = "hello".into();
-    let _ = Request::new()
-        .target(our_hyperware_chat_address.clone())
-        .body(HyperwareChatRequest::Send(SendRequest {
-            target: node_names[1].clone(),
-            message: message.clone(),
-        }))
-        .send_and_await_response(15)?.unwrap();
-
-    // Get history from receiver & test
-    print_to_terminal(0, "hyperware_chat_test: c");
-    let response = Request::new()
-        .target(their_hyperware_chat_address.clone())
-        .body(HyperwareChatRequest::History(our.node.clone()))
-        .send_and_await_response(15)?.unwrap();
-    if response.is_request() { fail!("hyperware_chat_test"); };
-    let HyperwareChatResponse::History(messages) = response.body().try_into()? else {
-        fail!("hyperware_chat_test");
-    };
-    let expected_messages = vec![HyperwareChatMessage {
-        author: our.node.clone(),
-        content: message,
-    }];
-
-    if messages != expected_messages {
-        println!("{messages:?} != {expected_messages:?}");
-        fail!("hyperware_chat_test");
-    }
+    start_conversation(our, node0, node1)?;
+    test_message_requests(our, node0, node1)?;
+    test_message_ids(our, node0, node1)?;
+    test_peer_updates(our, node0, node1)?;
+    test_spoofed_author(our, node0, node1)?;
+    test_threads(our, node0, node1)?;
+    test_attachment_chunks(node0, node1)?;
+    test_search(our, node0, node1)?;
+    test_read_receipts(our, node0, node1)?;
+    test_heartbeats(our, node0, node1)?;
+    test_group_sync(our, node0, node1)?;
+    test_key_exchange(our, node0, node1)?;
+    test_ws_auth()?;
+    test_http_auth()?;
+    test_archive_auth()?;
+    test_rate_limit(node0, node1)?;
 
     Response::new()
         .body(TesterResponse::Run(Ok(())))
@@ -1,4 +1,4 @@
-import { useState, useEffect, useCallback, FormEvent, ChangeEvent } from "react";
+import { useState, useEffect, useCallback, useRef, FormEvent, ChangeEvent } from "react";
 import HyperwareClientApi from "@hyperware-ai/client-api";
 import "./App.css";
 import { 
@@ -8,9 +8,11 @@ import {
   CreateGroupRequest,
   GroupMemberRequest,
   SendGroupMessageRequest,
-  Contact
+  Contact,
+  ConversationRef,
+  HyperwareChatMessage
 } from "./types/HyperwareChat";
-import useHyperwareChatStore from "./store/hyperware_chat";
+import useHyperwareChatStore, { conversationKey } from "./store/hyperware_chat";
 
 // Use the full process path
 const PROCESS_PATH = "hyperware-chat:hyperware-chat:template.os";
@@ -24,11 +26,47 @@ const WEBSOCKET_URL = import.meta.env.DEV
   ? `${PROXY_TARGET.replace('http', 'ws')}`
   : undefined;
 
+// Typing notices are resent at most this often while the user keeps typing
+const TYPING_RESEND_MS = 3000;
+
 function formatDate(timestamp: number | undefined): string {
   if (!timestamp) return '';
   return new Date(timestamp * 1000).toLocaleTimeString([], { hour: '2-digit', minute: '2-digit' });
 }
 
+// Build WebSocket message dynamically
+function sendWs(api: HyperwareClientApi, request: object) {
+  const wsMessage: any = {};
+  wsMessage.data = JSON.stringify(request);
+  api.send(wsMessage);
+}
+
+function selectedConversation(chatId: string, groupId: string): ConversationRef | undefined {
+  if (chatId && chatId !== "New Chat") return { Direct: chatId };
+  if (groupId && !groupId.startsWith('temp_')) return { Group: groupId };
+  return undefined;
+}
+
+// Reactions and how the rest of the conversation saw our messages
+function messageDetails(message: HyperwareChatMessage, isRead: boolean) {
+  const reactions = Object.entries(message.reactions || {}).filter(([, nodes]) => nodes.length > 0);
+  const ours = message.author === window.our?.node;
+  return (
+    <>
+      {reactions.length > 0 && (
+        <div className="message-reactions">
+          {reactions.map(([emoji, nodes]) => (
+            <span key={emoji} title={nodes.join(', ')}>{emoji} {nodes.length}</span>
+          ))}
+        </div>
+      )}
+      {ours && (isRead || message.delivered) && (
+        <div className="message-status">{isRead ? '✓✓' : '✓'}</div>
+      )}
+    </>
+  );
+}
+
 function App() {
   const { 
     hyperware_chats, 
@@ -44,7 +82,12 @@ function App() {
     selectedChatId,
     selectedGroupId,
     setSelectedChatId,
-    setSelectedGroupId
+    setSelectedGroupId,
+    updateMessage,
+    typing,
+    setTyping,
+    presence,
+    setPresence
   } = useHyperwareChatStore();
 
   // Form states
@@ -65,6 +108,9 @@ function App() {
   const [showContactForm, setShowContactForm] = useState(false);
   const [newContactId, setNewContactId] = useState("");
 
+  // When we last told the conversation on screen that we are typing
+  const typingSentAt = useRef(0);
+
   useEffect(() => {
     // Connect to the WebSocket first
     console.log('WEBSOCKET URL', WEBSOCKET_URL);
@@ -132,6 +178,62 @@ function App() {
                 // Set selected group to the new one
                 setSelectedGroupId(newGroup.id);
                 break;
+              case "GroupUpdated":
+                set({ groups: groups.map(g => g.id === data.GroupUpdated.id ? data.GroupUpdated : g) });
+                break;
+              case "GroupDeleted": {
+                const { group_id } = data.GroupDeleted;
+                const { [group_id]: _deleted, ...remainingMessages } = groupMessages;
+                set({ groups: groups.filter(g => g.id !== group_id), groupMessages: remainingMessages });
+                // Read from the store so this handler doesn't reconnect on every selection
+                if (useHyperwareChatStore.getState().selectedGroupId === group_id) {
+                  setSelectedGroupId("");
+                }
+                break;
+              }
+              case "Typing": {
+                const { conversation, node, is_typing } = data.Typing;
+                setTyping(conversation, node, is_typing);
+                break;
+              }
+              case "Presence":
+                setPresence(data.Presence.node, data.Presence.online);
+                break;
+              case "DeliveryReceipt": {
+                const { conversation, message_id } = data.DeliveryReceipt;
+                updateMessage(conversation, message_id, m => ({ ...m, delivered: true }));
+                break;
+              }
+              case "ReadReceipt": {
+                // Receipts from our own node come from another tab marking it read
+                const { conversation, node, message_id } = data.ReadReceipt;
+                if (node !== window.our?.node) {
+                  updateMessage(conversation, message_id, m => ({ ...m, read: true }));
+                }
+                break;
+              }
+              case "MessageEdited": {
+                const { conversation, message_id, content, edited_at } = data.MessageEdited;
+                updateMessage(conversation, message_id, m => ({
+                  ...m,
+                  content,
+                  edits: [...(m.edits || []), { content: m.content, edited_at }],
+                }));
+                break;
+              }
+              case "MessageDeleted": {
+                const { conversation, message_id, for_everyone } = data.MessageDeleted;
+                updateMessage(conversation, message_id, m => for_everyone ? { ...m, content: "", deleted: true } : null);
+                break;
+              }
+              case "ReactionChanged": {
+                const { conversation, message_id, emoji, node, add } = data.ReactionChanged;
+                updateMessage(conversation, message_id, m => {
+                  const nodes = (m.reactions?.[emoji] || []).filter(n => n !== node);
+                  return { ...m, reactions: { ...m.reactions, [emoji]: add ? [...nodes, node] : nodes } };
+                });
+                break;
+              }
               case "ContactAdded":
                 console.log("Contact added:", data.ContactAdded);
                 // Check if already exists
@@ -140,6 +242,9 @@ function App() {
                   addContact(data.ContactAdded);
                 }
                 break;
+              case "ContactRemoved":
+                removeContact(data.ContactRemoved.id);
+                break;
               case "Messages":
                 console.log("Received message history:", data.Messages);
                 // Update message history
@@ -179,7 +284,56 @@ function App() {
     } else {
       setNodeConnected(false);
     }
-  }, [groups, contacts, groupMessages, addMessage, addGroupMessage, set, setSelectedGroupId, addContact]);
+  }, [groups, contacts, groupMessages, addMessage, addGroupMessage, set, setSelectedGroupId, addContact, removeContact, updateMessage, setTyping, setPresence]);
+
+  // Only take events for the conversation on screen. Events for the others
+  // aren't pushed while it is open, so reload its messages on switching.
+  useEffect(() => {
+    if (!api) return;
+    const conversation = selectedConversation(selectedChatId, selectedGroupId);
+    if (!conversation) return;
+    sendWs(api, { Subscribe: { conversations: [conversation] } });
+    sendWs(api, 'Direct' in conversation
+      ? { GetMessages: {} }
+      : { GetGroupMessages: { group_id: conversation.Group } });
+    return () => {
+      sendWs(api, { Typing: { conversation, is_typing: false } });
+      sendWs(api, { Unsubscribe: { conversations: [conversation] } });
+      typingSentAt.current = 0;
+    };
+  }, [api, selectedChatId, selectedGroupId]);
+
+  const handleMessageChange = (e: ChangeEvent<HTMLInputElement>) => {
+    setMessage(e.target.value);
+    const conversation = selectedConversation(selectedChatId, selectedGroupId);
+    if (!api || !conversation) return;
+    const isTyping = e.target.value !== "";
+    const now = Date.now();
+    if (isTyping && now - typingSentAt.current < TYPING_RESEND_MS) return;
+    typingSentAt.current = isTyping ? now : 0;
+    sendWs(api, { Typing: { conversation, is_typing: isTyping } });
+  };
+
+  const typingNotice = (conversation: ConversationRef | undefined) => {
+    const typists = conversation ? typing[conversationKey(conversation)] || [] : [];
+    if (typists.length === 0) return null;
+    return <div className="typing-notice">{typists.join(', ')} typing…</div>;
+  };
+
+  const renderMessages = (messages: HyperwareChatMessage[] | undefined, showAuthor: boolean) => {
+    const lastRead = (messages || []).map(m => !!m.read).lastIndexOf(true);
+    return messages?.map((message, index) => (
+      <li key={message.id || index} className={`message ${message.author === window.our?.node ? 'ours' : ''}`}>
+        {showAuthor && <div className="message-author">{message.author}</div>}
+        <div className="message-content">
+          {message.deleted ? <em>This message was deleted</em> : message.content}
+          {!message.deleted && (message.edits?.length || 0) > 0 && <span className="message-edited"> (edited)</span>}
+        </div>
+        <div className="message-time">{formatDate(message.timestamp)}</div>
+        {messageDetails(message, index <= lastRead)}
+      </li>
+    ));
+  };
 
   const startChat = useCallback(
     (event: FormEvent) => {
@@ -204,6 +358,12 @@ function App() {
 
       if (!message || !api) return;
 
+      const conversation = selectedConversation(selectedChatId, selectedGroupId);
+      if (conversation && typingSentAt.current) {
+        sendWs(api, { Typing: { conversation, is_typing: false } });
+        typingSentAt.current = 0;
+      }
+
       try {
         // If a direct chat is selected
         if (selectedChatId && selectedChatId !== "New Chat") {
@@ -365,6 +525,9 @@ function App() {
               .map((chatId) => (
                 <li key={chatId} className={selectedChatId === chatId ? 'active' : ''}>
                   <button onClick={() => setSelectedChatId(chatId)}>
+                    {presence[chatId] !== undefined && (
+                      <span className={`presence ${presence[chatId] ? 'online' : 'offline'}`} />
+                    )}
                     {chatId}
                   </button>
                 </li>
@@ -395,17 +558,12 @@ function App() {
             <div className="chat-container">
               <div className="chat-header">
                 <h3>{selectedChatId}</h3>
+                {typingNotice(selectedConversation(selectedChatId, selectedGroupId))}
               </div>
               
               <div className="messages-container">
                 <ul className="message-list">
-                  {selectedChatId &&
-                    hyperware_chats[selectedChatId]?.map((message, index) => (
-                      <li key={index} className={`message ${message.author === window.our?.node ? 'ours' : ''}`}>
-                        <div className="message-content">{message.content}</div>
-                        <div className="message-time">{formatDate(message.timestamp)}</div>
-                      </li>
-                    ))}
+                  {selectedChatId && renderMessages(hyperware_chats[selectedChatId], false)}
                 </ul>
               </div>
               
@@ -414,7 +572,7 @@ function App() {
                   type="text"
                   placeholder="Type a message..."
                   value={message}
-                  onChange={(e) => setMessage(e.target.value)}
+                  onChange={handleMessageChange}
                 />
                 <button type="submit">Send</button>
               </form>
@@ -501,17 +659,12 @@ function App() {
                 <div className="group-members">
                   {groups.find(g => g.id === selectedGroupId)?.members.length || 0} members
                 </div>
+                {typingNotice(selectedConversation(selectedChatId, selectedGroupId))}
               </div>
               
               <div className="messages-container">
                 <ul className="message-list">
-                  {groupMessages[selectedGroupId]?.map((message, index) => (
-                    <li key={index} className={`message ${message.author === window.our?.node ? 'ours' : ''}`}>
-                      <div className="message-author">{message.author}</div>
-                      <div className="message-content">{message.content}</div>
-                      <div className="message-time">{formatDate(message.timestamp)}</div>
-                    </li>
-                  ))}
+                  {renderMessages(groupMessages[selectedGroupId], true)}
                 </ul>
               </div>
               
@@ -520,7 +673,7 @@ function App() {
                   type="text"
                   placeholder="Type a message..."
                   value={message}
-                  onChange={(e) => setMessage(e.target.value)}
+                  onChange={handleMessageChange}
                 />
                 <button type="submit">Send</button>
               </form>
@@ -599,6 +752,9 @@ function App() {
                           onClick={() => {
                             if (window.confirm(`Remove ${contact.name || contact.id} from contacts?`)) {
                               removeContact(contact.id);
+                              if (api) {
+                                sendWs(api, { RemoveContact: contact.id });
+                              }
                             }
                           }}
                           className="action-button danger"
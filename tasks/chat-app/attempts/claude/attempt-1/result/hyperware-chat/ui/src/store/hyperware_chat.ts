@@ -5,7 +5,9 @@ import {
   HyperwareChats, 
   GroupMessages, 
   Group,
-  Contact
+  Contact,
+  ConversationRef,
+  HyperwareChatMessage
 } from '../types/HyperwareChat'
 import { persist, createJSONStorage } from 'zustand/middleware'
 
@@ -19,6 +21,20 @@ export interface HyperwareChatStore {
   groupMessages: GroupMessages
   addGroupMessage: (msg: NewGroupMessage) => void
   addGroup: (group: Group) => void
+  // Change one loaded message in place
+  updateMessage: (
+    conversation: ConversationRef,
+    messageId: string,
+    // null removes the message, e.g. when another tab deleted it for us
+    update: (message: HyperwareChatMessage) => HyperwareChatMessage | null
+  ) => void
+
+  // Who is typing in each conversation, keyed by conversationKey
+  typing: { [conversation: string]: string[] }
+  setTyping: (conversation: ConversationRef, node: string, isTyping: boolean) => void
+  // Whether each peer answered its last heartbeat
+  presence: { [node: string]: boolean }
+  setPresence: (node: string, online: boolean) => void
   
   // Contacts
   contacts: Contact[]
@@ -43,11 +59,11 @@ const useHyperwareChatStore = create<HyperwareChatStore>()(
       hyperware_chats: { "New Chat": [] },
       addMessage: (msg: NewMessage) => {
         const { hyperware_chats } = get()
-        const { hyperware_chat, author, content, timestamp } = msg
+        const { hyperware_chat, id, author, content, timestamp } = msg
         if (!hyperware_chats[hyperware_chat]) {
           hyperware_chats[hyperware_chat] = []
         }
-        hyperware_chats[hyperware_chat].push({ author, content, timestamp })
+        hyperware_chats[hyperware_chat].push({ id, author, content, timestamp })
         set({ hyperware_chats })
       },
       
@@ -56,11 +72,11 @@ const useHyperwareChatStore = create<HyperwareChatStore>()(
       groupMessages: {},
       addGroupMessage: (msg: NewGroupMessage) => {
         const { groupMessages } = get()
-        const { group_id, author, content, timestamp } = msg
+        const { group_id, id, author, content, timestamp } = msg
         if (!groupMessages[group_id]) {
           groupMessages[group_id] = []
         }
-        groupMessages[group_id].push({ author, content, timestamp })
+        groupMessages[group_id].push({ id, author, content, timestamp })
         set({ groupMessages })
       },
       addGroup: (group: Group) => {
@@ -77,6 +93,39 @@ const useHyperwareChatStore = create<HyperwareChatStore>()(
           set({ groups: newGroups, groupMessages })
         }
       },
+      updateMessage: (conversation, messageId, update) => {
+        const { hyperware_chats, groupMessages } = get()
+        const replace = (messages: HyperwareChatMessage[] | undefined) =>
+          messages?.flatMap(message => {
+            if (message.id !== messageId) return [message]
+            const updated = update(message)
+            return updated ? [updated] : []
+          })
+        if ('Direct' in conversation) {
+          const messages = replace(hyperware_chats[conversation.Direct])
+          if (messages) {
+            set({ hyperware_chats: { ...hyperware_chats, [conversation.Direct]: messages } })
+          }
+        } else {
+          const messages = replace(groupMessages[conversation.Group])
+          if (messages) {
+            set({ groupMessages: { ...groupMessages, [conversation.Group]: messages } })
+          }
+        }
+      },
+
+      typing: {},
+      setTyping: (conversation, node, isTyping) => {
+        const { typing } = get()
+        const key = conversationKey(conversation)
+        const others = (typing[key] || []).filter(typist => typist !== node)
+        set({ typing: { ...typing, [key]: isTyping ? [...others, node] : others } })
+      },
+      presence: {},
+      setPresence: (node, online) => {
+        const { presence } = get()
+        set({ presence: { ...presence, [node]: online } })
+      },
       
       // Contacts
       contacts: [],
@@ -105,8 +154,22 @@ const useHyperwareChatStore = create<HyperwareChatStore>()(
     {
       name: 'hyperware_chat', // unique name
       storage: createJSONStorage(() => localStorage), // Using localStorage instead of sessionStorage for persistence
+      // Typing and presence are only true while the UI is connected
+      partialize: (state) => ({
+        hyperware_chats: state.hyperware_chats,
+        groups: state.groups,
+        groupMessages: state.groupMessages,
+        contacts: state.contacts,
+        selectedChatId: state.selectedChatId,
+        selectedGroupId: state.selectedGroupId,
+      }),
     }
   )
 )
 
+// Key for per-conversation state, e.g. "Direct:node.os"
+export function conversationKey(conversation: ConversationRef): string {
+  return 'Direct' in conversation ? `Direct:${conversation.Direct}` : `Group:${conversation.Group}`
+}
+
 export default useHyperwareChatStore
@@ -1,11 +1,20 @@
 export interface HyperwareChatMessage {
+  id?: string
   author: string
   content: string
   timestamp?: number  // Optional for backward compatibility
+  deleted?: boolean
+  edits?: { content: string, edited_at: number }[]
+  // emoji -> nodes that reacted with it
+  reactions?: { [emoji: string]: string[] }
+  // Set from receipts pushed while the UI is open
+  delivered?: boolean
+  read?: boolean
 }
 
 export interface NewMessage {
   hyperware_chat: string
+  id: string
   author: string
   content: string
   timestamp: number
@@ -13,11 +22,15 @@ export interface NewMessage {
 
 export interface NewGroupMessage {
   group_id: string
+  id: string
   author: string
   content: string
   timestamp: number
 }
 
+// A conversation as the process names it in WebSocket events and requests
+export type ConversationRef = { Direct: string } | { Group: string }
+
 export interface SendHyperwareChatMessage {
   Send: {
     target: string
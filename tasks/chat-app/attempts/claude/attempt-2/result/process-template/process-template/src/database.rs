@@ -1,11 +1,76 @@
 use anyhow::{anyhow, Result};
-use hyperware_process_lib::logging::{debug, error, info};
-use hyperware_process_lib::sqlite::{RowId, SqliteQuery, SqliteResult, Transaction};
+use hyperware_process_lib::logging::{info, warn};
+use hyperware_process_lib::sqlite::{self, RowId, SqliteQuery, SqliteResult, Transaction};
+use hyperware_process_lib::Address;
 use shared_types::{Contact, Conversation, ConversationMember, ChatMessage};
+
+use crate::migrations;
+use crate::row::{column, from_rows, FromRow};
 use std::time::{SystemTime, UNIX_EPOCH};
 use uuid::Uuid;
 
-const DB_NAME: &str = "hyperchat.db";
+pub const DB_NAME: &str = "hyperchat.db";
+
+/// Most messages or conversations one page holds
+pub const MAX_PAGE_SIZE: u32 = 200;
+
+// Conversations as seen by a member (`cm.member_address`)
+macro_rules! conversation_select {
+    () => {
+        r#"
+        SELECT 
+            c.id, c.type, c.title, c.created_at, c.last_message_at,
+            (SELECT content FROM messages WHERE conversation_id = c.id ORDER BY sent_at_ms DESC, id DESC LIMIT 1) as last_message,
+            (
+                SELECT COUNT(*) FROM messages m
+                WHERE m.conversation_id = c.id AND m.sender_id != cm.member_address
+                AND NOT EXISTS (SELECT 1 FROM message_reads r WHERE r.message_id = m.id AND r.reader = cm.member_address)
+            ) as unread_count
+        FROM conversations c
+        JOIN conversation_members cm ON c.id = cm.conversation_id
+        "#
+    };
+}
+
+// Queries on the hot paths, checked against the indexes at startup.
+// Conversations run from most recently active, messages from newest; a page
+// holds what comes after the `before_id` cursor in that order. A
+// conversation's activity is its last message's send time in milliseconds,
+// or its creation time until it has one.
+const CONVERSATIONS_FOR_USER_SQL: &str = concat!(
+    conversation_select!(),
+    r#"
+    WHERE cm.member_address = ?1
+    AND (?2 IS NULL OR (COALESCE(c.last_message_at, c.created_at * 1000), c.id) < (?2, ?3))
+    ORDER BY COALESCE(c.last_message_at, c.created_at * 1000) DESC, c.id DESC
+    LIMIT ?4
+    "#
+);
+const CONVERSATION_SQL: &str = concat!(
+    conversation_select!(),
+    "WHERE cm.member_address = ?1 AND c.id = ?2"
+);
+// `read_status` is as seen by the viewer (second parameter): their own
+// messages are read once anyone else has read them, others' once they have.
+// The page is selected newest first and returned oldest first.
+const MESSAGES_SQL: &str = r#"
+    SELECT * FROM (
+        SELECT
+            m.id, m.conversation_id, m.sender_id, m.content, m.timestamp, m.has_attachment, m.attachment_path,
+            m.sent_at_ms,
+            CASE WHEN m.sender_id = ?2
+                THEN EXISTS (SELECT 1 FROM message_reads r WHERE r.message_id = m.id AND r.reader != ?2)
+                ELSE EXISTS (SELECT 1 FROM message_reads r WHERE r.message_id = m.id AND r.reader = ?2)
+            END as read_status
+        FROM messages m
+        WHERE m.conversation_id = ?1
+        AND (?3 IS NULL OR (m.sent_at_ms, m.id) <
+            (SELECT sent_at_ms, id FROM messages WHERE id = ?3 AND conversation_id = ?1))
+        ORDER BY m.sent_at_ms DESC, m.id DESC
+        LIMIT ?4
+    )
+    ORDER BY sent_at_ms ASC, id ASC
+"#;
 
 /// Gets the current timestamp in seconds since the Unix epoch
 pub fn get_timestamp() -> u64 {
@@ -15,75 +80,97 @@ pub fn get_timestamp() -> u64 {
         .as_secs()
 }
 
+/// Gets the current timestamp in milliseconds since the Unix epoch
+pub fn get_timestamp_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Cursor for the conversations listed after `conversation`: its activity
+/// and id, as "activity:id"
+fn conversation_cursor(conversation: &Conversation) -> String {
+    let activity = conversation.last_message_at.unwrap_or(conversation.created_at * 1000);
+    format!("{}:{}", activity, conversation.id)
+}
+
+/// Split a cursor made by `conversation_cursor` into its activity and id
+pub fn parse_conversation_cursor(cursor: &str) -> Result<(u64, &str)> {
+    cursor
+        .split_once(':')
+        .and_then(|(activity, id)| Some((activity.parse().ok()?, id)))
+        .filter(|(_, id)| !id.is_empty())
+        .ok_or_else(|| anyhow!("Invalid conversation cursor: {}", cursor))
+}
+
 /// Generate a unique ID for a conversation
 pub fn generate_id() -> String {
     Uuid::new_v4().to_string()
 }
 
-/// Initialize the database by creating the necessary tables
-pub fn initialize_database() -> Result<()> {
+/// Initialize the database, migrating it to the current schema
+pub fn initialize_database(our: &Address) -> Result<()> {
     info!("Initializing database");
     
-    // Create contacts table
-    let create_contacts_table = r#"
-        CREATE TABLE IF NOT EXISTS contacts (
-            id INTEGER PRIMARY KEY,
-            name TEXT NOT NULL,
-            node_address TEXT UNIQUE NOT NULL,
-            avatar_path TEXT,
-            status TEXT DEFAULT 'offline',
-            last_seen INTEGER,
-            created_at INTEGER NOT NULL
-        )
-    "#;
-    
-    // Create conversations table
-    let create_conversations_table = r#"
-        CREATE TABLE IF NOT EXISTS conversations (
-            id TEXT PRIMARY KEY,
-            type TEXT NOT NULL,
-            title TEXT,
-            created_at INTEGER NOT NULL,
-            last_message_at INTEGER
-        )
-    "#;
-    
-    // Create conversation members table
-    let create_members_table = r#"
-        CREATE TABLE IF NOT EXISTS conversation_members (
-            conversation_id TEXT NOT NULL,
-            member_address TEXT NOT NULL,
-            join_timestamp INTEGER NOT NULL,
-            is_admin INTEGER DEFAULT 0,
-            PRIMARY KEY (conversation_id, member_address)
-        )
-    "#;
+    migrations::run()?;
     
-    // Create messages table
-    let create_messages_table = r#"
-        CREATE TABLE IF NOT EXISTS messages (
-            id INTEGER PRIMARY KEY,
-            conversation_id TEXT NOT NULL,
-            sender_id TEXT NOT NULL,
-            content TEXT NOT NULL,
-            timestamp INTEGER NOT NULL,
-            read_status INTEGER DEFAULT 0,
-            has_attachment INTEGER DEFAULT 0,
-            attachment_path TEXT
-        )
-    "#;
+    // SQLite ignores `PRAGMA foreign_keys` inside a transaction, so it goes
+    // straight to the connection, which keeps it from here on. Enabled after
+    // migrating, as rebuilding a referenced table needs them off.
+    sqlite::open(our.package_id(), DB_NAME, None)?
+        .write("PRAGMA foreign_keys = ON".to_string(), vec![], None)?;
     
-    let transaction = Transaction::new()?;
+    if let Err(e) = check_query_plans() {
+        warn!("Query plan check failed: {}", e);
+    }
     
-    // Execute all table creation statements
-    transaction.execute(DB_NAME, SqliteQuery::new(create_contacts_table, vec![]))?;
-    transaction.execute(DB_NAME, SqliteQuery::new(create_conversations_table, vec![]))?;
-    transaction.execute(DB_NAME, SqliteQuery::new(create_members_table, vec![]))?;
-    transaction.execute(DB_NAME, SqliteQuery::new(create_messages_table, vec![]))?;
+    info!("Database initialization complete");
+    Ok(())
+}
+
+/// Flag hot-path queries that scan a whole table instead of using an index
+fn check_query_plans() -> Result<()> {
+    // (caller, query, number of parameters)
+    let queries = [
+        ("get_conversations_for_user", CONVERSATIONS_FOR_USER_SQL, 4),
+        ("get_messages", MESSAGES_SQL, 4),
+        ("mark_messages_read", READ_MESSAGE_SQL, 2),
+    ];
+    // Plans name a table by its alias when it has one (`SCAN m`)
+    let guarded = ["messages", "m", "conversation_members", "cm"];
     
-    transaction.commit()?;
+    for (name, sql, param_count) in queries {
+        let query = SqliteQuery::new(&format!("EXPLAIN QUERY PLAN {}", sql), vec!["".into(); param_count]);
+        
+        let transaction = Transaction::new()?;
+        let result = transaction.read(DB_NAME, query)?;
+        transaction.commit()?;
+        
+        let SqliteResult::Read(rows) = result else {
+            return Err(anyhow!("Failed to read query plan for {}", name));
+        };
+        for row in &rows {
+            // Scanning the table we drive from is fine; scanning messages or members is not
+            let detail: String = column(row, "detail")?;
+            let Some(scanned) = detail.strip_prefix("SCAN ") else {
+                continue;
+            };
+            if detail.contains("USING") {
+                continue;
+            }
+            // "SCAN m", "SCAN messages AS m" or, before SQLite 3.36, "SCAN TABLE messages AS m"
+            let scanned = scanned.strip_prefix("TABLE ").unwrap_or(scanned);
+            if scanned
+                .split_whitespace()
+                .filter(|word| *word != "AS")
+                .any(|word| guarded.contains(&word))
+            {
+                return Err(anyhow!("{} does not use an index: {}", name, detail));
+            }
+        }
+    }
     
-    info!("Database initialization complete");
     Ok(())
 }
 
@@ -101,7 +188,7 @@ impl ContactRepository {
         
         let transaction = Transaction::new()?;
         let result = transaction.execute(DB_NAME, query)?;
-        let commit_result = transaction.commit()?;
+        transaction.commit()?;
         
         match result {
             SqliteResult::Write(RowId(id)) => Ok(id),
@@ -118,21 +205,11 @@ impl ContactRepository {
         
         let transaction = Transaction::new()?;
         let result = transaction.read(DB_NAME, query)?;
-        let commit_result = transaction.commit()?;
+        transaction.commit()?;
         
         match result {
             SqliteResult::Read(rows) => {
-                let contacts = rows.iter().map(|row| {
-                    Contact {
-                        id: row.get("id").map(|v| v.as_i64().unwrap_or(0)),
-                        name: row.get("name").map(|v| v.as_string().unwrap_or_default()).unwrap_or_default(),
-                        node_address: row.get("node_address").map(|v| v.as_string().unwrap_or_default()).unwrap_or_default(),
-                        avatar_path: row.get("avatar_path").and_then(|v| v.as_string()),
-                        status: row.get("status").map(|v| v.as_string().unwrap_or("offline".to_string())).unwrap_or_else(|| "offline".to_string()),
-                        last_seen: row.get("last_seen").and_then(|v| v.as_i64().map(|ts| ts as u64)),
-                        created_at: row.get("created_at").map(|v| v.as_i64().unwrap_or(0) as u64).unwrap_or_else(|| get_timestamp()),
-                    }
-                }).collect();
+                let contacts = from_rows::<Contact>(&rows)?;
                 
                 Ok(contacts)
             },
@@ -149,21 +226,11 @@ impl ContactRepository {
         
         let transaction = Transaction::new()?;
         let result = transaction.read(DB_NAME, query)?;
-        let commit_result = transaction.commit()?;
+        transaction.commit()?;
         
         match result {
             SqliteResult::Read(rows) => {
-                let contacts = rows.iter().map(|row| {
-                    Contact {
-                        id: row.get("id").map(|v| v.as_i64().unwrap_or(0)),
-                        name: row.get("name").map(|v| v.as_string().unwrap_or_default()).unwrap_or_default(),
-                        node_address: row.get("node_address").map(|v| v.as_string().unwrap_or_default()).unwrap_or_default(),
-                        avatar_path: row.get("avatar_path").and_then(|v| v.as_string()),
-                        status: row.get("status").map(|v| v.as_string().unwrap_or("offline".to_string())).unwrap_or_else(|| "offline".to_string()),
-                        last_seen: row.get("last_seen").and_then(|v| v.as_i64().map(|ts| ts as u64)),
-                        created_at: row.get("created_at").map(|v| v.as_i64().unwrap_or(0) as u64).unwrap_or_else(|| get_timestamp()),
-                    }
-                }).collect();
+                let contacts = from_rows::<Contact>(&rows)?;
                 
                 Ok(contacts)
             },
@@ -180,7 +247,7 @@ impl ContactRepository {
         
         let transaction = Transaction::new()?;
         let result = transaction.read(DB_NAME, query)?;
-        let commit_result = transaction.commit()?;
+        transaction.commit()?;
         
         match result {
             SqliteResult::Read(rows) => {
@@ -188,16 +255,7 @@ impl ContactRepository {
                     return Err(anyhow!("Contact not found"));
                 }
                 
-                let row = &rows[0];
-                let contact = Contact {
-                    id: row.get("id").map(|v| v.as_i64().unwrap_or(0)),
-                    name: row.get("name").map(|v| v.as_string().unwrap_or_default()).unwrap_or_default(),
-                    node_address: row.get("node_address").map(|v| v.as_string().unwrap_or_default()).unwrap_or_default(),
-                    avatar_path: row.get("avatar_path").and_then(|v| v.as_string()),
-                    status: row.get("status").map(|v| v.as_string().unwrap_or("offline".to_string())).unwrap_or_else(|| "offline".to_string()),
-                    last_seen: row.get("last_seen").and_then(|v| v.as_i64().map(|ts| ts as u64)),
-                    created_at: row.get("created_at").map(|v| v.as_i64().unwrap_or(0) as u64).unwrap_or_else(|| get_timestamp()),
-                };
+                let contact = Contact::from_row(&rows[0])?;
                 
                 Ok(contact)
             },
@@ -215,7 +273,7 @@ impl ContactRepository {
         
         let transaction = Transaction::new()?;
         let result = transaction.execute(DB_NAME, query)?;
-        let commit_result = transaction.commit()?;
+        transaction.commit()?;
         
         match result {
             SqliteResult::Write(_) => Ok(()),
@@ -223,16 +281,29 @@ impl ContactRepository {
         }
     }
     
-    /// Delete a contact
+    /// Delete a contact along with the direct conversations with them; their
+    /// members, messages and reads go with them through `ON DELETE CASCADE`
     pub fn delete_contact(id: i64) -> Result<()> {
+        // Conversations don't reference contacts, so these are deleted here
+        let conversations_query = SqliteQuery::new(
+            r#"
+            DELETE FROM conversations WHERE type = 'direct' AND id IN (
+                SELECT cm.conversation_id FROM conversation_members cm
+                JOIN contacts ct ON ct.node_address = cm.member_address
+                WHERE ct.id = ?
+            )
+            "#,
+            vec![id.into()],
+        );
         let query = SqliteQuery::new(
             "DELETE FROM contacts WHERE id = ?",
             vec![id.into()],
         );
         
         let transaction = Transaction::new()?;
+        transaction.execute(DB_NAME, conversations_query)?;
         let result = transaction.execute(DB_NAME, query)?;
-        let commit_result = transaction.commit()?;
+        transaction.commit()?;
         
         match result {
             SqliteResult::Write(_) => Ok(()),
@@ -252,16 +323,28 @@ impl ConversationRepository {
         member_addresses: &[String],
     ) -> Result<String> {
         let conversation_id = generate_id();
+        Self::create_conversation_with_id(&conversation_id, type_, title, member_addresses)?;
+        Ok(conversation_id)
+    }
+    
+    /// Create a conversation under an id chosen elsewhere, e.g. by the member
+    /// who started it on another node. Does nothing if it already exists.
+    pub fn create_conversation_with_id(
+        conversation_id: &str,
+        type_: &str,
+        title: Option<&str>,
+        member_addresses: &[String],
+    ) -> Result<()> {
         let timestamp = get_timestamp();
         let transaction = Transaction::new()?;
         
         // Insert conversation
         let conversation_query = SqliteQuery::new(
-            "INSERT INTO conversations (id, type, title, created_at) VALUES (?, ?, ?, ?)",
+            "INSERT OR IGNORE INTO conversations (id, type, title, created_at) VALUES (?, ?, ?, ?)",
             vec![
-                conversation_id.clone().into(),
+                conversation_id.into(),
                 type_.into(),
-                title.map(|t| t.into()).unwrap_or_else(|| "NULL".into()),
+                title.into(),
                 timestamp.into(),
             ],
         );
@@ -271,9 +354,9 @@ impl ConversationRepository {
         // Add members
         for address in member_addresses {
             let member_query = SqliteQuery::new(
-                "INSERT INTO conversation_members (conversation_id, member_address, join_timestamp, is_admin) VALUES (?, ?, ?, ?)",
+                "INSERT OR IGNORE INTO conversation_members (conversation_id, member_address, join_timestamp, is_admin) VALUES (?, ?, ?, ?)",
                 vec![
-                    conversation_id.clone().into(),
+                    conversation_id.into(),
                     address.clone().into(),
                     timestamp.into(),
                     // First member is admin in group chats
@@ -286,49 +369,97 @@ impl ConversationRepository {
         
         transaction.commit()?;
         
-        Ok(conversation_id)
+        Ok(())
     }
     
-    /// Get all conversations for a user
-    pub fn get_conversations_for_user(user_address: &str) -> Result<Vec<Conversation>> {
+    /// Check whether a conversation exists
+    pub fn conversation_exists(conversation_id: &str) -> Result<bool> {
         let query = SqliteQuery::new(
-            r#"
-            SELECT 
-                c.id, c.type, c.title, c.created_at, c.last_message_at,
-                (SELECT content FROM messages WHERE conversation_id = c.id ORDER BY timestamp DESC LIMIT 1) as last_message,
-                (SELECT COUNT(*) FROM messages WHERE conversation_id = c.id AND read_status = 0) as unread_count
-            FROM conversations c
-            JOIN conversation_members cm ON c.id = cm.conversation_id
-            WHERE cm.member_address = ?
-            ORDER BY c.last_message_at DESC NULLS LAST, c.created_at DESC
-            "#,
-            vec![user_address.into()],
+            "SELECT id FROM conversations WHERE id = ?",
+            vec![conversation_id.into()],
+        );
+        
+        let transaction = Transaction::new()?;
+        let result = transaction.read(DB_NAME, query)?;
+        transaction.commit()?;
+        
+        match result {
+            SqliteResult::Read(rows) => Ok(!rows.is_empty()),
+            _ => Err(anyhow!("Failed to look up conversation")),
+        }
+    }
+    
+    /// Check whether a node is a member of a conversation
+    pub fn is_member(conversation_id: &str, node_address: &str) -> Result<bool> {
+        let query = SqliteQuery::new(
+            "SELECT member_address FROM conversation_members WHERE conversation_id = ? AND member_address = ?",
+            vec![conversation_id.into(), node_address.into()],
+        );
+        
+        let transaction = Transaction::new()?;
+        let result = transaction.read(DB_NAME, query)?;
+        transaction.commit()?;
+        
+        match result {
+            SqliteResult::Read(rows) => Ok(!rows.is_empty()),
+            _ => Err(anyhow!("Failed to look up conversation member")),
+        }
+    }
+    
+    /// Get a page of a user's conversations, most recently active first,
+    /// starting after the `(activity, id)` of `before`. Returns the cursor for
+    /// the next page if there is one.
+    pub fn get_conversations_for_user(
+        user_address: &str,
+        before: Option<(u64, &str)>,
+        limit: u32,
+    ) -> Result<(Vec<Conversation>, Option<String>)> {
+        let limit = limit.clamp(1, MAX_PAGE_SIZE);
+        let (activity, id) = before.unzip();
+        // One extra row tells whether there is another page
+        let query = SqliteQuery::new(
+            CONVERSATIONS_FOR_USER_SQL,
+            vec![user_address.into(), activity.into(), id.into(), (limit + 1).into()],
         );
         
         let transaction = Transaction::new()?;
         let result = transaction.read(DB_NAME, query)?;
-        let commit_result = transaction.commit()?;
+        transaction.commit()?;
         
         match result {
             SqliteResult::Read(rows) => {
-                let conversations = rows.iter().map(|row| {
-                    Conversation {
-                        id: row.get("id").map(|v| v.as_string().unwrap_or_default()).unwrap_or_default(),
-                        type_: row.get("type").map(|v| v.as_string().unwrap_or_default()).unwrap_or_default(),
-                        title: row.get("title").and_then(|v| v.as_string()),
-                        created_at: row.get("created_at").map(|v| v.as_i64().unwrap_or(0) as u64).unwrap_or_else(|| get_timestamp()),
-                        last_message_at: row.get("last_message_at").and_then(|v| v.as_i64().map(|ts| ts as u64)),
-                        last_message: row.get("last_message").and_then(|v| v.as_string()),
-                        unread_count: row.get("unread_count").map(|v| v.as_i64().unwrap_or(0)).unwrap_or(0),
-                    }
-                }).collect();
+                let mut conversations = from_rows::<Conversation>(&rows)?;
                 
-                Ok(conversations)
+                let next_before_id = if conversations.len() > limit as usize {
+                    conversations.truncate(limit as usize);
+                    conversations.last().map(conversation_cursor)
+                } else {
+                    None
+                };
+                
+                Ok((conversations, next_before_id))
             },
             _ => Err(anyhow!("Failed to fetch conversations")),
         }
     }
     
+    /// Get one conversation as a member sees it
+    pub fn get_conversation(conversation_id: &str, member_address: &str) -> Result<Option<Conversation>> {
+        let query = SqliteQuery::new(
+            CONVERSATION_SQL,
+            vec![member_address.into(), conversation_id.into()],
+        );
+        
+        let transaction = Transaction::new()?;
+        let result = transaction.read(DB_NAME, query)?;
+        transaction.commit()?;
+        
+        match result {
+            SqliteResult::Read(rows) => rows.first().map(Conversation::from_row).transpose(),
+            _ => Err(anyhow!("Failed to fetch conversation")),
+        }
+    }
+    
     /// Get conversation members
     pub fn get_conversation_members(conversation_id: &str) -> Result<Vec<ConversationMember>> {
         let query = SqliteQuery::new(
@@ -346,19 +477,11 @@ impl ConversationRepository {
         
         let transaction = Transaction::new()?;
         let result = transaction.read(DB_NAME, query)?;
-        let commit_result = transaction.commit()?;
+        transaction.commit()?;
         
         match result {
             SqliteResult::Read(rows) => {
-                let members = rows.iter().map(|row| {
-                    ConversationMember {
-                        conversation_id: row.get("conversation_id").map(|v| v.as_string().unwrap_or_default()).unwrap_or_default(),
-                        member_address: row.get("member_address").map(|v| v.as_string().unwrap_or_default()).unwrap_or_default(),
-                        join_timestamp: row.get("join_timestamp").map(|v| v.as_i64().unwrap_or(0) as u64).unwrap_or_else(|| get_timestamp()),
-                        is_admin: row.get("is_admin").map(|v| v.as_i64().unwrap_or(0) == 1).unwrap_or(false),
-                        name: row.get("name").and_then(|v| v.as_string()),
-                    }
-                }).collect();
+                let members = from_rows::<ConversationMember>(&rows)?;
                 
                 Ok(members)
             },
@@ -366,6 +489,23 @@ impl ConversationRepository {
         }
     }
     
+    /// Check whether a node administers a conversation
+    pub fn is_admin(conversation_id: &str, node_address: &str) -> Result<bool> {
+        let query = SqliteQuery::new(
+            "SELECT member_address FROM conversation_members WHERE conversation_id = ? AND member_address = ? AND is_admin = 1",
+            vec![conversation_id.into(), node_address.into()],
+        );
+        
+        let transaction = Transaction::new()?;
+        let result = transaction.read(DB_NAME, query)?;
+        transaction.commit()?;
+        
+        match result {
+            SqliteResult::Read(rows) => Ok(!rows.is_empty()),
+            _ => Err(anyhow!("Failed to look up conversation admin")),
+        }
+    }
+    
     /// Add a member to a conversation
     pub fn add_conversation_member(conversation_id: &str, node_address: &str) -> Result<()> {
         let timestamp = get_timestamp();
@@ -376,7 +516,7 @@ impl ConversationRepository {
         
         let transaction = Transaction::new()?;
         let result = transaction.execute(DB_NAME, query)?;
-        let commit_result = transaction.commit()?;
+        transaction.commit()?;
         
         match result {
             SqliteResult::Write(_) => Ok(()),
@@ -385,40 +525,64 @@ impl ConversationRepository {
     }
 }
 
+/// A message someone has read, with what its sender's node needs to hear of it
+#[derive(Debug, Clone)]
+pub struct ReadMessage {
+    pub id: i64,
+    pub uid: String,
+    pub conversation_id: String,
+    pub sender_id: String,
+}
+
+// A message by id, provided the reader (second parameter) is a member of its
+// conversation and didn't send it
+const READ_MESSAGE_SQL: &str = r#"
+    SELECT m.id, m.uid, m.conversation_id, m.sender_id
+    FROM messages m
+    JOIN conversation_members cm ON cm.conversation_id = m.conversation_id AND cm.member_address = ?2
+    WHERE m.id = ?1 AND m.sender_id != ?2
+"#;
+
 /// Message management functions
 pub struct MessageRepository;
 
 impl MessageRepository {
-    /// Add a new message
+    /// Add a new message, sent at `sent_at_ms` by its sender's clock. `uid`
+    /// identifies it on every member's node.
     pub fn add_message(
+        uid: &str,
         conversation_id: &str,
         sender_id: &str,
         content: &str,
         has_attachment: bool,
         attachment_path: Option<&str>,
+        sent_at_ms: u64,
     ) -> Result<i64> {
-        let timestamp = get_timestamp();
+        let timestamp = sent_at_ms / 1000;
         let transaction = Transaction::new()?;
         
         // Insert message
         let message_query = SqliteQuery::new(
-            "INSERT INTO messages (conversation_id, sender_id, content, timestamp, has_attachment, attachment_path) VALUES (?, ?, ?, ?, ?, ?)",
+            "INSERT INTO messages (uid, conversation_id, sender_id, content, timestamp, sent_at_ms, has_attachment, attachment_path) VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
             vec![
+                uid.into(),
                 conversation_id.into(),
                 sender_id.into(),
                 content.into(),
                 timestamp.into(),
+                sent_at_ms.into(),
                 (if has_attachment { 1 } else { 0 }).into(),
-                attachment_path.map(|p| p.into()).unwrap_or_else(|| "NULL".into()),
+                attachment_path.into(),
             ],
         );
         
         let message_result = transaction.execute(DB_NAME, message_query)?;
         
-        // Update conversation last message time
+        // Update conversation last message time; a late delivery of an older
+        // message doesn't move it back
         let update_query = SqliteQuery::new(
-            "UPDATE conversations SET last_message_at = ? WHERE id = ?",
-            vec![timestamp.into(), conversation_id.into()],
+            "UPDATE conversations SET last_message_at = MAX(COALESCE(last_message_at, 0), ?) WHERE id = ?",
+            vec![sent_at_ms.into(), conversation_id.into()],
         );
         
         transaction.execute(DB_NAME, update_query)?;
@@ -430,52 +594,227 @@ impl MessageRepository {
         }
     }
     
-    /// Get messages for a conversation
-    pub fn get_messages(conversation_id: &str) -> Result<Vec<ChatMessage>> {
+    /// Check whether a message with this uid is stored
+    pub fn uid_exists(uid: &str) -> Result<bool> {
         let query = SqliteQuery::new(
-            "SELECT id, conversation_id, sender_id, content, timestamp, read_status, has_attachment, attachment_path FROM messages WHERE conversation_id = ? ORDER BY timestamp ASC",
-            vec![conversation_id.into()],
+            "SELECT id FROM messages WHERE uid = ?",
+            vec![uid.into()],
+        );
+        
+        let transaction = Transaction::new()?;
+        let result = transaction.read(DB_NAME, query)?;
+        transaction.commit()?;
+        
+        match result {
+            SqliteResult::Read(rows) => Ok(!rows.is_empty()),
+            _ => Err(anyhow!("Failed to look up message {}", uid)),
+        }
+    }
+    
+    /// Check whether message `id` was posted in the conversation
+    pub fn is_in_conversation(id: i64, conversation_id: &str) -> Result<bool> {
+        let query = SqliteQuery::new(
+            "SELECT id FROM messages WHERE id = ? AND conversation_id = ?",
+            vec![id.into(), conversation_id.into()],
+        );
+        
+        let transaction = Transaction::new()?;
+        let result = transaction.read(DB_NAME, query)?;
+        transaction.commit()?;
+        
+        match result {
+            SqliteResult::Read(rows) => Ok(!rows.is_empty()),
+            _ => Err(anyhow!("Failed to look up message {}", id)),
+        }
+    }
+    
+    /// Get a page of messages in a conversation, with read status as `viewer`
+    /// sees it: the `limit` newest before the message `before_id`, oldest
+    /// first. Returns the cursor for the next (older) page if there is one.
+    pub fn get_messages(
+        conversation_id: &str,
+        viewer: &str,
+        before_id: Option<i64>,
+        limit: u32,
+    ) -> Result<(Vec<ChatMessage>, Option<i64>)> {
+        let limit = limit.clamp(1, MAX_PAGE_SIZE);
+        // One extra row tells whether there is another page
+        let query = SqliteQuery::new(
+            MESSAGES_SQL,
+            vec![conversation_id.into(), viewer.into(), before_id.into(), (limit + 1).into()],
         );
         
         let transaction = Transaction::new()?;
         let result = transaction.read(DB_NAME, query)?;
-        let commit_result = transaction.commit()?;
+        transaction.commit()?;
         
         match result {
             SqliteResult::Read(rows) => {
-                let messages = rows.iter().map(|row| {
-                    ChatMessage {
-                        id: row.get("id").map(|v| v.as_i64().unwrap_or(0)),
-                        conversation_id: row.get("conversation_id").map(|v| v.as_string().unwrap_or_default()).unwrap_or_default(),
-                        sender_id: row.get("sender_id").map(|v| v.as_string().unwrap_or_default()).unwrap_or_default(),
-                        content: row.get("content").map(|v| v.as_string().unwrap_or_default()).unwrap_or_default(),
-                        timestamp: row.get("timestamp").map(|v| v.as_i64().unwrap_or(0) as u64).unwrap_or_else(|| get_timestamp()),
-                        read_status: row.get("read_status").map(|v| v.as_i64().unwrap_or(0)).unwrap_or(0),
-                        has_attachment: row.get("has_attachment").map(|v| v.as_i64().unwrap_or(0) == 1).unwrap_or(false),
-                        attachment_path: row.get("attachment_path").and_then(|v| v.as_string()),
-                    }
-                }).collect();
+                let mut messages = from_rows::<ChatMessage>(&rows)?;
+                
+                // The extra row is the oldest
+                let next_before_id = if messages.len() > limit as usize {
+                    messages.remove(0);
+                    messages.first().and_then(|message| message.id)
+                } else {
+                    None
+                };
                 
-                Ok(messages)
+                Ok((messages, next_before_id))
             },
             _ => Err(anyhow!("Failed to fetch messages")),
         }
     }
     
-    /// Mark a message as read
-    pub fn mark_message_read(message_id: i64) -> Result<()> {
+    /// Mark messages as read by `reader`, returning those the reader may
+    /// mark: messages by someone else in a conversation they belong to
+    pub fn mark_messages_read(message_ids: &[i64], reader: &str, read_at: u64) -> Result<Vec<ReadMessage>> {
+        let transaction = Transaction::new()?;
+        let mut read = Vec::new();
+        
+        for &message_id in message_ids {
+            let query = SqliteQuery::new(READ_MESSAGE_SQL, vec![message_id.into(), reader.into()]);
+            let SqliteResult::Read(rows) = transaction.read(DB_NAME, query)? else {
+                return Err(anyhow!("Failed to fetch message {}", message_id));
+            };
+            let Some(row) = rows.first() else {
+                continue;
+            };
+            let message = ReadMessage::from_row(row)?;
+            
+            let insert_query = SqliteQuery::new(
+                "INSERT OR IGNORE INTO message_reads (message_id, reader, read_at) VALUES (?, ?, ?)",
+                vec![message.id.into(), reader.into(), read_at.into()],
+            );
+            transaction.execute(DB_NAME, insert_query)?;
+            read.push(message);
+        }
+        
+        transaction.commit()?;
+        Ok(read)
+    }
+    
+    /// Record a receipt from another node: `reader` read the messages with
+    /// these uids in the conversation. Returns the local ids of those found.
+    pub fn mark_read_by_uid(
+        conversation_id: &str,
+        uids: &[String],
+        reader: &str,
+        read_at: u64,
+    ) -> Result<Vec<i64>> {
+        let transaction = Transaction::new()?;
+        let mut read = Vec::new();
+        
+        for uid in uids {
+            let query = SqliteQuery::new(
+                "SELECT id FROM messages WHERE uid = ? AND conversation_id = ? AND sender_id != ?",
+                vec![uid.into(), conversation_id.into(), reader.into()],
+            );
+            let SqliteResult::Read(rows) = transaction.read(DB_NAME, query)? else {
+                return Err(anyhow!("Failed to fetch message {}", uid));
+            };
+            let Some(row) = rows.first() else {
+                continue;
+            };
+            let message_id: i64 = column(row, "id")?;
+            
+            let insert_query = SqliteQuery::new(
+                "INSERT OR IGNORE INTO message_reads (message_id, reader, read_at) VALUES (?, ?, ?)",
+                vec![message_id.into(), reader.into(), read_at.into()],
+            );
+            transaction.execute(DB_NAME, insert_query)?;
+            read.push(message_id);
+        }
+        
+        transaction.commit()?;
+        Ok(read)
+    }
+}
+
+/// A request to another node that hasn't been acknowledged yet
+#[derive(Debug, Clone)]
+pub struct OutboxEntry {
+    pub id: i64,
+    pub node: String,
+    /// The request, as JSON
+    pub body: String,
+    pub attempts: i64,
+}
+
+const OUTBOX_COLUMNS: &str = "SELECT id, node, body, attempts FROM outbox";
+
+/// Requests waiting for other nodes to acknowledge them
+pub struct OutboxRepository;
+
+impl OutboxRepository {
+    /// Queue a request to `node`, due to be sent now
+    pub fn add(node: &str, body: &str) -> Result<i64> {
         let query = SqliteQuery::new(
-            "UPDATE messages SET read_status = 1 WHERE id = ?",
-            vec![message_id.into()],
+            "INSERT INTO outbox (node, body, attempts, next_attempt_at) VALUES (?, ?, 0, ?)",
+            vec![node.into(), body.into(), get_timestamp().into()],
         );
         
         let transaction = Transaction::new()?;
         let result = transaction.execute(DB_NAME, query)?;
-        let commit_result = transaction.commit()?;
+        transaction.commit()?;
         
         match result {
-            SqliteResult::Write(_) => Ok(()),
-            _ => Err(anyhow!("Failed to mark message as read")),
+            SqliteResult::Write(RowId(id)) => Ok(id),
+            _ => Err(anyhow!("Failed to queue request to {}", node)),
+        }
+    }
+    
+    pub fn get(id: i64) -> Result<Option<OutboxEntry>> {
+        let query = SqliteQuery::new(&format!("{} WHERE id = ?", OUTBOX_COLUMNS), vec![id.into()]);
+        
+        let transaction = Transaction::new()?;
+        let result = transaction.read(DB_NAME, query)?;
+        transaction.commit()?;
+        
+        match result {
+            SqliteResult::Read(rows) => rows.first().map(OutboxEntry::from_row).transpose(),
+            _ => Err(anyhow!("Failed to fetch queued request {}", id)),
         }
     }
-}
\ No newline at end of file
+    
+    /// Requests due to be sent (again) by `now`, oldest first
+    pub fn due(now: u64, limit: u32) -> Result<Vec<OutboxEntry>> {
+        let query = SqliteQuery::new(
+            &format!("{} WHERE next_attempt_at <= ? ORDER BY id LIMIT ?", OUTBOX_COLUMNS),
+            vec![now.into(), limit.into()],
+        );
+        
+        let transaction = Transaction::new()?;
+        let result = transaction.read(DB_NAME, query)?;
+        transaction.commit()?;
+        
+        match result {
+            SqliteResult::Read(rows) => from_rows::<OutboxEntry>(&rows),
+            _ => Err(anyhow!("Failed to fetch queued requests")),
+        }
+    }
+    
+    /// Record an attempt to send a request and when to try again if it
+    /// isn't acknowledged by then
+    pub fn record_attempt(id: i64, attempts: i64, next_attempt_at: u64) -> Result<()> {
+        let query = SqliteQuery::new(
+            "UPDATE outbox SET attempts = ?, next_attempt_at = ? WHERE id = ?",
+            vec![attempts.into(), next_attempt_at.into(), id.into()],
+        );
+        
+        let transaction = Transaction::new()?;
+        transaction.execute(DB_NAME, query)?;
+        transaction.commit()?;
+        Ok(())
+    }
+    
+    /// Drop a request, once acknowledged or given up on
+    pub fn remove(id: i64) -> Result<()> {
+        let query = SqliteQuery::new("DELETE FROM outbox WHERE id = ?", vec![id.into()]);
+        
+        let transaction = Transaction::new()?;
+        transaction.execute(DB_NAME, query)?;
+        transaction.commit()?;
+        Ok(())
+    }
+}
@@ -8,12 +8,16 @@ use hyperware_process_lib::{
         HttpBindingConfig, HttpServer,
         WsBindingConfig,
     },
-    Address, Message
+    timer, Address, Message
 };
 use shared_types::{MessageChannel, MessageType, MessageLog, AppConfig, AppState};
 
+mod database;
 mod message_handlers;
+mod migrations;
+mod row;
 use message_handlers::*;
+use message_handlers::handle_node::{handle_node_response, handle_send_error, RETRY_INTERVAL_MS};
 
 wit_bindgen::generate!({
     path: "target/wit",
@@ -26,10 +30,19 @@ const WS_PATH: &str = "/ws";
 
 fn bind_http_endpoints(server: &mut HttpServer) {
     let public_config = HttpBindingConfig::new(false, false, false, None);
+    // Only the node's owner gets through, so requests act as our node
+    let authenticated_config = HttpBindingConfig::new(true, false, false, None);
     
-    // Define main API endpoint - will handle all requests
-    server.bind_http_path("/api", public_config.clone())
-        .expect("failed to bind main HTTP API path");
+    // Bind every API route; requests are dispatched by bound path
+    for path in handle_http::API_PATHS {
+        let config = if *path == handle_http::PUBLIC_PATH {
+            &public_config
+        } else {
+            &authenticated_config
+        };
+        server.bind_http_path(*path, config.clone())
+            .expect("failed to bind HTTP API path");
+    }
 }
 
 fn get_timestamp() -> u64 {
@@ -71,30 +84,32 @@ fn handle_message(
     state: &mut AppState,
     server: &mut HttpServer,
 ) -> anyhow::Result<()> {
+    // Timers fire as responses to the requests that set them
+    if message.source() == &make_timer_address(our) {
+        return handle_timer_message(our, message.body(), state, server);
+    }
+    
+    // Acknowledgements from nodes we delivered messages to
+    if !message.is_request() {
+        return handle_node_response(message.source(), message.context(), message.body());
+    }
+    
     match message.source() {
-        // Handling HTTP requests
+        // Handling HTTP requests and WebSocket events
         source if source == &make_http_address(our) => {
             handle_http_server_request(our, message.body(), state, server)
         }
-        // Handling WebSocket messages
-        source if source == &make_ws_address(our) => {
-            handle_ws_server_request(our, message.body(), state, server)
-        }
-        // Handling timer messages
-        source if source == &make_timer_address(our) => {
-            handle_timer_message(message.body(), state, server)
-        }
         // Handling terminal messages
         source if source == &make_terminal_address(our) => {
             handle_terminal_message(message.body(), state, server)
         }
         // Handling internal messages
         source if source.node == our.node => {
-            handle_internal_message(source, message.body(), state, server)
+            handle_internal_message(our, message, state, server)
         }
         // Handling external messages (from other nodes)
         _ => {
-            handle_external_message(message.source(), message.body(), state, server)
+            handle_external_message(our, message.source(), message.body(), state, server)
         },
     }
 }
@@ -115,6 +130,12 @@ fn init(our: Address) {
         ..Default::default()
     };
 
+    // Set up the message store
+    database::initialize_database(&our).expect("failed to initialize database");
+    
+    // Check the outbox for requests other nodes haven't acknowledged
+    timer::set_timer(RETRY_INTERVAL_MS, None);
+
     // Set up HTTP server
     let mut server = HttpServer::new(5);
     let http_config = HttpBindingConfig::default();
@@ -125,9 +146,9 @@ fn init(our: Address) {
         .serve_ui("ui", vec!["/"], http_config.clone())
         .expect("failed to serve UI");
 
-    // Bind WebSocket for real-time communication
+    // Bind WebSocket for real-time communication, for the node's owner only
     server
-        .bind_ws_path(WS_PATH, WsBindingConfig::default())
+        .bind_ws_path(WS_PATH, WsBindingConfig::new(true, false, false))
         .expect("failed to bind WebSocket API");
 
     // Log initialization
@@ -143,7 +164,7 @@ fn init(our: Address) {
     loop {
         match await_message() {
             Err(send_error) => {
-                error!("got SendError: {send_error}");
+                handle_send_error(&send_error);
                 log_message(
                     &mut state,
                     "System".to_string(),
@@ -1,85 +1,228 @@
+use std::collections::HashMap;
+
 use anyhow::{anyhow, Result};
 use hyperware_process_lib::{
-    http::server::{HttpRequest, HttpServer},
-    logging::{debug, error, info},
-    Address, Message,
+    get_blob,
+    http::server::{send_response, send_ws_push, HttpServer, IncomingHttpRequest, WsMessageType},
+    http::StatusCode,
+    logging::{error, info},
+    Address, LazyLoadBlob, Message,
 };
 use serde_json::json;
 use shared_types::{
-    ApiRequest, ApiResponse, AppState, ChatMessage,
-    MessageChannel, MessageType, WebSocketMessage,
+    ApiRequest, ApiResponse, AppState, ChatMessage, WebSocketMessage,
 };
 
-use crate::database::{ContactRepository, ConversationRepository, MessageRepository, get_timestamp};
-use crate::message_handlers::{broadcast_to_websocket_clients, get_user_channels};
+use crate::database::{ContactRepository, ConversationRepository, MessageRepository, generate_id, get_timestamp, get_timestamp_ms, parse_conversation_cursor};
+use crate::message_handlers::get_user_channels;
+use crate::migrations;
+use crate::message_handlers::handle_node::{deliver_to_members, send_read_receipts, share_members};
+
+// Liveness check, the only path bound without authentication
+pub const PUBLIC_PATH: &str = "/api";
+
+// Paths bound with the http-server; `:id` segments arrive as url params. All
+// but PUBLIC_PATH are authenticated, so the caller is our node's owner.
+pub const API_PATHS: &[&str] = &[
+    PUBLIC_PATH,
+    "/api/status",
+    "/api/contacts",
+    "/api/contacts/:id",
+    "/api/conversations",
+    "/api/conversations/:id/messages",
+    "/api/conversations/:id/members",
+    "/api/messages/:id/read",
+];
+
+// Page size when a listing doesn't ask for one
+const DEFAULT_PAGE_SIZE: u32 = 50;
 
 pub fn handle_http_request(
     our: &Address,
-    body: &[u8],
+    http_req: &IncomingHttpRequest,
     state: &mut AppState,
     server: &mut HttpServer,
 ) -> Result<()> {
-    let http_req = serde_json::from_slice::<HttpRequest>(body)?;
+    let method = http_req.method()?;
+    let path = http_req.bound_path(Some(&our.process.to_string())).to_string();
+    let body = get_blob().map(|blob| blob.bytes).unwrap_or_default();
     
     // Log HTTP request
-    info!("HTTP Request: {} {}", http_req.method, http_req.path);
-    info!("HTTP Body: {:?}", String::from_utf8_lossy(&http_req.body));
+    info!("HTTP Request: {} {}", method, path);
+    info!("HTTP Body: {:?}", String::from_utf8_lossy(&body));
     
-    let response = match (http_req.method.as_str(), http_req.path.as_str()) {
+    let response = match (method.as_str(), path.as_str()) {
+        // Liveness check
+        ("GET", "/api") => {
+            let response = json!({
+                "status": "ok",
+                "message": "Hello from Hyperchat!",
+                "connected_clients": state.connected_clients.len(),
+                "timestamp": get_timestamp()
+            });
+            send_json(StatusCode::OK, serde_json::to_vec(&response)?);
+            return Ok(());
+        },
+        
         // Status endpoint
         ("GET", "/api/status") => handle_status(state),
         
         // Contacts endpoints
         ("GET", "/api/contacts") => handle_get_contacts(),
-        ("POST", "/api/contacts") => {
-            info!("Adding contact with body: {:?}", String::from_utf8_lossy(&http_req.body));
-            handle_add_contact(&http_req)
-        },
-        ("DELETE", path) if path.starts_with("/api/contacts/") => {
-            let id = extract_id(path, "/api/contacts/")?;
-            handle_delete_contact(id)
+        ("POST", "/api/contacts") => handle_add_contact(&body),
+        ("DELETE", "/api/contacts/:id") => match url_param(http_req, "id").and_then(parse_id) {
+            Ok(id) => handle_delete_contact(id),
+            Err(e) => bad_request(e),
         },
         
         // Conversations endpoints
         ("GET", "/api/conversations") => {
-            // Extract user from query params
-            let user_address = http_req.params.get("user").ok_or_else(|| anyhow!("User address is required"))?;
-            handle_get_conversations(user_address)
+            let claimed_user = http_req.query_params().get("user").map(|user| user.as_str());
+            match (check_identity(our, claimed_user), page_params(http_req)) {
+                (Err(response), _) => response,
+                (_, Err(e)) => bad_request(e),
+                (Ok(()), Ok((before_id, limit))) => {
+                    handle_get_conversations(our.node(), before_id, limit)
+                },
+            }
         },
-        ("POST", "/api/conversations") => handle_create_conversation(&http_req),
-        ("GET", path) if path.starts_with("/api/conversations/") && path.ends_with("/messages") => {
-            let conversation_id = extract_id_from_middle(path, "/api/conversations/", "/messages")?;
-            handle_get_messages(conversation_id)
+        ("POST", "/api/conversations") => handle_create_conversation(our, &body),
+        ("GET", "/api/conversations/:id/messages") => {
+            let params = url_param(http_req, "id").and_then(|conversation_id| {
+                let (before_id, limit) = page_params(http_req)?;
+                let before_id = before_id.map(parse_id).transpose()?;
+                Ok((conversation_id, before_id, limit))
+            });
+            match params {
+                Ok((conversation_id, before_id, limit)) => {
+                    handle_get_messages(our, conversation_id, before_id, limit)
+                },
+                Err(e) => bad_request(e),
+            }
         },
-        ("POST", path) if path.starts_with("/api/conversations/") && path.ends_with("/messages") => {
-            let conversation_id = extract_id_from_middle(path, "/api/conversations/", "/messages")?;
-            handle_send_message(conversation_id, &http_req, state, server)
+        ("POST", "/api/conversations/:id/messages") => match url_param(http_req, "id") {
+            Ok(conversation_id) => handle_send_message(our, conversation_id, &body, state, server),
+            Err(e) => bad_request(e),
         },
-        ("POST", path) if path.starts_with("/api/conversations/") && path.ends_with("/members") => {
-            let conversation_id = extract_id_from_middle(path, "/api/conversations/", "/members")?;
-            handle_add_member(conversation_id, &http_req)
+        ("POST", "/api/conversations/:id/members") => match url_param(http_req, "id") {
+            Ok(conversation_id) => handle_add_member(our, conversation_id, &body),
+            Err(e) => bad_request(e),
         },
         
         // Message operations
-        ("PUT", path) if path.starts_with("/api/messages/") && path.ends_with("/read") => {
-            let message_id = extract_id_from_middle(path, "/api/messages/", "/read")?;
-            handle_mark_message_read(message_id)
+        ("PUT", "/api/messages/:id/read") => match url_param(http_req, "id").and_then(parse_id) {
+            Ok(message_id) => handle_mark_message_read(our, message_id),
+            Err(e) => bad_request(e),
+        },
+        
+        // The path is bound but not for this method
+        (_, path) if API_PATHS.contains(&path) => ApiResponse::Error {
+            code: 405,
+            message: format!("{} not allowed on {}", method, path),
         },
         
         // Return 404 for unknown paths
         _ => ApiResponse::Error {
             code: 404,
-            message: format!("Not found: {}", http_req.path),
+            message: format!("Not found: {}", path),
         },
     };
     
-    // Send response
-    let response_json = serde_json::to_string(&response)?;
-    server.http_respond(&http_req.channel_id, 200, "application/json", response_json.as_bytes())?;
+    // Errors carry their own status code
+    let status = match &response {
+        ApiResponse::Error { code, .. } => {
+            StatusCode::from_u16(*code).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+        },
+        _ => StatusCode::OK,
+    };
+    send_json(status, serde_json::to_vec(&response)?);
     
     Ok(())
 }
 
+// The same operations for local processes we gave our messaging capability,
+// such as the integration tests. Like authenticated HTTP, they act as our node.
+pub fn handle_api_request(
+    our: &Address,
+    request: ApiRequest,
+    state: &mut AppState,
+    server: &mut HttpServer,
+) -> Result<ApiResponse> {
+    let response = match request {
+        ApiRequest::GetStatus => handle_status(state),
+        ApiRequest::GetHistory => ApiResponse::History {
+            messages: state.message_history.clone(),
+        },
+        ApiRequest::CustomMessage { message_type, .. } => ApiResponse::Success {
+            message: format!("Received {} message", message_type),
+        },
+        ApiRequest::CreateContact { name, address } => handle_add_contact(&serde_json::to_vec(&json!({
+            "name": name,
+            "node_address": address,
+        }))?),
+        ApiRequest::ListContacts => handle_get_contacts(),
+        ApiRequest::DeleteContact { id } => handle_delete_contact(id),
+        ApiRequest::MarkRead { message_id } => handle_mark_message_read(our, message_id),
+        ApiRequest::StartConversation { name, participants } => {
+            let title = Some(name).filter(|name| !name.trim().is_empty());
+            let is_group = participants.len() > 1 || title.is_some();
+            handle_create_conversation(our, &serde_json::to_vec(&json!({
+                "title": title,
+                "members": participants,
+                "is_group": is_group,
+            }))?)
+        },
+        ApiRequest::AddMember { conversation_id, node_address } => handle_add_member(
+            our,
+            &conversation_id,
+            &serde_json::to_vec(&json!({ "node_address": node_address }))?,
+        ),
+        ApiRequest::SendMessage { conversation_id, content } => handle_send_message(
+            our,
+            &conversation_id,
+            &serde_json::to_vec(&json!({
+                "content": content,
+                "has_attachment": false,
+            }))?,
+            state,
+            server,
+        ),
+        ApiRequest::ListConversations { before_id, limit } => {
+            handle_get_conversations(our.node(), before_id.as_deref(), limit.unwrap_or(DEFAULT_PAGE_SIZE))
+        },
+        ApiRequest::GetMessages { conversation_id, before_id, limit } => {
+            handle_get_messages(our, &conversation_id, before_id, limit.unwrap_or(DEFAULT_PAGE_SIZE))
+        },
+    };
+    Ok(response)
+}
+
+fn send_json(status: StatusCode, body: Vec<u8>) {
+    let headers = HashMap::from([(
+        "Content-Type".to_string(),
+        "application/json".to_string(),
+    )]);
+    send_response(status, Some(headers), body);
+}
+
+// Callers act as our node; an explicit claim to be anyone else is refused
+fn check_identity(our: &Address, claimed: Option<&str>) -> Result<(), ApiResponse> {
+    match claimed {
+        Some(claimed) if claimed != our.node() => Err(ApiResponse::Error {
+            code: 403,
+            message: format!("Requests to this node act as {}, not {}", our.node(), claimed),
+        }),
+        _ => Ok(()),
+    }
+}
+
+fn bad_request(error: anyhow::Error) -> ApiResponse {
+    ApiResponse::Error {
+        code: 400,
+        message: error.to_string(),
+    }
+}
+
 // Status endpoint handler
 fn handle_status(state: &AppState) -> ApiResponse {
     let mut counts_by_channel = std::collections::HashMap::new();
@@ -88,10 +231,19 @@ fn handle_status(state: &AppState) -> ApiResponse {
         counts_by_channel.insert(format!("{:?}", channel), *count);
     }
     
+    let schema_version = match migrations::current_version() {
+        Ok(version) => version,
+        Err(e) => return ApiResponse::Error {
+            code: 500,
+            message: format!("Failed to read schema version: {}", e),
+        },
+    };
+    
     ApiResponse::Status {
         connected_clients: state.connected_clients.len(),
         message_count: state.message_history.len(),
         message_counts_by_channel: counts_by_channel,
+        schema_version,
     }
 }
 
@@ -106,17 +258,17 @@ fn handle_get_contacts() -> ApiResponse {
     }
 }
 
-fn handle_add_contact(req: &HttpRequest) -> ApiResponse {
+fn handle_add_contact(body: &[u8]) -> ApiResponse {
     #[derive(serde::Deserialize, Debug)]
     struct AddContactRequest {
         name: String,
         node_address: String,
     }
     
-    info!("Processing add contact request with body: {:?}", String::from_utf8_lossy(&req.body));
+    info!("Processing add contact request with body: {:?}", String::from_utf8_lossy(body));
     
     // Try to parse the JSON body
-    let add_request = match serde_json::from_slice::<AddContactRequest>(&req.body) {
+    let add_request = match serde_json::from_slice::<AddContactRequest>(body) {
         Ok(req) => {
             info!("Successfully parsed request: {:?}", req);
             req
@@ -176,9 +328,16 @@ fn handle_delete_contact(id: i64) -> ApiResponse {
 }
 
 // Conversation endpoints handlers
-fn handle_get_conversations(user_address: &str) -> ApiResponse {
-    match ConversationRepository::get_conversations_for_user(user_address) {
-        Ok(conversations) => ApiResponse::Conversations { conversations },
+fn handle_get_conversations(user_address: &str, before_id: Option<&str>, limit: u32) -> ApiResponse {
+    let before = match before_id.map(parse_conversation_cursor).transpose() {
+        Ok(before) => before,
+        Err(e) => return bad_request(e),
+    };
+    match ConversationRepository::get_conversations_for_user(user_address, before, limit) {
+        Ok((conversations, next_before_id)) => ApiResponse::Conversations {
+            conversations,
+            next_before_id,
+        },
         Err(e) => ApiResponse::Error {
             code: 500,
             message: format!("Failed to get conversations: {}", e),
@@ -186,7 +345,7 @@ fn handle_get_conversations(user_address: &str) -> ApiResponse {
     }
 }
 
-fn handle_create_conversation(req: &HttpRequest) -> ApiResponse {
+fn handle_create_conversation(our: &Address, body: &[u8]) -> ApiResponse {
     #[derive(serde::Deserialize)]
     struct CreateConversationRequest {
         title: Option<String>,
@@ -194,7 +353,7 @@ fn handle_create_conversation(req: &HttpRequest) -> ApiResponse {
         is_group: bool,
     }
     
-    let create_request = match serde_json::from_slice::<CreateConversationRequest>(&req.body) {
+    let create_request = match serde_json::from_slice::<CreateConversationRequest>(body) {
         Ok(req) => req,
         Err(_) => return ApiResponse::Error {
             code: 400,
@@ -204,10 +363,14 @@ fn handle_create_conversation(req: &HttpRequest) -> ApiResponse {
     
     let conversation_type = if create_request.is_group { "group" } else { "direct" };
     
+    // We create it, so we are a member and, as the first one, its admin
+    let mut members = vec![our.node().to_string()];
+    members.extend(create_request.members.into_iter().filter(|member| member != our.node()));
+    
     match ConversationRepository::create_conversation(
         conversation_type,
         create_request.title.as_deref(),
-        &create_request.members,
+        &members,
     ) {
         Ok(id) => ApiResponse::Success {
             message: format!("Conversation created with ID: {}", id),
@@ -219,9 +382,31 @@ fn handle_create_conversation(req: &HttpRequest) -> ApiResponse {
     }
 }
 
-fn handle_get_messages(conversation_id: &str) -> ApiResponse {
-    match MessageRepository::get_messages(conversation_id) {
-        Ok(messages) => ApiResponse::Messages { messages },
+fn handle_get_messages(
+    our: &Address,
+    conversation_id: &str,
+    before_id: Option<i64>,
+    limit: u32,
+) -> ApiResponse {
+    // An unknown cursor would otherwise read as the start of the conversation
+    if let Some(before_id) = before_id {
+        match MessageRepository::is_in_conversation(before_id, conversation_id) {
+            Ok(true) => {},
+            Ok(false) => return ApiResponse::Error {
+                code: 404,
+                message: format!("No message {} in conversation {}", before_id, conversation_id),
+            },
+            Err(e) => return ApiResponse::Error {
+                code: 500,
+                message: format!("Failed to get messages: {}", e),
+            },
+        }
+    }
+    match MessageRepository::get_messages(conversation_id, our.node(), before_id, limit) {
+        Ok((messages, next_before_id)) => ApiResponse::Messages {
+            messages,
+            next_before_id,
+        },
         Err(e) => ApiResponse::Error {
             code: 500,
             message: format!("Failed to get messages: {}", e),
@@ -230,20 +415,22 @@ fn handle_get_messages(conversation_id: &str) -> ApiResponse {
 }
 
 fn handle_send_message(
+    our: &Address,
     conversation_id: &str,
-    req: &HttpRequest,
+    body: &[u8],
     state: &mut AppState,
-    server: &mut HttpServer,
+    _server: &mut HttpServer,
 ) -> ApiResponse {
     #[derive(serde::Deserialize)]
     struct SendMessageRequest {
-        sender_id: String,
+        // Optional; the sender is always our node
+        sender_id: Option<String>,
         content: String,
         has_attachment: bool,
         attachment_path: Option<String>,
     }
     
-    let send_request = match serde_json::from_slice::<SendMessageRequest>(&req.body) {
+    let send_request = match serde_json::from_slice::<SendMessageRequest>(body) {
         Ok(req) => req,
         Err(_) => return ApiResponse::Error {
             code: 400,
@@ -251,13 +438,36 @@ fn handle_send_message(
         },
     };
     
+    if let Err(response) = check_identity(our, send_request.sender_id.as_deref()) {
+        return response;
+    }
+    let sender_id = our.node();
+    
+    // Only members may post
+    match ConversationRepository::is_member(conversation_id, sender_id) {
+        Ok(true) => {},
+        Ok(false) => return ApiResponse::Error {
+            code: 403,
+            message: format!("{} is not a member of the conversation", sender_id),
+        },
+        Err(e) => return ApiResponse::Error {
+            code: 500,
+            message: format!("Failed to check membership: {}", e),
+        },
+    }
+    
     // Add message to database
+    let sent_at_ms = get_timestamp_ms();
+    let timestamp = sent_at_ms / 1000;
+    let uid = generate_id();
     let message_id = match MessageRepository::add_message(
+        &uid,
         conversation_id,
-        &send_request.sender_id,
+        sender_id,
         &send_request.content,
         send_request.has_attachment,
         send_request.attachment_path.as_deref(),
+        sent_at_ms,
     ) {
         Ok(id) => id,
         Err(e) => return ApiResponse::Error {
@@ -277,15 +487,17 @@ fn handle_send_message(
     
     // Create WebSocket message for real-time notification
     let ws_message = WebSocketMessage::ChatMessage {
+        id: message_id,
         conversation_id: conversation_id.to_string(),
-        sender_id: send_request.sender_id.clone(),
+        sender_id: sender_id.to_string(),
         content: send_request.content.clone(),
-        timestamp: get_timestamp(),
+        timestamp,
+        sent_at_ms,
         has_attachment: send_request.has_attachment,
         attachment_path: send_request.attachment_path.clone(),
     };
     
-    let ws_message_json = match serde_json::to_string(&ws_message) {
+    let ws_message_json = match serde_json::to_vec(&ws_message) {
         Ok(json) => json,
         Err(e) => return ApiResponse::Error {
             code: 500,
@@ -297,24 +509,44 @@ fn handle_send_message(
     for member in members {
         let user_channels = get_user_channels(state, &member.member_address);
         for channel_id in user_channels {
-            if let Err(e) = server.ws_push(channel_id, &ws_message_json) {
-                error!("Failed to push WebSocket message: {}", e);
-            }
+            send_ws_push(
+                channel_id,
+                WsMessageType::Text,
+                LazyLoadBlob {
+                    mime: Some("application/json".to_string()),
+                    bytes: ws_message_json.clone(),
+                },
+            );
         }
     }
     
+    // Forward to members on other nodes
+    if let Err(e) = deliver_to_members(
+        our,
+        &uid,
+        conversation_id,
+        &send_request.content,
+        sent_at_ms,
+        send_request.has_attachment,
+        send_request.attachment_path.clone(),
+    ) {
+        error!("Failed to deliver message to remote members: {}", e);
+    }
+    
     ApiResponse::Success {
         message: format!("Message sent with ID: {}", message_id),
     }
 }
 
-fn handle_add_member(conversation_id: &str, req: &HttpRequest) -> ApiResponse {
+// Members on other nodes only accept member changes from an admin, so only
+// an admin may make them
+fn handle_add_member(our: &Address, conversation_id: &str, body: &[u8]) -> ApiResponse {
     #[derive(serde::Deserialize)]
     struct AddMemberRequest {
         node_address: String,
     }
     
-    let add_request = match serde_json::from_slice::<AddMemberRequest>(&req.body) {
+    let add_request = match serde_json::from_slice::<AddMemberRequest>(body) {
         Ok(req) => req,
         Err(_) => return ApiResponse::Error {
             code: 400,
@@ -322,9 +554,26 @@ fn handle_add_member(conversation_id: &str, req: &HttpRequest) -> ApiResponse {
         },
     };
     
+    match ConversationRepository::is_admin(conversation_id, our.node()) {
+        Ok(true) => {},
+        Ok(false) => return ApiResponse::Error {
+            code: 403,
+            message: format!("{} does not administer the conversation", our.node()),
+        },
+        Err(e) => return ApiResponse::Error {
+            code: 500,
+            message: format!("Failed to check membership: {}", e),
+        },
+    }
+    
     match ConversationRepository::add_conversation_member(conversation_id, &add_request.node_address) {
-        Ok(_) => ApiResponse::Success {
-            message: "Member added to conversation".to_string(),
+        Ok(_) => {
+            if let Err(e) = share_members(our, conversation_id) {
+                error!("Failed to tell members about {}: {}", add_request.node_address, e);
+            }
+            ApiResponse::Success {
+                message: "Member added to conversation".to_string(),
+            }
         },
         Err(e) => ApiResponse::Error {
             code: 500,
@@ -333,10 +582,21 @@ fn handle_add_member(conversation_id: &str, req: &HttpRequest) -> ApiResponse {
     }
 }
 
-fn handle_mark_message_read(message_id: i64) -> ApiResponse {
-    match MessageRepository::mark_message_read(message_id) {
-        Ok(_) => ApiResponse::Success {
-            message: "Message marked as read".to_string(),
+// Messages are read by the user of this node
+fn handle_mark_message_read(our: &Address, message_id: i64) -> ApiResponse {
+    let read_at = get_timestamp();
+    match MessageRepository::mark_messages_read(&[message_id], our.node(), read_at) {
+        Ok(read) if read.is_empty() => ApiResponse::Error {
+            code: 404,
+            message: format!("No message {} from another member of your conversations", message_id),
+        },
+        Ok(read) => {
+            if let Err(e) = send_read_receipts(our, &read, read_at) {
+                error!("Failed to send read receipt: {}", e);
+            }
+            ApiResponse::Success {
+                message: "Message marked as read".to_string(),
+            }
         },
         Err(e) => ApiResponse::Error {
             code: 500,
@@ -346,12 +606,23 @@ fn handle_mark_message_read(message_id: i64) -> ApiResponse {
 }
 
 // Helper functions for path parameter extraction
-fn extract_id(path: &str, prefix: &str) -> Result<i64> {
-    let id_str = path.strip_prefix(prefix).ok_or_else(|| anyhow!("Invalid path"))?;
-    id_str.parse::<i64>().map_err(|_| anyhow!("Invalid ID"))
+fn url_param<'a>(req: &'a IncomingHttpRequest, name: &str) -> Result<&'a str> {
+    req.url_params()
+        .get(name)
+        .map(|value| value.as_str())
+        .ok_or_else(|| anyhow!("Missing path parameter: {}", name))
 }
 
-fn extract_id_from_middle(path: &str, prefix: &str, suffix: &str) -> Result<&str> {
-    let without_prefix = path.strip_prefix(prefix).ok_or_else(|| anyhow!("Invalid path"))?;
-    without_prefix.strip_suffix(suffix).ok_or_else(|| anyhow!("Invalid path"))
-}
\ No newline at end of file
+// `before_id` and `limit` query params of a paginated listing
+fn page_params(req: &IncomingHttpRequest) -> Result<(Option<&str>, u32)> {
+    let params = req.query_params();
+    let limit = match params.get("limit") {
+        Some(limit) => limit.parse::<u32>().map_err(|_| anyhow!("Invalid limit"))?,
+        None => DEFAULT_PAGE_SIZE,
+    };
+    Ok((params.get("before_id").map(|value| value.as_str()), limit))
+}
+
+fn parse_id(id_str: &str) -> Result<i64> {
+    id_str.parse::<i64>().map_err(|_| anyhow!("Invalid ID"))
+}
@@ -1,22 +1,20 @@
-use std::collections::HashMap;
 use hyperware_process_lib::{
-    Address, Response,
-    http::server::{HttpServer, WsMessageType, send_ws_push},
+    Address, Message, Response,
+    http::server::{HttpServer, HttpServerRequest},
     logging::{info, error},
-    LazyLoadBlob,
+    timer,
 };
-use serde_json;
-use shared_types::{MessageChannel, MessageType, AppState};
+use shared_types::{ApiRequest, ApiResponse, MessageChannel, MessageType, AppState};
+use crate::hyperware::process::process_template::Request as NodeRequest;
 use crate::log_message;
+use crate::message_handlers::handle_http::{handle_api_request, handle_http_request};
+use crate::message_handlers::handle_ws::handle_ws_message;
+use crate::message_handlers::handle_node::{handle_node_request, retry_outbox, RETRY_INTERVAL_MS};
 
 pub fn make_http_address(our: &Address) -> Address {
     Address::from((our.node(), "http-server", "distro", "sys"))
 }
 
-pub fn make_ws_address(our: &Address) -> Address {
-    Address::from((our.node(), "ws-server", "distro", "sys"))
-}
-
 pub fn make_timer_address(our: &Address) -> Address {
     Address::from((our.node(), "timer", "distro", "sys"))
 }
@@ -27,164 +25,41 @@ pub fn make_terminal_address(our: &Address) -> Address {
 
 // HTTP request handler
 pub fn handle_http_server_request(
-    _our: &Address,
+    our: &Address,
     body: &[u8],
     state: &mut AppState,
-    _server: &mut HttpServer,
+    server: &mut HttpServer,
 ) -> anyhow::Result<()> {
-    // First 4 bytes are the channel ID
-    let channel_id = if body.len() >= 4 {
-        u32::from_le_bytes([body[0], body[1], body[2], body[3]])
-    } else {
-        info!("Invalid HTTP request body (too short)");
+    let Ok(server_request) = serde_json::from_slice::<HttpServerRequest>(body) else {
+        error!("Failed to parse HTTP server request");
         return Ok(());
     };
     
-    // Log the HTTP request with path info if available
-    let content = if body.len() > 4 {
-        if let Ok(request_str) = std::str::from_utf8(&body[4..]) {
-            Some(format!("HTTP Request: {}", request_str))
-        } else {
-            Some(format!("Binary HTTP data: {} bytes", body.len() - 4))
-        }
-    } else {
-        Some("Empty HTTP request".to_string())
-    };
-    
-    log_message(
-        state,
-        "HTTP".to_string(),
-        MessageChannel::HttpApi,
-        MessageType::HttpGet,
-        content,
-    );
-    
-    info!("Received HTTP request");
-    
-    // Create a simple status response
-    let response = serde_json::json!({
-        "status": "ok",
-        "message": "Hello from Hyperchat!",
-        "connected_clients": state.connected_clients.len(),
-        "timestamp": crate::get_timestamp()
-    });
-    
-    // Send response
-    let response_json = serde_json::to_string(&response)?;
-    
-    Response::new()
-        .body(response_json.as_bytes().to_vec())
-        .send()?;
-    
-    Ok(())
-}
-
-// WebSocket handler
-pub fn handle_ws_server_request(
-    _our: &Address,
-    body: &[u8],
-    state: &mut AppState,
-    _server: &mut HttpServer,
-) -> anyhow::Result<()> {
-    info!("Received WebSocket event");
-    
-    
-    // First 4 bytes are the channel ID
-    let channel_id = u32::from_le_bytes([body[0], body[1], body[2], body[3]]);
-    
-    // Next byte indicates the event type: 0 = open, 1 = message, 2 = close
-    let event_type = body[4];
-    
-    match event_type {
-        0 => { // Open event
-            // Store client information
-            state.connected_clients.insert(channel_id, "/ws".to_string());
-            
-            // Log connection
+    match server_request {
+        HttpServerRequest::Http(http_req) => {
+            let message_type = match http_req.method().map(|method| method.to_string()).as_deref() {
+                Ok("GET") => MessageType::HttpGet,
+                Ok("POST") => MessageType::HttpPost,
+                _ => MessageType::Other("HTTP".to_string()),
+            };
             log_message(
                 state,
-                "WebSocket".to_string(),
-                MessageChannel::WebSocket,
-                MessageType::WebSocketOpen,
-                Some(format!("WebSocket connection opened: {}", channel_id)),
+                "HTTP".to_string(),
+                MessageChannel::HttpApi,
+                message_type,
+                Some(format!("HTTP Request: {}", http_req.bound_path(Some(&our.process.to_string())))),
             );
             
-            // Send welcome message
-            let welcome = serde_json::json!({
-                "type": "welcome",
-                "message": "Welcome to Hyperchat!",
-                "channel_id": channel_id
-            });
-            
-            if let Ok(welcome_json) = serde_json::to_string(&welcome) {
-                send_ws_push(
-                    channel_id,
-                    WsMessageType::Text,
-                    LazyLoadBlob {
-                        mime: Some("application/json".to_string()),
-                        bytes: welcome_json.as_bytes().to_vec(),
-                    },
-                );
-            }
-        },
-        1 => { // Message event
-            // Extract message content starting from byte 5
-            if body.len() > 5 {
-                let message = &body[5..];
-                
-                if let Ok(message_str) = std::str::from_utf8(message) {
-                    // Log message
-                    log_message(
-                        state,
-                        "WebSocket".to_string(),
-                        MessageChannel::WebSocket,
-                        MessageType::WebSocketPushA,
-                        Some(format!("WebSocket message: {}", message_str)),
-                    );
-                    
-                    // Echo message back
-                    let response = serde_json::json!({
-                        "type": "echo",
-                        "original": message_str,
-                        "timestamp": crate::get_timestamp()
-                    });
-                    
-                    if let Ok(response_json) = serde_json::to_string(&response) {
-                        send_ws_push(
-                            channel_id,
-                            WsMessageType::Text,
-                            LazyLoadBlob {
-                                mime: Some("application/json".to_string()),
-                                bytes: response_json.as_bytes().to_vec(),
-                            },
-                        );
-                    }
-                }
-            }
-        },
-        2 => { // Close event
-            // Remove client
-            state.connected_clients.remove(&channel_id);
-            
-            // Log disconnection
-            log_message(
-                state,
-                "WebSocket".to_string(),
-                MessageChannel::WebSocket,
-                MessageType::WebSocketClose,
-                Some(format!("WebSocket connection closed: {}", channel_id)),
-            );
+            handle_http_request(our, &http_req, state, server)
         },
-        _ => {
-            error!("Unknown WebSocket event type: {}", event_type);
-        }
+        // WebSocket events also arrive from the http-server
+        ws_request => handle_ws_message(our, ws_request, state, server),
     }
-    
-    Ok(())
 }
 
-// Timer handler, usually used for time-based events
+// Timer handler: resends what other nodes haven't acknowledged
 pub fn handle_timer_message(
+    our: &Address,
     _body: &[u8],
     state: &mut AppState,
     _server: &mut HttpServer,
@@ -199,7 +74,9 @@ pub fn handle_timer_message(
     );
     info!("Received timer message");
     
-    Ok(())
+    let retried = retry_outbox(our);
+    timer::set_timer(RETRY_INTERVAL_MS, None);
+    retried
 }
 
 // Terminal handler for debugging 
@@ -227,11 +104,14 @@ pub fn handle_terminal_message(
 }
 
 pub fn handle_internal_message(
-    source: &Address,
-    body: &[u8],
+    our: &Address,
+    message: &Message,
     state: &mut AppState,
-    _server: &mut HttpServer,
+    server: &mut HttpServer,
 ) -> anyhow::Result<()> {
+    let source = message.source();
+    let body = message.body();
+    
     // Log the internal message
     let content = if let Ok(str) = std::str::from_utf8(body) {
         Some(str.to_string())
@@ -247,6 +127,22 @@ pub fn handle_internal_message(
         content,
     );
     
+    // API requests act as our node, so only processes we let message us may make them
+    if let Ok(request) = serde_json::from_slice::<ApiRequest>(body) {
+        let response = if has_messaging_capability(our, message) {
+            handle_api_request(our, request, state, server)?
+        } else {
+            ApiResponse::Error {
+                code: 403,
+                message: format!("{} may not make API requests", source),
+            }
+        };
+        Response::new()
+            .body(serde_json::to_vec(&response)?)
+            .send()?;
+        return Ok(());
+    }
+    
     // Simple response for internal messages
     let response = serde_json::json!({
         "status": "ok",
@@ -261,11 +157,21 @@ pub fn handle_internal_message(
     Ok(())
 }
 
+// Whether the sender attached the capability to message our process, which
+// only processes granted it on install can attach
+fn has_messaging_capability(our: &Address, message: &Message) -> bool {
+    message
+        .capabilities()
+        .iter()
+        .any(|capability| &capability.issuer == our && capability.params == "\"messaging\"")
+}
+
 pub fn handle_external_message(
+    our: &Address,
     source: &Address,
     body: &[u8],
     state: &mut AppState,
-    _server: &mut HttpServer,
+    server: &mut HttpServer,
 ) -> anyhow::Result<()> {
     // Parse the message
     let message_str = match std::str::from_utf8(body) {
@@ -305,6 +211,11 @@ pub fn handle_external_message(
         Some(message_str.to_string()),
     );
     
+    // Requests from our process on other nodes
+    if let Ok(request) = serde_json::from_str::<NodeRequest>(message_str) {
+        return handle_node_request(our, source, request, state, server);
+    }
+    
     // Parse JSON if possible
     let parsed_json = serde_json::from_str::<serde_json::Value>(message_str);
     
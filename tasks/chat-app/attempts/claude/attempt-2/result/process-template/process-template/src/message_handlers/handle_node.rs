@@ -0,0 +1,374 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+use hyperware_process_lib::{
+    http::server::{send_ws_push, HttpServer, WsMessageType},
+    logging::{error, info},
+    Address, LazyLoadBlob, Request, Response, SendError,
+};
+use shared_types::{AppState, WebSocketMessage};
+
+use crate::database::{
+    get_timestamp, get_timestamp_ms, ConversationRepository, MessageRepository, OutboxEntry,
+    OutboxRepository, ReadMessage,
+};
+use crate::hyperware::process::process_template::{
+    ConversationInfo, DeliveredMessage, ReadReceipt, Request as NodeRequest,
+    Response as NodeResponse,
+};
+use crate::message_handlers::get_user_channels;
+
+// Seconds a member's node has to acknowledge a delivered message
+const DELIVERY_TIMEOUT_SECS: u64 = 30;
+// How often the outbox is checked for requests to send again
+pub const RETRY_INTERVAL_MS: u64 = 30 * 1000;
+// Delay before resending an unacknowledged request, doubling with each
+// attempt up to the cap
+const RETRY_BASE_SECS: u64 = 30;
+const RETRY_MAX_SECS: u64 = 60 * 60;
+// Attempts after which a request is given up on
+const MAX_ATTEMPTS: i64 = 50;
+// Requests resent per outbox check
+const RETRY_BATCH: u32 = 20;
+// How far ahead of our clock a sender's clock may run before we stop
+// believing its send times
+const MAX_CLOCK_SKEW_MS: u64 = 5 * 60 * 1000;
+
+// Forward a message stored locally to the process of every member on another
+// node. Each delivery waits in the outbox until the member acknowledges it.
+pub fn deliver_to_members(
+    our: &Address,
+    uid: &str,
+    conversation_id: &str,
+    content: &str,
+    sent_at_ms: u64,
+    has_attachment: bool,
+    attachment_path: Option<String>,
+) -> Result<()> {
+    let conversation = conversation_info(our, conversation_id)?;
+    let members = conversation.members.clone();
+    let request = NodeRequest::DeliverMessage(DeliveredMessage {
+        uid: uid.to_string(),
+        conversation,
+        content: content.to_string(),
+        sent_at_ms,
+        has_attachment,
+        attachment_path,
+    });
+    let body = serde_json::to_string(&request)?;
+
+    for member in members.iter().filter(|member| member.as_str() != our.node()) {
+        send_to_node(our, member, &body)?;
+    }
+
+    Ok(())
+}
+
+// Tell every member on another node, including any just added, who the
+// members of a conversation we administer are now
+pub fn share_members(our: &Address, conversation_id: &str) -> Result<()> {
+    let conversation = conversation_info(our, conversation_id)?;
+    let members = conversation.members.clone();
+    let body = serde_json::to_string(&NodeRequest::UpdateMembers(conversation))?;
+
+    for member in members.iter().filter(|member| member.as_str() != our.node()) {
+        send_to_node(our, member, &body)?;
+    }
+
+    Ok(())
+}
+
+// Enough of a conversation for a member that hasn't seen it to create it.
+// Members are listed admins first, and the first becomes admin there.
+fn conversation_info(our: &Address, conversation_id: &str) -> Result<ConversationInfo> {
+    let members = ConversationRepository::get_conversation_members(conversation_id)?
+        .into_iter()
+        .map(|member| member.member_address)
+        .collect();
+    let conversation = ConversationRepository::get_conversation(conversation_id, our.node())?
+        .ok_or_else(|| anyhow!("Conversation {} not found", conversation_id))?;
+
+    Ok(ConversationInfo {
+        id: conversation_id.to_string(),
+        kind: conversation.type_,
+        title: conversation.title,
+        members,
+    })
+}
+
+// Tell the nodes of the senders of messages we read that we read them
+pub fn send_read_receipts(our: &Address, read: &[ReadMessage], read_at: u64) -> Result<()> {
+    let mut by_sender: HashMap<(&str, &str), Vec<String>> = HashMap::new();
+    for message in read.iter().filter(|message| message.sender_id != our.node()) {
+        by_sender
+            .entry((message.sender_id.as_str(), message.conversation_id.as_str()))
+            .or_default()
+            .push(message.uid.clone());
+    }
+
+    for ((sender, conversation_id), message_uids) in by_sender {
+        let request = NodeRequest::ReadReceipt(ReadReceipt {
+            conversation_id: conversation_id.to_string(),
+            message_uids,
+            read_at,
+        });
+        send_to_node(our, sender, &serde_json::to_string(&request)?)?;
+    }
+
+    Ok(())
+}
+
+// Queue a request to our process on another node and send it. It stays
+// queued, and is sent again from time to time, until the node acknowledges it.
+fn send_to_node(our: &Address, node: &str, body: &str) -> Result<()> {
+    let id = OutboxRepository::add(node, body)?;
+    let entry = OutboxRepository::get(id)?.ok_or_else(|| anyhow!("Queued request {} vanished", id))?;
+    attempt(our, &entry)
+}
+
+fn attempt(our: &Address, entry: &OutboxEntry) -> Result<()> {
+    let attempts = entry.attempts + 1;
+    // Not due again before this attempt has had time to be answered
+    let delay = RETRY_BASE_SECS
+        .saturating_mul(1u64 << (attempts - 1).min(16))
+        .min(RETRY_MAX_SECS);
+    OutboxRepository::record_attempt(entry.id, attempts, get_timestamp() + DELIVERY_TIMEOUT_SECS + delay)?;
+
+    let target = Address::new(entry.node.clone(), our.process.clone());
+    // The context names the queued request its response acknowledges
+    Request::to(target)
+        .body(entry.body.as_bytes().to_vec())
+        .context(entry.id.to_string().into_bytes())
+        .expects_response(DELIVERY_TIMEOUT_SECS)
+        .send()
+        .map_err(|e| anyhow!("Failed to send to {}: {}", entry.node, e))
+}
+
+// Send again whatever other nodes haven't acknowledged in time, giving up on
+// requests that have been tried too often
+pub fn retry_outbox(our: &Address) -> Result<()> {
+    for entry in OutboxRepository::due(get_timestamp(), RETRY_BATCH)? {
+        if entry.attempts >= MAX_ATTEMPTS {
+            error!("Giving up on request {} to {} after {} attempts", entry.id, entry.node, entry.attempts);
+            OutboxRepository::remove(entry.id)?;
+            continue;
+        }
+        if let Err(e) = attempt(our, &entry) {
+            error!("{}", e);
+        }
+    }
+    Ok(())
+}
+
+// A node we sent to didn't answer in time; the request stays queued
+pub fn handle_send_error(send_error: &SendError) {
+    let id = outbox_id(send_error.context.as_deref());
+    info!(
+        "{} did not acknowledge request {:?}; it will be sent again",
+        send_error.target.node(),
+        id
+    );
+}
+
+fn outbox_id(context: Option<&[u8]>) -> Option<i64> {
+    std::str::from_utf8(context?).ok()?.parse().ok()
+}
+
+// A request from the same process on another node
+pub fn handle_node_request(
+    our: &Address,
+    source: &Address,
+    request: NodeRequest,
+    state: &mut AppState,
+    _server: &mut HttpServer,
+) -> Result<()> {
+    let response = match request {
+        NodeRequest::DeliverMessage(message) => match receive_message(our, source, message, state) {
+            Ok(()) => NodeResponse::DeliverMessage,
+            Err(e) => {
+                error!("Rejected message from {}: {}", source, e);
+                NodeResponse::Err(e.to_string())
+            }
+        },
+        NodeRequest::ReadReceipt(receipt) => match receive_read_receipt(our, source, receipt, state) {
+            Ok(()) => NodeResponse::ReadReceipt,
+            Err(e) => {
+                error!("Rejected read receipt from {}: {}", source, e);
+                NodeResponse::Err(e.to_string())
+            }
+        },
+        NodeRequest::UpdateMembers(conversation) => match receive_members(our, source, conversation) {
+            Ok(()) => NodeResponse::UpdateMembers,
+            Err(e) => {
+                error!("Rejected member update from {}: {}", source, e);
+                NodeResponse::Err(e.to_string())
+            }
+        },
+        _ => NodeResponse::Err("Unsupported request".to_string()),
+    };
+
+    Response::new().body(serde_json::to_vec(&response)?).send()?;
+    Ok(())
+}
+
+// Acknowledgements of requests we sent. A refused request stays queued, as
+// the node may accept it once it has caught up, e.g. on membership.
+pub fn handle_node_response(source: &Address, context: Option<&[u8]>, body: &[u8]) -> Result<()> {
+    let response = serde_json::from_slice::<NodeResponse>(body)?;
+    let Some(id) = outbox_id(context) else {
+        return Ok(());
+    };
+    match response {
+        NodeResponse::Err(e) => {
+            error!("{} refused request {}: {}; it will be sent again", source.node(), id, e);
+            Ok(())
+        }
+        _ => OutboxRepository::remove(id),
+    }
+}
+
+fn receive_message(
+    our: &Address,
+    source: &Address,
+    message: DeliveredMessage,
+    state: &AppState,
+) -> Result<()> {
+    // The sender is whoever sent the message, through our process on their node
+    if source.process != our.process {
+        return Err(anyhow!("Messages must come from {}", our.process));
+    }
+    let sender = source.node();
+
+    // Already stored; our acknowledgement must have been lost
+    if MessageRepository::uid_exists(&message.uid)? {
+        return Ok(());
+    }
+
+    let conversation = &message.conversation;
+    if ConversationRepository::conversation_exists(&conversation.id)? {
+        // Membership is what we already know, not what the sender claims
+        if !ConversationRepository::is_member(&conversation.id, sender)? {
+            return Err(anyhow!("{} is not a member of the conversation", sender));
+        }
+    } else {
+        if !conversation.members.iter().any(|member| member == sender)
+            || !conversation.members.iter().any(|member| member == our.node())
+        {
+            return Err(anyhow!("Conversation must include both sender and recipient"));
+        }
+        info!("Joining conversation {} started by {}", conversation.id, sender);
+        ConversationRepository::create_conversation_with_id(
+            &conversation.id,
+            &conversation.kind,
+            conversation.title.as_deref(),
+            &conversation.members,
+        )?;
+    }
+
+    // A send time from the future would pin the message to the end of the
+    // conversation, and the conversation to the top of the list
+    let sent_at_ms = message.sent_at_ms.min(get_timestamp_ms() + MAX_CLOCK_SKEW_MS);
+    let id = MessageRepository::add_message(
+        &message.uid,
+        &conversation.id,
+        sender,
+        &message.content,
+        message.has_attachment,
+        message.attachment_path.as_deref(),
+        sent_at_ms,
+    )?;
+
+    // Let our UI know
+    let ws_message = WebSocketMessage::ChatMessage {
+        id,
+        conversation_id: conversation.id.clone(),
+        sender_id: sender.to_string(),
+        content: message.content.clone(),
+        timestamp: sent_at_ms / 1000,
+        sent_at_ms,
+        has_attachment: message.has_attachment,
+        attachment_path: message.attachment_path.clone(),
+    };
+    push_to_our_clients(our, state, &ws_message)
+}
+
+fn receive_members(our: &Address, source: &Address, conversation: ConversationInfo) -> Result<()> {
+    if source.process != our.process {
+        return Err(anyhow!("Member updates must come from {}", our.process));
+    }
+    let sender = source.node();
+
+    if ConversationRepository::conversation_exists(&conversation.id)? {
+        // Only an admin, as far as we know, may change the members
+        if !ConversationRepository::is_admin(&conversation.id, sender)? {
+            return Err(anyhow!("{} does not administer the conversation", sender));
+        }
+        for member in &conversation.members {
+            ConversationRepository::add_conversation_member(&conversation.id, member)?;
+        }
+    } else {
+        // We were added; the sender becomes admin here as its first member
+        if conversation.members.first().map(String::as_str) != Some(sender)
+            || !conversation.members.iter().any(|member| member == our.node())
+        {
+            return Err(anyhow!("Conversation must be led by the sender and include the recipient"));
+        }
+        info!("Added to conversation {} by {}", conversation.id, sender);
+        ConversationRepository::create_conversation_with_id(
+            &conversation.id,
+            &conversation.kind,
+            conversation.title.as_deref(),
+            &conversation.members,
+        )?;
+    }
+    Ok(())
+}
+
+fn receive_read_receipt(
+    our: &Address,
+    source: &Address,
+    receipt: ReadReceipt,
+    state: &AppState,
+) -> Result<()> {
+    // The reader is whoever sent the receipt
+    if source.process != our.process {
+        return Err(anyhow!("Receipts must come from {}", our.process));
+    }
+    let reader = source.node();
+    if !ConversationRepository::is_member(&receipt.conversation_id, reader)? {
+        return Err(anyhow!("{} is not a member of the conversation", reader));
+    }
+
+    // Like send times, read times (in seconds) may not run ahead of our clock
+    let read_at = receipt.read_at.min(get_timestamp() + MAX_CLOCK_SKEW_MS / 1000);
+    let message_ids = MessageRepository::mark_read_by_uid(
+        &receipt.conversation_id,
+        &receipt.message_uids,
+        reader,
+        read_at,
+    )?;
+    if message_ids.is_empty() {
+        return Ok(());
+    }
+
+    push_to_our_clients(our, state, &WebSocketMessage::MessagesRead {
+        conversation_id: receipt.conversation_id,
+        message_ids,
+        reader: reader.to_string(),
+    })
+}
+
+fn push_to_our_clients(our: &Address, state: &AppState, message: &WebSocketMessage) -> Result<()> {
+    let bytes = serde_json::to_vec(message)?;
+    for channel_id in get_user_channels(state, our.node()) {
+        send_ws_push(
+            channel_id,
+            WsMessageType::Text,
+            LazyLoadBlob {
+                mime: Some("application/json".to_string()),
+                bytes: bytes.clone(),
+            },
+        );
+    }
+    Ok(())
+}
@@ -1,31 +1,43 @@
+use std::collections::HashMap;
+
 use anyhow::Result;
 use hyperware_process_lib::{
-    http::server::{HttpServer, WsRequest, WsEventData, WsMessageType, send_ws_push},
+    get_blob,
+    http::server::{HttpServer, HttpServerRequest, WsMessageType, send_ws_push},
     logging::{error, info},
     Address, LazyLoadBlob
 };
-use serde_json;
 use shared_types::{
     AppState, MessageChannel, MessageType, WebSocketMessage
 };
 
-use crate::database::{ContactRepository, get_timestamp};
+use crate::database::{ContactRepository, MessageRepository, get_timestamp};
+use crate::message_handlers::handle_node::send_read_receipts;
 use crate::log_message;
 
+// WebSocket events as delivered by the http-server
 pub fn handle_ws_message(
-    _our: &Address,
-    body: &[u8],
+    our: &Address,
+    request: HttpServerRequest,
     state: &mut AppState,
-    server: &mut HttpServer,
+    _server: &mut HttpServer,
 ) -> Result<()> {
-    let ws_req = serde_json::from_slice::<WsRequest>(body)?;
-    
-    match ws_req.event {
-        WsEventData::Open => {
+    match request {
+        HttpServerRequest::WebSocketOpen { path, channel_id } => {
             // Store the connection in our state
-            state.connected_clients.insert(ws_req.channel_id, "/ws".to_string());
+            state.connected_clients.insert(channel_id, path);
+            
+            // The binding is authenticated, so the client is our node's owner.
+            // Channel ids can be reused; this replaces any earlier connection's user.
+            let node_address = our.node().to_string();
+            state.connected_users.insert(channel_id, node_address.clone());
+            
+            info!("WebSocket connection opened: {}", channel_id);
             
-            info!("WebSocket connection opened: {}", ws_req.channel_id);
+            // Update contact status to online
+            if let Err(e) = ContactRepository::update_contact_status(&node_address, "online") {
+                error!("Failed to update contact status: {}", e);
+            }
             
             // Log the connection
             log_message(
@@ -33,42 +45,39 @@ pub fn handle_ws_message(
                 "WebSocket".to_string(),
                 MessageChannel::WebSocket,
                 MessageType::WebSocketOpen,
-                Some(format!("WebSocket connection opened: {}", ws_req.channel_id)),
+                Some(format!("WebSocket connection opened: {}", channel_id)),
             );
             
             // Send welcome message
-            let welcome = serde_json::json!({
-                "type": "welcome",
-                "message": "Welcome to Hyperchat WebSocket server",
-                "channel_id": ws_req.channel_id
+            push(channel_id, &WebSocketMessage::Welcome {
+                message: "Welcome to Hyperchat WebSocket server".to_string(),
+                channel_id,
             });
             
-            if let Ok(welcome_json) = serde_json::to_string(&welcome) {
-                send_ws_push(
-                    ws_req.channel_id,
-                    WsMessageType::Text,
-                    LazyLoadBlob {
-                        mime: Some("application/json".to_string()),
-                        bytes: welcome_json.as_bytes().to_vec(),
-                    },
-                );
-            }
+            // Notify other clients that this user is online
+            push_to_others(state, channel_id, &WebSocketMessage::StatusChange {
+                node_address,
+                status: "online".to_string(),
+            });
         },
-        WsEventData::Close => {
+        HttpServerRequest::WebSocketClose(channel_id) => {
             // Remove from connected clients
-            state.connected_clients.remove(&ws_req.channel_id);
+            state.connected_clients.remove(&channel_id);
             
-            // If this user had authenticated, update their status and remove mapping
-            if let Some(node_address) = state.connected_users.remove(&ws_req.channel_id) {
+            // Update the user's status and remove the mapping
+            if let Some(node_address) = state.connected_users.remove(&channel_id) {
                 info!("User disconnected: {}", node_address);
                 
-                // Update contact status to offline
-                if let Err(e) = ContactRepository::update_contact_status(&node_address, "offline") {
-                    error!("Failed to update contact status: {}", e);
+                // Update contact status to offline once their last connection is gone
+                let still_connected = state.connected_users.values().any(|user| user == &node_address);
+                if !still_connected {
+                    if let Err(e) = ContactRepository::update_contact_status(&node_address, "offline") {
+                        error!("Failed to update contact status: {}", e);
+                    }
                 }
             }
             
-            info!("WebSocket connection closed: {}", ws_req.channel_id);
+            info!("WebSocket connection closed: {}", channel_id);
             
             // Log the disconnection
             log_message(
@@ -76,10 +85,25 @@ pub fn handle_ws_message(
                 "WebSocket".to_string(),
                 MessageChannel::WebSocket,
                 MessageType::WebSocketClose,
-                Some(format!("WebSocket connection closed: {}", ws_req.channel_id)),
+                Some(format!("WebSocket connection closed: {}", channel_id)),
             );
         },
-        WsEventData::Push(ref message) => {
+        HttpServerRequest::WebSocketPush { channel_id, message_type } => {
+            // Clients speak JSON; pings, pongs and binary frames carry nothing for us
+            if message_type != WsMessageType::Text {
+                return Ok(());
+            }
+            let Some(blob) = get_blob() else {
+                return Ok(());
+            };
+            let message = String::from_utf8_lossy(&blob.bytes).to_string();
+            
+            // Pushes from a channel we never saw open are ignored
+            if !state.connected_clients.contains_key(&channel_id) {
+                error!("WebSocket message on unknown channel: {}", channel_id);
+                return Ok(());
+            }
+            
             info!("Received WebSocket message: {}", message);
             
             // Log the message
@@ -92,178 +116,121 @@ pub fn handle_ws_message(
             );
             
             // Process WebSocket messages
-            if let Ok(ws_message) = serde_json::from_str::<WebSocketMessage>(message) {
-                match ws_message {
-                    WebSocketMessage::Auth { node_address } => {
-                        // Handle authentication
-                        state.connected_users.insert(ws_req.channel_id, node_address.clone());
-                        
-                        info!("User authenticated: {}", node_address);
-                        
-                        // Update contact status to online
-                        if let Err(e) = ContactRepository::update_contact_status(&node_address, "online") {
-                            error!("Failed to update contact status: {}", e);
-                        }
-                        
-                        // Send acknowledgement
-                        let ack = serde_json::json!({
-                            "type": "auth_success",
-                            "node_address": node_address,
-                            "timestamp": get_timestamp()
-                        });
-                        
-                        if let Ok(ack_json) = serde_json::to_string(&ack) {
-                            send_ws_push(
-                                ws_req.channel_id,
-                                WsMessageType::Text,
-                                LazyLoadBlob {
-                                    mime: Some("application/json".to_string()),
-                                    bytes: ack_json.as_bytes().to_vec(),
-                                },
-                            );
-                        }
-                        
-                        // Notify other users that this user is online
-                        let status_change = serde_json::json!({
-                            "type": "status_change",
-                            "node_address": node_address,
-                            "status": "online",
-                            "timestamp": get_timestamp()
-                        });
-                        
-                        if let Ok(status_json) = serde_json::to_string(&status_change) {
-                            for (&client_id, _) in &state.connected_clients {
-                                if client_id != ws_req.channel_id {
-                                    send_ws_push(
-                                        client_id,
-                                        WsMessageType::Text,
-                                        LazyLoadBlob {
-                                            mime: Some("application/json".to_string()),
-                                            bytes: status_json.as_bytes().to_vec(),
-                                        },
-                                    );
-                                }
-                            }
-                        }
-                    },
-                    WebSocketMessage::TypingIndicator { conversation_id, user_id, is_typing } => {
-                        // Broadcast typing indicator to all members of the conversation
-                        let typing_indicator = serde_json::json!({
-                            "type": "typing_indicator",
-                            "conversation_id": conversation_id,
-                            "user_id": user_id,
-                            "is_typing": is_typing,
-                            "timestamp": get_timestamp()
-                        });
-                        
-                        if let Ok(typing_json) = serde_json::to_string(&typing_indicator) {
-                            for (&client_id, _) in &state.connected_clients {
-                                if client_id != ws_req.channel_id {
-                                    send_ws_push(
-                                        client_id,
-                                        WsMessageType::Text,
-                                        LazyLoadBlob {
-                                            mime: Some("application/json".to_string()),
-                                            bytes: typing_json.as_bytes().to_vec(),
-                                        },
-                                    );
-                                }
+            let Ok(ws_message) = serde_json::from_str::<WebSocketMessage>(&message) else {
+                push(channel_id, &WebSocketMessage::Error {
+                    message: "Invalid message format".to_string(),
+                    timestamp: get_timestamp(),
+                });
+                return Ok(());
+            };
+            
+            // The binding is authenticated, so clients act as our node and may
+            // not claim to be anyone else
+            let claimed = match &ws_message {
+                WebSocketMessage::Auth { node_address }
+                | WebSocketMessage::StatusChange { node_address, .. } => Some(node_address),
+                WebSocketMessage::TypingIndicator { user_id, .. } => Some(user_id),
+                _ => None,
+            };
+            if let Some(claimed) = claimed.filter(|claimed| claimed.as_str() != our.node()) {
+                push(channel_id, &WebSocketMessage::Error {
+                    message: format!("Clients of this node act as {}, not {}", our.node(), claimed),
+                    timestamp: get_timestamp(),
+                });
+                return Ok(());
+            }
+            
+            match ws_message {
+                // The channel was registered as our node when it opened; older
+                // clients still authenticate, so acknowledge it
+                WebSocketMessage::Auth { node_address } => {
+                    push(channel_id, &WebSocketMessage::AuthSuccess {
+                        node_address,
+                        timestamp: get_timestamp(),
+                    });
+                },
+                // Broadcast typing indicators to the other clients
+                WebSocketMessage::TypingIndicator { .. } => {
+                    push_to_others(state, channel_id, &ws_message);
+                },
+                // Record the reads, then tell our other clients and the senders' nodes
+                WebSocketMessage::ReadReceipt { message_ids } => {
+                    let read_at = get_timestamp();
+                    match MessageRepository::mark_messages_read(&message_ids, our.node(), read_at) {
+                        Ok(read) => {
+                            let mut by_conversation: HashMap<&str, Vec<i64>> = HashMap::new();
+                            for message in &read {
+                                by_conversation.entry(&message.conversation_id).or_default().push(message.id);
                             }
-                        }
-                    },
-                    WebSocketMessage::ReadReceipt { message_ids } => {
-                        // Broadcast read receipt to all clients
-                        let read_receipt = serde_json::json!({
-                            "type": "read_receipt",
-                            "message_ids": message_ids,
-                            "timestamp": get_timestamp()
-                        });
-                        
-                        if let Ok(receipt_json) = serde_json::to_string(&read_receipt) {
-                            for (&client_id, _) in &state.connected_clients {
-                                if client_id != ws_req.channel_id {
-                                    send_ws_push(
-                                        client_id,
-                                        WsMessageType::Text,
-                                        LazyLoadBlob {
-                                            mime: Some("application/json".to_string()),
-                                            bytes: receipt_json.as_bytes().to_vec(),
-                                        },
-                                    );
-                                }
+                            for (conversation_id, message_ids) in by_conversation {
+                                push_to_others(state, channel_id, &WebSocketMessage::MessagesRead {
+                                    conversation_id: conversation_id.to_string(),
+                                    message_ids,
+                                    reader: our.node().to_string(),
+                                });
                             }
-                        }
-                    },
-                    // Messages are handled through HTTP API for persistence
-                    WebSocketMessage::ChatMessage { .. } => {
-                        let error_message = serde_json::json!({
-                            "type": "error",
-                            "message": "Chat messages should be sent via HTTP API for persistence",
-                            "timestamp": get_timestamp()
-                        });
-                        
-                        if let Ok(error_json) = serde_json::to_string(&error_message) {
-                            send_ws_push(
-                                ws_req.channel_id,
-                                WsMessageType::Text,
-                                LazyLoadBlob {
-                                    mime: Some("application/json".to_string()),
-                                    bytes: error_json.as_bytes().to_vec(),
-                                },
-                            );
-                        }
-                    },
-                    WebSocketMessage::StatusChange { node_address, status } => {
-                        // Update contact status
-                        if let Err(e) = ContactRepository::update_contact_status(&node_address, &status) {
-                            error!("Failed to update contact status: {}", e);
-                        }
-                        
-                        // Broadcast status change
-                        let status_change = serde_json::json!({
-                            "type": "status_change",
-                            "node_address": node_address,
-                            "status": status,
-                            "timestamp": get_timestamp()
-                        });
-                        
-                        if let Ok(status_json) = serde_json::to_string(&status_change) {
-                            for (&client_id, _) in &state.connected_clients {
-                                if client_id != ws_req.channel_id {
-                                    send_ws_push(
-                                        client_id,
-                                        WsMessageType::Text,
-                                        LazyLoadBlob {
-                                            mime: Some("application/json".to_string()),
-                                            bytes: status_json.as_bytes().to_vec(),
-                                        },
-                                    );
-                                }
+                            
+                            if let Err(e) = send_read_receipts(our, &read, read_at) {
+                                error!("Failed to send read receipts: {}", e);
                             }
-                        }
-                    }
-                }
-            } else {
-                // If we can't parse as WebSocketMessage, respond with error
-                let error_message = serde_json::json!({
-                    "type": "error",
-                    "message": "Invalid message format",
-                    "timestamp": get_timestamp()
-                });
-                
-                if let Ok(error_json) = serde_json::to_string(&error_message) {
-                    send_ws_push(
-                        ws_req.channel_id,
-                        WsMessageType::Text,
-                        LazyLoadBlob {
-                            mime: Some("application/json".to_string()),
-                            bytes: error_json.as_bytes().to_vec(),
                         },
-                    );
-                }
+                        Err(e) => error!("Failed to mark messages as read: {}", e),
+                    }
+                },
+                WebSocketMessage::StatusChange { ref node_address, ref status } => {
+                    // Update contact status
+                    if let Err(e) = ContactRepository::update_contact_status(node_address, status) {
+                        error!("Failed to update contact status: {}", e);
+                    }
+                    
+                    // Broadcast status change
+                    push_to_others(state, channel_id, &ws_message);
+                },
+                // Messages are handled through HTTP API for persistence
+                WebSocketMessage::ChatMessage { .. } => {
+                    push(channel_id, &WebSocketMessage::Error {
+                        message: "Chat messages should be sent via HTTP API for persistence".to_string(),
+                        timestamp: get_timestamp(),
+                    });
+                },
+                // Only the server sends these
+                WebSocketMessage::MessagesRead { .. }
+                | WebSocketMessage::Welcome { .. }
+                | WebSocketMessage::AuthSuccess { .. }
+                | WebSocketMessage::Error { .. } => {
+                    push(channel_id, &WebSocketMessage::Error {
+                        message: "Unexpected message type".to_string(),
+                        timestamp: get_timestamp(),
+                    });
+                },
             }
-        }
+        },
+        // Plain HTTP requests are routed elsewhere
+        HttpServerRequest::Http(_) => {},
     }
     
     Ok(())
-}
\ No newline at end of file
+}
+
+fn push(channel_id: u32, message: &WebSocketMessage) {
+    match serde_json::to_vec(message) {
+        Ok(bytes) => send_ws_push(
+            channel_id,
+            WsMessageType::Text,
+            LazyLoadBlob {
+                mime: Some("application/json".to_string()),
+                bytes,
+            },
+        ),
+        Err(e) => error!("Failed to serialize WebSocket message: {}", e),
+    }
+}
+
+// Send to every connected client except the one the message came from
+fn push_to_others(state: &AppState, from_channel: u32, message: &WebSocketMessage) {
+    for &client_id in state.connected_clients.keys() {
+        if client_id != from_channel {
+            push(client_id, message);
+        }
+    }
+}
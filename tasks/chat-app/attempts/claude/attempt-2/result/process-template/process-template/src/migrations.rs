@@ -0,0 +1,232 @@
+use anyhow::{anyhow, Context, Result};
+use hyperware_process_lib::logging::info;
+use hyperware_process_lib::sqlite::{SqliteQuery, SqliteResult, Transaction};
+
+use crate::database::{get_timestamp, DB_NAME};
+use crate::row::column;
+
+/// A forward migration: statements run together in one transaction
+type Migration = &'static [&'static str];
+
+/// MIGRATIONS[n] brings the schema from version n to n + 1. Never edit a
+/// migration that has shipped; append a new one instead.
+const MIGRATIONS: &[Migration] = &[
+    // 1: initial schema. `IF NOT EXISTS` so databases created before
+    // versioning are adopted as they are.
+    &[
+        r#"
+        CREATE TABLE IF NOT EXISTS contacts (
+            id INTEGER PRIMARY KEY,
+            name TEXT NOT NULL,
+            node_address TEXT UNIQUE NOT NULL,
+            avatar_path TEXT,
+            status TEXT DEFAULT 'offline',
+            last_seen INTEGER,
+            created_at INTEGER NOT NULL
+        )
+        "#,
+        r#"
+        CREATE TABLE IF NOT EXISTS conversations (
+            id TEXT PRIMARY KEY,
+            type TEXT NOT NULL,
+            title TEXT,
+            created_at INTEGER NOT NULL,
+            last_message_at INTEGER
+        )
+        "#,
+        r#"
+        CREATE TABLE IF NOT EXISTS conversation_members (
+            conversation_id TEXT NOT NULL,
+            member_address TEXT NOT NULL,
+            join_timestamp INTEGER NOT NULL,
+            is_admin INTEGER DEFAULT 0,
+            PRIMARY KEY (conversation_id, member_address)
+        )
+        "#,
+        r#"
+        CREATE TABLE IF NOT EXISTS messages (
+            id INTEGER PRIMARY KEY,
+            conversation_id TEXT NOT NULL,
+            sender_id TEXT NOT NULL,
+            content TEXT NOT NULL,
+            timestamp INTEGER NOT NULL,
+            read_status INTEGER DEFAULT 0,
+            has_attachment INTEGER DEFAULT 0,
+            attachment_path TEXT
+        )
+        "#,
+    ],
+    // 2: earlier versions stored missing values as the string "NULL"
+    &[
+        "UPDATE conversations SET title = NULL WHERE title = 'NULL'",
+        "UPDATE messages SET attachment_path = NULL WHERE attachment_path = 'NULL'",
+    ],
+    // 3: foreign keys from members and messages to their conversation, which
+    // SQLite can only add by rebuilding the tables, and indexes for the hot
+    // lookups. Rows of conversations that no longer exist are dropped.
+    &[
+        r#"
+        CREATE TABLE conversation_members_new (
+            conversation_id TEXT NOT NULL REFERENCES conversations(id) ON DELETE CASCADE,
+            member_address TEXT NOT NULL,
+            join_timestamp INTEGER NOT NULL,
+            is_admin INTEGER NOT NULL DEFAULT 0,
+            PRIMARY KEY (conversation_id, member_address)
+        )
+        "#,
+        r#"
+        INSERT INTO conversation_members_new (conversation_id, member_address, join_timestamp, is_admin)
+        SELECT conversation_id, member_address, join_timestamp, COALESCE(is_admin, 0)
+        FROM conversation_members
+        WHERE conversation_id IN (SELECT id FROM conversations)
+        "#,
+        "DROP TABLE conversation_members",
+        "ALTER TABLE conversation_members_new RENAME TO conversation_members",
+        r#"
+        CREATE TABLE messages_new (
+            id INTEGER PRIMARY KEY,
+            conversation_id TEXT NOT NULL REFERENCES conversations(id) ON DELETE CASCADE,
+            sender_id TEXT NOT NULL,
+            content TEXT NOT NULL,
+            timestamp INTEGER NOT NULL,
+            read_status INTEGER NOT NULL DEFAULT 0,
+            has_attachment INTEGER NOT NULL DEFAULT 0,
+            attachment_path TEXT
+        )
+        "#,
+        r#"
+        INSERT INTO messages_new (id, conversation_id, sender_id, content, timestamp, read_status, has_attachment, attachment_path)
+        SELECT id, conversation_id, sender_id, content, timestamp, COALESCE(read_status, 0), COALESCE(has_attachment, 0), attachment_path
+        FROM messages
+        WHERE conversation_id IN (SELECT id FROM conversations)
+        "#,
+        "DROP TABLE messages",
+        "ALTER TABLE messages_new RENAME TO messages",
+        "CREATE INDEX idx_messages_conversation_timestamp ON messages (conversation_id, timestamp)",
+        "CREATE INDEX idx_conversation_members_member ON conversation_members (member_address)",
+        "CREATE INDEX idx_conversations_last_message ON conversations (last_message_at)",
+    ],
+    // 4: reads are tracked per reader instead of one flag per message, and
+    // messages get an id that is the same on every member's node so receipts
+    // can name them. Messages flagged read before count as read by every
+    // member but their sender.
+    &[
+        "ALTER TABLE messages ADD COLUMN uid TEXT",
+        "UPDATE messages SET uid = lower(hex(randomblob(16))) WHERE uid IS NULL",
+        "CREATE UNIQUE INDEX idx_messages_uid ON messages (uid)",
+        r#"
+        CREATE TABLE message_reads (
+            message_id INTEGER NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+            reader TEXT NOT NULL,
+            read_at INTEGER NOT NULL,
+            PRIMARY KEY (message_id, reader)
+        )
+        "#,
+        r#"
+        INSERT INTO message_reads (message_id, reader, read_at)
+        SELECT m.id, cm.member_address, m.timestamp
+        FROM messages m
+        JOIN conversation_members cm ON cm.conversation_id = m.conversation_id
+        WHERE m.read_status = 1 AND cm.member_address != m.sender_id
+        "#,
+    ],
+    // 5: millisecond send times, so messages sent in the same second keep
+    // their order, and indexes matching the paginated listings
+    &[
+        "ALTER TABLE messages ADD COLUMN sent_at_ms INTEGER NOT NULL DEFAULT 0",
+        "UPDATE messages SET sent_at_ms = timestamp * 1000",
+        "DROP INDEX idx_messages_conversation_timestamp",
+        "CREATE INDEX idx_messages_conversation_sent ON messages (conversation_id, sent_at_ms, id)",
+        "DROP INDEX idx_conversations_last_message",
+        "CREATE INDEX idx_conversations_activity ON conversations (COALESCE(last_message_at, created_at), id)",
+    ],
+    // 6: requests to other nodes wait in an outbox until they are
+    // acknowledged, so a member that is offline gets them later
+    &[
+        r#"
+        CREATE TABLE outbox (
+            id INTEGER PRIMARY KEY,
+            node TEXT NOT NULL,
+            body TEXT NOT NULL,
+            attempts INTEGER NOT NULL DEFAULT 0,
+            next_attempt_at INTEGER NOT NULL
+        )
+        "#,
+        "CREATE INDEX idx_outbox_next_attempt ON outbox (next_attempt_at)",
+    ],
+    // 7: conversation activity in milliseconds like message send times, and
+    // an index matching the (activity, id) cursor of the conversation listing
+    &[
+        "UPDATE conversations SET last_message_at = last_message_at * 1000 WHERE last_message_at IS NOT NULL",
+        "DROP INDEX idx_conversations_activity",
+        "CREATE INDEX idx_conversations_activity ON conversations (COALESCE(last_message_at, created_at * 1000), id)",
+    ],
+];
+
+/// Schema version this build expects
+pub const SCHEMA_VERSION: i64 = MIGRATIONS.len() as i64;
+
+/// Bring the database up to SCHEMA_VERSION. Each migration commits together
+/// with its version row, so an interrupted run resumes where it stopped.
+pub fn run() -> Result<()> {
+    let transaction = Transaction::new()?;
+    transaction.execute(
+        DB_NAME,
+        SqliteQuery::new(
+            "CREATE TABLE IF NOT EXISTS schema_version (version INTEGER PRIMARY KEY, applied_at INTEGER NOT NULL)",
+            vec![],
+        ),
+    )?;
+    transaction.commit()?;
+
+    let current = current_version()?;
+    if current > SCHEMA_VERSION {
+        return Err(anyhow!(
+            "{} is at schema version {}, newer than supported {}",
+            DB_NAME,
+            current,
+            SCHEMA_VERSION
+        ));
+    }
+
+    for version in current + 1..=SCHEMA_VERSION {
+        info!("Migrating {} to schema version {}", DB_NAME, version);
+        apply(version).with_context(|| format!("migration to schema version {} failed", version))?;
+    }
+
+    Ok(())
+}
+
+/// Latest schema version applied to the database
+pub fn current_version() -> Result<i64> {
+    let query = SqliteQuery::new("SELECT MAX(version) AS version FROM schema_version", vec![]);
+
+    let transaction = Transaction::new()?;
+    let result = transaction.read(DB_NAME, query)?;
+    transaction.commit()?;
+
+    match result {
+        // MAX over no rows is NULL: a fresh or pre-versioning database
+        SqliteResult::Read(rows) => match rows.first() {
+            Some(row) => Ok(column::<Option<i64>>(row, "version")?.unwrap_or(0)),
+            None => Ok(0),
+        },
+        _ => Err(anyhow!("Failed to read schema version")),
+    }
+}
+
+fn apply(version: i64) -> Result<()> {
+    let transaction = Transaction::new()?;
+    for statement in MIGRATIONS[version as usize - 1] {
+        transaction.execute(DB_NAME, SqliteQuery::new(statement, vec![]))?;
+    }
+    transaction.execute(
+        DB_NAME,
+        SqliteQuery::new(
+            "INSERT INTO schema_version (version, applied_at) VALUES (?, ?)",
+            vec![version.into(), get_timestamp().into()],
+        ),
+    )?;
+    transaction.commit()?;
+    Ok(())
+}
@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Context, Result};
+use serde_json::Value;
+use shared_types::{ChatMessage, Contact, Conversation, ConversationMember};
+
+use crate::database::{OutboxEntry, ReadMessage};
+
+/// A row as returned by the sqlite process: column name -> value
+pub type Row = HashMap<String, Value>;
+
+/// Types that can be built from a query row
+pub trait FromRow: Sized {
+    fn from_row(row: &Row) -> Result<Self>;
+}
+
+/// Types that can be read from a single column
+pub trait FromColumn: Sized {
+    fn from_column(value: &Value) -> Result<Self>;
+}
+
+impl FromColumn for i64 {
+    fn from_column(value: &Value) -> Result<Self> {
+        value.as_i64().ok_or_else(|| anyhow!("expected integer, found {}", value))
+    }
+}
+
+impl FromColumn for u64 {
+    fn from_column(value: &Value) -> Result<Self> {
+        value.as_u64().ok_or_else(|| anyhow!("expected non-negative integer, found {}", value))
+    }
+}
+
+impl FromColumn for bool {
+    // SQLite has no booleans; they are stored as 0 or 1
+    fn from_column(value: &Value) -> Result<Self> {
+        match value.as_i64() {
+            Some(0) => Ok(false),
+            Some(1) => Ok(true),
+            _ => Err(anyhow!("expected 0 or 1, found {}", value)),
+        }
+    }
+}
+
+impl FromColumn for String {
+    fn from_column(value: &Value) -> Result<Self> {
+        value
+            .as_str()
+            .map(str::to_string)
+            .ok_or_else(|| anyhow!("expected text, found {}", value))
+    }
+}
+
+impl<T: FromColumn> FromColumn for Option<T> {
+    fn from_column(value: &Value) -> Result<Self> {
+        match value {
+            Value::Null => Ok(None),
+            value => T::from_column(value).map(Some),
+        }
+    }
+}
+
+/// Read a column, failing if it is missing or holds the wrong type
+pub fn column<T: FromColumn>(row: &Row, name: &str) -> Result<T> {
+    let value = row.get(name).ok_or_else(|| anyhow!("missing column `{}`", name))?;
+    T::from_column(value).with_context(|| format!("bad value in column `{}`", name))
+}
+
+/// Map every row, failing on the first that doesn't decode
+pub fn from_rows<T: FromRow>(rows: &[Row]) -> Result<Vec<T>> {
+    rows.iter().map(T::from_row).collect()
+}
+
+impl FromRow for Contact {
+    fn from_row(row: &Row) -> Result<Self> {
+        Ok(Contact {
+            id: column(row, "id")?,
+            name: column(row, "name")?,
+            node_address: column(row, "node_address")?,
+            avatar_path: column(row, "avatar_path")?,
+            status: column(row, "status")?,
+            last_seen: column(row, "last_seen")?,
+            created_at: column(row, "created_at")?,
+        })
+    }
+}
+
+impl FromRow for Conversation {
+    fn from_row(row: &Row) -> Result<Self> {
+        Ok(Conversation {
+            id: column(row, "id")?,
+            type_: column(row, "type")?,
+            title: column(row, "title")?,
+            created_at: column(row, "created_at")?,
+            last_message_at: column(row, "last_message_at")?,
+            last_message: column(row, "last_message")?,
+            unread_count: column(row, "unread_count")?,
+        })
+    }
+}
+
+impl FromRow for ConversationMember {
+    fn from_row(row: &Row) -> Result<Self> {
+        Ok(ConversationMember {
+            conversation_id: column(row, "conversation_id")?,
+            member_address: column(row, "member_address")?,
+            join_timestamp: column(row, "join_timestamp")?,
+            is_admin: column(row, "is_admin")?,
+            name: column(row, "name")?,
+        })
+    }
+}
+
+impl FromRow for ChatMessage {
+    fn from_row(row: &Row) -> Result<Self> {
+        Ok(ChatMessage {
+            id: column(row, "id")?,
+            conversation_id: column(row, "conversation_id")?,
+            sender_id: column(row, "sender_id")?,
+            content: column(row, "content")?,
+            timestamp: column(row, "timestamp")?,
+            sent_at_ms: column(row, "sent_at_ms")?,
+            read_status: column(row, "read_status")?,
+            has_attachment: column(row, "has_attachment")?,
+            attachment_path: column(row, "attachment_path")?,
+        })
+    }
+}
+
+impl FromRow for OutboxEntry {
+    fn from_row(row: &Row) -> Result<Self> {
+        Ok(OutboxEntry {
+            id: column(row, "id")?,
+            node: column(row, "node")?,
+            body: column(row, "body")?,
+            attempts: column(row, "attempts")?,
+        })
+    }
+}
+
+impl FromRow for ReadMessage {
+    fn from_row(row: &Row) -> Result<Self> {
+        Ok(ReadMessage {
+            id: column(row, "id")?,
+            uid: column(row, "uid")?,
+            conversation_id: column(row, "conversation_id")?,
+            sender_id: column(row, "sender_id")?,
+        })
+    }
+}
@@ -118,6 +118,11 @@ pub enum ApiRequest {
     },
     /// List all contacts
     ListContacts,
+    /// Delete a contact and the direct conversations with them
+    DeleteContact {
+        /// Contact ID
+        id: i64,
+    },
     /// Start a new conversation
     StartConversation {
         /// Conversation name
@@ -125,6 +130,13 @@ pub enum ApiRequest {
         /// Conversation participants (addresses)
         participants: Vec<String>,
     },
+    /// Add a member to a conversation we administer
+    AddMember {
+        /// Conversation ID
+        conversation_id: String,
+        /// Node of the new member
+        node_address: String,
+    },
     /// Send a message to a conversation
     SendMessage {
         /// Conversation ID
@@ -132,28 +144,208 @@ pub enum ApiRequest {
         /// Message content
         content: String,
     },
+    /// List a page of conversations, most recently active first
+    ListConversations {
+        /// Cursor from the previous page, "activity:id"
+        before_id: Option<String>,
+        /// Page size
+        limit: Option<u32>,
+    },
+    /// Mark a message from another member as read
+    MarkRead {
+        /// Message ID
+        message_id: i64,
+    },
+    /// Get a page of messages in a conversation, oldest first
+    GetMessages {
+        /// Conversation ID
+        conversation_id: String,
+        /// Cursor from the previous page
+        before_id: Option<i64>,
+        /// Page size
+        limit: Option<u32>,
+    },
+}
+
+/// A contact in the user's address book
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Contact {
+    /// Row id, if stored
+    pub id: Option<i64>,
+    /// Display name
+    pub name: String,
+    /// Node the contact lives on
+    pub node_address: String,
+    /// Path to the contact's avatar image
+    pub avatar_path: Option<String>,
+    /// "online" or "offline"
+    pub status: String,
+    /// When the contact was last seen online
+    pub last_seen: Option<u64>,
+    /// When the contact was added
+    pub created_at: u64,
 }
 
-/// API response types for Hyperchat application
+/// A direct or group conversation
 #[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Conversation {
+    /// Conversation id, shared by every member's node
+    pub id: String,
+    /// "direct" or "group"
+    pub type_: String,
+    /// Title, for group conversations
+    pub title: Option<String>,
+    /// When the conversation was created
+    pub created_at: u64,
+    /// When the latest message was sent, in milliseconds
+    pub last_message_at: Option<u64>,
+    /// Content of the latest message
+    pub last_message: Option<String>,
+    /// Number of messages the user hasn't read
+    pub unread_count: i64,
+}
+
+/// A member of a conversation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConversationMember {
+    /// Conversation the member belongs to
+    pub conversation_id: String,
+    /// Node of the member
+    pub member_address: String,
+    /// When the member joined
+    pub join_timestamp: u64,
+    /// Whether the member administers the conversation
+    pub is_admin: bool,
+    /// Contact name, if the member is a contact
+    pub name: Option<String>,
+}
+
+/// A message in a conversation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatMessage {
+    /// Row id, if stored
+    pub id: Option<i64>,
+    /// Conversation the message was posted in
+    pub conversation_id: String,
+    /// Node of the sender
+    pub sender_id: String,
+    /// Message text
+    pub content: String,
+    /// When the message was sent, in seconds
+    pub timestamp: u64,
+    /// When the message was sent, in milliseconds; orders the conversation
+    pub sent_at_ms: u64,
+    /// 1 once read, 0 otherwise: by the viewer for others' messages, by
+    /// anyone else for the viewer's own
+    pub read_status: i64,
+    /// Whether a file is attached
+    pub has_attachment: bool,
+    /// Path to the attached file
+    pub attachment_path: Option<String>,
+}
+
+/// HTTP API response types, serialized as `{"<variant>": {...}}`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum ApiResponse {
-    /// Success response with optional data
+    /// Response with message history
+    History {
+        messages: Vec<MessageLog>,
+    },
+    /// Response with message counts
+    MessageCounts {
+        counts: HashMap<String, usize>,
+    },
+    /// Status response
+    Status {
+        connected_clients: usize,
+        message_count: usize,
+        message_counts_by_channel: HashMap<String, usize>,
+        /// Latest migration applied to the database
+        schema_version: i64,
+    },
+    /// Contacts in the address book
+    Contacts {
+        contacts: Vec<Contact>,
+    },
+    /// A page of a user's conversations, most recently active first
+    Conversations {
+        conversations: Vec<Conversation>,
+        /// Cursor for the next page, if there is one
+        next_before_id: Option<String>,
+    },
+    /// A page of messages in a conversation, oldest first
+    Messages {
+        messages: Vec<ChatMessage>,
+        /// Cursor for the page of older messages, if there is one
+        next_before_id: Option<i64>,
+    },
+    /// Success response
     Success {
-        /// Response data (if any)
-        data: Option<serde_json::Value>,
+        message: String,
     },
-    /// Error response with message
+    /// Error response; `code` is also the HTTP status
     Error {
-        /// Error message
+        code: u16,
         message: String,
     },
 }
 
-/// WebSocket message for real-time updates
+/// WebSocket protocol, tagged by `type`
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct WebSocketMessage {
-    /// Type of message
-    pub message_type: String,
-    /// Message data
-    pub data: serde_json::Value,
-}
\ No newline at end of file
+#[serde(tag = "type")]
+pub enum WebSocketMessage {
+    /// Client identifies the node it acts for. Optional: every channel acts
+    /// for the node serving it from the moment it opens.
+    Auth {
+        node_address: String,
+    },
+    /// A message was posted in a conversation
+    ChatMessage {
+        /// Row id on the receiving node, for read receipts
+        id: i64,
+        conversation_id: String,
+        sender_id: String,
+        content: String,
+        timestamp: u64,
+        sent_at_ms: u64,
+        has_attachment: bool,
+        attachment_path: Option<String>,
+    },
+    /// A member started or stopped typing
+    TypingIndicator {
+        conversation_id: String,
+        user_id: String,
+        is_typing: bool,
+    },
+    /// Client read these messages
+    ReadReceipt {
+        message_ids: Vec<i64>,
+    },
+    /// Someone read messages in a conversation
+    MessagesRead {
+        conversation_id: String,
+        message_ids: Vec<i64>,
+        reader: String,
+    },
+    /// A contact went online or offline
+    StatusChange {
+        node_address: String,
+        status: String,
+    },
+    /// Server greeting on a new connection
+    Welcome {
+        message: String,
+        channel_id: u32,
+    },
+    /// Server accepted an `Auth`
+    AuthSuccess {
+        node_address: String,
+        timestamp: u64,
+    },
+    /// Server could not handle a client message
+    Error {
+        message: String,
+        timestamp: u64,
+    },
+}
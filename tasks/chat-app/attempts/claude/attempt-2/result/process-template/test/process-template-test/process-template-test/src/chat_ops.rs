@@ -0,0 +1,535 @@
+use crate::*;
+use crate::hyperware::process::process_template::{
+    ConversationInfo, DeliveredMessage, ReadReceipt, Request as NodeRequest,
+    Response as NodeResponse,
+};
+use hyperware_process_lib::http::client::send_request_await_response;
+use hyperware_process_lib::http::Method;
+use hyperware_process_lib::timer::set_and_await_timer;
+use hyperware_process_lib::Message;
+use shared_types::{ApiRequest, ApiResponse, ChatMessage, Conversation};
+use std::collections::HashMap;
+
+// Port client0, the master node, serves HTTP on; see tests.toml
+const MASTER_HTTP_PORT: u16 = 8080;
+const PROCESS_PATH: &str = "process-template:process-template:template.os";
+// How often, and how many times, to look for something another node sends
+const POLL_INTERVAL_MS: u64 = 500;
+const POLL_ATTEMPTS: u32 = 20;
+
+/// Run the chat tests: client0 is driven directly, the other clients through
+/// the test process on their nodes
+pub fn run_chat_tests(log_file: &mut File, client_addresses: &Vec<Address>) -> anyhow::Result<()> {
+    if client_addresses.len() < 3 {
+        return Err(anyhow::anyhow!("Chat tests need three clients"));
+    }
+    let node0 = client_addresses[0].node();
+    let node1 = client_addresses[1].node();
+    let node2 = client_addresses[2].node();
+    if node0 != our().node() {
+        return Err(anyhow::anyhow!("Chat tests must run on {}, not {}", node0, our().node()));
+    }
+
+    let conversation_id = test_node_delivery(log_file, node0, node1)?;
+    test_member_updates(log_file, node0, node1, node2)?;
+    test_http_routes(log_file)?;
+    test_websocket_events(log_file, node0)?;
+    test_shared_types(log_file, node0, node1, &conversation_id)?;
+    test_null_columns(log_file, node0, node1, &conversation_id)?;
+    test_migrations(log_file, node0, node1)?;
+    test_cascading_deletes(log_file, node0)?;
+    test_read_receipts(log_file, node0, node1, &conversation_id)?;
+    test_pagination(log_file, node0, &conversation_id)?;
+    test_identity_rejection(log_file, node1)?;
+
+    write_log(log_file, "All chat tests passed")?;
+    Ok(())
+}
+
+/// Forward an API request from the master node's test to our node's process,
+/// which only takes them from local processes holding its messaging capability
+pub fn proxy_api_request(message: &Message) -> anyhow::Result<()> {
+    let target = chat_address(our().node());
+    let response = Request::to(target)
+        .body(message.body().to_vec())
+        .attach_messaging()
+        .send_and_await_response(10)??;
+    Response::new()
+        .body(response.body().to_vec())
+        .send()?;
+    Ok(())
+}
+
+// A message sent on client0 is stored on client1, by client0
+fn test_node_delivery(log_file: &mut File, node0: &str, node1: &str) -> anyhow::Result<String> {
+    write_log(log_file, "Testing node delivery")?;
+
+    let conversation_id = created_id(api(node0, &ApiRequest::StartConversation {
+        name: String::new(),
+        participants: vec![node1.to_string()],
+    })?)?;
+    send_message(node0, &conversation_id, "hello from client0")?;
+
+    let messages = poll(|| {
+        let messages = get_messages(node1, &conversation_id, None, None)?.0;
+        Ok(Some(messages).filter(|messages| !messages.is_empty()))
+    })?;
+    let received: Vec<(&str, &str)> = messages
+        .iter()
+        .map(|message| (message.sender_id.as_str(), message.content.as_str()))
+        .collect();
+    if received != vec![(node0, "hello from client0")] {
+        return Err(anyhow::anyhow!("{} received {:?}", node1, received));
+    }
+
+    write_log(log_file, "Node delivery test passed")?;
+    Ok(conversation_id)
+}
+
+// A member the admin adds hears of the conversation, and the members who
+// were there take its messages; members who aren't admins can't add anyone
+fn test_member_updates(log_file: &mut File, node0: &str, node1: &str, node2: &str) -> anyhow::Result<()> {
+    write_log(log_file, "Testing member updates")?;
+
+    let conversation_id = created_id(api(node0, &ApiRequest::StartConversation {
+        name: "Member test".to_string(),
+        participants: vec![node1.to_string()],
+    })?)?;
+    send_message(node0, &conversation_id, "welcome")?;
+    poll(|| find_conversation(node1, &conversation_id))?;
+
+    let add_node2 = ApiRequest::AddMember {
+        conversation_id: conversation_id.clone(),
+        node_address: node2.to_string(),
+    };
+    match api(node1, &add_node2)? {
+        ApiResponse::Error { code: 403, .. } => {},
+        response => return Err(anyhow::anyhow!("{} added a member without being admin: {:?}", node1, response)),
+    }
+    expect_success(api(node0, &add_node2)?)?;
+
+    poll(|| find_conversation(node2, &conversation_id))?;
+    send_message(node2, &conversation_id, "hello from client2")?;
+    poll(|| {
+        let messages = get_messages(node1, &conversation_id, None, None)?.0;
+        Ok(messages.into_iter().find(|message| message.sender_id == node2))
+    })?;
+
+    write_log(log_file, "Member update tests passed")?;
+    Ok(())
+}
+
+// Only the liveness check answers without logging in
+fn test_http_routes(log_file: &mut File) -> anyhow::Result<()> {
+    write_log(log_file, "Testing HTTP routes")?;
+
+    let (status, body) = http_get("/api", HashMap::new())?;
+    let body: serde_json::Value = serde_json::from_slice(&body)?;
+    if status != 200 || body["status"] != "ok" {
+        return Err(anyhow::anyhow!("GET /api returned {} {}", status, body));
+    }
+    for path in ["/api/status", "/api/contacts", "/api/conversations"] {
+        let (status, _) = http_get(path, HashMap::new())?;
+        if status != 401 && status != 403 {
+            return Err(anyhow::anyhow!("GET {} without logging in returned {}", path, status));
+        }
+    }
+
+    write_log(log_file, "HTTP route tests passed")?;
+    Ok(())
+}
+
+// WebSocket upgrades go through the http-server's authentication, and no
+// channel is left behind by one that was refused
+fn test_websocket_events(log_file: &mut File, node0: &str) -> anyhow::Result<()> {
+    write_log(log_file, "Testing WebSocket events")?;
+
+    let headers = HashMap::from([
+        ("Connection".to_string(), "Upgrade".to_string()),
+        ("Upgrade".to_string(), "websocket".to_string()),
+        ("Sec-WebSocket-Version".to_string(), "13".to_string()),
+        ("Sec-WebSocket-Key".to_string(), "dGhlIHNhbXBsZSBub25jZQ==".to_string()),
+    ]);
+    let (status, _) = http_get("/ws", headers)?;
+    if status == 101 {
+        return Err(anyhow::anyhow!("WebSocket opened without logging in"));
+    }
+    let ApiResponse::Status { connected_clients, .. } = api(node0, &ApiRequest::GetStatus)? else {
+        return Err(anyhow::anyhow!("GetStatus did not return a status"));
+    };
+    if connected_clients != 0 {
+        return Err(anyhow::anyhow!("{} WebSocket clients connected", connected_clients));
+    }
+
+    write_log(log_file, "WebSocket event tests passed")?;
+    Ok(())
+}
+
+// Both nodes answer in the shared types the test is built with, and each
+// stores the delivered message under an id of its own with its send time
+fn test_shared_types(log_file: &mut File, node0: &str, node1: &str, conversation_id: &str) -> anyhow::Result<()> {
+    write_log(log_file, "Testing shared types")?;
+
+    for node in [node0, node1] {
+        match api(node, &ApiRequest::ListContacts)? {
+            ApiResponse::Contacts { .. } => {},
+            response => return Err(anyhow::anyhow!("{} listed contacts as {:?}", node, response)),
+        }
+        let messages = get_messages(node, conversation_id, None, None)?.0;
+        for message in &messages {
+            if message.id.is_none() || message.timestamp != message.sent_at_ms / 1000 {
+                return Err(anyhow::anyhow!("{} returned {:?}", node, message));
+            }
+        }
+    }
+
+    write_log(log_file, "Shared types tests passed")?;
+    Ok(())
+}
+
+// Columns left NULL come back as None on both nodes rather than failing the
+// whole listing
+fn test_null_columns(log_file: &mut File, node0: &str, node1: &str, conversation_id: &str) -> anyhow::Result<()> {
+    write_log(log_file, "Testing NULL columns")?;
+
+    for node in [node0, node1] {
+        let conversation = find_conversation(node, conversation_id)?
+            .ok_or_else(|| anyhow::anyhow!("{} has no conversation {}", node, conversation_id))?;
+        if conversation.title.is_some() {
+            return Err(anyhow::anyhow!("untitled conversation has title {:?} on {}", conversation.title, node));
+        }
+        for message in get_messages(node, conversation_id, None, None)?.0 {
+            if message.has_attachment || message.attachment_path.is_some() {
+                return Err(anyhow::anyhow!("message without attachment has {:?} on {}", message.attachment_path, node));
+            }
+        }
+    }
+
+    write_log(log_file, "NULL column tests passed")?;
+    Ok(())
+}
+
+// Both nodes migrated their fresh databases to the same version
+fn test_migrations(log_file: &mut File, node0: &str, node1: &str) -> anyhow::Result<()> {
+    write_log(log_file, "Testing migrations")?;
+
+    let mut versions = Vec::new();
+    for node in [node0, node1] {
+        let ApiResponse::Status { schema_version, .. } = api(node, &ApiRequest::GetStatus)? else {
+            return Err(anyhow::anyhow!("{} did not return a status", node));
+        };
+        versions.push(schema_version);
+    }
+    if versions[0] < 1 || versions[0] != versions[1] {
+        return Err(anyhow::anyhow!("nodes are at schema versions {:?}", versions));
+    }
+
+    write_log(log_file, "Migration tests passed")?;
+    Ok(())
+}
+
+// Deleting a contact takes its direct conversations with it
+fn test_cascading_deletes(log_file: &mut File, node0: &str) -> anyhow::Result<()> {
+    write_log(log_file, "Testing cascading deletes")?;
+
+    // A node that never answers, so nothing else is affected
+    let contact_node = "cascade-test.os";
+    expect_success(api(node0, &ApiRequest::CreateContact {
+        name: "Cascade".to_string(),
+        address: contact_node.to_string(),
+    })?)?;
+    let conversation_id = created_id(api(node0, &ApiRequest::StartConversation {
+        name: String::new(),
+        participants: vec![contact_node.to_string()],
+    })?)?;
+    if find_conversation(node0, &conversation_id)?.is_none() {
+        return Err(anyhow::anyhow!("conversation {} was not created", conversation_id));
+    }
+
+    let ApiResponse::Contacts { contacts } = api(node0, &ApiRequest::ListContacts)? else {
+        return Err(anyhow::anyhow!("ListContacts did not return contacts"));
+    };
+    let id = contacts
+        .iter()
+        .find(|contact| contact.node_address == contact_node)
+        .and_then(|contact| contact.id)
+        .ok_or_else(|| anyhow::anyhow!("contact {} was not added", contact_node))?;
+    expect_success(api(node0, &ApiRequest::DeleteContact { id })?)?;
+
+    if find_conversation(node0, &conversation_id)?.is_some() {
+        return Err(anyhow::anyhow!("conversation {} outlived its contact", conversation_id));
+    }
+
+    write_log(log_file, "Cascading delete tests passed")?;
+    Ok(())
+}
+
+// A message client1 reads is unread for client1 until then, and
+// client0 hears about it
+fn test_read_receipts(log_file: &mut File, node0: &str, node1: &str, conversation_id: &str) -> anyhow::Result<()> {
+    write_log(log_file, "Testing read receipts")?;
+
+    let unread = |node: &str| -> anyhow::Result<i64> {
+        find_conversation(node, conversation_id)?
+            .map(|conversation| conversation.unread_count)
+            .ok_or_else(|| anyhow::anyhow!("{} has no conversation {}", node, conversation_id))
+    };
+    if unread(node1)? != 1 || unread(node0)? != 0 {
+        return Err(anyhow::anyhow!("unread counts before reading are {} and {}", unread(node0)?, unread(node1)?));
+    }
+
+    let message = get_messages(node1, conversation_id, None, None)?
+        .0
+        .into_iter()
+        .find(|message| message.sender_id == node0)
+        .ok_or_else(|| anyhow::anyhow!("{} has no message from {}", node1, node0))?;
+    let message_id = message.id.ok_or_else(|| anyhow::anyhow!("message has no id"))?;
+    expect_success(api(node1, &ApiRequest::MarkRead { message_id })?)?;
+    if unread(node1)? != 0 {
+        return Err(anyhow::anyhow!("{} still has unread messages", node1));
+    }
+
+    poll(|| {
+        let messages = get_messages(node0, conversation_id, None, None)?.0;
+        Ok(messages
+            .into_iter()
+            .find(|message| message.sender_id == node0 && message.read_status == 1))
+    })?;
+
+    write_log(log_file, "Read receipt tests passed")?;
+    Ok(())
+}
+
+// Pages follow their cursors back through the whole listing, each item once
+// and in order; cursors that lead nowhere are refused
+fn test_pagination(log_file: &mut File, node0: &str, conversation_id: &str) -> anyhow::Result<()> {
+    write_log(log_file, "Testing pagination")?;
+
+    // Sent together, so several share a second
+    let sent: Vec<String> = (0..5).map(|n| format!("page test {}", n)).collect();
+    for content in &sent {
+        send_message(node0, conversation_id, content)?;
+    }
+
+    let mut pages = Vec::new();
+    let mut before_id = None;
+    loop {
+        let (messages, next) = get_messages(node0, conversation_id, before_id, Some(2))?;
+        if messages.len() > 2 {
+            return Err(anyhow::anyhow!("page of 2 has {} messages", messages.len()));
+        }
+        pages.push(messages);
+        match next {
+            Some(next) if Some(next) == before_id => {
+                return Err(anyhow::anyhow!("cursor {} repeats", next));
+            },
+            Some(next) => before_id = Some(next),
+            None => break,
+        }
+    }
+    // Pages run newest to oldest, messages within a page oldest to newest
+    let messages: Vec<ChatMessage> = pages.into_iter().rev().flatten().collect();
+    let contents: Vec<&str> = messages.iter().map(|message| message.content.as_str()).collect();
+    let expected: Vec<&str> = sent.iter().map(|content| content.as_str()).collect();
+    if !contents.ends_with(&expected) {
+        return Err(anyhow::anyhow!("paged through {:?}", contents));
+    }
+    if messages.windows(2).any(|pair| pair[0].sent_at_ms > pair[1].sent_at_ms) {
+        return Err(anyhow::anyhow!("messages out of order: {:?}", contents));
+    }
+    let (all, _) = get_messages(node0, conversation_id, None, Some(100))?;
+    if all.len() != messages.len() {
+        return Err(anyhow::anyhow!("pages hold {} messages, the conversation {}", messages.len(), all.len()));
+    }
+
+    // Conversations page by (activity, id), one at a time here
+    let mut listed = Vec::new();
+    let mut before_id = None;
+    loop {
+        let response = api(node0, &ApiRequest::ListConversations { before_id: before_id.clone(), limit: Some(1) })?;
+        let ApiResponse::Conversations { conversations, next_before_id } = response else {
+            return Err(anyhow::anyhow!("ListConversations returned {:?}", response));
+        };
+        if conversations.len() > 1 {
+            return Err(anyhow::anyhow!("page of 1 has {} conversations", conversations.len()));
+        }
+        listed.extend(conversations.into_iter().map(|conversation| conversation.id));
+        match next_before_id {
+            Some(next) if Some(&next) == before_id.as_ref() => {
+                return Err(anyhow::anyhow!("cursor {} repeats", next));
+            },
+            Some(next) => before_id = Some(next),
+            None => break,
+        }
+    }
+    let ApiResponse::Conversations { conversations, .. } =
+        api(node0, &ApiRequest::ListConversations { before_id: None, limit: Some(100) })?
+    else {
+        return Err(anyhow::anyhow!("ListConversations did not return conversations"));
+    };
+    let all: Vec<String> = conversations.into_iter().map(|conversation| conversation.id).collect();
+    if listed != all {
+        return Err(anyhow::anyhow!("paged through {:?}, listed {:?}", listed, all));
+    }
+    // The conversation just written to is the most recently active
+    if all.first().map(|id| id.as_str()) != Some(conversation_id) {
+        return Err(anyhow::anyhow!("{} is not listed first in {:?}", conversation_id, all));
+    }
+
+    let malformed = ApiRequest::ListConversations { before_id: Some("not-a-cursor".to_string()), limit: None };
+    match api(node0, &malformed)? {
+        ApiResponse::Error { code: 400, .. } => {},
+        response => return Err(anyhow::anyhow!("malformed cursor returned {:?}", response)),
+    }
+    let unknown = ApiRequest::GetMessages {
+        conversation_id: conversation_id.to_string(),
+        before_id: Some(i64::MAX),
+        limit: None,
+    };
+    match api(node0, &unknown)? {
+        ApiResponse::Error { code: 404, .. } => {},
+        response => return Err(anyhow::anyhow!("unknown message cursor returned {:?}", response)),
+    }
+
+    write_log(log_file, "Pagination tests passed")?;
+    Ok(())
+}
+
+// Node requests are taken only from our process on the sending node, and API
+// requests only from local processes holding the messaging capability
+fn test_identity_rejection(log_file: &mut File, node1: &str) -> anyhow::Result<()> {
+    write_log(log_file, "Testing identity rejection")?;
+
+    let target = chat_address(node1);
+    let conversation = ConversationInfo {
+        id: "forged".to_string(),
+        kind: "direct".to_string(),
+        title: None,
+        members: vec![our().node().to_string(), node1.to_string()],
+    };
+    let forged = [
+        NodeRequest::DeliverMessage(DeliveredMessage {
+            uid: "forged".to_string(),
+            conversation: conversation.clone(),
+            content: "forged".to_string(),
+            sent_at_ms: 0,
+            has_attachment: false,
+            attachment_path: None,
+        }),
+        NodeRequest::ReadReceipt(ReadReceipt {
+            conversation_id: "forged".to_string(),
+            message_uids: vec!["forged".to_string()],
+            read_at: 0,
+        }),
+        NodeRequest::UpdateMembers(conversation),
+    ];
+    for request in forged {
+        let response = Request::to(target.clone())
+            .body(serde_json::to_vec(&request)?)
+            .send_and_await_response(10)??;
+        match serde_json::from_slice::<NodeResponse>(response.body())? {
+            NodeResponse::Err(_) => {},
+            response => return Err(anyhow::anyhow!("{:?} from the test process was accepted: {:?}", request, response)),
+        }
+    }
+
+    // Without the messaging capability attached
+    let response = Request::to(chat_address(our().node()))
+        .body(serde_json::to_vec(&ApiRequest::ListConversations { before_id: None, limit: None })?)
+        .send_and_await_response(10)??;
+    match serde_json::from_slice::<ApiResponse>(response.body())? {
+        ApiResponse::Error { code: 403, .. } => {},
+        response => return Err(anyhow::anyhow!("API request without capability returned {:?}", response)),
+    }
+
+    write_log(log_file, "Identity rejection tests passed")?;
+    Ok(())
+}
+
+// Make an API request of the process on `node`: our own directly, another
+// node's through the test process there
+fn api(node: &str, request: &ApiRequest) -> anyhow::Result<ApiResponse> {
+    let request = Request::new().body(serde_json::to_vec(request)?);
+    let request = if node == our().node() {
+        request
+            .target(chat_address(node))
+            .attach_messaging()
+    } else {
+        request.target(Address::new(node, our().process.clone()))
+    };
+    let response = request.send_and_await_response(15)??;
+    Ok(serde_json::from_slice(response.body())?)
+}
+
+fn chat_address(node: &str) -> Address {
+    (node, "process-template", "process-template", "template.os").into()
+}
+
+fn send_message(node: &str, conversation_id: &str, content: &str) -> anyhow::Result<()> {
+    expect_success(api(node, &ApiRequest::SendMessage {
+        conversation_id: conversation_id.to_string(),
+        content: content.to_string(),
+    })?)
+}
+
+fn get_messages(
+    node: &str,
+    conversation_id: &str,
+    before_id: Option<i64>,
+    limit: Option<u32>,
+) -> anyhow::Result<(Vec<ChatMessage>, Option<i64>)> {
+    match api(node, &ApiRequest::GetMessages {
+        conversation_id: conversation_id.to_string(),
+        before_id,
+        limit,
+    })? {
+        ApiResponse::Messages { messages, next_before_id } => Ok((messages, next_before_id)),
+        response => Err(anyhow::anyhow!("{} returned {:?} for messages", node, response)),
+    }
+}
+
+fn find_conversation(node: &str, conversation_id: &str) -> anyhow::Result<Option<Conversation>> {
+    match api(node, &ApiRequest::ListConversations { before_id: None, limit: Some(100) })? {
+        ApiResponse::Conversations { conversations, .. } => Ok(conversations
+            .into_iter()
+            .find(|conversation| conversation.id == conversation_id)),
+        response => Err(anyhow::anyhow!("{} returned {:?} for conversations", node, response)),
+    }
+}
+
+fn expect_success(response: ApiResponse) -> anyhow::Result<()> {
+    match response {
+        ApiResponse::Success { .. } => Ok(()),
+        response => Err(anyhow::anyhow!("expected success, got {:?}", response)),
+    }
+}
+
+// Creations answer "... with ID: <id>"
+fn created_id(response: ApiResponse) -> anyhow::Result<String> {
+    match response {
+        ApiResponse::Success { message } => message
+            .rsplit(' ')
+            .next()
+            .map(|id| id.to_string())
+            .ok_or_else(|| anyhow::anyhow!("no id in {}", message)),
+        response => Err(anyhow::anyhow!("expected success, got {:?}", response)),
+    }
+}
+
+// Retry `check` until it finds something, for what arrives from another node
+fn poll<T>(mut check: impl FnMut() -> anyhow::Result<Option<T>>) -> anyhow::Result<T> {
+    for _ in 0..POLL_ATTEMPTS {
+        if let Some(found) = check()? {
+            return Ok(found);
+        }
+        set_and_await_timer(POLL_INTERVAL_MS)?;
+    }
+    Err(anyhow::anyhow!("nothing arrived after {} ms", POLL_INTERVAL_MS * POLL_ATTEMPTS as u64))
+}
+
+// GET a path of the process on the master node, as a browser that hasn't logged in
+fn http_get(path: &str, headers: HashMap<String, String>) -> anyhow::Result<(u16, Vec<u8>)> {
+    let url = format!("http://localhost:{}/{}{}", MASTER_HTTP_PORT, PROCESS_PATH, path);
+    let response = send_request_await_response(Method::GET, url.parse()?, Some(headers), 10, vec![])?;
+    Ok((response.status().as_u16(), response.body().clone()))
+}
@@ -1,5 +1,5 @@
 use crate::hyperware::process::tester::{Request as TesterRequest, Response as TesterResponse, RunRequest, FailResponse};
-use hyperware_process_lib::{await_message, call_init, print_to_terminal, println, Address, ProcessId, Request, Response, kiprintln,
+use hyperware_process_lib::{await_message, call_init, print_to_terminal, println, Address, Message, ProcessId, Request, Response, kiprintln,
     http::server::{
         send_response, HttpServer, HttpServerRequest, StatusCode, send_ws_push, WsMessageType,
     },
@@ -11,9 +11,11 @@ mod utils;
 mod client_ops;
 mod tester_lib;
 mod hyperchat_ops;
+mod chat_ops;
 
 use utils::*;
 use client_ops::*;
+use chat_ops::{proxy_api_request, run_chat_tests};
 
 wit_bindgen::generate!({
     path: "target/wit",
@@ -24,7 +26,20 @@ wit_bindgen::generate!({
 fn handle_message(log_file: &mut File) -> anyhow::Result<()> {
     kiprintln!("handle_message called");
     
-    match run_tests(log_file) {
+    let message = match await_message() {
+        Ok(msg) => msg,
+        Err(e) => {
+            kiprintln!("Error awaiting message: {:?}", e);
+            return Err(anyhow::anyhow!("Error awaiting message: {:?}", e));
+        }
+    };
+    
+    // The master node's test drives this node's process through us
+    if message.is_request() && message.source().node != our().node && message.source().process == our().process {
+        return proxy_api_request(&message);
+    }
+    
+    match run_tests(&message, log_file) {
         Ok(_) => {
             kiprintln!("Tests completed successfully");
             write_log(log_file, "Tests completed successfully")?;
@@ -36,23 +51,17 @@ fn handle_message(log_file: &mut File) -> anyhow::Result<()> {
         },
         Err(e) => {
             kiprintln!("Error running tests: {:?}", e);
+            write_log(log_file, &format!("Tests failed: {:?}", e))?;
+            fail!("process-template-test");
         }
     }
 
     kiprintln!("handle_message completed");
     Ok(())
 }
-fn init_tests(our: Address) -> anyhow::Result<Vec<String>> {
+fn init_tests(our: Address, message: &Message) -> anyhow::Result<Vec<String>> {
     kiprintln!("Init tests called with our address: {}", our);
     
-    let message = match await_message() {
-        Ok(msg) => msg,
-        Err(e) => {
-            kiprintln!("Error awaiting message: {:?}", e);
-            return Err(anyhow::anyhow!("Error awaiting message: {:?}", e));
-        }
-    };
-    
     if !message.is_request() {
         kiprintln!("Received message is not a request");
         fail!("received-non-request");
@@ -100,8 +109,8 @@ fn init_tests(our: Address) -> anyhow::Result<Vec<String>> {
     Ok(node_names)
 }
 
-fn run_tests(log_file: &mut File) -> anyhow::Result<()> {
-    let client_node_names = init_tests(our())?;
+fn run_tests(message: &Message, log_file: &mut File) -> anyhow::Result<()> {
+    let client_node_names = init_tests(our(), message)?;
     write_log(log_file, &format!("Found client nodes: {:?}", client_node_names))?;
     
     if client_node_names.is_empty() {
@@ -118,6 +127,11 @@ fn run_tests(log_file: &mut File) -> anyhow::Result<()> {
     write_log(log_file, "----------------------------------------")?;
     write_log(log_file, "Done running client operations")?;
 
+    write_log(log_file, "Starting chat tests")?;
+    run_chat_tests(log_file, &client_addresses)?;
+    write_log(log_file, "----------------------------------------")?;
+    write_log(log_file, "Done running chat tests")?;
+
     Ok(())
 }
 
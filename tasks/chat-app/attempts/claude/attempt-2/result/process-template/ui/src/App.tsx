@@ -1,7 +1,7 @@
 import { useEffect, useState } from 'react';
 import './App.css';
-import { fetchStatus, fetchContacts, fetchConversations, fetchMessages, sendMessage, addContact, createConversation } from './utilities/api';
-import { setupWebSocket } from './utilities/websocket';
+import { fetchStatus, fetchContacts, fetchConversations, fetchMessages, sendMessage, addContact, createConversation, markMessageRead } from './utilities/api';
+import { setupWebSocket, sendWebSocketMessage } from './utilities/websocket';
 import { Contact, Conversation, ChatMessage } from './types/types';
 import ContactsList from './components/ContactsList';
 import ConversationsList from './components/ConversationsList';
@@ -25,14 +25,13 @@ function App() {
   useEffect(() => {
     // In Hyperware, we can use the 'our.js' file to get our node info
     // The global 'our' object is added to window by Hyperware
-    if (window.our && window.our.address) {
-      // Format: node@app.publish.domain
-      const addr = window.our.address;
-      console.log('Got node address from Hyperware:', addr);
-      setNodeAddress(addr);
+    if (window.our && window.our.node) {
+      // The server acts as this node and refuses any other identity
+      const node = window.our.node;
+      console.log('Got node from Hyperware:', node);
+      setNodeAddress(node);
     } else {
-      console.warn('Could not get node address from Hyperware, using fallback');
-      setNodeAddress(`node-${Math.floor(Math.random() * 10000)}.example.os`);
+      console.error('Could not get node from Hyperware; is /our.js loaded?');
     }
   }, []);
 
@@ -47,36 +46,51 @@ function App() {
     
     const ws = setupWebSocket({
       onOpen: () => {
+        // The connection is authenticated by the node, which registers it as
+        // our node when it opens
         console.log('WebSocket connected');
         setConnected(true);
-        
-        // Authenticate with node address
-        // Match the expected WebSocketMessage::Auth format
-        ws.send(JSON.stringify({
-          type: 'Auth',
-          node_address: nodeAddress
-        }));
       },
       onMessage: (data) => {
         console.log('WebSocket message:', data);
         
         // Handle different message types
-        if (data.type === 'welcome' || data.type === 'auth_success') {
+        if (data.type === 'Welcome' || data.type === 'AuthSuccess') {
           console.log('Successfully connected to WebSocket server:', data);
         }
         else if (data.type === 'ChatMessage' && data.conversation_id === selectedConversation?.id) {
-          // Add new message to the list
-          setMessages(prev => [...prev, {
-            id: null, // ID will be assigned by server
+          const message: ChatMessage = {
+            id: data.id,
             conversation_id: data.conversation_id,
             sender_id: data.sender_id,
             content: data.content,
             timestamp: data.timestamp,
+            sent_at_ms: data.sent_at_ms,
             read_status: 0,
             has_attachment: data.has_attachment || false,
             attachment_path: data.attachment_path
-          }]);
-        } else if (data.type === 'status_change' || data.type === 'StatusChange') {
+          };
+          setMessages(prev => {
+            if (prev.some(existing => existing.id === message.id)) {
+              return prev;
+            }
+            // Our own message, shown while it was being sent, now has its id
+            const pending = prev.findIndex(existing =>
+              existing.id === null && existing.sender_id === message.sender_id && existing.content === message.content
+            );
+            if (pending !== -1) {
+              return prev.map((existing, index) => index === pending ? message : existing);
+            }
+            return [...prev, message];
+          });
+        } else if (data.type === 'MessagesRead' && data.conversation_id === selectedConversation?.id) {
+          // Mark messages as read
+          setMessages(prev => prev.map(message =>
+            message.id !== null && data.message_ids.includes(message.id)
+              ? { ...message, read_status: 1 }
+              : message
+          ));
+        } else if (data.type === 'StatusChange') {
           // Update contact status
           setContacts(prev => prev.map(contact => 
             contact.node_address === data.node_address 
@@ -120,8 +134,8 @@ function App() {
         setContacts(contactsData);
         
         // Get conversations
-        const conversationsData = await fetchConversations(nodeAddress);
-        setConversations(conversationsData);
+        const conversationsPage = await fetchConversations();
+        setConversations(conversationsPage.items);
       } catch (error) {
         console.error('Error loading initial data:', error);
       }
@@ -134,8 +148,8 @@ function App() {
   useEffect(() => {
     if (selectedConversation) {
       fetchMessages(selectedConversation.id)
-        .then(messagesData => {
-          setMessages(messagesData);
+        .then(messagesPage => {
+          setMessages(messagesPage.items);
         })
         .catch(error => {
           console.error('Error loading messages:', error);
@@ -145,6 +159,40 @@ function App() {
     }
   }, [selectedConversation]);
 
+  // Messages from others on screen are read; tell the node, which tells their senders
+  useEffect(() => {
+    if (!selectedConversation) {
+      return;
+    }
+    const unread = messages
+      .filter(message => message.id !== null && message.sender_id !== nodeAddress && message.read_status === 0)
+      .map(message => message.id as number);
+    if (unread.length === 0) {
+      return;
+    }
+    
+    if (socket && connected) {
+      sendWebSocketMessage(socket, 'ReadReceipt', { message_ids: unread });
+    } else {
+      unread.forEach(messageId => {
+        markMessageRead(messageId).catch(error => {
+          console.error('Error marking message read:', error);
+        });
+      });
+    }
+    
+    setMessages(prev => prev.map(message =>
+      message.id !== null && unread.includes(message.id)
+        ? { ...message, read_status: 1 }
+        : message
+    ));
+    setConversations(prev => prev.map(conversation =>
+      conversation.id === selectedConversation.id
+        ? { ...conversation, unread_count: 0 }
+        : conversation
+    ));
+  }, [messages, selectedConversation, socket, connected, nodeAddress]);
+
   const handleContactSelect = (contact: Contact) => {
     setSelectedContact(contact);
     
@@ -166,12 +214,12 @@ function App() {
       })
         .then(response => {
           // Refresh conversations
-          return fetchConversations(nodeAddress);
+          return fetchConversations();
         })
-        .then(conversationsData => {
-          setConversations(conversationsData);
+        .then(conversationsPage => {
+          setConversations(conversationsPage.items);
           // Find the newly created conversation
-          const newConversation = conversationsData.find(conv => 
+          const newConversation = conversationsPage.items.find(conv => 
             conv.type_ === 'direct' && 
             conv.id.includes(contact.node_address) && 
             conv.id.includes(nodeAddress)
@@ -196,7 +244,6 @@ function App() {
     
     sendMessage({
       conversation_id: selectedConversation.id,
-      sender_id: nodeAddress,
       content: newMessage,
       has_attachment: false,
       attachment_path: null
@@ -209,6 +256,7 @@ function App() {
           sender_id: nodeAddress,
           content: newMessage,
           timestamp: Date.now() / 1000,
+          sent_at_ms: Date.now(),
           read_status: 0,
           has_attachment: false,
           attachment_path: null
@@ -218,10 +266,10 @@ function App() {
         setNewMessage('');
         
         // Refresh conversations to update last message
-        return fetchConversations(nodeAddress);
+        return fetchConversations();
       })
-      .then(conversationsData => {
-        setConversations(conversationsData);
+      .then(conversationsPage => {
+        setConversations(conversationsPage.items);
       })
       .catch(error => {
         console.error('Error sending message:', error);
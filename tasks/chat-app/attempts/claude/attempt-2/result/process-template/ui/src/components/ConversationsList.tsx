@@ -12,10 +12,11 @@ const ConversationsList: React.FC<ConversationsListProps> = ({
   selectedConversation, 
   onSelectConversation 
 }) => {
-  const formatTime = (timestamp: number | undefined | null) => {
-    if (!timestamp) return '';
+  // Conversation activity is in milliseconds
+  const formatTime = (timestampMs: number | undefined | null) => {
+    if (!timestampMs) return '';
     
-    const date = new Date(timestamp * 1000);
+    const date = new Date(timestampMs);
     return new Intl.DateTimeFormat('en-US', {
       hour: '2-digit',
       minute: '2-digit',
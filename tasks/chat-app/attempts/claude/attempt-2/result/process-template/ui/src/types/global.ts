@@ -4,7 +4,6 @@ declare module "global" {
       our: {
         node: string;
         process: string;
-        address: string;  // Added address property which contains the full node address
       };
     }
   }
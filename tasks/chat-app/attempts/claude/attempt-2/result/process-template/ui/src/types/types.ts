@@ -5,6 +5,7 @@ export type ApiResponse = {
     connected_clients: number;
     message_count: number;
     message_counts_by_channel: Record<string, number>;
+    schema_version: number;
   };
   success?: {
     message: string;
@@ -17,12 +18,18 @@ export type ApiResponse = {
   // Data responses
   contacts?: Contact[];
   contact?: Contact;
-  conversations?: Conversation[];
+  conversations?: { conversations: Conversation[]; next_before_id: string | null };
   conversation_detail?: {
     conversation: Conversation;
     members: ConversationMember[];
   };
-  messages?: ChatMessage[];
+  messages?: { messages: ChatMessage[]; next_before_id: number | null };
+};
+
+// One page of a paginated listing; pass `next_before_id` back to get the next
+export interface Page<T, Cursor> {
+  items: T[];
+  next_before_id: Cursor | null;
 };
 
 // Contact model
@@ -63,6 +70,7 @@ export interface ChatMessage {
   sender_id: string;
   content: string;
   timestamp: number;
+  sent_at_ms: number;
   read_status: number;
   has_attachment: boolean;
   attachment_path?: string | null;
@@ -71,7 +79,11 @@ export interface ChatMessage {
 // WebSocket message types
 export type WebSocketMessage = 
   | { type: 'Auth'; node_address: string }
-  | { type: 'ChatMessage'; conversation_id: string; sender_id: string; content: string; timestamp: number; has_attachment: boolean; attachment_path?: string | null }
+  | { type: 'ChatMessage'; id: number; conversation_id: string; sender_id: string; content: string; timestamp: number; sent_at_ms: number; has_attachment: boolean; attachment_path?: string | null }
   | { type: 'TypingIndicator'; conversation_id: string; user_id: string; is_typing: boolean }
   | { type: 'ReadReceipt'; message_ids: number[] }
-  | { type: 'StatusChange'; node_address: string; status: string };
\ No newline at end of file
+  | { type: 'MessagesRead'; conversation_id: string; message_ids: number[]; reader: string }
+  | { type: 'StatusChange'; node_address: string; status: string }
+  | { type: 'Welcome'; message: string; channel_id: number }
+  | { type: 'AuthSuccess'; node_address: string; timestamp: number }
+  | { type: 'Error'; message: string; timestamp: number };
\ No newline at end of file
@@ -1,4 +1,4 @@
-import { ApiResponse, Contact, Conversation, ChatMessage } from '../types/types';
+import { ApiResponse, Contact, Conversation, ChatMessage, Page } from '../types/types';
 
 // Use the base URL from the Vite config
 // This will properly prepend process name and package info for Hyperware
@@ -24,8 +24,9 @@ async function apiRequest<T>(
   const response = await fetch(`${API_BASE_URL}${endpoint}`, options);
   
   if (!response.ok) {
-    const errorData = await response.json();
-    throw new Error(errorData.message || 'API request failed');
+    // Errors arrive as { error: { code, message } }
+    const errorData = await response.json().catch(() => null);
+    throw new Error(errorData?.error?.message || 'API request failed');
   }
   
   return response.json();
@@ -57,14 +58,20 @@ export async function deleteContact(id: number): Promise<ApiResponse> {
 }
 
 // Conversation Management
-export async function fetchConversations(userAddress: string): Promise<Conversation[]> {
-  const response = await apiRequest<ApiResponse>(`/conversations?user=${userAddress}`);
+// Conversations are those of the node serving the UI
+export async function fetchConversations(
+  page: { before_id?: string; limit?: number } = {}
+): Promise<Page<Conversation, string>> {
+  const params = new URLSearchParams();
+  if (page.before_id) params.set('before_id', page.before_id);
+  if (page.limit) params.set('limit', String(page.limit));
+  const response = await apiRequest<ApiResponse>(`/conversations?${params}`);
   
-  if ('conversations' in response) {
-    return response.conversations;
+  if (response.conversations) {
+    return { items: response.conversations.conversations, next_before_id: response.conversations.next_before_id };
   }
   
-  return [];
+  return { items: [], next_before_id: null };
 }
 
 export async function createConversation(data: { 
@@ -87,19 +94,25 @@ export async function addConversationMember(
 }
 
 // Message Management
-export async function fetchMessages(conversationId: string): Promise<ChatMessage[]> {
-  const response = await apiRequest<ApiResponse>(`/conversations/${conversationId}/messages`);
+export async function fetchMessages(
+  conversationId: string,
+  page: { before_id?: number; limit?: number } = {}
+): Promise<Page<ChatMessage, number>> {
+  const params = new URLSearchParams();
+  if (page.before_id !== undefined) params.set('before_id', String(page.before_id));
+  if (page.limit) params.set('limit', String(page.limit));
+  const response = await apiRequest<ApiResponse>(`/conversations/${conversationId}/messages?${params}`);
   
-  if ('messages' in response) {
-    return response.messages;
+  if (response.messages) {
+    return { items: response.messages.messages, next_before_id: response.messages.next_before_id };
   }
   
-  return [];
+  return { items: [], next_before_id: null };
 }
 
+// The sender is always the node serving the UI
 export async function sendMessage(data: {
   conversation_id: string;
-  sender_id: string;
   content: string;
   has_attachment: boolean;
   attachment_path: string | null;